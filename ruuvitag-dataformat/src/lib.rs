@@ -1,3 +1,5 @@
+mod v3;
 mod v5;
 
+pub use v3::RuuviTagDataFormat3;
 pub use v5::RuuviTagDataFormat5;
@@ -0,0 +1,124 @@
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::fmt;
+
+use crate::v5::RuuviTagAccelaration;
+
+// https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_03.md
+#[derive(Debug, Clone, Copy, structview::View)]
+#[repr(C)]
+pub struct RuuviTagDataFormat3 {
+    humidity: u8,
+    temperature: u8,
+    temperature_fraction: u8,
+    atmospheric_pressure: structview::u16_be,
+    acceleration_x: structview::i16_be,
+    acceleration_y: structview::i16_be,
+    acceleration_z: structview::i16_be,
+    battery: structview::u16_be,
+}
+
+impl Serialize for RuuviTagDataFormat3 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("RuuviTagDataFormat3", 7)?;
+        state.serialize_field("temperature", &self.get_temperature())?;
+        state.serialize_field("humidity", &self.get_humidity())?;
+        state.serialize_field("atmospheric_pressure", &self.get_pressure())?;
+        state.serialize_field("acceleration", &self.get_accelaration())?;
+        state.serialize_field("acceleration_total", &self.get_accelaration().sqrt())?;
+        state.serialize_field("tilt", &self.get_accelaration().tilt())?;
+        state.serialize_field("powerinfo", &self.get_battery())?;
+        state.end()
+    }
+}
+
+impl RuuviTagDataFormat3 {
+    pub fn get_temperature(&self) -> f32 {
+        // first bit is the sign and the rest is the integer part of temperature
+        let temperature =
+            (self.temperature & 0b0111_1111) as f32 + self.temperature_fraction as f32 / 100.0;
+        if self.temperature & 0b1000_0000 != 0 {
+            -temperature
+        } else {
+            temperature
+        }
+    }
+
+    pub fn get_humidity(&self) -> f32 {
+        self.humidity as f32 / 2.0
+    }
+
+    pub fn get_pressure(&self) -> f32 {
+        (self.atmospheric_pressure.to_int() as f32 + 50000.0) / 100.0
+    }
+
+    pub fn get_accelaration(&self) -> RuuviTagAccelaration {
+        RuuviTagAccelaration::new(
+            self.acceleration_x.to_int() as f32,
+            self.acceleration_y.to_int() as f32,
+            self.acceleration_z.to_int() as f32,
+        )
+    }
+
+    pub fn get_battery(&self) -> u16 {
+        self.battery.to_int()
+    }
+}
+
+impl fmt::Display for RuuviTagDataFormat3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(temperature={:.2}\u{00B0}C, humidity={:.2}%, pressure={:.2}hPa, acceleration={}, battery={}mV)",
+            self.get_temperature(),
+            self.get_humidity(),
+            self.get_pressure(),
+            self.get_accelaration(),
+            self.get_battery())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    /*
+     * About test cases:
+     *
+     * https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_03.md
+     * outlines the test cases for valid, min and max values
+     */
+    use super::RuuviTagDataFormat3;
+    use structview::View;
+    #[test]
+    fn valid_values() {
+        let hex_string = "03291A1ECE1EFC18F94202CA0B53";
+        let data = hex::decode(hex_string).unwrap();
+        let beacon = RuuviTagDataFormat3::view(&data[1..]).unwrap();
+        assert_eq!(beacon.get_temperature(), 26.3);
+        assert_eq!(beacon.get_pressure(), 1027.66);
+        assert_eq!(beacon.get_humidity(), 20.5);
+        assert_eq!(beacon.get_battery(), 2899);
+    }
+
+    #[test]
+    fn min_values() {
+        let hex_string = "0300FF6300008001800180010000";
+        let data = hex::decode(hex_string).unwrap();
+        let beacon = RuuviTagDataFormat3::view(&data[1..]).unwrap();
+        assert_eq!(beacon.get_temperature(), -127.99);
+        assert_eq!(beacon.get_pressure(), 500.0);
+        assert_eq!(beacon.get_humidity(), 0.0);
+        assert_eq!(beacon.get_battery(), 0);
+    }
+
+    #[test]
+    fn max_values() {
+        let hex_string = "03FF7F63FFFF7FFF7FFF7FFFFFFF";
+        let data = hex::decode(hex_string).unwrap();
+        let beacon = RuuviTagDataFormat3::view(&data[1..]).unwrap();
+        assert_eq!(beacon.get_temperature(), 127.99);
+        assert_eq!(beacon.get_pressure(), 1155.35);
+        assert_eq!(beacon.get_humidity(), 127.5);
+        assert_eq!(beacon.get_battery(), 65535);
+    }
+}
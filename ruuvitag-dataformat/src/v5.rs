@@ -10,12 +10,38 @@ pub struct RuuviTagAccelaration {
 }
 
 impl RuuviTagAccelaration {
-    fn sqrt(&self) -> f32 {
+    pub(crate) fn new(on_x_axis: f32, on_y_axis: f32, on_z_axis: f32) -> RuuviTagAccelaration {
+        RuuviTagAccelaration {
+            on_x_axis,
+            on_y_axis,
+            on_z_axis,
+        }
+    }
+
+    fn magnitude(&self) -> f32 {
         (self.on_x_axis * self.on_x_axis
             + self.on_y_axis * self.on_y_axis
             + self.on_z_axis * self.on_z_axis)
             .sqrt()
-            .round()
+    }
+
+    /// Total acceleration in mG
+    pub fn sqrt(&self) -> f32 {
+        self.magnitude().round()
+    }
+
+    /// Angle in degrees between the z axis of the tag and the acceleration it measures, 0 when
+    /// lying face up and 90 when standing on its edge at rest. None in free fall
+    pub fn tilt(&self) -> Option<f32> {
+        let magnitude = self.magnitude();
+        if magnitude == 0.0 {
+            return None;
+        }
+        let tilt = (self.on_z_axis / magnitude)
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        Some((tilt * 10.0).round() / 10.0)
     }
 }
 
@@ -52,12 +78,15 @@ impl Serialize for RuuviTagDataFormat5 {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("RuuviTagDataFormat5", 7)?;
+        let mut state = serializer.serialize_struct("RuuviTagDataFormat5", 10)?;
         state.serialize_field("temperature", &self.get_temperature())?;
         state.serialize_field("humidity", &self.get_humidity())?;
         state.serialize_field("atmospheric_pressure", &self.get_pressure())?;
         state.serialize_field("acceleration", &self.get_accelaration())?;
+        state.serialize_field("acceleration_total", &self.get_accelaration().sqrt())?;
+        state.serialize_field("tilt", &self.get_accelaration().tilt())?;
         state.serialize_field("powerinfo", &self.get_battery())?;
+        state.serialize_field("tx_power", &self.get_tx_power())?;
         state.serialize_field("movement_counter", &self.get_movement_counter())?;
         state.serialize_field(
             "measurement_sequence_number",
@@ -68,6 +97,18 @@ impl Serialize for RuuviTagDataFormat5 {
 }
 
 impl RuuviTagDataFormat5 {
+    // length of the payload after data format byte: measurements and 6 byte MAC address
+    pub const PAYLOAD_LENGTH: usize = 23;
+
+    // newer firmware may append extended fields after the payload
+    pub fn extensions(payload: &[u8]) -> Option<&[u8]> {
+        if payload.len() > RuuviTagDataFormat5::PAYLOAD_LENGTH {
+            Some(&payload[RuuviTagDataFormat5::PAYLOAD_LENGTH..])
+        } else {
+            None
+        }
+    }
+
     pub fn get_temperature(&self) -> f32 {
         self.temperature.to_int() as f32 / 200.0
     }
@@ -88,16 +129,15 @@ impl RuuviTagDataFormat5 {
         }
     }
 
+    // power info combines battery voltage (first 11 bits) and tx power (last 5 bits)
     pub fn get_battery(&self) -> u16 {
-        let powerinfo = self.powerinfo.to_int();
-        let battery_voltage = powerinfo >> 5;
-        (battery_voltage + 1600) as u16
+        let battery_voltage = self.powerinfo.to_int() >> 5;
+        battery_voltage + 1600
     }
 
     pub fn get_tx_power(&self) -> i8 {
-        let powerinfo = self.powerinfo.to_int();
-        let tx_power = powerinfo & 0b11111;
-        (tx_power * 2) as i8 - 40
+        let tx_power = (self.powerinfo.to_int() & 0b11111) as i8;
+        tx_power * 2 - 40
     }
 
     pub fn get_movement_counter(&self) -> u8 {
@@ -131,7 +171,7 @@ mod tests {
      * https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
      * outlines the test cases for valid, min and max values
      */
-    use crate::lib::ruuvi::RuuviTagDataFormat5;
+    use super::RuuviTagDataFormat5;
     use structview::View;
     #[test]
     fn valid_values() {
@@ -144,6 +184,8 @@ mod tests {
         assert_eq!(beacon.get_accelaration().on_x_axis / 1000.0, 0.004);
         assert_eq!(beacon.get_accelaration().on_y_axis / 1000.0, -0.004);
         assert_eq!(beacon.get_accelaration().on_z_axis / 1000.0, 1.036);
+        assert_eq!(beacon.get_accelaration().sqrt(), 1036.0);
+        assert_eq!(beacon.get_accelaration().tilt(), Some(0.3));
         assert_eq!(beacon.get_tx_power(), 4);
         assert_eq!(beacon.get_battery(), 2977);
         assert_eq!(beacon.get_movement_counter(), 66);
@@ -174,7 +216,7 @@ mod tests {
         let beacon = RuuviTagDataFormat5::view(&data[1..]).unwrap();
         assert_eq!(beacon.get_temperature(), 163.835);
         assert_eq!(beacon.get_pressure(), 1155.34);
-        assert_eq!(beacon.get_humidity(), 163.8350);
+        assert_eq!(beacon.get_humidity(), 163.835);
         assert_eq!(beacon.get_accelaration().on_x_axis / 1000.0, 32.767);
         assert_eq!(beacon.get_accelaration().on_y_axis / 1000.0, 32.767);
         assert_eq!(beacon.get_accelaration().on_z_axis / 1000.0, 32.767);
@@ -183,4 +225,31 @@ mod tests {
         assert_eq!(beacon.get_movement_counter(), 254);
         assert_eq!(beacon.get_measurement_sequence_number(), 65534);
     }
+
+    #[test]
+    fn extensions() {
+        let hex_string = "0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+        let data = hex::decode(hex_string).unwrap();
+        assert_eq!(RuuviTagDataFormat5::extensions(&data[1..]), None);
+
+        let hex_string = "0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F0102";
+        let data = hex::decode(hex_string).unwrap();
+        let beacon = RuuviTagDataFormat5::view(&data[1..]).unwrap();
+        assert_eq!(beacon.get_temperature(), 24.3);
+        assert_eq!(
+            RuuviTagDataFormat5::extensions(&data[1..]),
+            Some(&[0x01, 0x02][..])
+        );
+    }
+
+    #[test]
+    fn serialized_values() {
+        let hex_string = "0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+        let data = hex::decode(hex_string).unwrap();
+        let beacon = RuuviTagDataFormat5::view(&data[1..]).unwrap();
+        let value = serde_json::to_value(beacon).unwrap();
+        assert_eq!(value["powerinfo"], 2977);
+        assert_eq!(value["tx_power"], 4);
+        assert_eq!(value["movement_counter"], 66);
+    }
 }
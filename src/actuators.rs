@@ -0,0 +1,330 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use crate::alerts::metric_value;
+use crate::configfile::{ActuatorConfig, AlertOperator, GpioBackend};
+use crate::scanner::RuuviBluetoothBeacon;
+
+// _IOWR(0xB4, 0x03, struct gpiohandle_request) from linux gpio.h
+const GPIO_GET_LINEHANDLE_IOCTL: libc::c_ulong = 0xc16cb403;
+// _IOWR(0xB4, 0x09, struct gpiohandle_data) from linux gpio.h
+const GPIOHANDLE_SET_LINE_VALUES_IOCTL: libc::c_ulong = 0xc040b409;
+const GPIOHANDLE_REQUEST_OUTPUT: u32 = 1 << 1;
+const GPIOHANDLES_MAX: usize = 64;
+
+/// Output pin switched by an actuator.
+pub trait OutputPin: Send {
+    /// Name of the pin in log messages
+    fn name(&self) -> String;
+    /// Drives the pin high or low
+    fn set(&mut self, high: bool) -> Result<(), Report>;
+}
+
+fn write_sysfs(path: &Path, value: &str) -> Result<(), Report> {
+    match fs::write(path, value) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(eyre!("Unable to write to GPIO sysfs")
+            .with_section(move || path.display().to_string().header("Path:"))
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+/// Pin driven through the legacy sysfs interface.
+pub struct SysfsPin {
+    value: PathBuf,
+}
+
+impl SysfsPin {
+    pub fn open(sysfs_path: &Path, pin: u32) -> Result<SysfsPin, Report> {
+        trace!("in open");
+        let gpio = sysfs_path.join(format!("gpio{}", pin));
+        if !gpio.exists() {
+            write_sysfs(&sysfs_path.join("export"), &pin.to_string())?;
+        }
+        write_sysfs(&gpio.join("direction"), "out")?;
+        Ok(SysfsPin {
+            value: gpio.join("value"),
+        })
+    }
+}
+
+impl OutputPin for SysfsPin {
+    fn name(&self) -> String {
+        self.value.display().to_string()
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), Report> {
+        write_sysfs(&self.value, if high { "1" } else { "0" })
+    }
+}
+
+// struct gpiohandle_request from linux gpio.h
+#[repr(C)]
+struct GpioHandleRequest {
+    line_offsets: [u32; GPIOHANDLES_MAX],
+    flags: u32,
+    default_values: [u8; GPIOHANDLES_MAX],
+    consumer_label: [u8; 32],
+    lines: u32,
+    fd: libc::c_int,
+}
+
+// struct gpiohandle_data from linux gpio.h
+#[repr(C)]
+struct GpioHandleData {
+    values: [u8; GPIOHANDLES_MAX],
+}
+
+/// Line of a GPIO character device, held as output for as long as the pin exists.
+pub struct GpiodPin {
+    name: String,
+    handle: File,
+}
+
+impl GpiodPin {
+    pub fn open(chip: &Path, line: u32) -> Result<GpiodPin, Report> {
+        trace!("in open");
+        let name = format!("{}:{}", chip.display(), line);
+        let device = match File::open(chip) {
+            Ok(device) => device,
+            Err(error) => {
+                return Err(eyre!("Unable to open GPIO chip")
+                    .with_section(move || name.header("Pin:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let mut request = GpioHandleRequest {
+            line_offsets: [0; GPIOHANDLES_MAX],
+            flags: GPIOHANDLE_REQUEST_OUTPUT,
+            default_values: [0; GPIOHANDLES_MAX],
+            consumer_label: [0; 32],
+            lines: 1,
+            fd: -1,
+        };
+        request.line_offsets[0] = line;
+        let label = env!("CARGO_PKG_NAME").as_bytes();
+        request.consumer_label[..label.len()].copy_from_slice(label);
+        let result = unsafe {
+            libc::ioctl(
+                device.as_raw_fd(),
+                GPIO_GET_LINEHANDLE_IOCTL as _,
+                &mut request,
+            )
+        };
+        if result < 0 || request.fd < 0 {
+            let error = std::io::Error::last_os_error();
+            return Err(eyre!("Unable to request GPIO line as output")
+                .with_section(move || name.header("Pin:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        Ok(GpiodPin {
+            name,
+            // the line is released when the handle is closed
+            handle: unsafe { File::from_raw_fd(request.fd) },
+        })
+    }
+}
+
+impl OutputPin for GpiodPin {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn set(&mut self, high: bool) -> Result<(), Report> {
+        let mut data = GpioHandleData {
+            values: [0; GPIOHANDLES_MAX],
+        };
+        data.values[0] = high as u8;
+        let result = unsafe {
+            libc::ioctl(
+                self.handle.as_raw_fd(),
+                GPIOHANDLE_SET_LINE_VALUES_IOCTL as _,
+                &mut data,
+            )
+        };
+        if result < 0 {
+            let error = std::io::Error::last_os_error();
+            return Err(eyre!("Unable to set value of GPIO line")
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        Ok(())
+    }
+}
+
+/// Change of the state of an actuator, published to the state topic of the gateway.
+#[derive(Debug, Serialize, Clone)]
+pub struct ActuatorState {
+    pub name: String,
+    pub pin: String,
+    pub on: bool,
+    pub address: String,
+    pub metric: String,
+    pub value: f64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl ActuatorState {
+    pub fn payload(&self) -> serde_json::Value {
+        json!({ "actuator": self })
+    }
+}
+
+struct Actuator {
+    config: ActuatorConfig,
+    pin: Box<dyn OutputPin>,
+    // unknown until the first beacon of the tag decides it
+    on: Option<bool>,
+}
+
+impl Actuator {
+    // state the value asks for, None while within the hysteresis band where the pin is kept as
+    //  it is
+    fn wanted(&self, value: f64) -> Option<bool> {
+        let threshold = self.config.threshold();
+        let hysteresis = self.config.hysteresis();
+        let (breaching, cleared) = match self.config.operator() {
+            AlertOperator::ABOVE => (value > threshold, value <= threshold - hysteresis),
+            AlertOperator::BELOW => (value < threshold, value >= threshold + hysteresis),
+        };
+        if breaching {
+            Some(true)
+        } else if cleared || self.on.is_none() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Switches GPIO pins on received beacons, so that simple control loops keep working locally
+/// even when IoT Core is not reachable.
+#[derive(Default)]
+pub struct Actuators {
+    actuators: Vec<Actuator>,
+}
+
+impl Actuators {
+    /// Actuators switched by the beacon. A pin that fails to switch is tried again on the next
+    /// beacon of the tag.
+    pub fn check(&mut self, beacon: &RuuviBluetoothBeacon) -> Vec<ActuatorState> {
+        let mut states = Vec::new();
+        for actuator in self.actuators.iter_mut() {
+            if !actuator.config.tag().eq_ignore_ascii_case(&beacon.address) {
+                continue;
+            }
+            let value = match metric_value(beacon, &actuator.config.metric()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let on = match actuator.wanted(value) {
+                Some(on) if actuator.on != Some(on) => on,
+                _ => continue,
+            };
+            if let Err(error) = actuator.pin.set(on != actuator.config.active_low()) {
+                warn!(
+                    "Unable to switch actuator '{}': {}",
+                    actuator.config.name(),
+                    error
+                );
+                continue;
+            }
+            actuator.on = Some(on);
+            states.push(ActuatorState {
+                name: actuator.config.name(),
+                pin: actuator.pin.name(),
+                on,
+                address: beacon.address.clone(),
+                metric: actuator.config.metric(),
+                value,
+                timestamp: beacon.timestamp,
+            });
+        }
+        states
+    }
+
+    pub fn build(configs: &[ActuatorConfig]) -> Result<Actuators, Report> {
+        trace!("in build");
+        let mut actuators = Vec::new();
+        for config in configs {
+            let pin: Box<dyn OutputPin> = match config.backend() {
+                GpioBackend::SYSFS => Box::new(SysfsPin::open(&config.sysfs_path(), config.pin())?),
+                GpioBackend::GPIOD => Box::new(GpiodPin::open(&config.chip(), config.pin())?),
+            };
+            info!(
+                "Actuator '{}' switches {} on {} of '{}'",
+                config.name(),
+                pin.name(),
+                config.metric(),
+                config.tag()
+            );
+            actuators.push(Actuator {
+                config: config.clone(),
+                pin,
+                on: None,
+            });
+        }
+        Ok(Actuators { actuators })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Actuators;
+    use crate::configfile::ActuatorConfig;
+    use crate::mock::{beacon, temp_path};
+    use std::fs;
+
+    #[test]
+    fn actuator_switches_with_hysteresis() {
+        let sysfs = temp_path("gpio-sysfs");
+        fs::create_dir_all(sysfs.join("gpio17")).unwrap();
+        let configs: Vec<ActuatorConfig> = serde_json::from_value(json!([{
+            "name": "fan",
+            "tag": "aa:bb:cc:dd:ee:ff",
+            "metric": "temperature",
+            "operator": "above",
+            "threshold": 25.0,
+            "hysteresis": 2.0,
+            "pin": 17,
+            "sysfs_path": sysfs,
+            "active_low": true,
+        }]))
+        .unwrap();
+        let mut actuators = Actuators::build(&configs).unwrap();
+        assert_eq!(
+            fs::read_to_string(sysfs.join("gpio17/direction")).unwrap(),
+            "out"
+        );
+        let value = || fs::read_to_string(sysfs.join("gpio17/value")).unwrap();
+
+        let mut check = |temperature: f64| {
+            let mut tag = beacon("AA:BB:CC:DD:EE:FF", 1);
+            tag.data = Some(json!({ "temperature": temperature }));
+            actuators
+                .check(&tag)
+                .iter()
+                .map(|state| state.on)
+                .collect::<Vec<bool>>()
+        };
+        // first beacon decides the initial state
+        assert_eq!(check(21.0), vec![false]);
+        assert_eq!(value(), "1");
+        assert!(check(24.0).is_empty());
+        assert_eq!(check(25.5), vec![true]);
+        assert_eq!(value(), "0");
+        // within hysteresis the fan keeps running
+        assert!(check(24.0).is_empty());
+        assert_eq!(check(22.5), vec![false]);
+        assert_eq!(value(), "1");
+        let mut other = beacon("11:22:33:44:55:66", 1);
+        other.data = Some(json!({ "temperature": 99.0 }));
+        assert!(actuators.check(&other).is_empty());
+
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+}
+
+// eof
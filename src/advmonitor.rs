@@ -0,0 +1,276 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+#[cfg(feature = "advmonitor")]
+use crossbeam::channel;
+#[cfg(feature = "advmonitor")]
+use dbus::arg::{PropMap, RefArg, Variant};
+#[cfg(feature = "advmonitor")]
+use dbus::channel::{default_reply, BusType, Channel};
+#[cfg(feature = "advmonitor")]
+use dbus::{Message, MessageType, Path};
+#[cfg(feature = "advmonitor")]
+use std::collections::HashMap;
+#[cfg(feature = "advmonitor")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "advmonitor")]
+use std::sync::Arc;
+#[cfg(feature = "advmonitor")]
+use std::thread;
+#[cfg(feature = "advmonitor")]
+use std::time::Duration;
+
+// AD type of manufacturer specific data in advertisements
+#[cfg(any(feature = "advmonitor", test))]
+const MANUFACTURER_DATA: u8 = 0xff;
+
+#[cfg(feature = "advmonitor")]
+const MANAGER_INTERFACE: &str = "org.bluez.AdvertisementMonitorManager1";
+#[cfg(feature = "advmonitor")]
+const MONITOR_INTERFACE: &str = "org.bluez.AdvertisementMonitor1";
+#[cfg(feature = "advmonitor")]
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+// object manager of the monitors of the gateway, and the monitor under it
+#[cfg(feature = "advmonitor")]
+const APPLICATION_PATH: &str = "/ruuvi2iotcore/advmonitor";
+#[cfg(feature = "advmonitor")]
+const MONITOR_PATH: &str = "/ruuvi2iotcore/advmonitor/beacons";
+// time BlueZ has to answer the registration of the monitor
+#[cfg(feature = "advmonitor")]
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// start position in the AD data, AD type and content an advertisement has to match
+#[cfg(any(feature = "advmonitor", test))]
+type Pattern = (u8, u8, Vec<u8>);
+
+// advertisements with manufacturer data of any of the manufacturers, whose little endian id
+//  starts the data
+#[cfg(any(feature = "advmonitor", test))]
+fn patterns(manufacturer_ids: &[u16]) -> Vec<Pattern> {
+    manufacturer_ids
+        .iter()
+        .map(|manufacturer_id| (0, MANUFACTURER_DATA, manufacturer_id.to_le_bytes().to_vec()))
+        .collect()
+}
+
+/// BlueZ Advertisement Monitor matching the manufacturer data of the beacons the scanner
+/// decodes, so that controllers able to filter advertisements report only those. The monitor is
+/// unregistered when it is dropped.
+pub struct AdvertisementMonitor {
+    #[cfg(feature = "advmonitor")]
+    running: Arc<AtomicBool>,
+    #[cfg(feature = "advmonitor")]
+    connection: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(feature = "advmonitor")]
+impl AdvertisementMonitor {
+    /// Registers a monitor for advertisements of the manufacturers with the adapter, e.g. "hci0".
+    /// The calls of BlueZ to the monitor are answered in a thread of its own.
+    pub fn register(
+        adapter_name: &str,
+        manufacturer_ids: &[u16],
+    ) -> Result<AdvertisementMonitor, Report> {
+        trace!("in register");
+        let adapter_path = format!("/org/bluez/{}", adapter_name);
+        let patterns = patterns(manufacturer_ids);
+        let running = Arc::new(AtomicBool::new(true));
+        let (result_sender, result_receiver) = channel::bounded(1);
+        let connection = {
+            let running = running.clone();
+            thread::spawn(move || serve(&adapter_path, &patterns, &running, result_sender))
+        };
+        let reason = match result_receiver.recv_timeout(REGISTER_TIMEOUT) {
+            Ok(Ok(())) => {
+                info!(
+                    "Registered BlueZ advertisement monitor with adapter {}",
+                    adapter_name
+                );
+                return Ok(AdvertisementMonitor {
+                    running,
+                    connection: Some(connection),
+                });
+            }
+            Ok(Err(reason)) => reason,
+            Err(_) => "no answer from BlueZ".to_string(),
+        };
+        running.store(false, Ordering::Relaxed);
+        connection.join().ok();
+        let adapter_name = adapter_name.to_string();
+        Err(eyre!("Unable to register BlueZ advertisement monitor")
+            .with_section(move || adapter_name.header("Adapter:"))
+            .with_section(move || reason.header("Reason:")))
+    }
+}
+
+#[cfg(not(feature = "advmonitor"))]
+impl AdvertisementMonitor {
+    pub fn register(
+        adapter_name: &str,
+        _manufacturer_ids: &[u16],
+    ) -> Result<AdvertisementMonitor, Report> {
+        let adapter_name = adapter_name.to_string();
+        Err(eyre!("BlueZ advertisement monitor is not compiled in")
+            .with_section(move || adapter_name.header("Adapter:"))
+            .with_section(|| "cargo build --features advmonitor".header("Build with:")))
+    }
+}
+
+#[cfg(feature = "advmonitor")]
+impl Drop for AdvertisementMonitor {
+    // the connection unregisters the monitor before it is closed
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(connection) = self.connection.take() {
+            connection.join().ok();
+        }
+        debug!("Unregistered BlueZ advertisement monitor");
+    }
+}
+
+// properties of the monitor as BlueZ reads them from the object manager of the application
+#[cfg(feature = "advmonitor")]
+fn managed_objects(patterns: &[Pattern]) -> HashMap<Path<'static>, HashMap<String, PropMap>> {
+    let mut properties = PropMap::new();
+    properties.insert(
+        "Type".to_string(),
+        Variant(Box::new("or_patterns".to_string()) as Box<dyn RefArg>),
+    );
+    properties.insert(
+        "Patterns".to_string(),
+        Variant(Box::new(patterns.to_vec()) as Box<dyn RefArg>),
+    );
+    let mut interfaces = HashMap::new();
+    interfaces.insert(MONITOR_INTERFACE.to_string(), properties);
+    let mut objects = HashMap::new();
+    objects.insert(Path::from(MONITOR_PATH), interfaces);
+    objects
+}
+
+// answer to a call of BlueZ to the application or the monitor, none for calls to other objects
+#[cfg(feature = "advmonitor")]
+fn answer(call: &Message, patterns: &[Pattern]) -> Option<Message> {
+    let (path, interface, member) = (call.path()?, call.interface()?, call.member()?);
+    match (&*path, &*interface, &*member) {
+        (APPLICATION_PATH, OBJECT_MANAGER_INTERFACE, "GetManagedObjects") => {
+            Some(call.method_return().append1(managed_objects(patterns)))
+        }
+        (MONITOR_PATH, MONITOR_INTERFACE, "Activate") => {
+            debug!("BlueZ advertisement monitor activated");
+            Some(call.method_return())
+        }
+        (MONITOR_PATH, MONITOR_INTERFACE, "Release") => {
+            warn!("BlueZ released the advertisement monitor");
+            Some(call.method_return())
+        }
+        // beacons are relayed from the advertisements, not from devices found by the monitor
+        (MONITOR_PATH, MONITOR_INTERFACE, _) => Some(call.method_return()),
+        _ => None,
+    }
+}
+
+// registers the monitor over a connection to the system bus of its own and answers the calls of
+//  BlueZ to it until the monitor is dropped. the result of the registration is sent to
+//  registered, and the monitor is unregistered before the connection is closed
+#[cfg(feature = "advmonitor")]
+fn serve(
+    adapter_path: &str,
+    patterns: &[Pattern],
+    running: &AtomicBool,
+    registered: channel::Sender<Result<(), String>>,
+) {
+    let connection = match Channel::get_private(BusType::System) {
+        Ok(connection) => connection,
+        Err(error) => {
+            registered.send(Err(error.to_string())).ok();
+            return;
+        }
+    };
+    let register = Message::new_method_call(
+        "org.bluez",
+        adapter_path,
+        MANAGER_INTERFACE,
+        "RegisterMonitor",
+    )
+    .map(|call| call.append1(Path::from(APPLICATION_PATH)));
+    let serial = match register.and_then(|call| {
+        connection
+            .send(call)
+            .map_err(|_| "unable to call BlueZ".to_string())
+    }) {
+        Ok(serial) => serial,
+        Err(reason) => {
+            registered.send(Err(reason)).ok();
+            return;
+        }
+    };
+
+    // BlueZ reads the monitor from the application before it answers the registration
+    let mut registered = Some(registered);
+    while running.load(Ordering::Relaxed) {
+        if connection
+            .read_write(Some(Duration::from_millis(100)))
+            .is_err()
+        {
+            match registered.take() {
+                Some(registered) => {
+                    registered
+                        .send(Err("D-Bus connection closed".to_string()))
+                        .ok();
+                }
+                None => warn!("D-Bus connection of BlueZ advertisement monitor closed"),
+            }
+            return;
+        }
+        while let Some(message) = connection.pop_message() {
+            match message.msg_type() {
+                MessageType::MethodReturn | MessageType::Error
+                    if message.get_reply_serial() == Some(serial) =>
+                {
+                    let result = match message.msg_type() {
+                        MessageType::Error => Err(message.get1::<String>().unwrap_or_default()),
+                        _ => Ok(()),
+                    };
+                    if let Some(registered) = registered.take() {
+                        registered.send(result).ok();
+                    }
+                }
+                MessageType::MethodCall => {
+                    if let Some(reply) =
+                        answer(&message, patterns).or_else(|| default_reply(&message))
+                    {
+                        connection.send(reply).ok();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let unregister = Message::new_method_call(
+        "org.bluez",
+        adapter_path,
+        MANAGER_INTERFACE,
+        "UnregisterMonitor",
+    );
+    if let Ok(call) = unregister {
+        connection
+            .send(call.append1(Path::from(APPLICATION_PATH)))
+            .ok();
+        connection.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::patterns;
+
+    #[test]
+    fn manufacturer_data_of_decoders_is_monitored() {
+        // Apple for iBeacons and Ruuvi Innovations
+        assert_eq!(
+            patterns(&[0x004c, 0x0499]),
+            vec![(0, 0xff, vec![0x4c, 0x00]), (0, 0xff, vec![0x99, 0x04])]
+        );
+    }
+}
+
+// eof
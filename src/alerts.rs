@@ -0,0 +1,336 @@
+use color_eyre::eyre::Report;
+use crossbeam::channel;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::thread;
+
+use crate::chat::{SlackNotifier, TelegramNotifier};
+use crate::configfile::{AlertOperator, AlertRule, AlertsConfig};
+use crate::email::EmailNotifier;
+use crate::scanner::RuuviBluetoothBeacon;
+use crate::snmp::SnmpNotifier;
+use crate::zabbix::ZabbixNotifier;
+
+/// State of an alert rule for a tag.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum AlertState {
+    /// A value breached the threshold of the rule
+    #[serde(rename = "triggered")]
+    TRIGGERED,
+    /// A value no longer breaches the threshold
+    #[serde(rename = "cleared")]
+    CLEARED,
+}
+
+/// Change of the state of an alert rule for a tag, published to the alerts subfolder of the
+/// events topic of the tag and handed to notifiers.
+#[derive(Debug, Serialize, Clone)]
+pub struct Alert {
+    pub address: String,
+    pub metric: String,
+    pub operator: AlertOperator,
+    pub threshold: f64,
+    pub value: f64,
+    pub state: AlertState,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl Alert {
+    pub fn payload(&self) -> serde_json::Value {
+        json!({ "alert": self })
+    }
+
+    /// One line description of the alert for people, used by notifiers sending messages
+    pub fn summary(&self) -> String {
+        let operator = match self.operator {
+            AlertOperator::ABOVE => "above",
+            AlertOperator::BELOW => "below",
+        };
+        match self.state {
+            AlertState::TRIGGERED => format!(
+                "Alert triggered: {} of {} is {}, {} threshold {}",
+                self.metric, self.address, self.value, operator, self.threshold
+            ),
+            AlertState::CLEARED => format!(
+                "Alert cleared: {} of {} is {}, no longer {} threshold {}",
+                self.metric, self.address, self.value, operator, self.threshold
+            ),
+        }
+    }
+}
+
+/// Destination of alerts beside IoT Core, e.g. monitoring systems of the site. Notifiers are
+/// built from the "alerts" section of the configuration.
+pub trait Notifier: Send {
+    /// Name of the notifier in log messages
+    fn name(&self) -> String;
+    /// Delivers an alert, an alert that fails is dropped
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report>;
+}
+
+// delivers alerts received from the channel to the notifier until the channel is closed
+fn run_notifier(mut notifier: Box<dyn Notifier>, receiver: channel::Receiver<Alert>) {
+    trace!("in run_notifier");
+    for alert in receiver {
+        if let Err(error) = notifier.notify(&alert) {
+            warn!(
+                "Dropping alert not delivered to {}: {}",
+                notifier.name(),
+                error
+            );
+        }
+    }
+}
+
+/// Value of the metric in the data of the beacon, dots separate fields of nested objects.
+pub fn metric_value(beacon: &RuuviBluetoothBeacon, metric: &str) -> Option<f64> {
+    let mut value = beacon.data.as_ref()?;
+    for field in metric.split('.') {
+        value = value.get(field)?;
+    }
+    value.as_f64()
+}
+
+// state of a rule for a tag
+#[derive(Clone, Default)]
+struct TagAlert {
+    triggered: bool,
+    // since when the value has asked for the opposite state, changed once it has done so for
+    //  the duration of the rule
+    pending: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Evaluates alert rules on received beacons. An alert is raised when a value of a tag has
+/// breached the threshold of a rule for the duration of the rule and cleared when it has been
+/// back past the threshold by the hysteresis for as long, and notifiers receive the alerts
+/// each in a thread of its own.
+#[derive(Default)]
+pub struct Alerts {
+    // rules of the "alerts" section, used unless the collect config has rules of its own
+    local_rules: Vec<AlertRule>,
+    rules: Vec<AlertRule>,
+    // state of each rule for the tags it has seen
+    states: Vec<HashMap<String, TagAlert>>,
+    subfolder: String,
+    notifiers: Vec<channel::Sender<Alert>>,
+}
+
+impl Alerts {
+    /// Subfolder of the events topic of the tag alerts are published to
+    pub fn subfolder(&self) -> &str {
+        &self.subfolder
+    }
+
+    /// Rules of the "alerts" section
+    pub fn local_rules(&self) -> &[AlertRule] {
+        &self.local_rules
+    }
+
+    /// Replaces the rules with the ones of a collect config, or restores the local rules when
+    /// None. Rules that did not change keep the state of their alerts.
+    pub fn set_rules(&mut self, rules: Option<Vec<AlertRule>>) {
+        trace!("in set_rules");
+        let rules = rules.unwrap_or_else(|| self.local_rules.clone());
+        if rules == self.rules {
+            return;
+        }
+        info!("Evaluating {} alert rules", rules.len());
+        let states = rules
+            .iter()
+            .map(
+                |rule| match self.rules.iter().position(|current| current == rule) {
+                    Some(index) => self.states[index].clone(),
+                    None => HashMap::new(),
+                },
+            )
+            .collect();
+        self.rules = rules;
+        self.states = states;
+    }
+
+    /// Starts delivering alerts to the notifier
+    pub fn start(&mut self, notifier: Box<dyn Notifier>) {
+        trace!("in start");
+        info!("Delivering alerts to {}", notifier.name());
+        let (sender, receiver) = channel::unbounded();
+        thread::spawn(move || run_notifier(notifier, receiver));
+        self.notifiers.push(sender);
+    }
+
+    /// Alerts raised or cleared by the beacon, which are also handed to notifiers
+    pub fn check(&mut self, beacon: &RuuviBluetoothBeacon) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for (rule, states) in self.rules.iter().zip(self.states.iter_mut()) {
+            if let Some(tag) = rule.tag() {
+                if !tag.eq_ignore_ascii_case(&beacon.address) {
+                    continue;
+                }
+            }
+            let value = match metric_value(beacon, &rule.metric()) {
+                Some(value) => value,
+                None => continue,
+            };
+            let (breaching, cleared) = match rule.operator() {
+                AlertOperator::ABOVE => (
+                    value > rule.threshold(),
+                    value <= rule.threshold() - rule.hysteresis(),
+                ),
+                AlertOperator::BELOW => (
+                    value < rule.threshold(),
+                    value >= rule.threshold() + rule.hysteresis(),
+                ),
+            };
+            let tag_alert = states.entry(beacon.address.clone()).or_default();
+            let changing = if tag_alert.triggered {
+                cleared
+            } else {
+                breaching
+            };
+            if !changing {
+                tag_alert.pending = None;
+                continue;
+            }
+            // beacon timestamps measure the duration, so replayed beacons behave the same
+            let since = *tag_alert.pending.get_or_insert(beacon.timestamp);
+            if beacon.timestamp - since < chrono::Duration::seconds(rule.duration() as i64) {
+                continue;
+            }
+            tag_alert.pending = None;
+            tag_alert.triggered = !tag_alert.triggered;
+            let state = if tag_alert.triggered {
+                AlertState::TRIGGERED
+            } else {
+                AlertState::CLEARED
+            };
+            alerts.push(Alert {
+                address: beacon.address.clone(),
+                metric: rule.metric(),
+                operator: rule.operator(),
+                threshold: rule.threshold(),
+                value,
+                state,
+                timestamp: beacon.timestamp,
+            });
+        }
+        for alert in &alerts {
+            for notifier in &self.notifiers {
+                notifier.send(alert.clone()).ok();
+            }
+        }
+        alerts
+    }
+
+    pub fn build(config: &AlertsConfig, device_id: &str) -> Result<Alerts, Report> {
+        trace!("in build");
+        let rules = config.rules();
+        let mut alerts = Alerts {
+            states: vec![HashMap::new(); rules.len()],
+            local_rules: rules.clone(),
+            rules,
+            subfolder: config.subfolder(),
+            notifiers: Vec::new(),
+        };
+        if let Some(zabbix) = config.zabbix() {
+            alerts.start(Box::new(ZabbixNotifier::build(&zabbix, device_id)));
+        }
+        if let Some(snmp) = config.snmp() {
+            alerts.start(Box::new(SnmpNotifier::build(&snmp)?));
+        }
+        if let Some(email) = config.email() {
+            alerts.start(Box::new(EmailNotifier::build(&email, device_id)?));
+        }
+        if let Some(slack) = config.slack() {
+            alerts.start(Box::new(SlackNotifier::build(&slack, device_id)));
+        }
+        if let Some(telegram) = config.telegram() {
+            alerts.start(Box::new(TelegramNotifier::build(&telegram, device_id)));
+        }
+        Ok(alerts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertState, Alerts};
+    use crate::configfile::AlertsConfig;
+    use crate::mock::beacon;
+
+    #[test]
+    fn alerts_trigger_and_clear() {
+        let config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [
+                { "metric": "temperature", "operator": "above", "threshold": 25.0 },
+                {
+                    "tag": "aa:bb:cc:dd:ee:02",
+                    "metric": "humidity",
+                    "operator": "below",
+                    "threshold": 30.0,
+                },
+            ],
+        }))
+        .unwrap();
+        let mut alerts = Alerts::build(&config, "test-gateway").unwrap();
+        assert_eq!(alerts.subfolder(), "alerts");
+
+        let mut hot = beacon("AA:BB:CC:DD:EE:01", 1);
+        hot.data.as_mut().unwrap()["temperature"] = json!(26.0);
+        hot.data.as_mut().unwrap()["humidity"] = json!(20.0);
+        let triggered = alerts.check(&hot);
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].metric, "temperature");
+        assert_eq!(triggered[0].state, AlertState::TRIGGERED);
+        assert_eq!(triggered[0].payload()["alert"]["state"], "triggered");
+        // breaching again does not raise the alert again
+        assert!(alerts.check(&hot).is_empty());
+
+        let mut dry = beacon("AA:BB:CC:DD:EE:02", 2);
+        dry.data.as_mut().unwrap()["humidity"] = json!(20.0);
+        let triggered = alerts.check(&dry);
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(triggered[0].metric, "humidity");
+
+        let cleared = alerts.check(&beacon("AA:BB:CC:DD:EE:01", 3));
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].state, AlertState::CLEARED);
+        assert_eq!(cleared[0].value, 21.5);
+    }
+
+    #[test]
+    fn alerts_are_debounced() {
+        let config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [{
+                "metric": "temperature",
+                "operator": "above",
+                "threshold": 25.0,
+                "hysteresis": 1.0,
+                "duration": 60,
+            }],
+        }))
+        .unwrap();
+        let mut alerts = Alerts::build(&config, "test-gateway").unwrap();
+        let started = chrono::Utc::now();
+        let mut check = |seconds: i64, temperature: f64| {
+            let mut tag = beacon("AA:BB:CC:DD:EE:01", seconds as u64);
+            tag.timestamp = started + chrono::Duration::seconds(seconds);
+            tag.data.as_mut().unwrap()["temperature"] = json!(temperature);
+            alerts
+                .check(&tag)
+                .iter()
+                .map(|alert| alert.state)
+                .collect::<Vec<AlertState>>()
+        };
+        // a short spike does not trigger
+        assert!(check(0, 26.0).is_empty());
+        assert!(check(30, 24.0).is_empty());
+        assert!(check(40, 26.0).is_empty());
+        assert!(check(90, 26.0).is_empty());
+        assert_eq!(check(100, 26.0), vec![AlertState::TRIGGERED]);
+        // within hysteresis the alert stays triggered
+        assert!(check(110, 24.5).is_empty());
+        assert!(check(200, 24.5).is_empty());
+        assert!(check(210, 23.0).is_empty());
+        assert_eq!(check(270, 23.5), vec![AlertState::CLEARED]);
+    }
+}
+
+// eof
@@ -0,0 +1,917 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::configfile::BacnetConfig;
+use crate::status::SharedStatus;
+
+// BACnet/IP virtual link control: type and functions of unicast and broadcast NPDUs
+const BVLC_TYPE: u8 = 0x81;
+const BVLC_UNICAST: u8 = 0x0a;
+const BVLC_BROADCAST: u8 = 0x0b;
+
+// APDU types
+const PDU_CONFIRMED: u8 = 0;
+const PDU_UNCONFIRMED: u8 = 1;
+const PDU_COMPLEX_ACK: u8 = 0x30;
+const PDU_ERROR: u8 = 0x50;
+const PDU_REJECT: u8 = 0x60;
+const PDU_ABORT: u8 = 0x71;
+
+// services
+const SERVICE_I_AM: u8 = 0;
+const SERVICE_WHO_IS: u8 = 8;
+const SERVICE_READ_PROPERTY: u8 = 12;
+const SERVICE_READ_PROPERTY_MULTIPLE: u8 = 14;
+
+// reasons of rejects and aborts
+const REJECT_MISSING_REQUIRED_PARAMETER: u8 = 5;
+const REJECT_UNRECOGNIZED_SERVICE: u8 = 9;
+const ABORT_SEGMENTATION_NOT_SUPPORTED: u8 = 4;
+
+// error classes and codes
+const ERROR_CLASS_OBJECT: u32 = 1;
+const ERROR_CLASS_PROPERTY: u32 = 2;
+const ERROR_UNKNOWN_OBJECT: u32 = 31;
+const ERROR_UNKNOWN_PROPERTY: u32 = 32;
+const ERROR_INVALID_ARRAY_INDEX: u32 = 42;
+const ERROR_PROPERTY_IS_NOT_AN_ARRAY: u32 = 50;
+
+// object types
+const OBJECT_ANALOG_INPUT: u16 = 0;
+const OBJECT_DEVICE: u16 = 8;
+// instance that stands for the device receiving the request
+const WILDCARD_INSTANCE: u32 = 4_194_303;
+
+// properties
+const PROP_APDU_TIMEOUT: u32 = 11;
+const PROP_APPLICATION_SOFTWARE_VERSION: u32 = 12;
+const PROP_DESCRIPTION: u32 = 28;
+const PROP_DEVICE_ADDRESS_BINDING: u32 = 30;
+const PROP_EVENT_STATE: u32 = 36;
+const PROP_FIRMWARE_REVISION: u32 = 44;
+const PROP_MAX_APDU_LENGTH_ACCEPTED: u32 = 62;
+const PROP_MODEL_NAME: u32 = 70;
+const PROP_NUMBER_OF_APDU_RETRIES: u32 = 73;
+const PROP_OBJECT_IDENTIFIER: u32 = 75;
+const PROP_OBJECT_LIST: u32 = 76;
+const PROP_OBJECT_NAME: u32 = 77;
+const PROP_OBJECT_TYPE: u32 = 79;
+const PROP_OUT_OF_SERVICE: u32 = 81;
+const PROP_PRESENT_VALUE: u32 = 85;
+const PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED: u32 = 96;
+const PROP_PROTOCOL_SERVICES_SUPPORTED: u32 = 97;
+const PROP_PROTOCOL_VERSION: u32 = 98;
+const PROP_RELIABILITY: u32 = 103;
+const PROP_SEGMENTATION_SUPPORTED: u32 = 107;
+const PROP_STATUS_FLAGS: u32 = 111;
+const PROP_SYSTEM_STATUS: u32 = 112;
+const PROP_UNITS: u32 = 117;
+const PROP_VENDOR_IDENTIFIER: u32 = 120;
+const PROP_VENDOR_NAME: u32 = 121;
+const PROP_PROTOCOL_REVISION: u32 = 139;
+const PROP_DATABASE_REVISION: u32 = 155;
+// special properties of ReadPropertyMultiple standing for sets of properties
+const PROP_ALL: u32 = 8;
+const PROP_OPTIONAL: u32 = 80;
+const PROP_REQUIRED: u32 = 105;
+
+const DEVICE_PROPERTIES: [u32; 21] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_SYSTEM_STATUS,
+    PROP_VENDOR_NAME,
+    PROP_VENDOR_IDENTIFIER,
+    PROP_MODEL_NAME,
+    PROP_FIRMWARE_REVISION,
+    PROP_APPLICATION_SOFTWARE_VERSION,
+    PROP_PROTOCOL_VERSION,
+    PROP_PROTOCOL_REVISION,
+    PROP_PROTOCOL_SERVICES_SUPPORTED,
+    PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED,
+    PROP_OBJECT_LIST,
+    PROP_MAX_APDU_LENGTH_ACCEPTED,
+    PROP_SEGMENTATION_SUPPORTED,
+    PROP_APDU_TIMEOUT,
+    PROP_NUMBER_OF_APDU_RETRIES,
+    PROP_DEVICE_ADDRESS_BINDING,
+    PROP_DATABASE_REVISION,
+    PROP_DESCRIPTION,
+];
+const INPUT_PROPERTIES: [u32; 10] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_PRESENT_VALUE,
+    PROP_STATUS_FLAGS,
+    PROP_EVENT_STATE,
+    PROP_OUT_OF_SERVICE,
+    PROP_UNITS,
+    PROP_RELIABILITY,
+    PROP_DESCRIPTION,
+];
+// properties the standard does not require, the rest of the lists above are required
+const OPTIONAL_PROPERTIES: [u32; 2] = [PROP_DESCRIPTION, PROP_RELIABILITY];
+
+const MAX_APDU_LENGTH: u32 = 1476;
+// segmentation-supported: no-segmentation
+const NO_SEGMENTATION: u32 = 3;
+// ruuvi2iotcore has no vendor identifier of its own, 0 is that of ASHRAE
+const VENDOR_IDENTIFIER: u32 = 0;
+const PROTOCOL_REVISION: u32 = 14;
+
+// engineering units
+const UNITS_PERCENT_RELATIVE_HUMIDITY: u32 = 29;
+const UNITS_DEGREES_CELSIUS: u32 = 62;
+
+// analog inputs of each tag, in the order of their instance numbers
+const METRICS: [(&str, u32); 2] = [
+    ("temperature", UNITS_DEGREES_CELSIUS),
+    ("humidity", UNITS_PERCENT_RELATIVE_HUMIDITY),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Boolean(bool),
+    Unsigned(u32),
+    Real(f32),
+    CharacterString(String),
+    BitString(Vec<bool>),
+    Enumerated(u32),
+    ObjectIdentifier(u16, u32),
+}
+
+// header of a tag with its number and the length of its content
+fn tag(number: u8, context: bool, length: usize) -> Vec<u8> {
+    let class = if context { 0x08 } else { 0x00 };
+    if length < 5 {
+        vec![number << 4 | class | length as u8]
+    } else if length < 254 {
+        vec![number << 4 | class | 5, length as u8]
+    } else {
+        let mut header = vec![number << 4 | class | 5, 254];
+        header.extend((length as u16).to_be_bytes());
+        header
+    }
+}
+
+fn unsigned_content(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(3);
+    bytes[start..].to_vec()
+}
+
+fn object_id(object_type: u16, instance: u32) -> [u8; 4] {
+    ((object_type as u32) << 22 | instance).to_be_bytes()
+}
+
+fn opening(number: u8) -> u8 {
+    number << 4 | 0x0e
+}
+
+fn closing(number: u8) -> u8 {
+    number << 4 | 0x0f
+}
+
+fn context_unsigned(number: u8, value: u32) -> Vec<u8> {
+    let content = unsigned_content(value);
+    let mut encoded = tag(number, true, content.len());
+    encoded.extend(content);
+    encoded
+}
+
+fn context_object(number: u8, object_type: u16, instance: u32) -> Vec<u8> {
+    let mut encoded = tag(number, true, 4);
+    encoded.extend(object_id(object_type, instance));
+    encoded
+}
+
+// application tagged encoding of the value
+fn encode(value: &Value) -> Vec<u8> {
+    let (number, content) = match value {
+        // the value of a boolean is in its length
+        Value::Boolean(value) => return vec![0x10 | *value as u8],
+        Value::Unsigned(value) => (2, unsigned_content(*value)),
+        Value::Real(value) => (4, value.to_be_bytes().to_vec()),
+        Value::CharacterString(value) => {
+            // character set UTF-8
+            let mut content = vec![0];
+            content.extend(value.as_bytes());
+            (7, content)
+        }
+        Value::BitString(bits) => {
+            let mut content = vec![((8 - bits.len() % 8) % 8) as u8];
+            for byte in bits.chunks(8) {
+                content.push(
+                    byte.iter()
+                        .enumerate()
+                        .fold(0, |packed, (bit, set)| packed | (*set as u8) << (7 - bit)),
+                );
+            }
+            (8, content)
+        }
+        Value::Enumerated(value) => (9, unsigned_content(*value)),
+        Value::ObjectIdentifier(object_type, instance) => {
+            (12, object_id(*object_type, *instance).to_vec())
+        }
+    };
+    let mut encoded = tag(number, false, content.len());
+    encoded.extend(content);
+    encoded
+}
+
+// bit string with the given bits set
+fn bits(length: usize, set: &[usize]) -> Value {
+    Value::BitString((0..length).map(|bit| set.contains(&bit)).collect())
+}
+
+#[derive(Debug, PartialEq)]
+enum Tag {
+    Value {
+        number: u8,
+        context: bool,
+        length: usize,
+    },
+    Opening(u8),
+    Closing(u8),
+}
+
+// reads tagged values of a request
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn tag(&mut self) -> Option<Tag> {
+        let header = *self.bytes(1)?.first()?;
+        let mut number = header >> 4;
+        if number == 0x0f {
+            number = self.bytes(1)?[0];
+        }
+        let context = header & 0x08 != 0;
+        let length = match header & 0x07 {
+            6 if context => return Some(Tag::Opening(number)),
+            7 if context => return Some(Tag::Closing(number)),
+            5 => match self.bytes(1)?[0] {
+                254 => u16::from_be_bytes([self.bytes(1)?[0], self.bytes(1)?[0]]) as usize,
+                255 => return None,
+                length => length as usize,
+            },
+            length => length as usize,
+        };
+        Some(Tag::Value {
+            number,
+            context,
+            length,
+        })
+    }
+
+    fn peek(&mut self) -> Option<Tag> {
+        let position = self.position;
+        let tag = self.tag();
+        self.position = position;
+        tag
+    }
+
+    fn unsigned(&mut self, length: usize) -> Option<u32> {
+        if length == 0 || length > 4 {
+            return None;
+        }
+        Some(
+            self.bytes(length)?
+                .iter()
+                .fold(0, |value, byte| value << 8 | *byte as u32),
+        )
+    }
+
+    // unsigned value of the context tag, None if the next tag is another one
+    fn context(&mut self, number: u8) -> Option<u32> {
+        match self.peek()? {
+            Tag::Value {
+                number: next,
+                context: true,
+                length,
+            } if next == number => {
+                self.tag()?;
+                self.unsigned(length)
+            }
+            _ => None,
+        }
+    }
+
+    fn object(&mut self, number: u8) -> Option<(u16, u32)> {
+        let id = self.context(number)?;
+        Some(((id >> 22) as u16, id & 0x3f_ffff))
+    }
+
+    fn expect(&mut self, expected: Tag) -> Option<()> {
+        if self.tag()? == expected {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
+
+// network layer header of a reply, routed back to the network of the request
+fn reply_npdu(source: &Option<(u16, Vec<u8>)>) -> Vec<u8> {
+    match source {
+        Some((network, address)) => {
+            let mut npdu = vec![0x01, 0x20];
+            npdu.extend(network.to_be_bytes());
+            npdu.push(address.len() as u8);
+            npdu.extend(address);
+            // hop count
+            npdu.push(0xff);
+            npdu
+        }
+        None => vec![0x01, 0x00],
+    }
+}
+
+fn bvlc(function: u8, npdu: Vec<u8>, apdu: Vec<u8>) -> Vec<u8> {
+    let length = (4 + npdu.len() + apdu.len()) as u16;
+    let mut packet = vec![BVLC_TYPE, function];
+    packet.extend(length.to_be_bytes());
+    packet.extend(npdu);
+    packet.extend(apdu);
+    packet
+}
+
+#[derive(Debug, Clone)]
+enum Object {
+    Device,
+    Input {
+        instance: u32,
+        address: String,
+        metric: usize,
+    },
+}
+
+/// BACnet/IP server presenting temperature and humidity of each Ruuvi tag as analog input
+/// objects of a virtual device, for building management systems.
+pub struct BacnetServer {
+    config: BacnetConfig,
+    device_name: String,
+    status: SharedStatus,
+    running: Arc<AtomicBool>,
+}
+
+impl BacnetServer {
+    // tags in the order of their objects, the configured ones or those seen so far
+    fn tags(&self) -> Vec<String> {
+        match self.config.tags() {
+            Some(tags) => tags.iter().map(|tag| tag.to_uppercase()).collect(),
+            None => self.status.lock().unwrap().tags.keys().cloned().collect(),
+        }
+    }
+
+    fn object(&self, object_type: u16, instance: u32) -> Option<Object> {
+        match object_type {
+            OBJECT_DEVICE
+                if instance == self.config.device_instance() || instance == WILDCARD_INSTANCE =>
+            {
+                Some(Object::Device)
+            }
+            OBJECT_ANALOG_INPUT if instance > 0 => {
+                let index = (instance - 1) as usize;
+                let address = self.tags().get(index / METRICS.len())?.clone();
+                Some(Object::Input {
+                    instance,
+                    address,
+                    metric: index % METRICS.len(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn object_list(&self) -> Vec<Value> {
+        let mut objects = vec![Value::ObjectIdentifier(
+            OBJECT_DEVICE,
+            self.config.device_instance(),
+        )];
+        let inputs = (self.tags().len() * METRICS.len()) as u32;
+        objects.extend(
+            (1..=inputs).map(|instance| Value::ObjectIdentifier(OBJECT_ANALOG_INPUT, instance)),
+        );
+        objects
+    }
+
+    // latest value of the metric of the tag
+    fn present_value(&self, address: &str, metric: usize) -> Option<f64> {
+        let status = self.status.lock().unwrap();
+        let beacon = status.tags.get(address)?.last_beacon.as_ref()?;
+        beacon.data.as_ref()?[METRICS[metric].0].as_f64()
+    }
+
+    fn properties(object: &Object) -> &'static [u32] {
+        match object {
+            Object::Device => &DEVICE_PROPERTIES,
+            Object::Input { .. } => &INPUT_PROPERTIES,
+        }
+    }
+
+    // values of the property, several only for the whole object list
+    fn property(&self, object: &Object, property: u32) -> Result<Vec<Value>, (u32, u32)> {
+        let value = match (object, property) {
+            (Object::Device, PROP_OBJECT_LIST) => return Ok(self.object_list()),
+            (Object::Device, PROP_DEVICE_ADDRESS_BINDING) => return Ok(Vec::new()),
+            (Object::Device, PROP_OBJECT_IDENTIFIER) => {
+                Value::ObjectIdentifier(OBJECT_DEVICE, self.config.device_instance())
+            }
+            (Object::Device, PROP_OBJECT_NAME) => Value::CharacterString(self.device_name.clone()),
+            (Object::Device, PROP_OBJECT_TYPE) => Value::Enumerated(OBJECT_DEVICE as u32),
+            // operational
+            (Object::Device, PROP_SYSTEM_STATUS) => Value::Enumerated(0),
+            (Object::Device, PROP_VENDOR_NAME) => {
+                Value::CharacterString(env!("CARGO_PKG_AUTHORS").to_string())
+            }
+            (Object::Device, PROP_VENDOR_IDENTIFIER) => Value::Unsigned(VENDOR_IDENTIFIER),
+            (Object::Device, PROP_MODEL_NAME) => {
+                Value::CharacterString(env!("CARGO_PKG_NAME").to_string())
+            }
+            (Object::Device, PROP_FIRMWARE_REVISION)
+            | (Object::Device, PROP_APPLICATION_SOFTWARE_VERSION) => {
+                Value::CharacterString(env!("CARGO_PKG_VERSION").to_string())
+            }
+            (Object::Device, PROP_PROTOCOL_VERSION) => Value::Unsigned(1),
+            (Object::Device, PROP_PROTOCOL_REVISION) => Value::Unsigned(PROTOCOL_REVISION),
+            (Object::Device, PROP_PROTOCOL_SERVICES_SUPPORTED) => bits(
+                41,
+                &[
+                    SERVICE_READ_PROPERTY as usize,
+                    SERVICE_READ_PROPERTY_MULTIPLE as usize,
+                    // i-Am and who-Is in the bit string of services
+                    26,
+                    34,
+                ],
+            ),
+            (Object::Device, PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED) => {
+                bits(60, &[OBJECT_ANALOG_INPUT as usize, OBJECT_DEVICE as usize])
+            }
+            (Object::Device, PROP_MAX_APDU_LENGTH_ACCEPTED) => Value::Unsigned(MAX_APDU_LENGTH),
+            (Object::Device, PROP_SEGMENTATION_SUPPORTED) => Value::Enumerated(NO_SEGMENTATION),
+            (Object::Device, PROP_APDU_TIMEOUT) => Value::Unsigned(3000),
+            (Object::Device, PROP_NUMBER_OF_APDU_RETRIES) => Value::Unsigned(3),
+            (Object::Device, PROP_DATABASE_REVISION) => Value::Unsigned(0),
+            (Object::Device, PROP_DESCRIPTION) => {
+                Value::CharacterString("Ruuvi tags received by ruuvi2iotcore".to_string())
+            }
+            (
+                Object::Input {
+                    instance,
+                    address,
+                    metric,
+                },
+                property,
+            ) => {
+                let value = self.present_value(address, *metric);
+                match property {
+                    PROP_OBJECT_IDENTIFIER => {
+                        Value::ObjectIdentifier(OBJECT_ANALOG_INPUT, *instance)
+                    }
+                    PROP_OBJECT_NAME => {
+                        Value::CharacterString(format!("{} {}", address, METRICS[*metric].0))
+                    }
+                    PROP_OBJECT_TYPE => Value::Enumerated(OBJECT_ANALOG_INPUT as u32),
+                    // no value is received from the tag yet
+                    PROP_PRESENT_VALUE => Value::Real(value.unwrap_or_default() as f32),
+                    // fault flag while there is no value
+                    PROP_STATUS_FLAGS => bits(4, if value.is_some() { &[] } else { &[1] }),
+                    // normal
+                    PROP_EVENT_STATE => Value::Enumerated(0),
+                    PROP_OUT_OF_SERVICE => Value::Boolean(false),
+                    PROP_UNITS => Value::Enumerated(METRICS[*metric].1),
+                    // no-fault-detected or no-sensor
+                    PROP_RELIABILITY => Value::Enumerated(if value.is_some() { 0 } else { 1 }),
+                    PROP_DESCRIPTION => Value::CharacterString(format!(
+                        "{} of Ruuvi tag {}",
+                        METRICS[*metric].0, address
+                    )),
+                    _ => return Err((ERROR_CLASS_PROPERTY, ERROR_UNKNOWN_PROPERTY)),
+                }
+            }
+            (Object::Device, _) => return Err((ERROR_CLASS_PROPERTY, ERROR_UNKNOWN_PROPERTY)),
+        };
+        Ok(vec![value])
+    }
+
+    // encoded value of the property, or an element of the object list by its index
+    fn read(
+        &self,
+        object_type: u16,
+        instance: u32,
+        property: u32,
+        index: Option<u32>,
+    ) -> Result<Vec<u8>, (u32, u32)> {
+        let object = match self.object(object_type, instance) {
+            Some(object) => object,
+            None => return Err((ERROR_CLASS_OBJECT, ERROR_UNKNOWN_OBJECT)),
+        };
+        let values = self.property(&object, property)?;
+        let values = match index {
+            None => values,
+            Some(_) if property != PROP_OBJECT_LIST => {
+                return Err((ERROR_CLASS_PROPERTY, ERROR_PROPERTY_IS_NOT_AN_ARRAY))
+            }
+            // index 0 is the length of the array
+            Some(0) => vec![Value::Unsigned(values.len() as u32)],
+            Some(index) => match values.get(index as usize - 1) {
+                Some(value) => vec![value.clone()],
+                None => return Err((ERROR_CLASS_PROPERTY, ERROR_INVALID_ARRAY_INDEX)),
+            },
+        };
+        Ok(values.iter().flat_map(encode).collect())
+    }
+
+    fn read_property(&self, request: &mut Reader) -> Option<Result<Vec<u8>, (u32, u32)>> {
+        let (object_type, instance) = request.object(0)?;
+        let property = request.context(1)?;
+        let index = request.context(2);
+        let value = match self.read(object_type, instance, property, index) {
+            Ok(value) => value,
+            Err(error) => return Some(Err(error)),
+        };
+        let mut ack = context_object(0, object_type, instance);
+        ack.extend(context_unsigned(1, property));
+        if let Some(index) = index {
+            ack.extend(context_unsigned(2, index));
+        }
+        ack.push(opening(3));
+        ack.extend(value);
+        ack.push(closing(3));
+        Some(Ok(ack))
+    }
+
+    fn read_property_multiple(&self, request: &mut Reader) -> Option<Vec<u8>> {
+        let mut ack = Vec::new();
+        while !request.at_end() {
+            let (object_type, instance) = request.object(0)?;
+            request.expect(Tag::Opening(1))?;
+            ack.extend(context_object(0, object_type, instance));
+            ack.push(opening(1));
+            while request.peek()? != Tag::Closing(1) {
+                let property = request.context(0)?;
+                let index = request.context(1);
+                let object = self.object(object_type, instance);
+                // special properties stand for all, required or optional properties of the object
+                let properties: Vec<(u32, Option<u32>)> = match (&object, property) {
+                    (Some(object), PROP_ALL) => BacnetServer::properties(object)
+                        .iter()
+                        .map(|property| (*property, None))
+                        .collect(),
+                    (Some(object), PROP_REQUIRED) | (Some(object), PROP_OPTIONAL) => {
+                        BacnetServer::properties(object)
+                            .iter()
+                            .filter(|candidate| {
+                                OPTIONAL_PROPERTIES.contains(candidate)
+                                    == (property == PROP_OPTIONAL)
+                            })
+                            .map(|property| (*property, None))
+                            .collect()
+                    }
+                    _ => vec![(property, index)],
+                };
+                for (property, index) in properties {
+                    ack.extend(context_unsigned(2, property));
+                    if let Some(index) = index {
+                        ack.extend(context_unsigned(3, index));
+                    }
+                    match self.read(object_type, instance, property, index) {
+                        Ok(value) => {
+                            ack.push(opening(4));
+                            ack.extend(value);
+                            ack.push(closing(4));
+                        }
+                        Err((class, code)) => {
+                            ack.push(opening(5));
+                            ack.extend(encode(&Value::Enumerated(class)));
+                            ack.extend(encode(&Value::Enumerated(code)));
+                            ack.push(closing(5));
+                        }
+                    }
+                }
+            }
+            request.expect(Tag::Closing(1))?;
+            ack.push(closing(1));
+        }
+        Some(ack)
+    }
+
+    fn i_am(&self) -> Vec<u8> {
+        let mut apdu = vec![PDU_UNCONFIRMED << 4, SERVICE_I_AM];
+        apdu.extend(encode(&Value::ObjectIdentifier(
+            OBJECT_DEVICE,
+            self.config.device_instance(),
+        )));
+        apdu.extend(encode(&Value::Unsigned(MAX_APDU_LENGTH)));
+        apdu.extend(encode(&Value::Enumerated(NO_SEGMENTATION)));
+        apdu.extend(encode(&Value::Unsigned(VENDOR_IDENTIFIER)));
+        apdu
+    }
+
+    // reply to the APDU of a request, None for those that are not answered
+    fn reply(&self, apdu: &[u8]) -> Option<Vec<u8>> {
+        match apdu.first()? >> 4 {
+            PDU_UNCONFIRMED if apdu.get(1) == Some(&SERVICE_WHO_IS) => {
+                let mut request = Reader {
+                    data: &apdu[2..],
+                    position: 0,
+                };
+                let low = request.context(0).unwrap_or(0);
+                let high = request.context(1).unwrap_or(WILDCARD_INSTANCE);
+                let instance = self.config.device_instance();
+                if instance >= low && instance <= high {
+                    Some(self.i_am())
+                } else {
+                    None
+                }
+            }
+            PDU_CONFIRMED => {
+                let invoke_id = *apdu.get(2)?;
+                if apdu[0] & 0x08 != 0 {
+                    return Some(vec![PDU_ABORT, invoke_id, ABORT_SEGMENTATION_NOT_SUPPORTED]);
+                }
+                let service = *apdu.get(3)?;
+                let mut request = Reader {
+                    data: &apdu[4..],
+                    position: 0,
+                };
+                let result = match service {
+                    SERVICE_READ_PROPERTY => self.read_property(&mut request),
+                    SERVICE_READ_PROPERTY_MULTIPLE => {
+                        self.read_property_multiple(&mut request).map(Ok)
+                    }
+                    _ => return Some(vec![PDU_REJECT, invoke_id, REJECT_UNRECOGNIZED_SERVICE]),
+                };
+                match result {
+                    Some(Ok(ack)) => {
+                        let mut reply = vec![PDU_COMPLEX_ACK, invoke_id, service];
+                        reply.extend(ack);
+                        Some(reply)
+                    }
+                    Some(Err((class, code))) => {
+                        let mut reply = vec![PDU_ERROR, invoke_id, service];
+                        reply.extend(encode(&Value::Enumerated(class)));
+                        reply.extend(encode(&Value::Enumerated(code)));
+                        Some(reply)
+                    }
+                    None => Some(vec![
+                        PDU_REJECT,
+                        invoke_id,
+                        REJECT_MISSING_REQUIRED_PARAMETER,
+                    ]),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // answer to a BACnet/IP packet, None for packets that are not answered
+    fn handle_packet(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        trace!("in handle_packet");
+        if packet.len() < 6
+            || packet[0] != BVLC_TYPE
+            || !(packet[1] == BVLC_UNICAST || packet[1] == BVLC_BROADCAST)
+            || u16::from_be_bytes([packet[2], packet[3]]) as usize != packet.len()
+        {
+            return None;
+        }
+        let mut npdu = Reader {
+            data: &packet[4..],
+            position: 0,
+        };
+        let control = match npdu.bytes(2)? {
+            [0x01, control] => *control,
+            _ => return None,
+        };
+        // network layer messages are for routers
+        if control & 0x80 != 0 {
+            return None;
+        }
+        if control & 0x20 != 0 {
+            let network = u16::from_be_bytes([npdu.bytes(1)?[0], npdu.bytes(1)?[0]]);
+            let length = npdu.bytes(1)?[0] as usize;
+            npdu.bytes(length)?;
+            // the gateway is not a router, only global broadcasts from other networks reach it
+            if network != 0xffff {
+                return None;
+            }
+        }
+        let source = if control & 0x08 != 0 {
+            let network = u16::from_be_bytes([npdu.bytes(1)?[0], npdu.bytes(1)?[0]]);
+            let length = npdu.bytes(1)?[0] as usize;
+            Some((network, npdu.bytes(length)?.to_vec()))
+        } else {
+            None
+        };
+        if control & 0x20 != 0 {
+            // hop count
+            npdu.bytes(1)?;
+        }
+        let apdu = self.reply(&packet[4 + npdu.position..])?;
+        Some(bvlc(BVLC_UNICAST, reply_npdu(&source), apdu))
+    }
+
+    pub fn start_server(&self) -> Result<(), Report> {
+        trace!("in start_server");
+        let port = match self.config.port() {
+            Some(port) => port,
+            None => {
+                debug!("BACnet/IP server not enabled.");
+                return Ok(());
+            }
+        };
+        let address = format!("{}:{}", self.config.address(), port);
+        let socket = match UdpSocket::bind(&address) {
+            Ok(socket) => socket,
+            Err(error) => {
+                return Err(eyre!("Unable to bind BACnet/IP server")
+                    .with_section(move || address.header("Address:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        // wake up regularly to notice shutdown
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        info!(
+            "BACnet/IP device {} listening at {}",
+            self.config.device_instance(),
+            address
+        );
+
+        let mut buffer = [0; 1500];
+        while self.running.load(Ordering::Relaxed) {
+            let (length, sender) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(error) => {
+                    error!("Unable to receive from BACnet/IP socket: {}", error);
+                    continue;
+                }
+            };
+            if let Some(reply) = self.handle_packet(&buffer[..length]) {
+                if let Err(error) = socket.send_to(&reply, sender) {
+                    warn!(
+                        "Unable to answer BACnet/IP request of {}: {}",
+                        sender, error
+                    );
+                }
+            }
+        }
+        info!("Shutting down BACnet/IP server.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &BacnetConfig,
+        device_id: &str,
+        status: &SharedStatus,
+        running: &Arc<AtomicBool>,
+    ) -> BacnetServer {
+        trace!("in build");
+        BacnetServer {
+            config: config.clone(),
+            device_name: config
+                .device_name()
+                .unwrap_or_else(|| device_id.to_string()),
+            status: status.clone(),
+            running: running.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BacnetServer;
+    use crate::configfile::BacnetConfig;
+    use crate::mock::shared_beacon;
+    use crate::status::{GatewayStatus, TagStatus};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+
+    fn request(apdu: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x81, 0x0a, 0x00, (6 + apdu.len()) as u8, 0x01, 0x04];
+        packet.extend(apdu);
+        packet
+    }
+
+    #[test]
+    fn tags_are_analog_inputs_of_a_bacnet_device() {
+        let status = Arc::new(Mutex::new(GatewayStatus::default()));
+        status.lock().unwrap().tags.insert(
+            "AA:BB:CC:DD:EE:01".to_string(),
+            TagStatus {
+                last_beacon: Some(shared_beacon("AA:BB:CC:DD:EE:01", 1)),
+                ..Default::default()
+            },
+        );
+        let config: BacnetConfig = serde_json::from_value(json!({
+            "port": 47808,
+            "device_instance": 1234,
+            "tags": ["aa:bb:cc:dd:ee:01", "AA:BB:CC:DD:EE:02"],
+        }))
+        .unwrap();
+        let server = BacnetServer::build(
+            &config,
+            "test-gateway",
+            &status,
+            &Arc::new(AtomicBool::new(true)),
+        );
+
+        // who-is of all devices is answered with i-am of device 1234
+        let mut who_is = vec![0x81, 0x0b, 0x00, 0x08, 0x01, 0x20, 0xff, 0xff, 0x00, 0xff];
+        who_is.extend([0x10, 0x08]);
+        who_is[3] = who_is.len() as u8;
+        assert_eq!(
+            server.handle_packet(&who_is).unwrap(),
+            [
+                0x81, 0x0a, 0x00, 0x14, 0x01, 0x00, 0x10, 0x00, 0xc4, 0x02, 0x00, 0x04, 0xd2, 0x22,
+                0x05, 0xc4, 0x91, 0x03, 0x21, 0x00
+            ]
+        );
+        // who-is of other device instances is not
+        let mut who_is = request(&[0x10, 0x08, 0x09, 0x01, 0x19, 0x0a]);
+        who_is[5] = 0x00;
+        assert!(server.handle_packet(&who_is).is_none());
+
+        // present value of analog input 1 is the temperature of the first tag
+        let read = request(&[
+            0x00, 0x05, 0x07, 0x0c, 0x0c, 0x00, 0x00, 0x00, 0x01, 0x19, 0x55,
+        ]);
+        let mut expected = vec![0x81, 0x0a, 0x00, 0x17, 0x01, 0x00, 0x30, 0x07, 0x0c];
+        expected.extend([0x0c, 0x00, 0x00, 0x00, 0x01, 0x19, 0x55, 0x3e, 0x44]);
+        expected.extend(21.5f32.to_be_bytes());
+        expected.push(0x3f);
+        assert_eq!(server.handle_packet(&read).unwrap(), expected);
+
+        // object list of the device has the device and two inputs of each tag
+        let read = request(&[
+            0x00, 0x05, 0x08, 0x0c, 0x0c, 0x02, 0x00, 0x04, 0xd2, 0x19, 0x4c, 0x29, 0x00,
+        ]);
+        let reply = server.handle_packet(&read).unwrap();
+        assert_eq!(reply[reply.len() - 4..], [0x3e, 0x21, 0x05, 0x3f]);
+        let read = request(&[
+            0x00, 0x05, 0x09, 0x0c, 0x0c, 0x02, 0x00, 0x04, 0xd2, 0x19, 0x4c, 0x29, 0x05,
+        ]);
+        let reply = server.handle_packet(&read).unwrap();
+        assert_eq!(
+            reply[reply.len() - 6..],
+            [0xc4, 0x00, 0x00, 0x00, 0x04, 0x3f]
+        );
+
+        // humidity of the second tag is not known yet, analog input 5 does not exist
+        let rpm = request(&[
+            0x00, 0x05, 0x0a, 0x0e, 0x0c, 0x00, 0x00, 0x00, 0x04, 0x1e, 0x09, 0x75, 0x09, 0x6f,
+            0x1f, 0x0c, 0x00, 0x00, 0x00, 0x05, 0x1e, 0x09, 0x55, 0x1f,
+        ]);
+        let reply = server.handle_packet(&rpm).unwrap();
+        assert_eq!(
+            reply[6..],
+            [
+                0x30, 0x0a, 0x0e, 0x0c, 0x00, 0x00, 0x00, 0x04, 0x1e, 0x29, 0x75, 0x4e, 0x91, 0x1d,
+                0x4f, 0x29, 0x6f, 0x4e, 0x82, 0x04, 0x40, 0x4f, 0x1f, 0x0c, 0x00, 0x00, 0x00, 0x05,
+                0x1e, 0x29, 0x55, 0x5e, 0x91, 0x01, 0x91, 0x1f, 0x5f, 0x1f
+            ]
+        );
+
+        // unknown properties are errors and other services are rejected
+        let read = request(&[
+            0x00, 0x05, 0x0b, 0x0c, 0x0c, 0x00, 0x00, 0x00, 0x01, 0x19, 0x4c,
+        ]);
+        assert_eq!(
+            server.handle_packet(&read).unwrap()[6..],
+            [0x50, 0x0b, 0x0c, 0x91, 0x02, 0x91, 0x20]
+        );
+        let write = request(&[0x00, 0x05, 0x0c, 0x0f]);
+        assert_eq!(
+            server.handle_packet(&write).unwrap()[6..],
+            [0x60, 0x0c, 0x09]
+        );
+    }
+}
+
+// eof
@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::alerts::metric_value;
+use crate::configfile::AlertRule;
+use crate::scanner::RuuviBluetoothBeacon;
+
+// millivolts the battery has to recover above the critical level before its alert is cleared
+const CRITICAL_HYSTERESIS: f64 = 100.0;
+
+/// Beacons of a tag whose battery is below the millivolts are published at most once in the
+/// interval.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub struct BatteryTier {
+    pub below: f64,
+    pub interval: u64,
+}
+
+/// Publishing of tags with low batteries, part of collect config. Voltages are millivolts of
+/// the "powerinfo" field of beacon data.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, PartialOrd)]
+pub struct BatteryConfig {
+    tiers: Option<Vec<BatteryTier>>,
+    critical: Option<f64>,
+}
+impl BatteryConfig {
+    /// Interval of the lowest tier the voltage is below, if any
+    pub fn interval(&self, voltage: f64) -> Option<u64> {
+        self.tiers
+            .iter()
+            .flatten()
+            .filter(|tier| voltage < tier.below)
+            .min_by(|a, b| a.below.partial_cmp(&b.below).unwrap())
+            .map(|tier| tier.interval)
+    }
+
+    /// Alert rule of batteries entering the critical band, if one is set
+    pub fn critical_rule(&self) -> Option<AlertRule> {
+        self.critical
+            .map(|critical| AlertRule::below("powerinfo", critical, CRITICAL_HYSTERESIS))
+    }
+}
+
+/// Skips beacons of tags with low batteries that are published more often than the tier of
+/// their voltage allows. Tags advertise on their own schedule, so this thins out what is
+/// published of them rather than saving their batteries.
+#[derive(Default)]
+pub struct BatterySampler {
+    // beacon timestamp of the last beacon of each tag let through
+    last_sampled: HashMap<String, chrono::DateTime<chrono::Utc>>,
+}
+
+impl BatterySampler {
+    /// Whether the beacon is published, beacons without battery voltage always are
+    pub fn sample(&mut self, config: &BatteryConfig, beacon: &RuuviBluetoothBeacon) -> bool {
+        let interval =
+            match metric_value(beacon, "powerinfo").and_then(|voltage| config.interval(voltage)) {
+                Some(interval) => interval,
+                None => {
+                    self.last_sampled
+                        .insert(beacon.address.clone(), beacon.timestamp);
+                    return true;
+                }
+            };
+        // beacon timestamps measure the interval, so replayed beacons behave the same
+        if let Some(last_sampled) = self.last_sampled.get(&beacon.address) {
+            if beacon.timestamp - *last_sampled < chrono::Duration::seconds(interval as i64) {
+                return false;
+            }
+        }
+        self.last_sampled
+            .insert(beacon.address.clone(), beacon.timestamp);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatteryConfig, BatterySampler};
+    use crate::mock::beacon;
+
+    #[test]
+    fn low_batteries_are_sampled_by_tier() {
+        let config: BatteryConfig = serde_json::from_value(json!({
+            "tiers": [
+                { "below": 2800.0, "interval": 300 },
+                { "below": 2500.0, "interval": 1800 },
+            ],
+            "critical": 2400.0,
+        }))
+        .unwrap();
+        assert_eq!(config.interval(2900.0), None);
+        assert_eq!(config.interval(2700.0), Some(300));
+        assert_eq!(config.interval(2450.0), Some(1800));
+        assert_eq!(config.critical_rule().unwrap().threshold(), 2400.0);
+
+        let mut sampler = BatterySampler::default();
+        let started = chrono::Utc::now();
+        let mut sample = |seconds: i64, voltage: f64| {
+            let mut tag = beacon("AA:BB:CC:DD:EE:01", seconds as u64);
+            tag.timestamp = started + chrono::Duration::seconds(seconds);
+            tag.data.as_mut().unwrap()["powerinfo"] = json!(voltage);
+            sampler.sample(&config, &tag)
+        };
+        // healthy battery is published every time
+        assert!(sample(0, 2900.0));
+        assert!(sample(1, 2900.0));
+        assert!(!sample(2, 2700.0));
+        assert!(!sample(300, 2700.0));
+        assert!(sample(301, 2700.0));
+        assert!(!sample(1800, 2450.0));
+        assert!(sample(2101, 2450.0));
+    }
+}
+
+// eof
@@ -0,0 +1,253 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::time::Duration;
+
+use crate::configfile::SinkConfig;
+use crate::scanner::{RuuviBluetoothBeacon, SharedBeacon};
+use crate::serviceaccount::ServiceAccount;
+use crate::sinks::Sink;
+
+// API of BigQuery when the sink does not give another one
+const DEFAULT_API_URL: &str = "https://bigquery.googleapis.com";
+// scope of access tokens, inserting rows into tables is all the sink does
+const SCOPE: &str = "https://www.googleapis.com/auth/bigquery.insertdata";
+
+// columns of data format 5 fields and the JSON pointer of the field in decoded beacon data
+const DATA_COLUMNS: [(&str, &str); 9] = [
+    ("temperature", "/temperature"),
+    ("humidity", "/humidity"),
+    ("pressure", "/atmospheric_pressure"),
+    ("acceleration_x", "/acceleration/on_x_axis"),
+    ("acceleration_y", "/acceleration/on_y_axis"),
+    ("acceleration_z", "/acceleration/on_z_axis"),
+    ("tx_power", "/tx_power"),
+    ("movement_counter", "/movement_counter"),
+    (
+        "measurement_sequence_number",
+        "/measurement_sequence_number",
+    ),
+];
+
+// row of the beacon in the table: the gateway, the tag, the time it was received and the data
+//  format 5 fields of the tag. fields the data format of the tag lacks are null
+fn row(beacon: &RuuviBluetoothBeacon, device_id: &str) -> serde_json::Value {
+    let data = beacon.data.clone().unwrap_or(serde_json::Value::Null);
+    let field = |pointer: &str| {
+        data.pointer(pointer)
+            .and_then(|value| value.as_f64())
+            .map_or(serde_json::Value::Null, |value| json!(value))
+    };
+    let mut row = serde_json::Map::new();
+    row.insert("gateway".to_string(), json!(device_id));
+    row.insert("address".to_string(), json!(beacon.address));
+    row.insert(
+        "timestamp".to_string(),
+        json!(beacon.timestamp.to_rfc3339()),
+    );
+    row.insert(
+        "data_format".to_string(),
+        match beacon.decoder.as_deref() {
+            Some("ruuvi_v3") => json!(3),
+            Some("ruuvi_v5") => json!(5),
+            _ => serde_json::Value::Null,
+        },
+    );
+    for (column, pointer) in DATA_COLUMNS.iter() {
+        row.insert(column.to_string(), field(pointer));
+    }
+    row.insert(
+        "battery_voltage".to_string(),
+        json!(data["powerinfo"]
+            .as_f64()
+            .map(|powerinfo| powerinfo / 1000.0)),
+    );
+    row.insert("sequence".to_string(), json!(beacon.sequence));
+    serde_json::Value::Object(row)
+}
+
+/// Sink streaming beacons as rows into a BigQuery table with the streaming insert API,
+/// authenticated as a service account.
+pub struct BigQuerySink {
+    insert_url: String,
+    table: String,
+    device_id: String,
+    account: ServiceAccount,
+    agent: ureq::Agent,
+}
+
+impl BigQuerySink {
+    fn insert(&mut self, beacons: &[SharedBeacon]) -> Result<(), String> {
+        trace!("in insert");
+        let rows: Vec<serde_json::Value> = beacons
+            .iter()
+            .map(|beacon| {
+                json!({
+                    // lets BigQuery drop rows of a batch that is sent again
+                    "insertId": format!(
+                        "{}-{}-{}",
+                        self.device_id,
+                        beacon.address,
+                        beacon.timestamp.timestamp_millis()
+                    ),
+                    "json": row(beacon, &self.device_id),
+                })
+            })
+            .collect();
+        let body = json!({ "rows": rows });
+        let token = self.account.access_token(&self.agent)?;
+        let response = match self
+            .agent
+            .post(&self.insert_url)
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                if status == 401 {
+                    // the token was revoked or the clock jumped, a new one is used next time
+                    self.account.revoke();
+                }
+                return Err(format!(
+                    "{}: {}",
+                    status,
+                    response.into_string().unwrap_or_default()
+                ));
+            }
+            Err(error) => return Err(error.to_string()),
+        };
+        let result: serde_json::Value = response
+            .into_string()
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or_default();
+        // rows not matching the schema of the table are rejected one by one
+        match result["insertErrors"].as_array() {
+            Some(errors) if !errors.is_empty() => Err(format!(
+                "{} rows rejected, first: {}",
+                errors.len(),
+                errors[0]["errors"]
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn build(config: &SinkConfig, device_id: &str) -> Result<BigQuerySink, Report> {
+        trace!("in build");
+        let key_file = match config.service_account_key() {
+            Some(key_file) => key_file,
+            None => return Err(eyre!("No service account key given for BigQuery sink")),
+        };
+        let account = ServiceAccount::read(&key_file, SCOPE)?;
+        let project_id = match config.project_id().or_else(|| account.project_id()) {
+            Some(project_id) => project_id,
+            None => return Err(eyre!("No project id given for BigQuery sink")),
+        };
+        let (dataset, table) = match (config.dataset(), config.table()) {
+            (Some(dataset), Some(table)) => (dataset, table),
+            _ => return Err(eyre!("No dataset and table given for BigQuery sink")),
+        };
+        Ok(BigQuerySink {
+            insert_url: format!(
+                "{}/bigquery/v2/projects/{}/datasets/{}/tables/{}/insertAll",
+                config
+                    .url()
+                    .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+                    .trim_end_matches('/'),
+                project_id,
+                dataset,
+                table
+            ),
+            table: format!("{}.{}.{}", project_id, dataset, table),
+            device_id: device_id.to_string(),
+            account,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout()))
+                .build(),
+        })
+    }
+}
+
+impl Sink for BigQuerySink {
+    fn name(&self) -> String {
+        format!("BigQuery table {}", self.table)
+    }
+
+    fn deliver(&mut self, beacons: &[SharedBeacon]) -> Result<(), Report> {
+        trace!("in deliver");
+        match self.insert(beacons) {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                let table = self.table.clone();
+                Err(eyre!("Unable to insert beacons into BigQuery")
+                    .with_section(move || table.header("Table:"))
+                    .with_section(move || reason.header("Reason:")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BigQuerySink;
+    use crate::configfile::SinkConfig;
+    use crate::mock::{beacon, serve, service_account_key, shared_beacon, HttpRequest};
+    use crate::sinks::Sink;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn beacons_are_inserted_into_bigquery() {
+        let token = json!({ "access_token": "ya29.token", "expires_in": 3600 }).to_string();
+        let rejected = json!({
+            "insertErrors": [{ "index": 0, "errors": [{ "reason": "invalid" }] }],
+        })
+        .to_string();
+        let (url, server) = serve(vec![(200, token), (200, "{}".to_string()), (200, rejected)]);
+        let key_file = service_account_key(&format!("{}/token", url));
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "bigquery",
+            "url": url,
+            "service_account_key": key_file,
+            "dataset": "ruuvi",
+            "table": "beacons",
+        }))
+        .unwrap();
+        let mut sink = BigQuerySink::build(&config, "test-gateway").unwrap();
+        assert_eq!(sink.name(), "BigQuery table ruuvi-project.ruuvi.beacons");
+        let mut tag = beacon("AA:BB:CC:DD:EE:01", 1);
+        tag.data.as_mut().unwrap()["powerinfo"] = json!(2977);
+        sink.deliver(&[Arc::new(tag), shared_beacon("AA:BB:CC:DD:EE:02", 2)])
+            .unwrap();
+        // the token is reused and rejected rows fail the batch
+        assert!(sink
+            .deliver(&[shared_beacon("AA:BB:CC:DD:EE:01", 3)])
+            .is_err());
+        fs::remove_file(key_file).unwrap();
+
+        let requests: Vec<HttpRequest> = server.iter().collect();
+        assert_eq!(requests[0].line, "POST /token HTTP/1.1");
+        assert!(requests[0].body.starts_with(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion=ey"
+        ));
+        assert_eq!(
+            requests[1].line,
+            "POST /bigquery/v2/projects/ruuvi-project/datasets/ruuvi/tables/beacons/insertAll HTTP/1.1"
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        let row = &body["rows"][0]["json"];
+        assert_eq!(row["gateway"], "test-gateway");
+        assert_eq!(row["address"], "AA:BB:CC:DD:EE:01");
+        assert_eq!(row["data_format"], 5);
+        assert_eq!(row["temperature"], 21.5);
+        assert_eq!(row["battery_voltage"], 2.977);
+        assert!(row["pressure"].is_null());
+        assert!(body["rows"][0]["insertId"]
+            .as_str()
+            .unwrap()
+            .starts_with("test-gateway-AA:BB:CC:DD:EE:01-"));
+        assert_eq!(body["rows"][1]["json"]["address"], "AA:BB:CC:DD:EE:02");
+        assert_eq!(requests.len(), 3);
+    }
+}
+
+// eof
@@ -0,0 +1,240 @@
+use btleplug::api::BDAddr;
+use chrono::{TimeZone, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use paho_mqtt as mqtt;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::configfile::BridgeConfig;
+use crate::replay::{self, ReplayRecord};
+use crate::scanner::RuuviBluetoothBeacon;
+use crate::tls;
+
+// pause between attempts to connect to the bridge broker
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Ruuvi Gateway publishes each advertisement to ruuvi/<gateway mac>/<tag mac> with the full
+//  advertising data in hex, e.g. {"gw_mac": "..", "rssi": -62, "ts": "1614556800", "data": ".."}
+fn ruuvi_gateway_record(topic: &str, message: &serde_json::Value) -> Option<ReplayRecord> {
+    let address = topic.rsplit('/').next()?;
+    let address = BDAddr::from_str(address).ok()?;
+    let advertising_data = replay::from_hex(message["data"].as_str()?)?;
+    let timestamp = message["ts"]
+        .as_str()
+        .and_then(|timestamp| timestamp.parse::<i64>().ok())
+        .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single())
+        .unwrap_or_else(Utc::now);
+    Some(ReplayRecord::Advertisement {
+        timestamp,
+        address,
+        data: replay::manufacturer_data(&advertising_data)?,
+    })
+}
+
+// advertisements in a message from a Ruuvi Gateway, or beacons published by another
+//  ruuvi2iotcore instance as a single beacon or a collection of them
+pub fn parse_message(topic: &str, payload: &str) -> Vec<ReplayRecord> {
+    trace!("in parse_message");
+    let message: serde_json::Value = match serde_json::from_str(payload) {
+        Ok(message) => message,
+        Err(error) => {
+            warn!("Invalid JSON in bridge topic '{}': {}", topic, error);
+            return Vec::new();
+        }
+    };
+    if message.get("gw_mac").is_some() || message["data"].is_string() {
+        return match ruuvi_gateway_record(topic, &message) {
+            Some(record) => vec![record],
+            None => {
+                debug!(
+                    "No manufacturer data in Ruuvi Gateway message from '{}'",
+                    topic
+                );
+                Vec::new()
+            }
+        };
+    }
+    let beacons: Result<Vec<RuuviBluetoothBeacon>, serde_json::Error> = match message {
+        serde_json::Value::Array(_) => serde_json::from_value(message),
+        _ => serde_json::from_value(message).map(|beacon| vec![beacon]),
+    };
+    match beacons {
+        Ok(beacons) => beacons
+            .into_iter()
+            .filter_map(|beacon| match replay::beacon_record(beacon) {
+                Ok(record) => Some(record),
+                Err(error) => {
+                    warn!("Ignoring beacon from bridge topic '{}': {}", topic, error);
+                    None
+                }
+            })
+            .collect(),
+        Err(error) => {
+            warn!("Unknown message in bridge topic '{}': {}", topic, error);
+            Vec::new()
+        }
+    }
+}
+
+// receives advertisements that other scanners publish to an MQTT broker so that a single
+//  instance connected to IoT Core can aggregate several of them
+pub struct MqttBridge {
+    config: BridgeConfig,
+    client: mqtt::Client,
+    consumer: Receiver<Option<mqtt::Message>>,
+    last_connect: Option<Instant>,
+}
+
+impl MqttBridge {
+    fn connect(&mut self) -> Result<(), Report> {
+        trace!("in connect");
+        self.last_connect = Some(Instant::now());
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+        conn_opts.clean_session(true);
+        if let Some(username) = self.config.username() {
+            conn_opts.user_name(username);
+        }
+        if let Some(password) = self.config.password() {
+            conn_opts.password(password);
+        }
+        if tls::is_secure(&self.config.broker().unwrap_or_default()) {
+            conn_opts.ssl_options(
+                tls::ssl_options_builder(&self.config.tls, "bridge", None)?.finalize(),
+            );
+        }
+        if let Err(error) = self.client.connect(conn_opts.finalize()) {
+            return Err(eyre!("Unable to connect to bridge MQTT broker")
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        let topic = self.config.topic();
+        if let Err(error) = self.client.subscribe(&topic, mqtt::QOS_0) {
+            return Err(eyre!("Unable to subscribe to bridge topic")
+                .with_section(move || topic.header("Topic:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        info!("Receiving advertisements from bridge topic '{}'", topic);
+        Ok(())
+    }
+
+    // advertisements received since the last call. (re)connects to the broker when needed
+    pub fn receive(&mut self) -> Vec<ReplayRecord> {
+        if !self.client.is_connected() {
+            let connect_due = match self.last_connect {
+                Some(last_connect) => last_connect.elapsed() >= RECONNECT_INTERVAL,
+                None => true,
+            };
+            if connect_due {
+                if let Err(error) = self.connect() {
+                    error!("{}", error);
+                }
+            }
+            return Vec::new();
+        }
+        let mut records = Vec::new();
+        while let Ok(Some(message)) = self.consumer.try_recv() {
+            records.extend(parse_message(message.topic(), &message.payload_str()));
+        }
+        records
+    }
+
+    pub fn disconnect(&self) {
+        trace!("in disconnect");
+        if self.client.is_connected() {
+            self.client.disconnect(None).ok();
+        }
+    }
+
+    pub fn build(config: &BridgeConfig, device_id: &str) -> Result<Option<MqttBridge>, Report> {
+        trace!("in build");
+        let broker = match config.broker() {
+            Some(broker) => broker,
+            None => return Ok(None),
+        };
+        let create_opts = mqtt::CreateOptionsBuilder::new()
+            .server_uri(broker)
+            .client_id(format!("{}-{}-bridge", env!("CARGO_PKG_NAME"), device_id))
+            .finalize();
+        let mut client = match mqtt::Client::new(create_opts) {
+            Ok(client) => client,
+            Err(error) => {
+                return Err(eyre!("Unable to create Paho MQTT client instance")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let consumer = client.start_consuming();
+        Ok(Some(MqttBridge {
+            config: config.clone(),
+            client,
+            consumer,
+            last_connect: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_message;
+    use crate::replay::ReplayRecord;
+
+    #[test]
+    fn ruuvi_gateway_message() {
+        let payload = json!({
+            "gw_mac": "A1:B2:C3:D4:E5:F6",
+            "rssi": -62,
+            "aoa": [],
+            "gwts": "1614556801",
+            "ts": "1614556800",
+            "data": "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F",
+            "coords": "",
+        });
+        let records = parse_message(
+            "ruuvi/A1:B2:C3:D4:E5:F6/CB:B8:33:4C:88:4F",
+            &payload.to_string(),
+        );
+        assert_eq!(records.len(), 1);
+        match &records[0] {
+            ReplayRecord::Advertisement {
+                timestamp,
+                address,
+                data,
+            } => {
+                assert_eq!(timestamp.timestamp(), 1_614_556_800);
+                assert_eq!(address.to_string(), "CB:B8:33:4C:88:4F");
+                assert_eq!(data[..3], [0x99, 0x04, 0x05]);
+                assert_eq!(data.len(), 26);
+            }
+            _ => panic!("Ruuvi Gateway message was not parsed into an advertisement"),
+        }
+    }
+
+    #[test]
+    fn relayed_beacons() {
+        let beacon = json!({
+            "decoder": "ruuvi_v5",
+            "data": {"temperature": 21.5},
+            "raw": "",
+            "timestamp": "2021-03-01T00:00:00Z",
+            "address": "AA:BB:CC:DD:EE:01",
+            "sequence": 1,
+        });
+        let records = parse_message(
+            "ruuvi2iotcore/events",
+            &json!([beacon.clone(), beacon]).to_string(),
+        );
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[0], ReplayRecord::Beacon(_)));
+        assert!(parse_message("ruuvi/invalid", "not json").is_empty());
+
+        // beacons are published by their address, so an invalid one is dropped
+        let mut invalid = beacon.clone();
+        invalid["address"] = json!("not a tag");
+        let records = parse_message(
+            "ruuvi2iotcore/events",
+            &json!([invalid, beacon]).to_string(),
+        );
+        assert_eq!(records.len(), 1);
+    }
+}
+
+// eof
@@ -0,0 +1,97 @@
+use crossbeam::channel::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Channel delivering every message to each of its subscribers, e.g. collect configs and
+/// commands from IoT Core to every subsystem that reacts to them. Unlike a plain channel, where
+/// receivers compete for messages, no subscriber can take a message meant for another.
+pub struct Broadcast<T> {
+    subscribers: Arc<Mutex<Vec<Sender<T>>>>,
+}
+
+impl<T> Clone for Broadcast<T> {
+    fn clone(&self) -> Broadcast<T> {
+        Broadcast {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<T> Default for Broadcast<T> {
+    fn default() -> Broadcast<T> {
+        Broadcast {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<T: Clone> Broadcast<T> {
+    /// Receiver of the messages sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends the message to all subscribers and returns the number of subscribers it reached.
+    /// Subscribers whose receivers are gone, e.g. a scanner that has shut down, are dropped
+    /// instead of failing the sender.
+    pub fn send(&self, message: T) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(message.clone()).is_ok());
+        if subscribers.is_empty() {
+            trace!("no subscribers to broadcast to");
+        }
+        subscribers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Broadcast;
+    use crate::iotcore::{CNCCommand, CNCCommandMessage, IOTCoreCNCMessageKind};
+
+    fn command(command: CNCCommand) -> IOTCoreCNCMessageKind {
+        IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+            command,
+            duty_cycle: None,
+        }))
+    }
+
+    #[test]
+    fn commands_reach_every_subscriber() {
+        let broadcast = Broadcast::default();
+        let scanner = broadcast.subscribe();
+        let other = broadcast.clone().subscribe();
+        assert_eq!(broadcast.send(command(CNCCommand::RESET)), 2);
+        assert_eq!(broadcast.send(command(CNCCommand::PAUSE)), 2);
+        for receiver in [scanner, other] {
+            let received: Vec<_> = receiver.try_iter().collect();
+            assert!(matches!(
+                received.as_slice(),
+                [
+                    IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+                        command: CNCCommand::RESET,
+                        ..
+                    })),
+                    IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+                        command: CNCCommand::PAUSE,
+                        ..
+                    }))
+                ]
+            ));
+        }
+    }
+
+    #[test]
+    fn subscribers_that_are_gone_are_dropped() {
+        let broadcast = Broadcast::default();
+        assert_eq!(broadcast.send(command(CNCCommand::COLLECT)), 0);
+        let scanner = broadcast.subscribe();
+        drop(broadcast.subscribe());
+        assert_eq!(broadcast.send(command(CNCCommand::COLLECT)), 1);
+        drop(scanner);
+        assert_eq!(broadcast.send(command(CNCCommand::SHUTDOWN)), 0);
+    }
+}
+
+// eof
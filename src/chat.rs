@@ -0,0 +1,182 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::time::Duration;
+
+use crate::alerts::{Alert, Notifier};
+use crate::configfile::{SlackConfig, TelegramConfig};
+
+// message of the alert in chats, prefixed with the gateway that raised it
+fn message(device_id: &str, alert: &Alert) -> String {
+    format!("[{}] {}", device_id, alert.summary())
+}
+
+fn agent(timeout: u64) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(timeout))
+        .build()
+}
+
+// posts the message to the chat service. urls of both Slack webhooks and Telegram bots hold
+//  their secret, so they are left out of errors
+fn post(
+    agent: &ureq::Agent,
+    url: &str,
+    body: &serde_json::Value,
+    service: &str,
+) -> Result<(), Report> {
+    trace!("in post");
+    let error = match agent
+        .post(url)
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())
+    {
+        Ok(_) => return Ok(()),
+        Err(error) => error,
+    };
+    let report = eyre!("Unable to send alert to {}", service);
+    match error {
+        ureq::Error::Status(status, response) => {
+            let response = response.into_string().unwrap_or_default();
+            Err(report
+                .with_section(move || status.to_string().header("Status:"))
+                .with_section(move || response.header("Response:")))
+        }
+        ureq::Error::Transport(error) => {
+            let reason = match error.message() {
+                Some(message) => format!("{}: {}", error.kind(), message),
+                None => error.kind().to_string(),
+            };
+            Err(report.with_section(move || reason.header("Reason:")))
+        }
+    }
+}
+
+/// Notifier posting alerts to a Slack channel through an incoming webhook.
+pub struct SlackNotifier {
+    webhook_url: String,
+    device_id: String,
+    agent: ureq::Agent,
+}
+
+impl SlackNotifier {
+    pub fn build(config: &SlackConfig, device_id: &str) -> SlackNotifier {
+        trace!("in build");
+        SlackNotifier {
+            webhook_url: config.webhook_url(),
+            device_id: device_id.to_string(),
+            agent: agent(config.timeout()),
+        }
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> String {
+        "Slack".to_string()
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report> {
+        trace!("in notify");
+        let body = json!({ "text": message(&self.device_id, alert) });
+        post(&self.agent, &self.webhook_url, &body, "Slack")
+    }
+}
+
+/// Notifier sending alerts to a Telegram chat as messages of a bot.
+pub struct TelegramNotifier {
+    url: String,
+    chat_id: String,
+    device_id: String,
+    agent: ureq::Agent,
+}
+
+impl TelegramNotifier {
+    pub fn build(config: &TelegramConfig, device_id: &str) -> TelegramNotifier {
+        trace!("in build");
+        TelegramNotifier {
+            url: format!(
+                "{}/bot{}/sendMessage",
+                config.api_url().trim_end_matches('/'),
+                config.bot_token()
+            ),
+            chat_id: config.chat_id(),
+            device_id: device_id.to_string(),
+            agent: agent(config.timeout()),
+        }
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> String {
+        format!("Telegram chat {}", self.chat_id)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report> {
+        trace!("in notify");
+        let body = json!({
+            "chat_id": self.chat_id,
+            "text": message(&self.device_id, alert),
+        });
+        post(&self.agent, &self.url, &body, "Telegram")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlackNotifier, TelegramNotifier};
+    use crate::alerts::{Alerts, Notifier};
+    use crate::configfile::{AlertsConfig, SlackConfig, TelegramConfig};
+    use crate::mock::{beacon, serve, HttpRequest};
+
+    #[test]
+    fn alerts_are_sent_to_chats() {
+        let alerts_config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [{ "metric": "temperature", "operator": "below", "threshold": 25.0 }],
+        }))
+        .unwrap();
+        let alert = Alerts::build(&alerts_config, "test-gateway")
+            .unwrap()
+            .check(&beacon("AA:BB:CC:DD:EE:01", 1))
+            .remove(0);
+
+        let (url, server) = serve(vec![(200, "ok".to_string())]);
+        let config: SlackConfig =
+            serde_json::from_value(json!({ "webhook_url": format!("{}/services/T0/B0/x", url) }))
+                .unwrap();
+        SlackNotifier::build(&config, "test-gateway")
+            .notify(&alert)
+            .unwrap();
+        let requests: Vec<HttpRequest> = server.iter().collect();
+        assert_eq!(requests[0].line, "POST /services/T0/B0/x HTTP/1.1");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&requests[0].body).unwrap(),
+            json!({
+                "text": "[test-gateway] Alert triggered: temperature of AA:BB:CC:DD:EE:01 is 21.5, below threshold 25",
+            })
+        );
+
+        // the bot is not a member of the chat
+        let response = r#"{"ok":false,"description":"Bad Request: chat not found"}"#;
+        let (url, server) = serve(vec![
+            (200, r#"{"ok":true}"#.to_string()),
+            (400, response.to_string()),
+        ]);
+        let config: TelegramConfig = serde_json::from_value(json!({
+            "bot_token": "123:secret",
+            "chat_id": "-1001234",
+            "api_url": url,
+        }))
+        .unwrap();
+        let mut notifier = TelegramNotifier::build(&config, "test-gateway");
+        notifier.notify(&alert).unwrap();
+        assert!(notifier.notify(&alert).is_err());
+        let requests: Vec<HttpRequest> = server.iter().collect();
+        assert_eq!(requests[0].line, "POST /bot123:secret/sendMessage HTTP/1.1");
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["chat_id"], "-1001234");
+        assert!(body["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("[test-gateway] Alert triggered"));
+    }
+}
+
+// eof
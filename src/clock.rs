@@ -0,0 +1,274 @@
+use chrono::{Datelike, TimeZone};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use serde::Serialize;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use std::{thread, time};
+
+use crate::configfile::ClockConfig;
+
+// seconds between the NTP epoch (1900-01-01) and the unix epoch (1970-01-01)
+const NTP_UNIX_EPOCH_DELTA: i64 = 2_208_988_800;
+// no gateway running this software can legitimately have a clock before this year
+const MINIMUM_SANE_YEAR: i32 = 2021;
+
+fn ntp_timestamp_to_datetime(buffer: &[u8]) -> chrono::DateTime<chrono::Utc> {
+    let seconds = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as i64;
+    let fraction = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as i64;
+    let nanos = (fraction * 1_000_000_000) >> 32;
+    chrono::Utc
+        .timestamp_opt(seconds - NTP_UNIX_EPOCH_DELTA, nanos as u32)
+        .unwrap()
+}
+
+// Queries the given (S)NTP server and returns the offset of the local clock compared to it.
+//  A positive offset means that the local clock is behind the server.
+pub fn sntp_offset(server: &str) -> Result<chrono::Duration, Report> {
+    trace!("in sntp_offset");
+    // port of the server can be given with an IP address
+    let address = match server.parse::<SocketAddr>() {
+        Ok(address) => Ok(vec![address].into_iter()),
+        Err(_) => format!("{}:123", server).to_socket_addrs(),
+    };
+    let address = match address {
+        Ok(mut addresses) => match addresses.next() {
+            Some(address) => address,
+            None => {
+                return Err(eyre!("NTP server name did not resolve to any address")
+                    .with_section(move || server.to_string().header("NTP server:")))
+            }
+        },
+        Err(error) => {
+            return Err(eyre!("Unable to resolve NTP server address")
+                .with_section(move || server.to_string().header("NTP server:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(error) => {
+            return Err(eyre!("Unable to open UDP socket for NTP query")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    socket.set_read_timeout(Some(Duration::from_secs(5))).ok();
+
+    // client request: leap indicator 0, version 3, mode 3 (client)
+    let mut request = [0u8; 48];
+    request[0] = 0x1B;
+
+    let sent_at = chrono::Utc::now();
+    if let Err(error) = socket.send_to(&request, address) {
+        return Err(eyre!("Unable to send NTP query")
+            .with_section(move || server.to_string().header("NTP server:"))
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+    let mut response = [0u8; 48];
+    match socket.recv_from(&mut response) {
+        Ok((size, _)) if size >= 48 => {}
+        Ok((size, _)) => {
+            return Err(eyre!("Truncated response from NTP server")
+                .with_section(move || size.to_string().header("Response size:")))
+        }
+        Err(error) => {
+            return Err(eyre!("No response from NTP server")
+                .with_section(move || server.to_string().header("NTP server:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    let received_at = chrono::Utc::now();
+
+    let server_received = ntp_timestamp_to_datetime(&response[32..40]);
+    let server_transmitted = ntp_timestamp_to_datetime(&response[40..48]);
+    let offset = ((server_received - sent_at) + (server_transmitted - received_at)) / 2;
+    debug!(
+        "NTP server {} reports local clock offset of {} ms",
+        server,
+        offset.num_milliseconds()
+    );
+
+    Ok(offset)
+}
+
+// Checks if the local clock can be trusted for issuing JWT tokens. Returns a human readable
+//  description of the problem if it can not.
+pub fn check_clock(config: &ClockConfig) -> Result<(), String> {
+    trace!("in check_clock");
+    let now = chrono::Utc::now();
+    if now.year() < MINIMUM_SANE_YEAR {
+        return Err(format!(
+            "System clock is set to {} which is before {}. The device has probably booted without a real time clock and has not synchronized its time yet.",
+            now.to_rfc3339(),
+            MINIMUM_SANE_YEAR
+        ));
+    }
+
+    if let Some(server) = config.ntp_server() {
+        match sntp_offset(&server) {
+            Ok(offset) => {
+                if offset.num_seconds().unsigned_abs() > config.max_skew() {
+                    return Err(format!(
+                        "System clock differs {} seconds from NTP server {}. Maximum allowed skew is {} seconds.",
+                        offset.num_seconds(),
+                        server,
+                        config.max_skew()
+                    ));
+                }
+            }
+            // unreachable NTP server is not a reason to halt, we have no better information
+            Err(error) => warn!("Unable to verify clock against NTP server: {}", error),
+        }
+    }
+
+    Ok(())
+}
+
+// Time zone of local timestamps, None unless one is configured.
+pub fn timezone(config: &ClockConfig) -> Result<Option<chrono_tz::Tz>, Report> {
+    trace!("in timezone");
+    match config.timezone() {
+        Some(name) => match name.parse() {
+            Ok(timezone) => Ok(Some(timezone)),
+            Err(reason) => Err(eyre!("Unknown time zone")
+                .with_section(move || name.header("Time zone:"))
+                .with_section(move || reason.to_string().header("Reason:"))),
+        },
+        None => Ok(None),
+    }
+}
+
+// Timestamp in the time zone with its UTC offset, e.g. 2021-06-01T15:00:00+03:00.
+pub fn local_timestamp(
+    timestamp: &chrono::DateTime<chrono::Utc>,
+    timezone: &chrono_tz::Tz,
+) -> String {
+    timestamp.with_timezone(timezone).to_rfc3339()
+}
+
+/// Drift of the local clock from the NTP server, measured by the drift monitor.
+#[derive(Debug, Serialize, Clone)]
+pub struct ClockDrift {
+    pub ntp_server: String,
+    /// Milliseconds the local clock is ahead of the NTP server, negative when it is behind
+    pub clock_drift_ms: i64,
+    pub measured: chrono::DateTime<chrono::Utc>,
+}
+
+impl ClockDrift {
+    pub fn payload(&self) -> serde_json::Value {
+        json!({ "clock": self })
+    }
+}
+
+// Measures drift of the local clock against the NTP server at startup and every drift interval
+//  in a thread of its own, until the receiver is dropped. Nothing is measured unless an NTP
+//  server is configured.
+pub fn start_drift_monitor(config: &ClockConfig) -> Option<channel::Receiver<ClockDrift>> {
+    trace!("in start_drift_monitor");
+    let ntp_server = config.ntp_server()?;
+    if config.drift_interval() == 0 {
+        return None;
+    }
+    info!(
+        "Measuring clock drift against NTP server {} every {} seconds",
+        ntp_server,
+        config.drift_interval()
+    );
+    let (sender, receiver) = channel::bounded(1);
+    let config = config.clone();
+    thread::spawn(move || loop {
+        match sntp_offset(&ntp_server) {
+            Ok(offset) => {
+                let clock_drift = ClockDrift {
+                    ntp_server: ntp_server.clone(),
+                    clock_drift_ms: -offset.num_milliseconds(),
+                    measured: chrono::Utc::now(),
+                };
+                if offset.num_seconds().unsigned_abs() > config.max_skew() {
+                    warn!(
+                        "System clock has drifted {} ms from NTP server {}",
+                        clock_drift.clock_drift_ms, ntp_server
+                    );
+                }
+                if sender.send(clock_drift).is_err() {
+                    break;
+                }
+            }
+            Err(error) => warn!("Unable to measure clock drift: {}", error),
+        }
+        thread::sleep(time::Duration::from_secs(config.drift_interval()));
+    });
+    Some(receiver)
+}
+
+// Blocks until the local clock is considered sane so that the issued JWT tokens are not
+//  rejected by IoT Core.
+pub fn wait_for_sane_clock(config: &ClockConfig) {
+    trace!("in wait_for_sane_clock");
+    loop {
+        match check_clock(config) {
+            Ok(_) => {
+                debug!("System clock is sane.");
+                return;
+            }
+            Err(reason) => {
+                error!("{}", reason);
+                error!("JWT tokens issued now would be rejected by IoT Core. Make sure time synchronization (e.g. systemd-timesyncd or chrony) is enabled. Waiting {} seconds before checking again.", config.retry_interval());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(config.retry_interval()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start_drift_monitor, NTP_UNIX_EPOCH_DELTA};
+    use crate::configfile::ClockConfig;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn clock_drift_is_measured() {
+        // NTP server with a clock two seconds ahead of the local one
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut request = [0; 48];
+            let (_, client) = server.recv_from(&mut request).unwrap();
+            let now = chrono::Utc::now() + chrono::Duration::seconds(2);
+            let seconds = (now.timestamp() + NTP_UNIX_EPOCH_DELTA) as u32;
+            let fraction = ((now.timestamp_subsec_nanos() as u64) << 32) / 1_000_000_000;
+            let mut timestamp = seconds.to_be_bytes().to_vec();
+            timestamp.extend_from_slice(&(fraction as u32).to_be_bytes());
+            let mut response = [0; 48];
+            response[0] = 0x1c;
+            response[32..40].copy_from_slice(&timestamp);
+            response[40..48].copy_from_slice(&timestamp);
+            server.send_to(&response, client).unwrap();
+        });
+
+        let config: ClockConfig = serde_json::from_value(json!({
+            "ntp_server": address.to_string(),
+            "drift_interval": 3600,
+        }))
+        .unwrap();
+        let clock_drifts = start_drift_monitor(&config).unwrap();
+        let clock_drift = clock_drifts.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(clock_drift.ntp_server, address.to_string());
+        assert!((clock_drift.clock_drift_ms + 2000).abs() < 500);
+        assert_eq!(
+            clock_drift.payload()["clock"]["clock_drift_ms"],
+            clock_drift.clock_drift_ms
+        );
+
+        let config: ClockConfig =
+            serde_json::from_value(json!({ "ntp_server": "127.0.0.1", "drift_interval": 0 }))
+                .unwrap();
+        assert!(start_drift_monitor(&config).is_none());
+    }
+}
+
+// eof
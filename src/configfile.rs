@@ -1,13 +1,24 @@
 use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[cfg(feature = "dns-discovery")]
+use crate::dnsconfig;
+use crate::iotcore::CollectConfig;
+use crate::secrets;
+
+/// Keys and certificates of the gateway device.
+#[derive(Debug, Deserialize, Serialize, Default)]
 pub struct IdentityConfig {
     pub public_key: String,
     pub private_key: String,
     pub ca_certs: Option<String>,
     token_lifetime: Option<u64>,
+    token_cache: Option<String>,
 }
 
 impl IdentityConfig {
@@ -19,14 +30,42 @@ impl IdentityConfig {
 
         self.token_lifetime.unwrap()
     }
+
+    pub fn token_cache(&self) -> Option<PathBuf> {
+        self.token_cache
+            .as_ref()
+            .map(|token_cache| Path::new(token_cache).to_path_buf())
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Gateway device in IoT Core and its MQTT session options.
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct IotCoreConfig {
     pub device_id: String,
+    #[serde(default)]
     pub project_id: String,
+    #[serde(default)]
     pub region: String,
+    #[serde(default)]
     pub registry: String,
+    discover: Option<String>,
+    clean_session: Option<bool>,
+    session_expiry: Option<u32>,
+    persistence_dir: Option<String>,
+    gateway_id: Option<String>,
+    max_messages_per_second: Option<f64>,
+    max_bytes_per_minute: Option<u64>,
+    port: Option<u16>,
+    transport: Option<Transport>,
+    endpoints: Option<Vec<String>>,
+    diagnostics_subfolder: Option<String>,
+    stats_subfolder: Option<String>,
+    watermark_file: Option<String>,
+    collectconfig_file: Option<String>,
+    pause_buffer_file: Option<String>,
+    rate_limit_buffer_file: Option<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
 }
 
 impl IotCoreConfig {
@@ -39,15 +78,1452 @@ impl IotCoreConfig {
         debug!("client_id is '{}'", client_id);
         client_id
     }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session.unwrap_or(true)
+    }
+
+    /// Domain whose TXT records define project, region and registry
+    pub fn discover(&self) -> Option<String> {
+        self.discover.clone()
+    }
+
+    pub fn session_expiry(&self) -> Option<u32> {
+        self.session_expiry
+    }
+
+    pub fn persistence_dir(&self) -> Option<String> {
+        self.persistence_dir.clone()
+    }
+
+    /// Identifies this gateway in published beacons when several gateways cover the same tags
+    pub fn gateway_id(&self) -> Option<String> {
+        self.gateway_id.clone()
+    }
+
+    /// Publishing is throttled to stay within IoT Core per-device telemetry quotas
+    pub fn max_messages_per_second(&self) -> Option<f64> {
+        self.max_messages_per_second
+            .filter(|max_messages_per_second| *max_messages_per_second > 0.0)
+    }
+
+    pub fn max_bytes_per_minute(&self) -> Option<u64> {
+        self.max_bytes_per_minute
+            .filter(|max_bytes_per_minute| *max_bytes_per_minute > 0)
+    }
+
+    /// Port of the IoT Core MQTT bridge, by default 8883 or 443 with WebSocket transport
+    pub fn port(&self) -> u16 {
+        match (self.port, self.transport()) {
+            (Some(port), _) => port,
+            (None, Transport::TCP) => 8883,
+            (None, Transport::WEBSOCKET) => 443,
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport.unwrap_or(Transport::TCP)
+    }
+
+    /// Endpoints of the MQTT bridge tried in order, by default only mqtt.googleapis.com
+    pub fn endpoints(&self) -> Vec<String> {
+        self.endpoints
+            .clone()
+            .filter(|endpoints| !endpoints.is_empty())
+            .unwrap_or_else(|| vec!["mqtt.googleapis.com".to_string()])
+    }
+
+    /// Last published tag sequence of each tag is kept only in memory unless a file is set
+    pub fn watermark_file(&self) -> Option<PathBuf> {
+        self.watermark_file
+            .as_ref()
+            .map(|watermark_file| Path::new(watermark_file).to_path_buf())
+    }
+
+    /// Last collect config received from IoT Core is kept in this file and used after a restart
+    /// until IoT Core delivers it again, an empty name keeps it only in memory
+    pub fn collectconfig_file(&self) -> Option<PathBuf> {
+        match &self.collectconfig_file {
+            Some(collectconfig_file) if collectconfig_file.is_empty() => None,
+            Some(collectconfig_file) => Some(Path::new(collectconfig_file).to_path_buf()),
+            None => Some(PathBuf::from(format!(
+                "collectconfig-{}.json",
+                self.device_id
+            ))),
+        }
+    }
+
+    /// Beacons received while collecting is paused are kept in this file when the collect config
+    /// buffers them
+    pub fn pause_buffer_file(&self) -> PathBuf {
+        match &self.pause_buffer_file {
+            Some(pause_buffer_file) => Path::new(pause_buffer_file).to_path_buf(),
+            None => PathBuf::from(format!("pause_buffer-{}.jsonl", self.device_id)),
+        }
+    }
+
+    /// Beacons received while publishing is over the rate limits are kept in this file
+    pub fn rate_limit_buffer_file(&self) -> PathBuf {
+        match &self.rate_limit_buffer_file {
+            Some(rate_limit_buffer_file) => Path::new(rate_limit_buffer_file).to_path_buf(),
+            None => PathBuf::from(format!("rate_limit_buffer-{}.jsonl", self.device_id)),
+        }
+    }
+
+    /// Events subfolder of the gateway where errors in received commands are reported
+    pub fn diagnostics_subfolder(&self) -> String {
+        self.diagnostics_subfolder
+            .clone()
+            .unwrap_or_else(|| "diagnostics".to_string())
+    }
+
+    /// Subfolder of the events topic of the gateway statistics are published to
+    pub fn stats_subfolder(&self) -> String {
+        self.stats_subfolder
+            .clone()
+            .unwrap_or_else(|| "stats".to_string())
+    }
+}
+
+/// Transport of MQTT, both over TLS.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Transport {
+    #[serde(rename = "tcp")]
+    TCP,
+    #[serde(rename = "websocket")]
+    WEBSOCKET,
+}
+
+/// TLS settings of a connection to an MQTT broker.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct TlsConfig {
+    min_version: Option<String>,
+    alpn_protocols: Option<Vec<String>>,
+    ca_certs: Option<Vec<String>>,
+    probe_handshake: Option<bool>,
+}
+
+impl TlsConfig {
+    /// Minimum TLS version: "1.0", "1.1" or "1.2"
+    pub fn min_version(&self) -> Option<String> {
+        self.min_version.clone()
+    }
+
+    pub fn alpn_protocols(&self) -> Vec<String> {
+        self.alpn_protocols.clone().unwrap_or_default()
+    }
+
+    /// Trust roots in PEM files in addition to the ones of the operating system
+    pub fn ca_certs(&self) -> Vec<String> {
+        self.ca_certs.clone().unwrap_or_default()
+    }
+
+    /// Same settings with a trust root added in front of the configured ones
+    pub fn with_ca_cert(&self, ca_cert: &str) -> TlsConfig {
+        let mut tls = self.clone();
+        tls.ca_certs = Some(
+            std::iter::once(ca_cert.to_string())
+                .chain(self.ca_certs())
+                .collect(),
+        );
+        tls
+    }
+
+    /// Parameters of the TLS handshake are probed on each connect and logged
+    pub fn probe_handshake(&self) -> bool {
+        self.probe_handshake.unwrap_or(true)
+    }
+}
+
+/// Sanity check of the system clock before JWT tokens are issued.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ClockConfig {
+    ntp_server: Option<String>,
+    max_skew: Option<u64>,
+    retry_interval: Option<u64>,
+    drift_interval: Option<u64>,
+    timezone: Option<String>,
+}
+
+impl ClockConfig {
+    pub fn ntp_server(&self) -> Option<String> {
+        self.ntp_server.clone()
+    }
+
+    pub fn max_skew(&self) -> u64 {
+        self.max_skew.unwrap_or(60)
+    }
+
+    pub fn retry_interval(&self) -> u64 {
+        self.retry_interval.unwrap_or(10)
+    }
+
+    /// Seconds between measurements of clock drift against the NTP server, zero disables them
+    pub fn drift_interval(&self) -> u64 {
+        self.drift_interval.unwrap_or(60 * 60)
+    }
+
+    /// IANA time zone of local timestamps in published beacons and CSV exports
+    pub fn timezone(&self) -> Option<String> {
+        self.timezone.clone()
+    }
+}
+
+/// Pushing metrics to a Prometheus Pushgateway and summarizing them in the log.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct MetricsConfig {
+    push_url: Option<String>,
+    push_interval: Option<u64>,
+    push_job: Option<String>,
+    push_username: Option<String>,
+    push_password: Option<String>,
+    log_interval: Option<u64>,
+}
+
+impl MetricsConfig {
+    pub fn push_url(&self) -> Option<String> {
+        self.push_url.clone()
+    }
+
+    pub fn push_interval(&self) -> u64 {
+        self.push_interval.unwrap_or(60)
+    }
+
+    pub fn push_job(&self) -> String {
+        self.push_job
+            .clone()
+            .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+    }
+
+    pub fn push_username(&self) -> Option<String> {
+        self.push_username.clone()
+    }
+
+    pub fn push_password(&self) -> Option<String> {
+        self.push_password.clone()
+    }
+
+    /// 0 disables the periodic throughput and latency summary in the log
+    pub fn log_interval(&self) -> u64 {
+        self.log_interval.unwrap_or(5 * 60)
+    }
+}
+
+/// Local web dashboard, disabled unless a port is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct WebUiConfig {
+    address: Option<String>,
+    port: Option<u16>,
+    token: Option<String>,
+}
+
+impl WebUiConfig {
+    /// only reachable from the gateway itself unless an address is configured
+    pub fn address(&self) -> String {
+        self.address
+            .clone()
+            .unwrap_or_else(|| "127.0.0.1".to_string())
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// bearer token POST requests must carry, if set
+    pub fn token(&self) -> Option<String> {
+        self.token.clone()
+    }
+}
+
+/// Local control socket, disabled unless a socket path is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ControlConfig {
+    socket: Option<String>,
+}
+
+impl ControlConfig {
+    pub fn socket(&self) -> Option<PathBuf> {
+        self.socket
+            .as_ref()
+            .map(|socket| Path::new(socket).to_path_buf())
+    }
+}
+
+/// Leader election between redundant gateways, disabled unless a broker is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CoordinationConfig {
+    broker: Option<String>,
+    topic: Option<String>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl CoordinationConfig {
+    pub fn broker(&self) -> Option<String> {
+        self.broker.clone()
+    }
+
+    pub fn topic(&self) -> String {
+        self.topic
+            .clone()
+            .unwrap_or_else(|| format!("{}/coordination", env!("CARGO_PKG_NAME")))
+    }
+
+    pub fn heartbeat_interval(&self) -> u64 {
+        self.heartbeat_interval.unwrap_or(5)
+    }
+
+    /// Leader is considered gone when its heartbeat has not been seen for this long
+    pub fn heartbeat_timeout(&self) -> u64 {
+        self.heartbeat_timeout
+            .unwrap_or(3 * self.heartbeat_interval())
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
+/// Receiving advertisements from other scanners through an MQTT broker instead of scanning,
+/// disabled unless a broker is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BridgeConfig {
+    broker: Option<String>,
+    topic: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl BridgeConfig {
+    pub fn broker(&self) -> Option<String> {
+        self.broker.clone()
+    }
+
+    /// Topic filter subscribed to, by default the topics of Ruuvi Gateways.
+    pub fn topic(&self) -> String {
+        self.topic.clone().unwrap_or_else(|| "ruuvi/#".to_string())
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
+/// Operating without IoT Core, beacons are only relayed to local sinks. Commands and collect
+/// configs can be received from a local MQTT broker.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LocalConfig {
+    enabled: Option<bool>,
+    broker: Option<String>,
+    topic: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl LocalConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(false)
+    }
+
+    pub fn broker(&self) -> Option<String> {
+        self.broker.clone()
+    }
+
+    /// Root of the topics of the gateway in the local broker, in place of /devices/{device_id}
+    /// in IoT Core.
+    pub fn topic(&self, device_id: &str) -> String {
+        self.topic
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", env!("CARGO_PKG_NAME"), device_id))
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
+/// Pipeline relaying beacons of an adapter or tags of its own with credentials and sinks of its
+/// own, next to the one of the top level configuration.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PipelineConfig {
+    pub name: String,
+    adapter: Option<String>,
+    tags: Option<Vec<String>>,
+    /// Sections of the configuration merged over the top level ones, e.g. iotcore and sinks
+    #[serde(flatten)]
+    pub sections: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl PipelineConfig {
+    /// Name or MAC address pattern of the adapter scanned by the pipeline alone
+    pub fn adapter(&self) -> Option<String> {
+        self.adapter.clone()
+    }
+
+    /// Addresses of the tags relayed by the pipeline, all tags of its adapter when unset
+    pub fn tags(&self) -> Vec<String> {
+        self.tags.clone().unwrap_or_default()
+    }
+}
+
+/// Listener for advertisements forwarded over UDP, disabled unless a port is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct UdpConfig {
+    address: Option<String>,
+    port: Option<u16>,
+}
+
+impl UdpConfig {
+    pub fn address(&self) -> String {
+        self.address
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0".to_string())
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+}
+
+/// BACnet/IP server presenting tags as analog inputs of a virtual device, disabled unless a
+/// port is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BacnetConfig {
+    address: Option<String>,
+    port: Option<u16>,
+    device_instance: Option<u32>,
+    device_name: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+impl BacnetConfig {
+    pub fn address(&self) -> String {
+        self.address
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0".to_string())
+    }
+
+    /// UDP port of the server, 47808 is the standard BACnet/IP port
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Instance number of the device, unique in the BACnet internetwork
+    pub fn device_instance(&self) -> u32 {
+        self.device_instance.unwrap_or(1)
+    }
+
+    /// Name of the device, the device id of the gateway if not set
+    pub fn device_name(&self) -> Option<String> {
+        self.device_name.clone()
+    }
+
+    /// MAC addresses of the tags in the order of their objects, all tags seen so far in the
+    /// order of their addresses if not set
+    pub fn tags(&self) -> Option<Vec<String>> {
+        self.tags.clone()
+    }
+}
+
+/// OPC UA server presenting gateways, tags and their metrics as a node tree, disabled unless
+/// a port is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OpcUaConfig {
+    address: Option<String>,
+    port: Option<u16>,
+    max_sessions: Option<usize>,
+    idle_timeout: Option<u64>,
+}
+
+impl OpcUaConfig {
+    pub fn address(&self) -> String {
+        self.address
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0".to_string())
+    }
+
+    /// TCP port of the server, 4840 is the standard OPC UA port
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Clients connected at the same time, further connections are rejected
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions.unwrap_or(10)
+    }
+
+    /// Seconds a client may stay connected without sending a message
+    pub fn idle_timeout(&self) -> u64 {
+        self.idle_timeout.unwrap_or(300)
+    }
+}
+
+/// Scripts transforming published beacons, by default none.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ScriptingConfig {
+    script: Option<String>,
+    tags: Option<HashMap<String, String>>,
+}
+
+impl ScriptingConfig {
+    /// Script used for tags without a script of their own.
+    pub fn script(&self) -> Option<PathBuf> {
+        self.script
+            .as_ref()
+            .map(|script| Path::new(script).to_path_buf())
+    }
+
+    /// Scripts of individual tags by tag address.
+    pub fn tags(&self) -> HashMap<String, PathBuf> {
+        match &self.tags {
+            Some(tags) => tags
+                .iter()
+                .map(|(address, script)| (address.clone(), Path::new(script).to_path_buf()))
+                .collect(),
+            None => HashMap::new(),
+        }
+    }
+}
+
+/// Local CSV export of received beacons, disabled unless a directory is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CsvConfig {
+    directory: Option<String>,
+    retention_days: Option<u64>,
+}
+
+impl CsvConfig {
+    pub fn directory(&self) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|directory| Path::new(directory).to_path_buf())
+    }
+
+    /// Files older than this many days are removed, by default files are kept forever.
+    pub fn retention_days(&self) -> Option<u64> {
+        self.retention_days
+    }
+}
+
+/// Kind of proxy the connection to IoT Core goes through.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ProxyKind {
+    /// HTTP proxy supporting the CONNECT method
+    #[serde(rename = "http")]
+    HTTP,
+    #[serde(rename = "socks5")]
+    SOCKS5,
+}
+
+/// Proxy for the connection to IoT Core, disabled unless a host is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProxyConfig {
+    kind: Option<ProxyKind>,
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl ProxyConfig {
+    pub fn kind(&self) -> ProxyKind {
+        self.kind.unwrap_or(ProxyKind::HTTP)
+    }
+
+    pub fn host(&self) -> Option<String> {
+        self.host.clone()
+    }
+
+    pub fn port(&self) -> u16 {
+        match (self.port, self.kind()) {
+            (Some(port), _) => port,
+            (None, ProxyKind::HTTP) => 3128,
+            (None, ProxyKind::SOCKS5) => 1080,
+        }
+    }
+
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
+/// Configuration fetched at startup and merged on top of the local one, disabled unless an URL
+/// is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RemoteConfig {
+    url: Option<String>,
+    cache: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl RemoteConfig {
+    /// HTTPS URL or Cloud Storage object as gs://bucket/object
+    pub fn url(&self) -> Option<String> {
+        self.url.clone()
+    }
+
+    /// Last fetched configuration used when fetching fails
+    pub fn cache(&self) -> PathBuf {
+        Path::new(self.cache.as_deref().unwrap_or("remote_config_cache.yaml")).to_path_buf()
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(10)
+    }
+}
+
+/// Beacons dropped when queues are full.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    #[serde(rename = "oldest")]
+    OLDEST,
+    #[serde(rename = "newest")]
+    NEWEST,
+}
+
+/// Limits of beacons waiting to be published, so that a gateway that can not publish does not
+/// run out of memory.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct QueueConfig {
+    max_beacons_per_tag: Option<usize>,
+    max_beacons: Option<usize>,
+    eviction: Option<Eviction>,
+}
+
+impl QueueConfig {
+    pub fn max_beacons_per_tag(&self) -> usize {
+        self.max_beacons_per_tag.unwrap_or(1000)
+    }
+
+    /// Limit of beacons of all tags together
+    pub fn max_beacons(&self) -> usize {
+        self.max_beacons.unwrap_or(10000)
+    }
+
+    pub fn eviction(&self) -> Eviction {
+        self.eviction.unwrap_or(Eviction::OLDEST)
+    }
+}
+
+/// Kind of a local output sink.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum SinkKind {
+    /// External command receiving each batch as a JSON array on its standard input
+    #[serde(rename = "exec")]
+    EXEC,
+    /// HTTP POST of each batch, or of each beacon with a batch size of one, to a URL
+    #[serde(rename = "webhook")]
+    WEBHOOK,
+    /// Publish of each beacon to a NATS subject of its tag, optionally through JetStream
+    #[serde(rename = "nats")]
+    NATS,
+    /// Publish of each beacon to an AMQP 0-9-1 exchange, e.g. of RabbitMQ, with a routing key of
+    /// its tag
+    #[serde(rename = "amqp")]
+    AMQP,
+    /// Streaming insert of each batch as rows into a BigQuery table
+    #[serde(rename = "bigquery")]
+    BIGQUERY,
+    /// Temperature, humidity and battery voltage of each tag as custom metrics of Cloud
+    /// Monitoring
+    #[serde(rename = "monitoring")]
+    MONITORING,
+    /// Push of each batch into a Grafana Live stream in the InfluxDB line protocol
+    #[serde(rename = "grafana")]
+    GRAFANA,
+    /// Publish of beacons as a Sparkplug B edge node with a device for each tag
+    #[serde(rename = "sparkplug")]
+    SPARKPLUG,
 }
 
+/// Local output sink receiving batches of beacons alongside IoT Core.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SinkConfig {
+    kind: SinkKind,
+    command: Option<Vec<String>>,
+    url: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    username: Option<String>,
+    password: Option<String>,
+    bearer_token: Option<String>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    subject: Option<String>,
+    jetstream: Option<bool>,
+    exchange: Option<String>,
+    routing_key: Option<String>,
+    service_account_key: Option<PathBuf>,
+    project_id: Option<String>,
+    dataset: Option<String>,
+    table: Option<String>,
+    stream_id: Option<String>,
+    group_id: Option<String>,
+    edge_node_id: Option<String>,
+    mqtt_version: Option<u8>,
+    message_expiry: Option<u32>,
+    batch_size: Option<usize>,
+    batch_interval: Option<u64>,
+    timeout: Option<u64>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+}
+
+impl SinkConfig {
+    pub fn kind(&self) -> SinkKind {
+        self.kind
+    }
+
+    /// Program and its arguments of an exec sink
+    pub fn command(&self) -> Vec<String> {
+        self.command.clone().unwrap_or_default()
+    }
+
+    /// URL a webhook sink posts to, of the NATS server, AMQP or MQTT broker or Grafana, or of
+    /// the BigQuery or Cloud Monitoring API
+    pub fn url(&self) -> Option<String> {
+        self.url.clone()
+    }
+
+    /// Headers of webhook requests, values may contain {device_id}, {count} and {timestamp}
+    pub fn headers(&self) -> HashMap<String, String> {
+        self.headers.clone().unwrap_or_default()
+    }
+
+    /// Basic authentication of webhook requests, or user of the NATS server, AMQP or MQTT broker
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+
+    /// Bearer token authentication of webhook requests, token of the NATS server or service
+    /// account token of Grafana
+    pub fn bearer_token(&self) -> Option<String> {
+        self.bearer_token.clone()
+    }
+
+    /// Attempts of a failed webhook request after the first one
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    /// Seconds before the first retry, doubled on every retry
+    pub fn retry_delay(&self) -> u64 {
+        self.retry_delay.unwrap_or(1)
+    }
+
+    /// NATS subject of beacons, may contain {device_id} and {address} of the tag
+    pub fn subject(&self) -> String {
+        self.subject
+            .clone()
+            .unwrap_or_else(|| "ruuvi.{device_id}.{address}".to_string())
+    }
+
+    /// Whether beacons are published to NATS JetStream and acknowledged by the stream
+    pub fn jetstream(&self) -> bool {
+        self.jetstream.unwrap_or(false)
+    }
+
+    /// AMQP exchange beacons are published to
+    pub fn exchange(&self) -> String {
+        self.exchange
+            .clone()
+            .unwrap_or_else(|| "amq.topic".to_string())
+    }
+
+    /// AMQP routing key of beacons, may contain {device_id} and {address} of the tag
+    pub fn routing_key(&self) -> String {
+        self.routing_key
+            .clone()
+            .unwrap_or_else(|| "ruuvi.{device_id}.{address}".to_string())
+    }
+
+    /// JSON key file of the Google Cloud service account of a BigQuery or Cloud Monitoring sink
+    pub fn service_account_key(&self) -> Option<PathBuf> {
+        self.service_account_key.clone()
+    }
+
+    /// Project of the BigQuery table or Cloud Monitoring metrics, the project of the service
+    /// account if not set
+    pub fn project_id(&self) -> Option<String> {
+        self.project_id.clone()
+    }
+
+    /// BigQuery dataset of the table
+    pub fn dataset(&self) -> Option<String> {
+        self.dataset.clone()
+    }
+
+    /// BigQuery table rows are inserted into
+    pub fn table(&self) -> Option<String> {
+        self.table.clone()
+    }
+
+    /// Grafana Live stream beacons are pushed to, dashboards subscribe to its channel
+    /// stream/{stream_id}/ruuvi
+    pub fn stream_id(&self) -> String {
+        self.stream_id
+            .clone()
+            .unwrap_or_else(|| "ruuvi".to_string())
+    }
+
+    /// Sparkplug group of the edge node
+    pub fn group_id(&self) -> String {
+        self.group_id.clone().unwrap_or_else(|| "ruuvi".to_string())
+    }
+
+    /// Sparkplug edge node of the gateway, the device id of the gateway if not set
+    pub fn edge_node_id(&self) -> Option<String> {
+        self.edge_node_id.clone()
+    }
+
+    /// MQTT protocol version of the Sparkplug sink, 5 for MQTT 5 and 3.1.1 otherwise
+    pub fn mqtt_version(&self) -> u8 {
+        self.mqtt_version.unwrap_or(4)
+    }
+
+    /// Seconds after which the broker should drop Sparkplug data messages it has not delivered
+    /// yet, not supported by the MQTT client and ignored with a warning
+    pub fn message_expiry(&self) -> Option<u32> {
+        self.message_expiry
+            .filter(|message_expiry| *message_expiry > 0)
+    }
+
+    /// Beacons delivered at most in a batch
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(100).max(1)
+    }
+
+    /// Seconds a beacon waits at most for its batch to fill up
+    pub fn batch_interval(&self) -> u64 {
+        self.batch_interval.unwrap_or(10)
+    }
+
+    /// Seconds a delivery may take before it is abandoned
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(30)
+    }
+}
+
+/// Comparison of a beacon value to the threshold of an alert rule.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum AlertOperator {
+    #[serde(rename = "above")]
+    ABOVE,
+    #[serde(rename = "below")]
+    BELOW,
+}
+
+/// Rule raising an alert while a value in beacons of a tag breaches its threshold.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub struct AlertRule {
+    tag: Option<String>,
+    metric: String,
+    operator: AlertOperator,
+    threshold: f64,
+    hysteresis: Option<f64>,
+    duration: Option<u64>,
+}
+
+impl AlertRule {
+    /// Rule of the metric of any tag falling below the threshold
+    pub fn below(metric: &str, threshold: f64, hysteresis: f64) -> AlertRule {
+        AlertRule {
+            tag: None,
+            metric: metric.to_string(),
+            operator: AlertOperator::BELOW,
+            threshold,
+            hysteresis: Some(hysteresis),
+            duration: None,
+        }
+    }
+
+    /// MAC address of the tag the rule applies to, all tags if not set
+    pub fn tag(&self) -> Option<String> {
+        self.tag.clone()
+    }
+
+    /// Field of beacon data compared to the threshold, e.g. "temperature"
+    pub fn metric(&self) -> String {
+        self.metric.clone()
+    }
+
+    pub fn operator(&self) -> AlertOperator {
+        self.operator
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// How far back past the threshold the value has to return before the alert is cleared
+    pub fn hysteresis(&self) -> f64 {
+        self.hysteresis.unwrap_or(0.0).abs()
+    }
+
+    /// Seconds a breach, or its clearing, has to last before the alert is triggered or cleared
+    pub fn duration(&self) -> u64 {
+        self.duration.unwrap_or(0)
+    }
+}
+
+/// Kernel interface used to drive GPIO pins of actuators.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum GpioBackend {
+    /// Legacy sysfs interface in /sys/class/gpio
+    #[serde(rename = "sysfs")]
+    SYSFS,
+    /// GPIO character device, e.g. /dev/gpiochip0
+    #[serde(rename = "gpiod")]
+    GPIOD,
+}
+
+/// GPIO pin switched on while a value in beacons of a tag breaches the threshold, e.g. a relay
+/// of a fan turned on when temperature is too high.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ActuatorConfig {
+    name: String,
+    tag: String,
+    metric: String,
+    operator: AlertOperator,
+    threshold: f64,
+    hysteresis: Option<f64>,
+    pin: u32,
+    backend: Option<GpioBackend>,
+    chip: Option<String>,
+    sysfs_path: Option<String>,
+    active_low: Option<bool>,
+}
+
+impl ActuatorConfig {
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// MAC address of the tag whose beacons control the pin
+    pub fn tag(&self) -> String {
+        self.tag.clone()
+    }
+
+    /// Field of beacon data compared to the threshold, e.g. "temperature"
+    pub fn metric(&self) -> String {
+        self.metric.clone()
+    }
+
+    pub fn operator(&self) -> AlertOperator {
+        self.operator
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// How far back past the threshold the value has to return before the pin is switched off
+    pub fn hysteresis(&self) -> f64 {
+        self.hysteresis.unwrap_or(0.0).abs()
+    }
+
+    /// Number of the pin in sysfs, or offset of the line on the chip with gpiod
+    pub fn pin(&self) -> u32 {
+        self.pin
+    }
+
+    pub fn backend(&self) -> GpioBackend {
+        self.backend.unwrap_or(GpioBackend::SYSFS)
+    }
+
+    pub fn chip(&self) -> PathBuf {
+        Path::new(self.chip.as_deref().unwrap_or("/dev/gpiochip0")).to_path_buf()
+    }
+
+    pub fn sysfs_path(&self) -> PathBuf {
+        Path::new(self.sysfs_path.as_deref().unwrap_or("/sys/class/gpio")).to_path_buf()
+    }
+
+    /// Pin is driven low when switched on, e.g. for relay boards with inverted inputs
+    pub fn active_low(&self) -> bool {
+        self.active_low.unwrap_or(false)
+    }
+}
+
+/// Zabbix server receiving alerts as values of trapper items through the sender protocol.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ZabbixConfig {
+    server: String,
+    port: Option<u16>,
+    host: Option<String>,
+    key: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl ZabbixConfig {
+    pub fn server(&self) -> String {
+        self.server.clone()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(10051)
+    }
+
+    /// Host of the items in Zabbix, the device id of the gateway if not set
+    pub fn host(&self) -> Option<String> {
+        self.host.clone()
+    }
+
+    /// Key of the item of an alert, may contain {address} of the tag and {metric}
+    pub fn key(&self) -> String {
+        self.key
+            .clone()
+            .unwrap_or_else(|| "ruuvi.alert[{address},{metric}]".to_string())
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(10)
+    }
+}
+
+/// SNMP manager receiving alerts as SNMPv2c traps.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SnmpConfig {
+    target: String,
+    port: Option<u16>,
+    community: Option<String>,
+    oid: Option<String>,
+}
+
+impl SnmpConfig {
+    /// Host of the SNMP manager
+    pub fn target(&self) -> String {
+        self.target.clone()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(162)
+    }
+
+    pub fn community(&self) -> String {
+        self.community
+            .clone()
+            .unwrap_or_else(|| "public".to_string())
+    }
+
+    /// Object identifier the traps and their variables are under
+    pub fn oid(&self) -> String {
+        self.oid
+            .clone()
+            .unwrap_or_else(|| "1.3.6.1.4.1.8072.9999.9999".to_string())
+    }
+}
+
+/// Protection of the connection to the SMTP server.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum SmtpSecurity {
+    /// Plain text connection, e.g. to a relay on the local network
+    #[serde(rename = "none")]
+    NONE,
+    /// Plain text connection upgraded to TLS with STARTTLS
+    #[serde(rename = "starttls")]
+    STARTTLS,
+    /// TLS from the start of the connection
+    #[serde(rename = "tls")]
+    TLS,
+}
+
+/// SMTP server relaying alerts as email.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EmailConfig {
+    server: String,
+    port: Option<u16>,
+    security: Option<SmtpSecurity>,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+    to: Vec<String>,
+    timeout: Option<u64>,
+}
+
+impl EmailConfig {
+    pub fn server(&self) -> String {
+        self.server.clone()
+    }
+
+    /// Port of the server, the standard one of the security of the connection if not set
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.security() {
+            SmtpSecurity::NONE => 25,
+            SmtpSecurity::STARTTLS => 587,
+            SmtpSecurity::TLS => 465,
+        })
+    }
+
+    pub fn security(&self) -> SmtpSecurity {
+        self.security.unwrap_or(SmtpSecurity::STARTTLS)
+    }
+
+    /// User of AUTH PLAIN, mail is sent without authentication if not set
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+
+    /// Sender address of the mails
+    pub fn from(&self) -> String {
+        self.from.clone()
+    }
+
+    /// Recipient addresses of the mails
+    pub fn to(&self) -> Vec<String> {
+        self.to.clone()
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(30)
+    }
+}
+
+/// Slack channel receiving alerts through an incoming webhook.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SlackConfig {
+    webhook_url: String,
+    timeout: Option<u64>,
+}
+
+impl SlackConfig {
+    pub fn webhook_url(&self) -> String {
+        self.webhook_url.clone()
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(10)
+    }
+}
+
+/// Telegram chat receiving alerts from a bot.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TelegramConfig {
+    bot_token: String,
+    chat_id: String,
+    api_url: Option<String>,
+    timeout: Option<u64>,
+}
+
+impl TelegramConfig {
+    /// Token given by BotFather
+    pub fn bot_token(&self) -> String {
+        self.bot_token.clone()
+    }
+
+    /// Id of the chat, or @username of a channel
+    pub fn chat_id(&self) -> String {
+        self.chat_id.clone()
+    }
+
+    pub fn api_url(&self) -> String {
+        self.api_url
+            .clone()
+            .unwrap_or_else(|| "https://api.telegram.org".to_string())
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(10)
+    }
+}
+
+/// Alerts raised by threshold rules on beacon values, disabled unless rules are set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AlertsConfig {
+    rules: Option<Vec<AlertRule>>,
+    subfolder: Option<String>,
+    zabbix: Option<ZabbixConfig>,
+    snmp: Option<SnmpConfig>,
+    email: Option<EmailConfig>,
+    slack: Option<SlackConfig>,
+    telegram: Option<TelegramConfig>,
+}
+
+impl AlertsConfig {
+    pub fn rules(&self) -> Vec<AlertRule> {
+        self.rules.clone().unwrap_or_default()
+    }
+
+    /// Subfolder of the events topic of the tag alerts are published to
+    pub fn subfolder(&self) -> String {
+        self.subfolder
+            .clone()
+            .unwrap_or_else(|| "alerts".to_string())
+    }
+
+    pub fn zabbix(&self) -> Option<ZabbixConfig> {
+        self.zabbix.clone()
+    }
+
+    pub fn snmp(&self) -> Option<SnmpConfig> {
+        self.snmp.clone()
+    }
+
+    pub fn email(&self) -> Option<EmailConfig> {
+        self.email.clone()
+    }
+
+    pub fn slack(&self) -> Option<SlackConfig> {
+        self.slack.clone()
+    }
+
+    pub fn telegram(&self) -> Option<TelegramConfig> {
+        self.telegram.clone()
+    }
+}
+
+/// Rollback of collect configs pushed from IoT Core that cause repeated failures.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RollbackConfig {
+    state_file: Option<String>,
+    failure_threshold: Option<u64>,
+    window: Option<u64>,
+}
+
+impl RollbackConfig {
+    /// Last known good and rejected configs are kept only in memory unless a file is set
+    pub fn state_file(&self) -> Option<PathBuf> {
+        self.state_file
+            .as_ref()
+            .map(|state_file| Path::new(state_file).to_path_buf())
+    }
+
+    /// Scanner restarts and publish errors that trigger a rollback, zero disables rollbacks
+    pub fn failure_threshold(&self) -> u64 {
+        self.failure_threshold.unwrap_or(3)
+    }
+
+    /// New config becomes the last known good one when it survives this many seconds
+    pub fn window(&self) -> u64 {
+        self.window.unwrap_or(10 * 60)
+    }
+}
+
+/// Release channel of the gateway software followed by the release checker.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum UpdateChannel {
+    #[serde(rename = "stable")]
+    STABLE,
+    #[serde(rename = "beta")]
+    BETA,
+}
+
+/// Software updates triggered with the update command, disabled unless a URL is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct UpdateConfig {
+    url: Option<String>,
+    version: Option<String>,
+    signature_url: Option<String>,
+    public_key: Option<String>,
+    timeout: Option<u64>,
+    exit_code: Option<i32>,
+    manifest_url: Option<String>,
+    channel: Option<UpdateChannel>,
+    check_interval: Option<u64>,
+    auto_install: Option<bool>,
+}
+
+impl UpdateConfig {
+    /// URL of the release binary of the gateway
+    pub fn url(&self) -> Option<String> {
+        self.url.clone()
+    }
+
+    /// Version of the release binary, signed along with the binary
+    pub fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
+    /// URL of the Ed25519 signature of the binary, the URL of the binary with .sig appended if not
+    /// set
+    pub fn signature_url(&self) -> Option<String> {
+        self.signature_url
+            .clone()
+            .or_else(|| self.url.as_ref().map(|url| format!("{}.sig", url)))
+    }
+
+    /// Base64 encoded raw Ed25519 public key the signature is verified against
+    pub fn public_key(&self) -> Option<String> {
+        self.public_key.clone()
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(5 * 60)
+    }
+
+    /// Exit status after an update, so that the service manager restarts the new version
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code.unwrap_or(75)
+    }
+
+    /// URL of the release manifest, which takes precedence over the URL of the binary
+    pub fn manifest_url(&self) -> Option<String> {
+        self.manifest_url.clone()
+    }
+
+    pub fn channel(&self) -> UpdateChannel {
+        self.channel.unwrap_or(UpdateChannel::STABLE)
+    }
+
+    /// Seconds between checks of the release manifest
+    pub fn check_interval(&self) -> u64 {
+        self.check_interval.unwrap_or(6 * 60 * 60)
+    }
+
+    /// Releases rolled out to the gateway are installed without an update command
+    pub fn auto_install(&self) -> bool {
+        self.auto_install.unwrap_or(false)
+    }
+}
+
+/// Positions of a mobile gateway from gpsd attached to beacons, disabled unless a host is set.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct GpsdConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    max_age: Option<u64>,
+}
+
+impl GpsdConfig {
+    pub fn host(&self) -> Option<String> {
+        self.host.clone()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(2947)
+    }
+
+    /// Seconds a position is attached to beacons after gpsd reported it
+    pub fn max_age(&self) -> u64 {
+        self.max_age.unwrap_or(10)
+    }
+}
+
+/// Static metadata of the site of the gateway merged into published payloads.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SiteConfig {
+    name: Option<String>,
+    building: Option<String>,
+    floor: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+}
+
+impl SiteConfig {
+    /// Fields of the site that are set, None if there are none
+    pub fn metadata(&self) -> Option<serde_json::Value> {
+        let mut metadata = serde_json::Map::new();
+        for (field, value) in [
+            ("name", json!(self.name)),
+            ("building", json!(self.building)),
+            ("floor", json!(self.floor)),
+            ("latitude", json!(self.latitude)),
+            ("longitude", json!(self.longitude)),
+            ("altitude", json!(self.altitude)),
+        ] {
+            if !value.is_null() {
+                metadata.insert(field.to_string(), value);
+            }
+        }
+        if metadata.is_empty() {
+            None
+        } else {
+            Some(serde_json::Value::Object(metadata))
+        }
+    }
+}
+
+/// Local configuration file of the gateway.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AppConfig {
+    // not needed in local mode
+    #[serde(default)]
     pub identity: IdentityConfig,
     pub iotcore: IotCoreConfig,
+    #[serde(default)]
+    pub clock: ClockConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub webui: WebUiConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub coordination: CoordinationConfig,
+    #[serde(default)]
+    pub scripting: ScriptingConfig,
+    #[serde(default)]
+    pub csv: CsvConfig,
+    #[serde(default)]
+    pub bridge: BridgeConfig,
+    #[serde(default)]
+    pub udp: UdpConfig,
+    #[serde(default)]
+    pub bacnet: BacnetConfig,
+    #[serde(default)]
+    pub opcua: OpcUaConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub remote_config: RemoteConfig,
+    #[serde(default)]
+    pub rollback: RollbackConfig,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    #[serde(default)]
+    pub actuators: Vec<ActuatorConfig>,
+    #[serde(default)]
+    pub update: UpdateConfig,
+    #[serde(default)]
+    pub site: SiteConfig,
+    #[serde(default)]
+    pub gpsd: GpsdConfig,
+    #[serde(default)]
+    pub default_collectconfig: Option<CollectConfig>,
+    #[serde(default)]
+    pub local: LocalConfig,
+    #[serde(default)]
+    pub pipelines: Vec<PipelineConfig>,
 }
 
 impl AppConfig {
+    /// Reads the YAML configuration file and decrypts encrypted secrets in it.
     pub fn read_config(config_file_path: &Path) -> Result<AppConfig, Report> {
         trace!("in read_config");
         let config_yaml = match fs::read_to_string(config_file_path) {
@@ -64,7 +1540,31 @@ impl AppConfig {
                     .with_section(move || error.to_string().header("Reason:")))
             }
         };
-        let config: AppConfig = match serde_yaml::from_str(&config_yaml) {
+        let mut config_value: serde_yaml::Value = match serde_yaml::from_str(&config_yaml) {
+            Ok(config_value) => config_value,
+            Err(error) => {
+                return Err(eyre!("Unable to parse config file")
+                    .with_section(move || {
+                        config_file_path
+                            .to_string_lossy()
+                            .trim()
+                            .to_string()
+                            .header("File name:")
+                    })
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        // decrypt secrets stored encrypted in the config file
+        if let Err(error) = secrets::decrypt_values(&mut config_value) {
+            return Err(error.with_section(move || {
+                config_file_path
+                    .to_string_lossy()
+                    .trim()
+                    .to_string()
+                    .header("File name:")
+            }));
+        }
+        let config: AppConfig = match serde_yaml::from_value(config_value) {
             Ok(config) => config,
             Err(error) => {
                 return Err(eyre!("Unable to parse config file")
@@ -78,10 +1578,54 @@ impl AppConfig {
                     .with_section(move || error.to_string().header("Reason:")))
             }
         };
-        debug!("application configuration is: {:?}", config);
+        debug!(
+            "application configuration is: {:?}",
+            secrets::Redacted(&config)
+        );
 
         Ok(config)
     }
+
+    /// Discovers IoT Core project, region and registry from DNS TXT records of the domain, or of
+    /// the domain in the configuration when none is given, and checks that they are all known.
+    pub fn discover(&mut self, domain: Option<&str>) -> Result<(), Report> {
+        trace!("in discover");
+        if let Some(domain) = domain
+            .map(|domain| domain.to_string())
+            .or_else(|| self.iotcore.discover())
+        {
+            #[cfg(feature = "dns-discovery")]
+            for (key, value) in dnsconfig::discover(&domain)? {
+                match key.as_str() {
+                    "project_id" => self.iotcore.project_id = value,
+                    "region" => self.iotcore.region = value,
+                    "registry" => self.iotcore.registry = value,
+                    _ => {}
+                }
+            }
+            #[cfg(not(feature = "dns-discovery"))]
+            return Err(eyre!("DNS discovery is not compiled in")
+                .with_section(move || domain.header("Domain:"))
+                .with_section(|| "cargo build --features dns-discovery".header("Build with:")));
+        }
+        let missing: Vec<&str> = [
+            ("project_id", &self.iotcore.project_id),
+            ("region", &self.iotcore.region),
+            ("registry", &self.iotcore.registry),
+        ]
+        .iter()
+        .filter(|(_, value)| value.is_empty())
+        .map(|(key, _)| *key)
+        .collect();
+        if !missing.is_empty() {
+            let missing = missing.join(", ");
+            return Err(
+                eyre!("IoT Core settings are neither configured nor discovered")
+                    .with_section(move || missing.header("Missing:")),
+            );
+        }
+        Ok(())
+    }
 }
 
 // eof
@@ -0,0 +1,156 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{thread, time};
+
+use crate::configfile::ControlConfig;
+use crate::iotcore::CNCCommandMessage;
+use crate::status::SharedStatus;
+
+pub struct ControlServer {
+    socket: Option<PathBuf>,
+    status: SharedStatus,
+    command_sender: channel::Sender<CNCCommandMessage>,
+    running: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    // requests use the same JSON format as CNC commands sent through IoT Core with the
+    //  addition of "status" command which is answered locally
+    fn handle_request(&self, request: &str) -> serde_json::Value {
+        trace!("in handle_request");
+        let request: serde_json::Value = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(error) => return json!({ "error": error.to_string() }),
+        };
+
+        if request["command"] == "status" {
+            let status = self.status.lock().unwrap().clone();
+            return json!({ "status": status });
+        }
+
+        let command: CNCCommandMessage = match serde_json::from_value(request) {
+            Ok(command) => command,
+            Err(error) => return json!({ "error": error.to_string() }),
+        };
+        info!("Control socket command received: {:?}", command.command);
+        match self.command_sender.send(command) {
+            Ok(_) => json!({ "accepted": true }),
+            Err(error) => json!({ "accepted": false, "error": error.to_string() }),
+        }
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> Result<(), std::io::Error> {
+        trace!("in handle_connection");
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        debug!("control socket request: {}", request.trim());
+
+        let response = self.handle_request(request.trim());
+        let mut stream = stream;
+        writeln!(stream, "{}", response)?;
+
+        Ok(())
+    }
+
+    pub fn start_server(&self) -> Result<(), Report> {
+        trace!("in start_server");
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => {
+                debug!("Control socket not enabled.");
+                return Ok(());
+            }
+        };
+
+        // remove stale socket left behind by an unclean shutdown
+        if socket.exists() {
+            fs::remove_file(socket).ok();
+        }
+        let listener = match UnixListener::bind(socket) {
+            Ok(listener) => listener,
+            Err(error) => {
+                return Err(eyre!("Unable to open control socket")
+                    .with_section(move || socket.display().to_string().header("Socket:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        // only owner and group are allowed to control the gateway
+        fs::set_permissions(socket, fs::Permissions::from_mode(0o660)).ok();
+        listener.set_nonblocking(true).ok();
+        info!("Control socket listening at {}", socket.display());
+
+        while self.running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(error) = self.handle_connection(stream) {
+                        warn!("Error while handling control socket connection: {}", error);
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(time::Duration::from_millis(100));
+                }
+                Err(error) => error!("Error while accepting control socket connection: {}", error),
+            }
+        }
+
+        fs::remove_file(socket).ok();
+        info!("Shutting down control socket.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &ControlConfig,
+        status: &SharedStatus,
+        command_sender: &channel::Sender<CNCCommandMessage>,
+        running: &Arc<AtomicBool>,
+    ) -> ControlServer {
+        trace!("in build");
+        ControlServer {
+            socket: config.socket(),
+            status: status.clone(),
+            command_sender: command_sender.clone(),
+            running: running.clone(),
+        }
+    }
+}
+
+// client side of the control socket used by the "control" subcommand
+pub fn send_command(config: &ControlConfig, command: &str) -> Result<String, Report> {
+    trace!("in send_command");
+    let socket = match config.socket() {
+        Some(socket) => socket,
+        None => return Err(eyre!("Control socket is not configured")),
+    };
+    let mut stream = match UnixStream::connect(&socket) {
+        Ok(stream) => stream,
+        Err(error) => {
+            return Err(eyre!("Unable to connect to control socket")
+                .with_section(move || socket.display().to_string().header("Socket:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
+
+    let mut response = String::new();
+    let result = writeln!(stream, "{}", json!({ "command": command }))
+        .and_then(|_| BufReader::new(&stream).read_line(&mut response));
+    match result {
+        Ok(_) => Ok(response.trim().to_string()),
+        Err(error) => Err(eyre!("Error while communicating with control socket")
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+// eof
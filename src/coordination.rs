@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use paho_mqtt as mqtt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{thread, time};
+
+use crate::configfile::CoordinationConfig;
+use crate::status::SharedStatus;
+use crate::tls;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Heartbeat {
+    gateway_id: String,
+    online: bool,
+    leader: bool,
+    timestamp: DateTime<Utc>,
+}
+
+// redundant gateways send heartbeats to each other through a shared MQTT broker. gateway with the
+//  lowest gateway id among those alive is the leader and the only one publishing beacons
+pub struct Coordinator {
+    config: CoordinationConfig,
+    gateway_id: String,
+    status: SharedStatus,
+    running: Arc<AtomicBool>,
+    peers: HashMap<String, Instant>,
+}
+
+impl Coordinator {
+    fn is_leader(&self) -> bool {
+        !self.status.lock().unwrap().standby
+    }
+
+    // returns true if leadership changed
+    fn set_leader(&self, leader: bool) -> bool {
+        let mut status = self.status.lock().unwrap();
+        let changed = status.standby == leader;
+        status.standby = !leader;
+        changed
+    }
+
+    fn heartbeat_topic(&self) -> String {
+        format!("{}/{}", self.config.topic(), self.gateway_id)
+    }
+
+    fn heartbeat(&self, online: bool) -> mqtt::Message {
+        let heartbeat = Heartbeat {
+            gateway_id: self.gateway_id.clone(),
+            online,
+            leader: online && self.is_leader(),
+            timestamp: Utc::now(),
+        };
+        mqtt::Message::new(
+            self.heartbeat_topic(),
+            serde_json::to_string(&heartbeat).unwrap(),
+            mqtt::QOS_1,
+        )
+    }
+
+    fn connect(&self, client: &mqtt::Client) -> Result<(), Report> {
+        trace!("in connect");
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+        conn_opts
+            .keep_alive_interval(Duration::from_secs(self.config.heartbeat_interval()))
+            .clean_session(true)
+            // peers fail over immediately instead of waiting for the heartbeat timeout
+            .will_message(self.heartbeat(false));
+        if let Some(username) = self.config.username() {
+            conn_opts.user_name(username);
+        }
+        if let Some(password) = self.config.password() {
+            conn_opts.password(password);
+        }
+        if tls::is_secure(&self.config.broker().unwrap_or_default()) {
+            conn_opts.ssl_options(
+                tls::ssl_options_builder(&self.config.tls, "coordination", None)?.finalize(),
+            );
+        }
+        if let Err(error) = client.connect(conn_opts.finalize()) {
+            return Err(eyre!("Unable to connect to coordination MQTT broker")
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        if let Err(error) = client.subscribe(&format!("{}/+", self.config.topic()), mqtt::QOS_1) {
+            return Err(eyre!("Unable to subscribe to coordination topic")
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        info!("Connected to coordination MQTT broker");
+        Ok(())
+    }
+
+    fn handle_heartbeat(&mut self, message: &mqtt::Message) {
+        trace!("in handle_heartbeat");
+        let heartbeat: Heartbeat = match serde_json::from_str(&message.payload_str()) {
+            Ok(heartbeat) => heartbeat,
+            Err(error) => {
+                warn!("Invalid heartbeat in coordination topic: {}", error);
+                return;
+            }
+        };
+        if heartbeat.gateway_id == self.gateway_id {
+            return;
+        }
+        if heartbeat.online {
+            if self
+                .peers
+                .insert(heartbeat.gateway_id.clone(), Instant::now())
+                .is_none()
+            {
+                info!("Redundant gateway '{}' is online", heartbeat.gateway_id);
+            }
+        } else if self.peers.remove(&heartbeat.gateway_id).is_some() {
+            warn!("Redundant gateway '{}' went offline", heartbeat.gateway_id);
+        }
+    }
+
+    fn elect(&mut self) {
+        trace!("in elect");
+        let timeout = Duration::from_secs(self.config.heartbeat_timeout());
+        self.peers.retain(|gateway_id, last_seen| {
+            if last_seen.elapsed() > timeout {
+                warn!("Heartbeat of redundant gateway '{}' timed out", gateway_id);
+                return false;
+            }
+            true
+        });
+        let leader = self
+            .peers
+            .keys()
+            .all(|gateway_id| self.gateway_id < *gateway_id);
+        if self.set_leader(leader) {
+            if leader {
+                warn!("This gateway is now the leader and publishes beacons.");
+            } else {
+                warn!("This gateway is now on standby and does not publish beacons.");
+            }
+        }
+    }
+
+    pub fn start_coordinator(&mut self) -> Result<(), Report> {
+        trace!("in start_coordinator");
+        let broker = match self.config.broker() {
+            Some(broker) => broker,
+            None => {
+                debug!("Gateway coordination not enabled.");
+                return Ok(());
+            }
+        };
+        // stay on standby until peers have had a chance to announce themselves
+        self.set_leader(false);
+
+        let create_opts = mqtt::CreateOptionsBuilder::new()
+            .server_uri(broker)
+            .client_id(format!("{}-{}", env!("CARGO_PKG_NAME"), self.gateway_id))
+            .finalize();
+        let mut client = match mqtt::Client::new(create_opts) {
+            Ok(client) => client,
+            Err(error) => {
+                return Err(eyre!("Unable to create Paho MQTT client instance")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let consumer = client.start_consuming();
+
+        let heartbeat_interval = Duration::from_secs(self.config.heartbeat_interval());
+        // election waits for peers to announce themselves after every connect
+        let mut connected = Instant::now();
+        let mut last_heartbeat: Option<Instant> = None;
+        while self.running.load(Ordering::Relaxed) {
+            if !client.is_connected() {
+                if let Err(error) = self.connect(&client) {
+                    error!("{}", error);
+                    // without coordination the gateway can not know whether a peer publishes,
+                    //  so it publishes itself: duplicates are better than no gateway publishing
+                    //  while the coordination broker is down
+                    self.peers.clear();
+                    if self.set_leader(true) {
+                        error!("Coordination MQTT broker is unreachable. This gateway publishes beacons regardless of redundant gateways until it is reachable again.");
+                    }
+                    thread::sleep(heartbeat_interval);
+                    continue;
+                }
+                connected = Instant::now();
+                last_heartbeat = None;
+            }
+
+            while let Ok(Some(message)) = consumer.try_recv() {
+                self.handle_heartbeat(&message);
+            }
+
+            if connected.elapsed() >= Duration::from_secs(self.config.heartbeat_timeout()) {
+                self.elect();
+            }
+
+            let heartbeat_due = match last_heartbeat {
+                Some(last_heartbeat) => last_heartbeat.elapsed() >= heartbeat_interval,
+                None => true,
+            };
+            if heartbeat_due {
+                if let Err(error) = client.publish(self.heartbeat(true)) {
+                    error!("Unable to publish heartbeat: {}", error);
+                }
+                last_heartbeat = Some(Instant::now());
+            }
+
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        client.publish(self.heartbeat(false)).ok();
+        client.disconnect(None).ok();
+        info!("Shutting down gateway coordination.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &CoordinationConfig,
+        gateway_id: &str,
+        status: &SharedStatus,
+        running: &Arc<AtomicBool>,
+    ) -> Coordinator {
+        trace!("in build");
+        Coordinator {
+            config: config.clone(),
+            gateway_id: gateway_id.to_string(),
+            status: status.clone(),
+            running: running.clone(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+// eof
@@ -0,0 +1,283 @@
+use chrono::{Duration, NaiveDate, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::clock;
+use crate::configfile::CsvConfig;
+use crate::scanner::RuuviBluetoothBeacon;
+
+// columns of data format 5 fields and the JSON pointer of the field in decoded beacon data
+const DATA_COLUMNS: [(&str, &str); 10] = [
+    ("temperature", "/temperature"),
+    ("humidity", "/humidity"),
+    ("atmospheric_pressure", "/atmospheric_pressure"),
+    ("acceleration_x", "/acceleration/on_x_axis"),
+    ("acceleration_y", "/acceleration/on_y_axis"),
+    ("acceleration_z", "/acceleration/on_z_axis"),
+    ("powerinfo", "/powerinfo"),
+    ("tx_power", "/tx_power"),
+    ("movement_counter", "/movement_counter"),
+    (
+        "measurement_sequence_number",
+        "/measurement_sequence_number",
+    ),
+];
+const DERIVED_COLUMNS: [&str; 3] = ["acceleration_total", "dew_point", "absolute_humidity"];
+
+// local timestamp follows the UTC one when a time zone is configured
+fn header(timezone: Option<&chrono_tz::Tz>) -> String {
+    let mut columns = vec!["timestamp"];
+    if timezone.is_some() {
+        columns.push("local_timestamp");
+    }
+    columns.push("address");
+    columns.extend(DATA_COLUMNS.iter().map(|(column, _)| *column));
+    columns.extend(DERIVED_COLUMNS.iter());
+    columns.join(",")
+}
+
+// total acceleration in mG
+fn acceleration_total(data: &serde_json::Value) -> Option<f64> {
+    let acceleration = data.get("acceleration")?;
+    let mut sum = 0.0;
+    for axis in ["on_x_axis", "on_y_axis", "on_z_axis"] {
+        sum += acceleration[axis].as_f64()?.powi(2);
+    }
+    Some(sum.sqrt())
+}
+
+// dew point in Celsius with the Magnus formula
+fn dew_point(temperature: f64, humidity: f64) -> Option<f64> {
+    if humidity <= 0.0 {
+        return None;
+    }
+    let gamma = (humidity / 100.0).ln() + 17.62 * temperature / (243.12 + temperature);
+    Some(243.12 * gamma / (17.62 - gamma))
+}
+
+// absolute humidity in g/m3
+fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    let saturation_pressure = 6.112 * (17.67 * temperature / (temperature + 243.5)).exp();
+    saturation_pressure * humidity * 2.1674 / (273.15 + temperature)
+}
+
+fn row(beacon: &RuuviBluetoothBeacon, timezone: Option<&chrono_tz::Tz>) -> String {
+    let data = beacon.data.clone().unwrap_or(serde_json::Value::Null);
+    let mut cells = vec![beacon.timestamp.to_rfc3339()];
+    if let Some(timezone) = timezone {
+        cells.push(clock::local_timestamp(&beacon.timestamp, timezone));
+    }
+    cells.push(beacon.address.clone());
+    // fields missing from other data formats are left empty
+    let cell = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+    cells.extend(
+        DATA_COLUMNS
+            .iter()
+            .map(|(_, pointer)| cell(data.pointer(pointer).and_then(|value| value.as_f64()))),
+    );
+    let temperature = data["temperature"].as_f64();
+    let humidity = data["humidity"].as_f64();
+    cells.push(cell(acceleration_total(&data)));
+    cells.push(cell(temperature.zip(humidity).and_then(
+        |(temperature, humidity)| dew_point(temperature, humidity),
+    )));
+    cells.push(cell(temperature.zip(humidity).map(
+        |(temperature, humidity)| absolute_humidity(temperature, humidity),
+    )));
+    cells.join(",")
+}
+
+// writes received beacons into CSV files in the configured directory, one file per tag per day
+//  (UTC). files older than the retention period are removed when a new day starts
+pub struct CsvExport {
+    directory: PathBuf,
+    retention_days: Option<u64>,
+    timezone: Option<chrono_tz::Tz>,
+    files: HashMap<String, (NaiveDate, File)>,
+    last_cleanup: Option<NaiveDate>,
+}
+
+impl CsvExport {
+    fn file_path(&self, address: &str, date: NaiveDate) -> PathBuf {
+        self.directory
+            .join(format!("{}-{}.csv", address.replace(':', "-"), date))
+    }
+
+    fn open(&self, path: &Path) -> Result<File, Report> {
+        trace!("in open");
+        let is_new = !path.exists();
+        let mut file = match OpenOptions::new().append(true).create(true).open(path) {
+            Ok(file) => file,
+            Err(error) => {
+                let path = path.display().to_string();
+                return Err(eyre!("Unable to open CSV export file")
+                    .with_section(move || path.header("File name:"))
+                    .with_section(move || error.to_string().header("Reason:")));
+            }
+        };
+        if is_new {
+            writeln!(file, "{}", header(self.timezone.as_ref())).ok();
+        }
+        Ok(file)
+    }
+
+    // removes CSV files whose date is older than the retention period
+    fn cleanup(&mut self, today: NaiveDate) {
+        trace!("in cleanup");
+        self.last_cleanup = Some(today);
+        let retention_days = match self.retention_days {
+            Some(retention_days) => retention_days,
+            None => return,
+        };
+        let oldest = today - Duration::days(retention_days as i64);
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(error) => {
+                warn!("Unable to list CSV export directory: {}", error);
+                return;
+            }
+        };
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            // file names end with the date, e.g. AA-BB-CC-DD-EE-FF-2021-03-01.csv
+            let date = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".csv"))
+                .filter(|name| name.len() >= 10)
+                .and_then(|name| {
+                    NaiveDate::parse_from_str(&name[name.len() - 10..], "%Y-%m-%d").ok()
+                });
+            if let Some(date) = date {
+                if date < oldest {
+                    debug!("removing expired CSV export file {}", path.display());
+                    if let Err(error) = fs::remove_file(&path) {
+                        warn!("Unable to remove CSV export file: {}", error);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, beacon: &RuuviBluetoothBeacon) -> Result<(), Report> {
+        trace!("in write");
+        let today = Utc::now().naive_utc().date();
+        if self.last_cleanup != Some(today) {
+            self.cleanup(today);
+        }
+        let reopen = match self.files.get(&beacon.address) {
+            Some((date, _)) => *date != today,
+            None => true,
+        };
+        if reopen {
+            let file = self.open(&self.file_path(&beacon.address, today))?;
+            self.files.insert(beacon.address.clone(), (today, file));
+        }
+        let (_, file) = self.files.get_mut(&beacon.address).unwrap();
+        match writeln!(file, "{}", row(beacon, self.timezone.as_ref())) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Unable to write to CSV export file")
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+
+    pub fn build(
+        config: &CsvConfig,
+        timezone: Option<chrono_tz::Tz>,
+    ) -> Result<Option<CsvExport>, Report> {
+        trace!("in build");
+        let directory = match config.directory() {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+        if let Err(error) = fs::create_dir_all(&directory) {
+            let directory = directory.display().to_string();
+            return Err(eyre!("Unable to create CSV export directory")
+                .with_section(move || directory.header("Directory:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        info!("Exporting beacons as CSV to {}", directory.display());
+        Ok(Some(CsvExport {
+            directory,
+            retention_days: config.retention_days(),
+            timezone,
+            files: HashMap::new(),
+            last_cleanup: None,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dew_point, header, row, CsvExport};
+    use crate::configfile::CsvConfig;
+    use crate::mock::{beacon, temp_path};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::fs;
+
+    #[test]
+    fn derived_metrics() {
+        let mut tag = beacon("AA:BB:CC:DD:EE:01", 7);
+        tag.data.as_mut().unwrap()["acceleration"] =
+            json!({"on_x_axis": 0.0, "on_y_axis": 600.0, "on_z_axis": 800.0});
+        let row = row(&tag, None);
+        let cells: Vec<&str> = row.split(',').collect();
+        assert_eq!(cells.len(), header(None).split(',').count());
+        assert_eq!(cells[1], "AA:BB:CC:DD:EE:01");
+        assert_eq!(cells[2], "21.5");
+        // data format 5 fields missing from the beacon are empty
+        assert_eq!(cells[4], "");
+        assert_eq!(cells[12], "1000");
+        let dew_point = dew_point(21.5, 45.0).unwrap();
+        assert!((dew_point - 9.06).abs() < 0.01);
+
+        // local timestamp follows the UTC one, summer time applies in June
+        tag.timestamp = Utc.with_ymd_and_hms(2021, 6, 1, 12, 0, 0).unwrap();
+        let timezone: chrono_tz::Tz = "Europe/Helsinki".parse().unwrap();
+        let row = super::row(&tag, Some(&timezone));
+        let cells: Vec<&str> = row.split(',').collect();
+        assert_eq!(cells.len(), header(Some(&timezone)).split(',').count());
+        assert_eq!(cells[0], "2021-06-01T12:00:00+00:00");
+        assert_eq!(cells[1], "2021-06-01T15:00:00+03:00");
+        assert_eq!(cells[2], "AA:BB:CC:DD:EE:01");
+    }
+
+    #[test]
+    fn one_file_per_tag_per_day() {
+        let directory = temp_path("csvexport");
+        let config: CsvConfig = serde_json::from_value(json!({
+            "directory": directory.display().to_string(),
+            "retention_days": 7,
+        }))
+        .unwrap();
+        let mut export = CsvExport::build(&config, None).unwrap().unwrap();
+        let expired = directory.join(format!(
+            "AA-BB-CC-DD-EE-01-{}.csv",
+            Utc::now().naive_utc().date() - Duration::days(8)
+        ));
+        fs::write(&expired, header(None)).unwrap();
+
+        export.write(&beacon("AA:BB:CC:DD:EE:01", 1)).unwrap();
+        export.write(&beacon("AA:BB:CC:DD:EE:01", 2)).unwrap();
+        export.write(&beacon("AA:BB:CC:DD:EE:02", 1)).unwrap();
+
+        assert!(!expired.exists());
+        let file = directory.join(format!(
+            "AA-BB-CC-DD-EE-01-{}.csv",
+            Utc::now().naive_utc().date()
+        ));
+        let content = fs::read_to_string(file).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], header(None));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
+
+// eof
@@ -0,0 +1,200 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use ruuvitag_dataformat::{RuuviTagDataFormat3, RuuviTagDataFormat5};
+use structview::View;
+
+pub const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+pub const APPLE_MANUFACTURER_ID: u16 = 0x004c;
+pub const IBEACON_DECODER: &str = "ibeacon";
+
+#[derive(Debug, Clone)]
+pub struct DecodedBeacon {
+    pub data: serde_json::Value,
+    pub extensions: Option<Vec<u8>>,
+}
+
+// decoders are matched against manufacturer id and the data format byte that follows it in
+//  advertised manufacturer data
+pub trait BeaconDecoder: Send {
+    fn name(&self) -> &str;
+    fn manufacturer_id(&self) -> u16;
+    fn data_format(&self) -> u8;
+    // payload starts from the data format byte
+    fn decode(&self, payload: &[u8]) -> Result<DecodedBeacon, Report>;
+}
+
+pub struct RuuviDataFormat3Decoder;
+
+impl BeaconDecoder for RuuviDataFormat3Decoder {
+    fn name(&self) -> &str {
+        "ruuvi_v3"
+    }
+
+    fn manufacturer_id(&self) -> u16 {
+        RUUVI_MANUFACTURER_ID
+    }
+
+    fn data_format(&self) -> u8 {
+        3
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DecodedBeacon, Report> {
+        trace!("in decode");
+        match RuuviTagDataFormat3::view(&payload[1..]) {
+            Ok(data) => Ok(DecodedBeacon {
+                data: serde_json::to_value(data).unwrap(),
+                extensions: None,
+            }),
+            Err(error) => Err(eyre!(
+                "Unable to parse Bluetooth packets peripheral properties into Ruuvitag v3 structure."
+            )
+            .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+}
+
+pub struct RuuviDataFormat5Decoder;
+
+impl BeaconDecoder for RuuviDataFormat5Decoder {
+    fn name(&self) -> &str {
+        "ruuvi_v5"
+    }
+
+    fn manufacturer_id(&self) -> u16 {
+        RUUVI_MANUFACTURER_ID
+    }
+
+    fn data_format(&self) -> u8 {
+        5
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DecodedBeacon, Report> {
+        trace!("in decode");
+        match RuuviTagDataFormat5::view(&payload[1..]) {
+            Ok(data) => Ok(DecodedBeacon {
+                data: serde_json::to_value(data).unwrap(),
+                extensions: RuuviTagDataFormat5::extensions(&payload[1..]).map(|e| e.to_vec()),
+            }),
+            Err(error) => Err(eyre!(
+                "Unable to parse Bluetooth packets peripheral properties into Ruuvitag v5 structure."
+            )
+            .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+}
+
+// https://developer.apple.com/ibeacon/
+pub struct IBeaconDecoder;
+
+impl BeaconDecoder for IBeaconDecoder {
+    fn name(&self) -> &str {
+        IBEACON_DECODER
+    }
+
+    fn manufacturer_id(&self) -> u16 {
+        APPLE_MANUFACTURER_ID
+    }
+
+    fn data_format(&self) -> u8 {
+        0x02
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<DecodedBeacon, Report> {
+        trace!("in decode");
+        // data format and length bytes are followed by 16 byte UUID, major, minor and measured power
+        if payload.len() < 23 || payload[1] != 0x15 {
+            let payload_length = payload.len().to_string();
+            return Err(eyre!(
+                "Unable to parse Bluetooth packets peripheral properties into iBeacon structure."
+            )
+            .with_section(move || payload_length.header("Payload length:")));
+        }
+        let uuid: Vec<String> = [
+            &payload[2..6],
+            &payload[6..8],
+            &payload[8..10],
+            &payload[10..12],
+            &payload[12..18],
+        ]
+        .iter()
+        .map(|part| part.iter().map(|byte| format!("{:02x}", byte)).collect())
+        .collect();
+        Ok(DecodedBeacon {
+            data: json!({
+                "uuid": uuid.join("-"),
+                "major": u16::from_be_bytes([payload[18], payload[19]]),
+                "minor": u16::from_be_bytes([payload[20], payload[21]]),
+                "measured_power": payload[22] as i8,
+            }),
+            extensions: None,
+        })
+    }
+}
+
+pub struct DecoderRegistry {
+    decoders: Vec<Box<dyn BeaconDecoder>>,
+}
+
+impl DecoderRegistry {
+    // decoders registered later take precedence, so that built-in decoders can be overridden
+    pub fn register(&mut self, decoder: Box<dyn BeaconDecoder>) {
+        trace!("in register");
+        info!(
+            "Registered beacon decoder '{}' for manufacturer {:#06x} data format {}",
+            decoder.name(),
+            decoder.manufacturer_id(),
+            decoder.data_format()
+        );
+        self.decoders.insert(0, decoder);
+    }
+
+    pub fn unregister(&mut self, name: &str) {
+        trace!("in unregister");
+        if self.has_decoder(name) {
+            info!("Unregistered beacon decoder '{}'", name);
+            self.decoders.retain(|decoder| decoder.name() != name);
+        }
+    }
+
+    pub fn has_decoder(&self, name: &str) -> bool {
+        self.decoders.iter().any(|decoder| decoder.name() == name)
+    }
+
+    pub fn knows_manufacturer(&self, manufacturer_id: u16) -> bool {
+        self.decoders
+            .iter()
+            .any(|decoder| decoder.manufacturer_id() == manufacturer_id)
+    }
+
+    pub fn manufacturer_ids(&self) -> Vec<u16> {
+        let mut manufacturer_ids: Vec<u16> = self
+            .decoders
+            .iter()
+            .map(|decoder| decoder.manufacturer_id())
+            .collect();
+        manufacturer_ids.sort_unstable();
+        manufacturer_ids.dedup();
+        manufacturer_ids
+    }
+
+    pub fn find(&self, manufacturer_id: u16, data_format: u8) -> Option<&dyn BeaconDecoder> {
+        self.decoders
+            .iter()
+            .find(|decoder| {
+                decoder.manufacturer_id() == manufacturer_id && decoder.data_format() == data_format
+            })
+            .map(|decoder| decoder.as_ref())
+    }
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> DecoderRegistry {
+        DecoderRegistry {
+            decoders: vec![
+                Box::new(RuuviDataFormat5Decoder),
+                Box::new(RuuviDataFormat3Decoder),
+            ],
+        }
+    }
+}
+
+// eof
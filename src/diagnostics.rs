@@ -0,0 +1,101 @@
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+use crate::clock;
+use crate::configfile::{AppConfig, ClockConfig, IotCoreConfig};
+use crate::fatal::{self, FatalError};
+use crate::scanner;
+use crate::status::ConnectionStatus;
+
+fn os_release() -> Option<String> {
+    trace!("in os_release");
+    let os_release = fs::read_to_string("/etc/os-release").ok()?;
+    os_release
+        .lines()
+        .find(|line| line.starts_with("PRETTY_NAME="))
+        .map(|line| {
+            line.trim_start_matches("PRETTY_NAME=")
+                .trim_matches('"')
+                .to_string()
+        })
+}
+
+fn kernel_release() -> Option<String> {
+    trace!("in kernel_release");
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string())
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct StartupReport {
+    software: String,
+    version: String,
+    os: String,
+    arch: String,
+    os_release: Option<String>,
+    kernel: Option<String>,
+    bluetooth_backend: String,
+    adapters: Vec<String>,
+    iotcore: IotCoreConfig,
+    clock: ClockConfig,
+    clock_status: String,
+    started: chrono::DateTime<chrono::Utc>,
+    connection: Option<ConnectionStatus>,
+    // fatal error that stopped an earlier run before it could be published
+    previous_failure: Option<FatalError>,
+}
+
+impl StartupReport {
+    pub fn build(appconfig: &AppConfig) -> StartupReport {
+        trace!("in build");
+        let adapters = match scanner::list_adapters() {
+            Ok(adapters) => adapters,
+            Err(error) => {
+                warn!(
+                    "Unable to list Bluetooth adapters for startup report: {}",
+                    error
+                );
+                Vec::new()
+            }
+        };
+
+        StartupReport {
+            software: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            os_release: os_release(),
+            kernel: kernel_release(),
+            bluetooth_backend: "bluez".to_string(),
+            adapters,
+            iotcore: appconfig.iotcore.clone(),
+            clock: appconfig.clock.clone(),
+            clock_status: "unknown".to_string(),
+            started: chrono::Utc::now(),
+            connection: None,
+            previous_failure: FatalError::persisted(Path::new(fatal::FATAL_ERROR_FILE)),
+        }
+    }
+
+    // clock status is evaluated at the time the report is sent, not when it was built
+    pub fn refresh_clock_status(&mut self) {
+        trace!("in refresh_clock_status");
+        self.clock_status = match clock::check_clock(&self.clock) {
+            Ok(_) => "ok".to_string(),
+            Err(reason) => reason,
+        };
+    }
+
+    // connection the report is published on, with its TLS handshake and CONNACK
+    pub fn set_connection(&mut self, connection: ConnectionStatus) {
+        self.connection = Some(connection);
+    }
+
+    pub fn previous_failure(&self) -> Option<&FatalError> {
+        self.previous_failure.as_ref()
+    }
+}
+
+// eof
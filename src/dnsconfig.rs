@@ -0,0 +1,208 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::collections::HashMap;
+use std::fs;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+// settings of the gateway that can be discovered from DNS
+const DISCOVERED_KEYS: [&str; 3] = ["project_id", "region", "registry"];
+const TXT: u16 = 16;
+
+// first name server in resolv.conf
+fn nameserver() -> String {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let nameserver = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .map(|nameserver| nameserver.trim())
+        .find(|nameserver| !nameserver.is_empty())
+        .unwrap_or("127.0.0.1");
+    if nameserver.contains(':') {
+        format!("[{}]:53", nameserver)
+    } else {
+        format!("{}:53", nameserver)
+    }
+}
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    // header with recursion desired and a single question
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(&TXT.to_be_bytes());
+    query.extend(&1u16.to_be_bytes());
+    query
+}
+
+// offset after the possibly compressed name at the offset
+fn skip_name(response: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *response.get(offset)? as usize;
+        if length == 0 {
+            return Some(offset + 1);
+        }
+        if length & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += length + 1;
+    }
+}
+
+fn read_u16(response: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *response.get(offset)?,
+        *response.get(offset + 1)?,
+    ]))
+}
+
+// TXT records in a response to the query, the character strings of each record concatenated
+fn parse_response(id: u16, response: &[u8]) -> Result<Vec<String>, String> {
+    if read_u16(response, 0) != Some(id) {
+        return Err("response to another query".to_string());
+    }
+    let flags = read_u16(response, 2).ok_or("truncated response")?;
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err("no such domain".to_string()),
+        rcode => return Err(format!("name server returned error code {}", rcode)),
+    }
+    let questions = read_u16(response, 4).ok_or("truncated response")?;
+    let answers = read_u16(response, 6).ok_or("truncated response")?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(response, offset).ok_or("truncated response")? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        offset = skip_name(response, offset).ok_or("truncated response")?;
+        let record_type = read_u16(response, offset).ok_or("truncated response")?;
+        let length = read_u16(response, offset + 8).ok_or("truncated response")? as usize;
+        offset += 10;
+        let data = response
+            .get(offset..offset + length)
+            .ok_or("truncated response")?;
+        offset += length;
+        if record_type != TXT {
+            continue;
+        }
+        let mut record = String::new();
+        let mut position = 0;
+        while position < data.len() {
+            let string_length = data[position] as usize;
+            let string = data
+                .get(position + 1..position + 1 + string_length)
+                .ok_or("truncated response")?;
+            record.push_str(&String::from_utf8_lossy(string));
+            position += 1 + string_length;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// TXT records of the name from the name server of the system
+fn lookup_txt(name: &str) -> Result<Vec<String>, Report> {
+    trace!("in lookup_txt");
+    let nameserver = nameserver();
+    let id: u16 = rand::random();
+    let result = (|| -> Result<Vec<String>, String> {
+        let socket = UdpSocket::bind(if nameserver.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })
+        .map_err(|error| error.to_string())?;
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .map_err(|error| error.to_string())?;
+        socket
+            .send_to(&query(id, name), &nameserver)
+            .map_err(|error| error.to_string())?;
+        let mut response = [0; 4096];
+        let length = socket
+            .recv(&mut response)
+            .map_err(|error| error.to_string())?;
+        parse_response(id, &response[..length])
+    })();
+    match result {
+        Ok(records) => Ok(records),
+        Err(reason) => {
+            let name = name.to_string();
+            Err(eyre!("Unable to look up TXT records")
+                .with_section(move || name.header("Name:"))
+                .with_section(move || nameserver.header("Name server:"))
+                .with_section(move || reason.header("Reason:")))
+        }
+    }
+}
+
+// key=value pairs separated by spaces or semicolons, unknown keys are ignored
+fn parse_settings(records: &[String]) -> HashMap<String, String> {
+    records
+        .iter()
+        .flat_map(|record| record.split(|c: char| c == ';' || c.is_whitespace()))
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| DISCOVERED_KEYS.contains(key))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// discovers IoT Core project, region and registry of the gateway from TXT records of
+//  _ruuvi2iotcore.<domain>, e.g. "project_id=my-project region=europe-west1 registry=gateways"
+pub fn discover(domain: &str) -> Result<HashMap<String, String>, Report> {
+    trace!("in discover");
+    let name = format!("_{}.{}", env!("CARGO_PKG_NAME"), domain);
+    let settings = parse_settings(&lookup_txt(&name)?);
+    if settings.is_empty() {
+        return Err(
+            eyre!("No gateway settings found in DNS").with_section(move || name.header("Name:"))
+        );
+    }
+    info!(
+        "Discovered IoT Core settings from DNS at {}: {:?}",
+        name, settings
+    );
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, parse_settings, query};
+
+    #[test]
+    fn txt_records() {
+        let mut response = query(0x1234, "_ruuvi2iotcore.example.com");
+        // answer count and response flags
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        let text = b"project_id=my-project region=europe-west1;registry=gateways other=1";
+        // compressed name pointing to the question, TXT type, class, ttl and two strings
+        response.extend(&[0xc0, 0x0c, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10]);
+        response.extend(&((text.len() + 2) as u16).to_be_bytes());
+        response.push(30);
+        response.extend(&text[..30]);
+        response.push((text.len() - 30) as u8);
+        response.extend(&text[30..]);
+
+        let records = parse_response(0x1234, &response).unwrap();
+        assert_eq!(records, vec![String::from_utf8(text.to_vec()).unwrap()]);
+        let settings = parse_settings(&records);
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings["region"], "europe-west1");
+        assert_eq!(settings["registry"], "gateways");
+
+        assert!(parse_response(0x4321, &response).is_err());
+        response[3] = 0x83;
+        assert_eq!(
+            parse_response(0x1234, &response).unwrap_err(),
+            "no such domain"
+        );
+    }
+}
+
+// eof
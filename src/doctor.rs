@@ -0,0 +1,391 @@
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::clock;
+use crate::configfile::{AppConfig, Transport};
+use crate::hci;
+use crate::jwt;
+use crate::publisher;
+use crate::scanner;
+use crate::tls::TlsProbe;
+
+// rfkill switches of the system, one directory per switch
+const RFKILL_DIR: &str = "/sys/class/rfkill";
+// time to wait for the TLS handshake with a broker
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    OK,
+    WARN,
+    FAIL,
+}
+
+/// Result of a check of the gateway with a hint on how to fix what the check found.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub verdict: Verdict,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, detail: String) -> Check {
+        Check {
+            name: name.to_string(),
+            verdict: Verdict::OK,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn warn(name: &str, detail: String, hint: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            verdict: Verdict::WARN,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(name: &str, detail: String, hint: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            verdict: Verdict::FAIL,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+fn check_capabilities() -> Check {
+    trace!("in check_capabilities");
+    let missing = hci::missing_capabilities();
+    if missing.is_empty() {
+        return Check::ok(
+            "Bluetooth permissions",
+            "CAP_NET_RAW and CAP_NET_ADMIN are effective".to_string(),
+        );
+    }
+    Check::fail(
+        "Bluetooth permissions",
+        format!("missing {}", missing.join(" and ")),
+        &format!(
+            "{}, or {} in the systemd unit",
+            hci::setcap_command(),
+            hci::SYSTEMD_CAPABILITIES
+        ),
+    )
+}
+
+fn check_adapters() -> Check {
+    trace!("in check_adapters");
+    match scanner::list_adapters() {
+        Ok(adapters) if !adapters.is_empty() => Check::ok("Bluetooth adapter", adapters.join(", ")),
+        Ok(_) => Check::fail(
+            "Bluetooth adapter",
+            "no adapters found".to_string(),
+            "plug in the adapter and check that its driver is loaded (hciconfig -a, dmesg)",
+        ),
+        Err(error) => Check::fail(
+            "Bluetooth adapter",
+            error.to_string(),
+            "check that the bluetooth kernel modules are loaded",
+        ),
+    }
+}
+
+// Bluetooth switches of the rfkill directory that are blocked, with whether the block is hard
+fn blocked_switches(rfkill_dir: &Path) -> Vec<(String, bool)> {
+    let read = |path: &Path| {
+        fs::read_to_string(path)
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let mut blocked = Vec::new();
+    for entry in fs::read_dir(rfkill_dir).into_iter().flatten().flatten() {
+        let switch = entry.path();
+        if read(&switch.join("type")) != "bluetooth" {
+            continue;
+        }
+        let name = read(&switch.join("name"));
+        if read(&switch.join("hard")) == "1" {
+            blocked.push((name, true));
+        } else if read(&switch.join("soft")) == "1" {
+            blocked.push((name, false));
+        }
+    }
+    blocked.sort();
+    blocked
+}
+
+fn check_rfkill(rfkill_dir: &Path) -> Check {
+    trace!("in check_rfkill");
+    let blocked = blocked_switches(rfkill_dir);
+    if blocked.is_empty() {
+        return Check::ok("rfkill", "Bluetooth is not blocked".to_string());
+    }
+    let detail = blocked
+        .iter()
+        .map(|(name, hard)| format!("{} {} blocked", name, if *hard { "hard" } else { "soft" }))
+        .collect::<Vec<String>>()
+        .join(", ");
+    if blocked.iter().any(|(_, hard)| *hard) {
+        Check::fail(
+            "rfkill",
+            detail,
+            "turn on the hardware switch or enable Bluetooth in the firmware settings",
+        )
+    } else {
+        Check::fail("rfkill", detail, "sudo rfkill unblock bluetooth")
+    }
+}
+
+fn check_config(config_file: &Path) -> (Check, Option<AppConfig>) {
+    trace!("in check_config");
+    match AppConfig::read_config(config_file) {
+        Ok(appconfig) => (
+            Check::ok("Configuration", config_file.display().to_string()),
+            Some(appconfig),
+        ),
+        // the report has the reason without the colors and sections of the error
+        Err(error) => (
+            Check::fail(
+                "Configuration",
+                format!("{}: {}", config_file.display(), error.root_cause()),
+                "compare the file with the example ruuvi2iotcore.yaml, or give another with --config",
+            ),
+            None,
+        ),
+    }
+}
+
+fn check_keys(appconfig: &AppConfig) -> Vec<Check> {
+    trace!("in check_keys");
+    let private_key = Path::new(&appconfig.identity.private_key);
+    let private_key_check = match jwt::check_private_key(private_key) {
+        Ok(()) => Check::ok("Private key", private_key.display().to_string()),
+        Err(reason) => Check::fail(
+            "Private key",
+            format!("{}: {}", private_key.display(), reason),
+            "give an RSA private key in PEM format, e.g. made with \
+             openssl genpkey -algorithm RSA -out rsa_private.pem",
+        ),
+    };
+    let public_key = Path::new(&appconfig.identity.public_key);
+    let public_key_check = match fs::read_to_string(public_key) {
+        Ok(pem) if pem.contains("-----BEGIN ") => {
+            Check::ok("Public key", public_key.display().to_string())
+        }
+        Ok(_) => Check::fail(
+            "Public key",
+            format!("{}: not in PEM format", public_key.display()),
+            "give the certificate or public key registered to the gateway in IoT Core",
+        ),
+        Err(error) => Check::fail(
+            "Public key",
+            format!("{}: {}", public_key.display(), error),
+            "give the certificate or public key registered to the gateway in IoT Core",
+        ),
+    };
+    vec![private_key_check, public_key_check]
+}
+
+fn check_endpoints(appconfig: &AppConfig) -> Vec<Check> {
+    trace!("in check_endpoints");
+    let endpoints = match publisher::configured_endpoints(&appconfig.iotcore) {
+        Ok(endpoints) => endpoints,
+        Err(error) => {
+            return vec![Check::fail(
+                "IoT Core endpoints",
+                error.root_cause().to_string(),
+                "give endpoints as host or host:port",
+            )]
+        }
+    };
+    if appconfig.proxy.host().is_some() {
+        return vec![Check::warn(
+            "IoT Core endpoints",
+            "connected through a proxy, not probed".to_string(),
+            "check that the proxy allows connections to the endpoints",
+        )];
+    }
+    let tls_config = match &appconfig.identity.ca_certs {
+        Some(ca_certs) => appconfig.iotcore.tls.with_ca_cert(ca_certs),
+        None => appconfig.iotcore.tls.clone(),
+    };
+    let mut checks = Vec::new();
+    for endpoint in endpoints {
+        let name = format!("IoT Core {}", endpoint);
+        let addresses = match (endpoint.host.as_str(), endpoint.port).to_socket_addrs() {
+            Ok(addresses) => addresses.collect::<Vec<_>>(),
+            Err(error) => {
+                checks.push(Check::fail(
+                    &name,
+                    format!("DNS lookup failed: {}", error),
+                    "check the name servers in /etc/resolv.conf and the network connection",
+                ));
+                continue;
+            }
+        };
+        // IoT Core requires the "mqtt" protocol to be negotiated on port 443
+        let mut alpn_protocols = tls_config.alpn_protocols();
+        if alpn_protocols.is_empty()
+            && endpoint.port == 443
+            && appconfig.iotcore.transport() == Transport::TCP
+        {
+            alpn_protocols.push("mqtt".to_string());
+        }
+        let handshake = TlsProbe::build(&tls_config, &alpn_protocols)
+            .map_err(|error| error.root_cause().to_string())
+            .and_then(|tls_probe| tls_probe.handshake(&endpoint, HANDSHAKE_TIMEOUT));
+        checks.push(match handshake {
+            Ok(handshake) => Check::ok(
+                &name,
+                format!(
+                    "{} addresses, {} with {} in {} ms",
+                    addresses.len(),
+                    handshake.version,
+                    handshake.cipher_suite,
+                    handshake.handshake_ms
+                ),
+            ),
+            Err(reason) => Check::fail(
+                &name,
+                format!("TLS handshake failed: {}", reason),
+                "check that the firewall allows the port, and give the trust roots of a \
+                 TLS-intercepting proxy in ca_certs of iotcore tls",
+            ),
+        });
+    }
+    checks
+}
+
+fn check_clock(appconfig: &AppConfig) -> Check {
+    trace!("in check_clock");
+    match clock::check_clock(&appconfig.clock) {
+        Ok(()) => Check::ok("Clock", chrono::Utc::now().to_rfc3339()),
+        Err(reason) => Check::fail(
+            "Clock",
+            reason,
+            "enable time synchronization, e.g. with timedatectl set-ntp true",
+        ),
+    }
+}
+
+/// Runs the checks of the gateway. Checks needing the configuration are left out when the
+/// configuration can not be read.
+pub fn run(config_file: &Path) -> Vec<Check> {
+    trace!("in run");
+    let mut checks = vec![
+        check_capabilities(),
+        check_adapters(),
+        check_rfkill(Path::new(RFKILL_DIR)),
+    ];
+    let (config_check, appconfig) = check_config(config_file);
+    checks.push(config_check);
+    if let Some(appconfig) = appconfig {
+        if appconfig.local.enabled() {
+            checks.push(Check::ok("IoT Core", "disabled in local mode".to_string()));
+        } else {
+            checks.extend(check_keys(&appconfig));
+            checks.extend(check_endpoints(&appconfig));
+        }
+        checks.push(check_clock(&appconfig));
+    }
+    checks
+}
+
+/// Report of the checks, one line per check followed by the hint of a check that did not pass.
+/// Verdicts are colored for terminals.
+pub fn report(checks: &[Check], colors: bool) -> String {
+    let mut report = String::new();
+    for check in checks {
+        let (label, color) = match check.verdict {
+            Verdict::OK => ("  OK", "32"),
+            Verdict::WARN => ("WARN", "33"),
+            Verdict::FAIL => ("FAIL", "31"),
+        };
+        let label = if colors {
+            format!("\x1b[1;{}m{}\x1b[0m", color, label)
+        } else {
+            label.to_string()
+        };
+        report.push_str(&format!("[{}] {}: {}\n", label, check.name, check.detail));
+        if let Some(hint) = &check.hint {
+            report.push_str(&format!("       hint: {}\n", hint));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_config, check_keys, check_rfkill, report, Check, Verdict};
+    use crate::mock::temp_path;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn blocked_bluetooth_is_reported() {
+        let rfkill_dir = temp_path("rfkill");
+        for (switch, kind, soft, hard) in [
+            ("rfkill0", "wlan", "1", "0"),
+            ("rfkill1", "bluetooth", "1", "0"),
+        ] {
+            let switch = rfkill_dir.join(switch);
+            fs::create_dir_all(&switch).unwrap();
+            fs::write(switch.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(switch.join("name"), "hci0\n").unwrap();
+            fs::write(switch.join("soft"), format!("{}\n", soft)).unwrap();
+            fs::write(switch.join("hard"), format!("{}\n", hard)).unwrap();
+        }
+        let check = check_rfkill(&rfkill_dir);
+        assert_eq!(check.verdict, Verdict::FAIL);
+        assert_eq!(check.detail, "hci0 soft blocked");
+        assert_eq!(check.hint.unwrap(), "sudo rfkill unblock bluetooth");
+
+        fs::write(rfkill_dir.join("rfkill1/soft"), "0\n").unwrap();
+        assert_eq!(check_rfkill(&rfkill_dir).verdict, Verdict::OK);
+        fs::remove_dir_all(rfkill_dir).unwrap();
+    }
+
+    #[test]
+    fn missing_config_and_keys_fail() {
+        let (check, appconfig) = check_config(Path::new("missing.yaml"));
+        assert_eq!(check.verdict, Verdict::FAIL);
+        assert!(appconfig.is_none());
+
+        let appconfig = serde_yaml::from_str(
+            r#"
+identity:
+  public_key: "missing.crt"
+  private_key: "missing.key"
+iotcore:
+  device_id: "test-gateway"
+"#,
+        )
+        .unwrap();
+        let checks = check_keys(&appconfig);
+        assert!(checks.iter().all(|check| check.verdict == Verdict::FAIL));
+        assert!(checks[0].detail.starts_with("missing.key: "));
+    }
+
+    #[test]
+    fn report_has_hints_of_failed_checks() {
+        let checks = vec![
+            Check::ok("Clock", "in sync".to_string()),
+            Check::fail("rfkill", "hci0 soft blocked".to_string(), "rfkill unblock"),
+        ];
+        assert_eq!(
+            report(&checks, false),
+            "[  OK] Clock: in sync\n[FAIL] rfkill: hci0 soft blocked\n       hint: rfkill unblock\n"
+        );
+        assert!(report(&checks, true).starts_with("[\x1b[1;32m  OK\x1b[0m] Clock"));
+    }
+}
+
+// eof
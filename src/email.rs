@@ -0,0 +1,316 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::alerts::{Alert, Notifier};
+use crate::configfile::{EmailConfig, SmtpSecurity};
+
+trait Stream: Read + Write {}
+impl<S: Read + Write> Stream for S {}
+
+// reads a reply of the server, lines of a multiline reply are joined
+fn reply(stream: &mut dyn Stream) -> Result<(u16, String), String> {
+    let mut text = Vec::new();
+    loop {
+        // replies are short, so they are read a byte at a time to not read past them before
+        //  STARTTLS
+        let mut line = Vec::new();
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\r\n") {
+            if stream.read(&mut byte).map_err(|error| error.to_string())? == 0 {
+                return Err("connection closed by the server".to_string());
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        let code = match line.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) => code,
+            None => return Err(format!("unexpected reply from the server: {}", line)),
+        };
+        text.push(line.get(4..).unwrap_or_default().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, text.join(" ")));
+        }
+    }
+}
+
+// sends the command and checks that the reply has the expected code
+fn command(stream: &mut dyn Stream, command: &str, expected: u16) -> Result<String, String> {
+    stream
+        .write_all(format!("{}\r\n", command).as_bytes())
+        .and_then(|_| stream.flush())
+        .map_err(|error| error.to_string())?;
+    expect(stream, expected).map_err(|reason| {
+        // credentials are not repeated in errors
+        let verb = command.split(' ').next().unwrap_or_default();
+        format!("{} failed: {}", verb, reason)
+    })
+}
+
+fn expect(stream: &mut dyn Stream, expected: u16) -> Result<String, String> {
+    match reply(stream)? {
+        (code, text) if code == expected => Ok(text),
+        (code, text) => Err(format!("{} {}", code, text)),
+    }
+}
+
+// body of the mail with lines starting with a dot escaped as the DATA command requires
+fn dot_stuffed(body: &str) -> String {
+    body.lines()
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}\r\n", line)
+            } else {
+                format!("{}\r\n", line)
+            }
+        })
+        .collect()
+}
+
+/// Notifier sending alerts as plain text mails through an SMTP server.
+pub struct EmailNotifier {
+    server: String,
+    port: u16,
+    security: SmtpSecurity,
+    credentials: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+    device_id: String,
+    timeout: Duration,
+    tls_config: Arc<rustls::ClientConfig>,
+}
+
+impl EmailNotifier {
+    // wraps the connection into TLS, verifying the certificate of the server against the
+    //  Mozilla trust roots
+    fn tls(&self, stream: TcpStream) -> Result<Box<dyn Stream>, String> {
+        trace!("in tls");
+        let name = rustls::ServerName::try_from(self.server.as_str())
+            .map_err(|error| error.to_string())?;
+        let connection = rustls::ClientConnection::new(self.tls_config.clone(), name)
+            .map_err(|error| error.to_string())?;
+        Ok(Box::new(rustls::StreamOwned::new(connection, stream)))
+    }
+
+    // greets the server, introducing the gateway by its device id
+    fn ehlo(&self, stream: &mut dyn Stream) -> Result<String, String> {
+        command(stream, &format!("EHLO {}", self.device_id), 250)
+    }
+
+    fn message(&self, alert: &Alert) -> String {
+        let subject = format!("[{}] {}", self.device_id, alert.summary());
+        let body = format!(
+            "{}\n\nGateway: {}\nTag: {}\nMetric: {}\nValue: {}\nThreshold: {}\nTime: {}\n",
+            alert.summary(),
+            self.device_id,
+            alert.address,
+            alert.metric,
+            alert.value,
+            alert.threshold,
+            alert.timestamp.to_rfc3339()
+        );
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
+            self.from,
+            self.to.join(", "),
+            subject,
+            chrono::Utc::now().to_rfc2822(),
+            dot_stuffed(&body)
+        )
+    }
+
+    fn send(&self, alert: &Alert) -> Result<(), String> {
+        trace!("in send");
+        let address = match (self.server.as_str(), self.port).to_socket_addrs() {
+            Ok(mut addresses) => match addresses.next() {
+                Some(address) => address,
+                None => return Err(format!("unable to resolve {}", self.server)),
+            },
+            Err(error) => return Err(error.to_string()),
+        };
+        let tcp = TcpStream::connect_timeout(&address, self.timeout)
+            .and_then(|tcp| {
+                tcp.set_read_timeout(Some(self.timeout))?;
+                tcp.set_write_timeout(Some(self.timeout))?;
+                Ok(tcp)
+            })
+            .map_err(|error| error.to_string())?;
+        // the connection is kept for upgrading it to TLS with STARTTLS
+        let plain = tcp.try_clone().map_err(|error| error.to_string())?;
+        let mut stream: Box<dyn Stream> = match self.security {
+            SmtpSecurity::TLS => self.tls(plain)?,
+            _ => Box::new(plain),
+        };
+        expect(&mut *stream, 220)?;
+        let mut extensions = self.ehlo(&mut *stream)?;
+        if self.security == SmtpSecurity::STARTTLS {
+            if !extensions.contains("STARTTLS") {
+                return Err("server does not support STARTTLS".to_string());
+            }
+            command(&mut *stream, "STARTTLS", 220)?;
+            stream = self.tls(tcp)?;
+            extensions = self.ehlo(&mut *stream)?;
+        }
+        if let Some((username, password)) = &self.credentials {
+            if !extensions.contains("AUTH") {
+                return Err("server does not support authentication".to_string());
+            }
+            let token = base64::encode(format!("\0{}\0{}", username, password));
+            command(&mut *stream, &format!("AUTH PLAIN {}", token), 235)?;
+        }
+        command(&mut *stream, &format!("MAIL FROM:<{}>", self.from), 250)?;
+        for to in &self.to {
+            command(&mut *stream, &format!("RCPT TO:<{}>", to), 250)?;
+        }
+        command(&mut *stream, "DATA", 354)?;
+        command(&mut *stream, &format!("{}.", self.message(alert)), 250)?;
+        // the mail is accepted already, so a failing QUIT does not matter
+        command(&mut *stream, "QUIT", 221).ok();
+        Ok(())
+    }
+
+    pub fn build(config: &EmailConfig, device_id: &str) -> Result<EmailNotifier, Report> {
+        trace!("in build");
+        if config.to().is_empty() {
+            return Err(eyre!("No recipients given for email alerts"));
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(EmailNotifier {
+            server: config.server(),
+            port: config.port(),
+            security: config.security(),
+            credentials: config
+                .username()
+                .map(|username| (username, config.password().unwrap_or_default())),
+            from: config.from(),
+            to: config.to(),
+            device_id: device_id.to_string(),
+            timeout: Duration::from_secs(config.timeout()),
+            tls_config: Arc::new(tls_config),
+        })
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> String {
+        format!("SMTP server {}:{}", self.server, self.port)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report> {
+        trace!("in notify");
+        self.send(alert).map_err(|reason| {
+            let recipients = self.to.join(", ");
+            eyre!("Unable to send alert as email")
+                .with_section(move || recipients.header("Recipients:"))
+                .with_section(move || reason.header("Reason:"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailNotifier;
+    use crate::alerts::{Alerts, Notifier};
+    use crate::configfile::{AlertsConfig, EmailConfig};
+    use crate::mock::{beacon, serve_lines};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn alerts_are_mailed() {
+        let (address, server) = serve_lines(1, |reader| {
+            let mut session = Vec::new();
+            let reply = |reader: &mut BufReader<TcpStream>, text: &str| {
+                write!(reader.get_mut(), "{}\r\n", text).unwrap();
+            };
+            reply(reader, "220 mail.local ESMTP");
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                session.push(line.trim_end().to_string());
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        reply(reader, "250 queued");
+                    }
+                    continue;
+                }
+                match line.split(' ').next().unwrap().trim_end() {
+                    "EHLO" => reply(reader, "250-mail.local\r\n250-AUTH PLAIN\r\n250 8BITMIME"),
+                    "AUTH" => reply(reader, "235 ok"),
+                    "DATA" => {
+                        in_data = true;
+                        reply(reader, "354 go ahead");
+                    }
+                    "QUIT" => {
+                        reply(reader, "221 bye");
+                        break;
+                    }
+                    _ => reply(reader, "250 ok"),
+                }
+            }
+            session
+        });
+
+        let config: EmailConfig = serde_json::from_value(json!({
+            "server": "127.0.0.1",
+            "port": address.port(),
+            "security": "none",
+            "username": "gateway",
+            "password": "secret",
+            "from": "gateway@example.com",
+            "to": ["ops@example.com", "oncall@example.com"],
+        }))
+        .unwrap();
+        let mut notifier = EmailNotifier::build(&config, "test-gateway").unwrap();
+        let alerts_config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [{ "metric": "temperature", "operator": "below", "threshold": 25.0 }],
+        }))
+        .unwrap();
+        let alert = Alerts::build(&alerts_config, "test-gateway")
+            .unwrap()
+            .check(&beacon("AA:BB:CC:DD:EE:01", 1))
+            .remove(0);
+        notifier.notify(&alert).unwrap();
+
+        let session = server.join().unwrap().remove(0);
+        assert_eq!(session[0], "EHLO test-gateway");
+        assert_eq!(
+            session[1],
+            format!("AUTH PLAIN {}", base64::encode("\0gateway\0secret"))
+        );
+        assert_eq!(session[2], "MAIL FROM:<gateway@example.com>");
+        assert_eq!(session[3], "RCPT TO:<ops@example.com>");
+        assert_eq!(session[4], "RCPT TO:<oncall@example.com>");
+        assert_eq!(session[5], "DATA");
+        assert!(session.contains(
+            &"Subject: [test-gateway] Alert triggered: temperature of AA:BB:CC:DD:EE:01 is 21.5, below threshold 25"
+                .to_string()
+        ));
+        assert!(session.contains(&"Tag: AA:BB:CC:DD:EE:01".to_string()));
+        assert_eq!(session[session.len() - 2], ".");
+        assert_eq!(session[session.len() - 1], "QUIT");
+
+        // the server is gone
+        assert!(notifier.notify(&alert).is_err());
+    }
+}
+
+// eof
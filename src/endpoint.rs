@@ -0,0 +1,176 @@
+use crossbeam::channel;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use crate::configfile::Transport;
+
+// delay between connection attempts to the addresses of an endpoint (RFC 8305)
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// endpoints not reached within this time are considered unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Broker endpoint given as a host name or an IP address and an optional port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    /// Parses "host", "host:port", "[IPv6 address]" or "[IPv6 address]:port", a bare IPv6
+    /// address is taken without port
+    pub fn parse(endpoint: &str, default_port: u16) -> Option<Endpoint> {
+        let (host, port) = if let Some(bracketed) = endpoint.strip_prefix('[') {
+            let (host, rest) = bracketed.split_once(']')?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => port.parse().ok()?,
+                None if rest.is_empty() => default_port,
+                None => return None,
+            };
+            (host, port)
+        } else if endpoint.matches(':').count() == 1 {
+            let (host, port) = endpoint.split_once(':')?;
+            (host, port.parse().ok()?)
+        } else {
+            (endpoint, default_port)
+        };
+        if host.is_empty() {
+            return None;
+        }
+        Some(Endpoint {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// URI of the endpoint for the MQTT client
+    pub fn uri(&self, transport: Transport) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match transport {
+            Transport::TCP => format!("ssl://{}:{}", host, self.port),
+            Transport::WEBSOCKET => format!("wss://{}:{}/mqtt", host, self.port),
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// addresses in the order they are attempted: IPv6 and IPv4 interleaved, IPv6 first
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut ipv6, mut ipv4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addresses.into_iter().partition(|address| address.is_ipv6());
+    ipv6.reverse();
+    ipv4.reverse();
+    let mut interleaved = Vec::new();
+    while !ipv6.is_empty() || !ipv4.is_empty() {
+        interleaved.extend(ipv6.pop());
+        interleaved.extend(ipv4.pop());
+    }
+    interleaved
+}
+
+// connects to the addresses of the endpoint happy eyeballs style, starting a new attempt every
+//  250 ms until one of them succeeds. returns the address that was reached first
+fn probe(endpoint: &Endpoint) -> Option<SocketAddr> {
+    trace!("in probe");
+    let addresses = match (endpoint.host.as_str(), endpoint.port).to_socket_addrs() {
+        Ok(addresses) => interleave(addresses.collect()),
+        Err(error) => {
+            warn!("Unable to resolve endpoint {}: {}", endpoint, error);
+            return None;
+        }
+    };
+    let (sender, receiver) = channel::unbounded();
+    for (attempt, address) in addresses.into_iter().enumerate() {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            thread::sleep(ATTEMPT_DELAY * attempt as u32);
+            if TcpStream::connect_timeout(&address, PROBE_TIMEOUT).is_ok() {
+                sender.send(address).ok();
+            }
+        });
+    }
+    // stop waiting as soon as all attempts have failed
+    drop(sender);
+    receiver.recv_timeout(PROBE_TIMEOUT).ok()
+}
+
+// endpoints reachable over IPv6 or IPv4 first, otherwise in the configured order, so that the
+//  MQTT client does not spend its connect timeout on an unreachable endpoint first
+pub fn order_by_reachability(endpoints: &[Endpoint]) -> Vec<Endpoint> {
+    trace!("in order_by_reachability");
+    let probes: Vec<thread::JoinHandle<Option<SocketAddr>>> = endpoints
+        .iter()
+        .cloned()
+        .map(|endpoint| thread::spawn(move || probe(&endpoint)))
+        .collect();
+    let (mut reachable, mut unreachable) = (Vec::new(), Vec::new());
+    for (endpoint, probe) in endpoints.iter().zip(probes) {
+        match probe.join().ok().flatten() {
+            Some(address) => {
+                debug!("endpoint {} reachable at {}", endpoint, address);
+                reachable.push(endpoint.clone());
+            }
+            None => {
+                warn!("Endpoint {} is unreachable.", endpoint);
+                unreachable.push(endpoint.clone());
+            }
+        }
+    }
+    reachable.extend(unreachable);
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{order_by_reachability, Endpoint};
+    use crate::configfile::Transport;
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_endpoints() {
+        let endpoint = Endpoint::parse("mqtt.2030.ltsapis.goog", 8883).unwrap();
+        assert_eq!(
+            endpoint.uri(Transport::TCP),
+            "ssl://mqtt.2030.ltsapis.goog:8883"
+        );
+        let endpoint = Endpoint::parse("mqtt.googleapis.com:443", 8883).unwrap();
+        assert_eq!(endpoint.port, 443);
+        let endpoint = Endpoint::parse("[2001:db8::1]:443", 8883).unwrap();
+        assert_eq!(endpoint.host, "2001:db8::1");
+        assert_eq!(
+            endpoint.uri(Transport::WEBSOCKET),
+            "wss://[2001:db8::1]:443/mqtt"
+        );
+        assert_eq!(Endpoint::parse("2001:db8::1", 8883).unwrap().port, 8883);
+        assert!(Endpoint::parse("host:port", 8883).is_none());
+        assert!(Endpoint::parse("[2001:db8::1", 8883).is_none());
+    }
+
+    #[test]
+    fn unreachable_endpoints_last() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable = Endpoint::parse(&listener.local_addr().unwrap().to_string(), 0).unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = Endpoint::parse(&closed.local_addr().unwrap().to_string(), 0).unwrap();
+        drop(closed);
+
+        let ordered = order_by_reachability(&[unreachable.clone(), reachable.clone()]);
+        assert_eq!(ordered, vec![reachable, unreachable]);
+    }
+}
+
+// eof
@@ -0,0 +1,204 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::configfile::AppConfig;
+use crate::publisher;
+
+/// File in the working directory that keeps a fatal error that could not be published, until it
+/// is published with the startup report of the next run.
+pub const FATAL_ERROR_FILE: &str = "fatal_error.json";
+
+/// Category of a failure that stops the gateway, published to the state topic so that
+/// automation in the cloud can tell failures apart without parsing log text.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    BT_ADAPTER_MISSING,
+    BT_PERMISSION_DENIED,
+    CONFIG_INVALID,
+    JWT_SIGN_FAIL,
+    MQTT_AUTH_REJECTED,
+    MQTT_UNREACHABLE,
+    INTERNAL,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+// message of an error tagged with its code. displays as the message alone so that tagging an
+//  error does not change how it is logged
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ErrorCode {
+    /// Error with the message, tagged with the code. Used in place of `eyre!` where the failure
+    /// category is known.
+    pub fn report(self, message: &str) -> Report {
+        Report::msg(CodedError {
+            code: self,
+            message: message.to_string(),
+        })
+    }
+}
+
+/// Code the error was tagged with, if any.
+pub fn code_of(error: &Report) -> Option<ErrorCode> {
+    error
+        .downcast_ref::<CodedError>()
+        .map(|coded_error| coded_error.code)
+}
+
+/// Fatal error as published to the state topic.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FatalError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl FatalError {
+    pub fn build(error: &Report, default_code: ErrorCode) -> FatalError {
+        FatalError {
+            code: code_of(error).unwrap_or(default_code),
+            message: error.to_string(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    pub fn persist(&self, file: &Path) -> Result<(), Report> {
+        trace!("in persist");
+        match fs::write(file, serde_json::to_string_pretty(self).unwrap()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Unable to save fatal error")
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+
+    /// Fatal error of an earlier run that has not been published yet.
+    pub fn persisted(file: &Path) -> Option<FatalError> {
+        trace!("in persisted");
+        let content = fs::read_to_string(file).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(fatal_error) => Some(fatal_error),
+            Err(error) => {
+                warn!(
+                    "Ignoring unreadable fatal error in {}: {}",
+                    file.display(),
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    pub fn clear(file: &Path) {
+        trace!("in clear");
+        if let Err(error) = fs::remove_file(file) {
+            warn!("Unable to remove {}: {}", file.display(), error);
+        }
+    }
+
+    // report of the failure published to the state topic
+    pub fn report(&self) -> serde_json::Value {
+        json!({ "fatal_error": self })
+    }
+}
+
+// connects to IoT Core just to publish the fatal error to the state topic of the gateway
+fn publish(appconfig: &AppConfig, fatal_error: &FatalError) -> Result<(), Report> {
+    trace!("in publish");
+    let mut client = publisher::build(appconfig)?;
+    client.connect()?;
+    let result = client.publish(
+        &format!("/devices/{}/state", appconfig.iotcore.device_id),
+        serde_json::to_string(&fatal_error.report())
+            .unwrap()
+            .as_bytes(),
+    );
+    client.disconnect().ok();
+    result
+}
+
+/// Classifies an error that stops the gateway and publishes it to the state topic before the
+/// gateway exits. Without configuration, or when IoT Core cannot be reached, the error is saved
+/// to be published with the startup report of the next run. Returns the error to be returned
+/// from main.
+pub fn fatal(appconfig: Option<&AppConfig>, default_code: ErrorCode, error: Report) -> Report {
+    trace!("in fatal");
+    let fatal_error = FatalError::build(&error, default_code);
+    error!("Fatal error {}: {}", fatal_error.code, fatal_error.message);
+    let published = match appconfig {
+        // there is no state topic to publish to in local mode
+        Some(appconfig) if appconfig.local.enabled() => false,
+        Some(appconfig) => match publish(appconfig, &fatal_error) {
+            Ok(_) => {
+                info!("Published fatal error to state topic.");
+                true
+            }
+            Err(publish_error) => {
+                warn!("Unable to publish fatal error: {}", publish_error);
+                false
+            }
+        },
+        None => false,
+    };
+    if !published {
+        if let Err(persist_error) = fatal_error.persist(Path::new(FATAL_ERROR_FILE)) {
+            warn!("{}", persist_error);
+        }
+    }
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code_of, ErrorCode, FatalError};
+    use crate::mock::temp_path;
+    use color_eyre::{eyre::eyre, Section, SectionExt};
+
+    #[test]
+    fn errors_are_classified() {
+        let error = ErrorCode::JWT_SIGN_FAIL
+            .report("Unable to issue original JWT token")
+            .with_section(|| "bad key".to_string().header("Reason:"));
+        assert_eq!(code_of(&error), Some(ErrorCode::JWT_SIGN_FAIL));
+        assert_eq!(error.to_string(), "Unable to issue original JWT token");
+        assert_eq!(code_of(&eyre!("Unable to start logging")), None);
+
+        let fatal_error = FatalError::build(&eyre!("Invalid option"), ErrorCode::CONFIG_INVALID);
+        assert_eq!(fatal_error.code, ErrorCode::CONFIG_INVALID);
+        let report = fatal_error.report();
+        assert_eq!(report["fatal_error"]["code"], "CONFIG_INVALID");
+        assert_eq!(report["fatal_error"]["message"], "Invalid option");
+    }
+
+    #[test]
+    fn unpublished_error_is_kept_for_next_run() {
+        let file = temp_path("fatal_error.json");
+        let fatal_error = FatalError::build(
+            &ErrorCode::MQTT_AUTH_REJECTED.report("Error while connecting to IoT core service"),
+            ErrorCode::INTERNAL,
+        );
+        fatal_error.persist(&file).unwrap();
+        assert_eq!(FatalError::persisted(&file), Some(fatal_error));
+        FatalError::clear(&file);
+        assert_eq!(FatalError::persisted(&file), None);
+    }
+}
+
+// eof
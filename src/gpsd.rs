@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configfile::GpsdConfig;
+
+// gpsd reports at least once a second while it has a GPS receiver, so a silent connection is
+//  considered broken after this
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Position of the gateway when a beacon was received, as reported by gpsd.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Altitude above mean sea level in meters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+    /// Speed over ground in meters per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+    /// Course over ground in degrees from true north
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<f64>,
+    /// Time of the fix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// report of gpsd, of which only time-position-velocity reports are of interest. newer gpsd
+//  versions report altitude above mean sea level as altMSL instead of alt
+#[derive(Debug, Deserialize)]
+struct TpvReport {
+    class: String,
+    #[serde(default)]
+    mode: u8,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    alt: Option<f64>,
+    #[serde(rename = "altMSL")]
+    alt_msl: Option<f64>,
+    speed: Option<f64>,
+    track: Option<f64>,
+    time: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl TpvReport {
+    // position of a report with a 2D or 3D fix
+    fn position(&self) -> Option<Position> {
+        if self.mode < 2 {
+            return None;
+        }
+        Some(Position {
+            latitude: self.lat?,
+            longitude: self.lon?,
+            altitude: self.alt_msl.or(self.alt),
+            speed: self.speed,
+            track: self.track,
+            time: self.time,
+        })
+    }
+}
+
+type SharedPosition = Arc<Mutex<Option<(Instant, Position)>>>;
+
+// follows the reports of gpsd until the connection breaks
+fn watch(address: &str, position: &SharedPosition) -> Result<(), String> {
+    trace!("in watch");
+    let mut stream = TcpStream::connect(address).map_err(|error| error.to_string())?;
+    stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .and_then(|_| stream.write_all(b"?WATCH={\"enable\":true,\"json\":true};\n"))
+        .map_err(|error| error.to_string())?;
+    debug!("watching reports of gpsd at {}", address);
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|error| error.to_string())?;
+        let report: TpvReport = match serde_json::from_str(&line) {
+            Ok(report) => report,
+            Err(error) => {
+                debug!("ignoring unexpected report from gpsd: {}", error);
+                continue;
+            }
+        };
+        if report.class != "TPV" {
+            continue;
+        }
+        // a lost fix is reported with mode 1, after which beacons are left without position
+        *position.lock().unwrap() = report.position().map(|current| (Instant::now(), current));
+    }
+    Err("gpsd closed the connection".to_string())
+}
+
+/// Position of a mobile gateway followed from gpsd in a thread of its own, attached to received
+/// beacons.
+pub struct Gps {
+    position: SharedPosition,
+    max_age: Duration,
+}
+
+impl Gps {
+    /// Latest position, None without a fix or if gpsd has not reported one within the maximum
+    /// age
+    pub fn position(&self) -> Option<Position> {
+        match &*self.position.lock().unwrap() {
+            Some((reported, position)) if reported.elapsed() <= self.max_age => {
+                Some(position.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn start(config: &GpsdConfig) -> Option<Gps> {
+        trace!("in start");
+        let address = format!("{}:{}", config.host()?, config.port());
+        info!("Attaching positions from gpsd at {} to beacons", address);
+        let position: SharedPosition = Arc::new(Mutex::new(None));
+        let shared = position.clone();
+        // gps thread stops when the position is no longer needed
+        thread::spawn(move || {
+            while Arc::strong_count(&shared) > 1 {
+                if let Err(reason) = watch(&address, &shared) {
+                    warn!("Unable to receive position from gpsd: {}", reason);
+                }
+                *shared.lock().unwrap() = None;
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+        Some(Gps {
+            position,
+            max_age: Duration::from_secs(config.max_age()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gps, Position};
+    use crate::configfile::GpsdConfig;
+    use crate::mock::serve_lines;
+    use std::io::{BufRead, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn position_is_followed_from_gpsd() {
+        let (fix_sender, fix_receiver) = crossbeam::channel::unbounded::<()>();
+        let (address, _) = serve_lines(1, move |reader| {
+            let mut watch = String::new();
+            reader.read_line(&mut watch).unwrap();
+            assert!(watch.starts_with("?WATCH="));
+            let stream = reader.get_mut();
+            writeln!(stream, r#"{{"class":"VERSION","release":"3.22"}}"#).unwrap();
+            writeln!(
+                stream,
+                r#"{{"class":"TPV","mode":3,"time":"2021-06-01T12:00:00.000Z","lat":60.17,"lon":24.94,"altHAE":35.2,"altMSL":17.1,"speed":13.9,"track":92.5}}"#
+            )
+            .unwrap();
+            // lost fix once the test has seen the position
+            fix_receiver.recv().unwrap();
+            writeln!(stream, r#"{{"class":"TPV","mode":1}}"#).unwrap();
+            fix_receiver.recv().ok();
+        });
+
+        let config: GpsdConfig =
+            serde_json::from_value(json!({ "host": "127.0.0.1", "port": address.port() })).unwrap();
+        let gps = Gps::start(&config).unwrap();
+        let wait_for_position = |expected: bool| {
+            let started = Instant::now();
+            while gps.position().is_some() != expected {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_for_position(true);
+        assert_eq!(
+            gps.position().unwrap(),
+            serde_json::from_value::<Position>(json!({
+                "latitude": 60.17,
+                "longitude": 24.94,
+                "altitude": 17.1,
+                "speed": 13.9,
+                "track": 92.5,
+                "time": "2021-06-01T12:00:00Z",
+            }))
+            .unwrap()
+        );
+        fix_sender.send(()).unwrap();
+        wait_for_position(false);
+
+        let config: GpsdConfig = serde_json::from_value(json!({})).unwrap();
+        assert!(Gps::start(&config).is_none());
+    }
+}
+
+// eof
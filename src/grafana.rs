@@ -0,0 +1,170 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::time::Duration;
+
+use crate::configfile::SinkConfig;
+use crate::scanner::{RuuviBluetoothBeacon, SharedBeacon};
+use crate::sinks::Sink;
+
+// measurement of beacons in the line protocol, the last part of the Grafana Live channel
+const MEASUREMENT: &str = "ruuvi";
+
+// escapes commas, equal signs and spaces of a tag key or value of the line protocol
+fn escape(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// numeric fields of decoded beacon data, names of nested fields joined with underscores, e.g.
+//  acceleration_on_x_axis
+fn fields(prefix: &str, value: &serde_json::Value, fields: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}_{}", prefix, key)
+                };
+                self::fields(&name, value, fields);
+            }
+        }
+        serde_json::Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                fields.push(format!("{}={}", escape(prefix), number));
+            }
+        }
+        _ => {}
+    }
+}
+
+// line of the beacon in the InfluxDB line protocol, None for beacons without numeric data
+fn line(beacon: &RuuviBluetoothBeacon, device_id: &str) -> Option<String> {
+    let mut data_fields = Vec::new();
+    fields("", beacon.data.as_ref()?, &mut data_fields);
+    if data_fields.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{},address={},gateway={} {} {}",
+        MEASUREMENT,
+        escape(&beacon.address),
+        escape(device_id),
+        data_fields.join(","),
+        beacon.timestamp.timestamp_millis() * 1_000_000
+    ))
+}
+
+/// Sink pushing beacons into a Grafana Live stream, so that dashboards show them as they are
+/// received without a database in between.
+pub struct GrafanaSink {
+    url: String,
+    channel: String,
+    token: Option<String>,
+    device_id: String,
+    agent: ureq::Agent,
+}
+
+impl GrafanaSink {
+    pub fn build(config: &SinkConfig, device_id: &str) -> Result<GrafanaSink, Report> {
+        trace!("in build");
+        let url = match config.url() {
+            Some(url) => url,
+            None => return Err(eyre!("No Grafana URL given for Grafana sink")),
+        };
+        let stream_id = config.stream_id();
+        Ok(GrafanaSink {
+            url: format!("{}/api/live/push/{}", url.trim_end_matches('/'), stream_id),
+            channel: format!("stream/{}/{}", stream_id, MEASUREMENT),
+            token: config.bearer_token(),
+            device_id: device_id.to_string(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout()))
+                .build(),
+        })
+    }
+}
+
+impl Sink for GrafanaSink {
+    fn name(&self) -> String {
+        format!("Grafana Live channel {}", self.channel)
+    }
+
+    fn deliver(&mut self, beacons: &[SharedBeacon]) -> Result<(), Report> {
+        trace!("in deliver");
+        let lines: Vec<String> = beacons
+            .iter()
+            .filter_map(|beacon| line(beacon, &self.device_id))
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut request = self.agent.post(&self.url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        match request.send_string(&lines.join("\n")) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                let channel = self.channel.clone();
+                Err(eyre!("Unable to push beacons to Grafana Live")
+                    .with_section(move || channel.header("Channel:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line, GrafanaSink};
+    use crate::configfile::SinkConfig;
+    use crate::mock::{beacon, serve, shared_beacon};
+    use crate::sinks::Sink;
+    use std::sync::Arc;
+
+    #[test]
+    fn beacons_are_pushed_to_grafana_live() {
+        let mut tag = beacon("AA:BB:CC:DD:EE:01", 1);
+        tag.data.as_mut().unwrap()["acceleration"] = json!({ "on_x_axis": -4 });
+        let timestamp = tag.timestamp.timestamp_millis() * 1_000_000;
+        assert_eq!(
+            line(&tag, "test gateway").unwrap(),
+            format!(
+                "ruuvi,address=AA:BB:CC:DD:EE:01,gateway=test\\ gateway acceleration_on_x_axis=-4,humidity=45,measurement_sequence_number=1,temperature=21.5,tx_power=4 {}",
+                timestamp
+            )
+        );
+        let mut unknown = beacon("AA:BB:CC:DD:EE:02", 2);
+        unknown.data = None;
+        assert!(line(&unknown, "test-gateway").is_none());
+
+        let (url, server) = serve(vec![(200, String::new())]);
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "grafana",
+            "url": format!("{}/", url),
+            "bearer_token": "glsa_secret",
+            "stream_id": "greenhouse",
+        }))
+        .unwrap();
+        let mut sink = GrafanaSink::build(&config, "test-gateway").unwrap();
+        assert_eq!(sink.name(), "Grafana Live channel stream/greenhouse/ruuvi");
+        sink.deliver(&[
+            Arc::new(tag),
+            Arc::new(unknown),
+            shared_beacon("AA:BB:CC:DD:EE:03", 3),
+        ])
+        .unwrap();
+        let request = server.recv().unwrap();
+        assert_eq!(request.line, "POST /api/live/push/greenhouse HTTP/1.1");
+        assert!(request
+            .headers
+            .contains("Authorization: Bearer glsa_secret\r\n"));
+        let lines: Vec<&str> = request.body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("ruuvi,address=AA:BB:CC:DD:EE:03,gateway=test-gateway "));
+    }
+}
+
+// eof
@@ -0,0 +1,172 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::fs;
+use std::path::Path;
+use std::{thread, time};
+
+use crate::fatal::ErrorCode;
+use crate::iotcore::HardResetMode;
+
+const BTPROTO_HCI: libc::c_int = 1;
+// _IOW('H', 203, int) from bluez hci.h
+const HCIDEVRESET: libc::c_ulong = 0x400448cb;
+// capabilities needed to scan through HCI sockets and their bits in the capability sets
+const CAPABILITIES: [(&str, u32); 2] = [("CAP_NET_ADMIN", 12), ("CAP_NET_RAW", 13)];
+
+/// Directive of a systemd service unit granting the capabilities needed for scanning.
+pub const SYSTEMD_CAPABILITIES: &str = "AmbientCapabilities=CAP_NET_RAW CAP_NET_ADMIN";
+
+fn device_id(adapter_name: &str) -> Result<u16, Report> {
+    match adapter_name
+        .strip_prefix("hci")
+        .and_then(|id| id.parse::<u16>().ok())
+    {
+        Some(id) => Ok(id),
+        None => {
+            let adapter_name = adapter_name.to_string();
+            Err(
+                eyre!("Unable to determine HCI device id of Bluetooth adapter")
+                    .with_section(move || adapter_name.header("Adapter:")),
+            )
+        }
+    }
+}
+
+// equivalent of "hciconfig hciX reset"
+fn hci_reset(adapter_name: &str) -> Result<(), Report> {
+    trace!("in hci_reset");
+    let dev_id = device_id(adapter_name)?;
+    let socket = unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            BTPROTO_HCI,
+        )
+    };
+    if socket < 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(eyre!("Unable to open HCI socket")
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+    let result = unsafe { libc::ioctl(socket, HCIDEVRESET as _, dev_id as libc::c_ulong) };
+    let error = std::io::Error::last_os_error();
+    unsafe { libc::close(socket) };
+    if result < 0 {
+        return Err(eyre!("Unable to reset HCI device")
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+    Ok(())
+}
+
+fn write_rfkill_state(rfkill: &Path, blocked: bool) -> Result<(), Report> {
+    let state = rfkill.join("soft");
+    match fs::write(&state, if blocked { "1" } else { "0" }) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(eyre!("Unable to change rfkill state of Bluetooth adapter")
+            .with_section(move || state.display().to_string().header("Path:"))
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+// equivalent of "rfkill block" followed by "rfkill unblock" for the adapter. power cycles the
+//  radio which also recovers from firmware hangs that HCI reset does not
+fn rfkill_reset(adapter_name: &str) -> Result<(), Report> {
+    trace!("in rfkill_reset");
+    let adapter = Path::new("/sys/class/bluetooth").join(adapter_name);
+    let rfkill = match fs::read_dir(&adapter).ok().and_then(|entries| {
+        entries
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("rfkill"))
+    }) {
+        Some(entry) => entry.path(),
+        None => {
+            return Err(eyre!("No rfkill switch found for Bluetooth adapter")
+                .with_section(move || adapter.display().to_string().header("Adapter:")))
+        }
+    };
+    write_rfkill_state(&rfkill, true)?;
+    thread::sleep(time::Duration::from_secs(1));
+    write_rfkill_state(&rfkill, false)?;
+    // give kernel time to bring the adapter back before it is reserved again
+    thread::sleep(time::Duration::from_secs(2));
+    Ok(())
+}
+
+// capabilities missing from the effective set in /proc/<pid>/status
+fn missing_from(status: &str) -> Vec<&'static str> {
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|effective| u64::from_str_radix(effective.trim(), 16).ok());
+    match effective {
+        Some(effective) => CAPABILITIES
+            .iter()
+            .filter(|(_, bit)| effective & (1 << bit) == 0)
+            .map(|(capability, _)| *capability)
+            .collect(),
+        // capabilities are not known outside of Linux, scanning tells
+        None => Vec::new(),
+    }
+}
+
+/// Capabilities needed for scanning that the process lacks. Root has all of them.
+pub fn missing_capabilities() -> Vec<&'static str> {
+    trace!("in missing_capabilities");
+    missing_from(&fs::read_to_string("/proc/self/status").unwrap_or_default())
+}
+
+/// Command granting the capabilities needed for scanning to the running binary.
+pub fn setcap_command() -> String {
+    let binary = match std::env::current_exe() {
+        Ok(binary) => binary.display().to_string(),
+        Err(_) => env!("CARGO_PKG_NAME").to_string(),
+    };
+    format!("sudo setcap 'cap_net_raw,cap_net_admin+eip' {}", binary)
+}
+
+/// Fails with the missing capabilities and how to grant them when scanning is not permitted,
+/// instead of the adapter errors scanning would fail with.
+pub fn check_capabilities() -> Result<(), Report> {
+    trace!("in check_capabilities");
+    let missing = missing_capabilities();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let missing = missing.join(", ");
+    Err(ErrorCode::BT_PERMISSION_DENIED
+        .report("Bluetooth scanning is not permitted")
+        .with_section(move || missing.header("Missing capabilities:"))
+        .with_section(|| setcap_command().header("Grant with:"))
+        .with_section(|| SYSTEMD_CAPABILITIES.header("Or in systemd unit:")))
+}
+
+// deeper reset of the adapter than the down/up done when it is reserved. requires root or
+//  CAP_NET_ADMIN
+pub fn hard_reset(mode: HardResetMode, adapter_name: &str) -> Result<(), Report> {
+    trace!("in hard_reset");
+    match mode {
+        HardResetMode::HCI => hci_reset(adapter_name),
+        HardResetMode::RFKILL => rfkill_reset(adapter_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::missing_from;
+
+    #[test]
+    fn missing_capabilities_are_found() {
+        let status = "Name:\truuvi2iotcore\nCapInh:\t0000000000000000\nCapEff:\t%s\n";
+        assert!(missing_from(&status.replace("%s", "000001ffffffffff")).is_empty());
+        assert_eq!(
+            missing_from(&status.replace("%s", "0000000000001000")),
+            vec!["CAP_NET_RAW"]
+        );
+        assert_eq!(
+            missing_from(&status.replace("%s", "0000000000000000")),
+            vec!["CAP_NET_ADMIN", "CAP_NET_RAW"]
+        );
+        assert!(missing_from("").is_empty());
+    }
+}
+
+// eof
@@ -0,0 +1,157 @@
+use btleplug::api::{Characteristic, Peripheral, UUID};
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Nordic UART service characteristics used by Ruuvi firmware for reading logged measurements
+//  https://docs.ruuvi.com/communication/bluetooth-connection/nordic-uart-service-nus
+const NUS_RX_CHARACTERISTIC: &str = "6E:40:00:02:B5:A3:F3:93:E0:A9:E5:0E:24:DC:CA:9E";
+const NUS_TX_CHARACTERISTIC: &str = "6E:40:00:03:B5:A3:F3:93:E0:A9:E5:0E:24:DC:CA:9E";
+
+const LOG_ENVIRONMENTAL: u8 = 0x3a;
+const LOG_TEMPERATURE: u8 = 0x30;
+const LOG_HUMIDITY: u8 = 0x31;
+const LOG_PRESSURE: u8 = 0x32;
+const LOG_READ: u8 = 0x11;
+
+// logged measurements are published as beacons decoded by this pseudo decoder
+pub const HISTORY_DECODER: &str = "history";
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct HistoryRecord {
+    pub timestamp: Option<DateTime<Utc>>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub atmospheric_pressure: Option<f32>,
+}
+
+fn find_characteristic(
+    characteristics: &[Characteristic],
+    uuid: &str,
+) -> Result<Characteristic, Report> {
+    let uuid: UUID = uuid.parse().unwrap();
+    match characteristics
+        .iter()
+        .find(|characteristic| characteristic.uuid == uuid)
+    {
+        Some(characteristic) => Ok(characteristic.clone()),
+        None => Err(
+            eyre!("Ruuvi tag does not support reading logged measurements")
+                .with_section(move || uuid.to_string().header("Missing characteristic:")),
+        ),
+    }
+}
+
+// each log entry is received as its own notification: destination (measurement type), source,
+//  operation, timestamp and value. end of log is signaled with all ones in timestamp and value
+fn parse_log_entry(value: &[u8], records: &mut BTreeMap<u32, HistoryRecord>) -> bool {
+    if value.len() < 11 {
+        return false;
+    }
+    let timestamp = u32::from_be_bytes([value[3], value[4], value[5], value[6]]);
+    let measurement = i32::from_be_bytes([value[7], value[8], value[9], value[10]]);
+    if timestamp == u32::MAX {
+        return true;
+    }
+    let record = records.entry(timestamp).or_default();
+    record.timestamp = Some(Utc.timestamp_opt(timestamp as i64, 0).unwrap());
+    match value[0] {
+        LOG_TEMPERATURE => record.temperature = Some(measurement as f32 / 100.0),
+        LOG_HUMIDITY => record.humidity = Some(measurement as f32 / 100.0),
+        LOG_PRESSURE => record.atmospheric_pressure = Some(measurement as f32 / 100.0),
+        _ => debug!("Unknown logged measurement type: {:#04x}", value[0]),
+    }
+    false
+}
+
+// btleplug keeps every notification handler for the lifetime of the peripheral, so the handler
+//  is registered once per peripheral and its receiver is reused for every history sync
+pub fn notifications<P: Peripheral>(peripheral: &P) -> channel::Receiver<Vec<u8>> {
+    trace!("in notifications");
+    let (sender, receiver) = channel::unbounded();
+    peripheral.on_notification(Box::new(move |notification| {
+        sender.send(notification.value).ok();
+    }));
+    receiver
+}
+
+// connects to the tag and downloads measurements logged after since
+pub fn read_history<P: Peripheral>(
+    peripheral: &P,
+    notifications: &channel::Receiver<Vec<u8>>,
+    since: DateTime<Utc>,
+    timeout: Duration,
+) -> Result<Vec<HistoryRecord>, Report> {
+    trace!("in read_history");
+    if let Err(error) = peripheral.connect() {
+        return Err(eyre!("Unable to connect to Ruuvi tag")
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+    let result = download_log(peripheral, notifications, since, timeout);
+    peripheral.disconnect().ok();
+    result
+}
+
+fn download_log<P: Peripheral>(
+    peripheral: &P,
+    receiver: &channel::Receiver<Vec<u8>>,
+    since: DateTime<Utc>,
+    timeout: Duration,
+) -> Result<Vec<HistoryRecord>, Report> {
+    trace!("in download_log");
+    let characteristics = match peripheral.discover_characteristics() {
+        Ok(characteristics) => characteristics,
+        Err(error) => {
+            return Err(eyre!("Unable to discover Ruuvi tag characteristics")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    let rx = find_characteristic(&characteristics, NUS_RX_CHARACTERISTIC)?;
+    let tx = find_characteristic(&characteristics, NUS_TX_CHARACTERISTIC)?;
+
+    // entries left over from a sync that timed out are not part of this log
+    receiver.try_iter().count();
+    if let Err(error) = peripheral.subscribe(&tx) {
+        return Err(eyre!("Unable to subscribe to Ruuvi tag log")
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+
+    let mut command = vec![LOG_ENVIRONMENTAL, LOG_ENVIRONMENTAL, LOG_READ];
+    command.extend_from_slice(&(Utc::now().timestamp() as u32).to_be_bytes());
+    command.extend_from_slice(&(since.timestamp() as u32).to_be_bytes());
+    if let Err(error) = peripheral.request(&rx, &command) {
+        return Err(eyre!("Unable to request Ruuvi tag log")
+            .with_section(move || error.to_string().header("Reason:")));
+    }
+
+    let mut records: BTreeMap<u32, HistoryRecord> = BTreeMap::new();
+    let started = Instant::now();
+    loop {
+        let remaining = match timeout.checked_sub(started.elapsed()) {
+            Some(remaining) => remaining,
+            None => {
+                warn!("Timeout while reading Ruuvi tag log. Log may be incomplete.");
+                break;
+            }
+        };
+        match receiver.recv_timeout(remaining) {
+            Ok(value) => {
+                if parse_log_entry(&value, &mut records) {
+                    break;
+                }
+            }
+            Err(_) => {
+                warn!("Timeout while reading Ruuvi tag log. Log may be incomplete.");
+                break;
+            }
+        }
+    }
+    peripheral.unsubscribe(&tx).ok();
+
+    Ok(records.into_values().collect())
+}
+
+// eof
@@ -0,0 +1,91 @@
+use crate::scanner::RuuviBluetoothBeacon;
+
+/// Outcome of a beacon hook.
+#[derive(Debug, Clone)]
+pub enum BeaconAction {
+    /// Publish the beacon as it is.
+    Publish,
+    /// Publish the given beacon instead, e.g. with modified or enriched data.
+    Replace(RuuviBluetoothBeacon),
+    /// Do not publish the beacon.
+    Drop,
+    /// Publish the beacon to the given subfolder of the events topic of the tag instead of the
+    /// configured one.
+    Redirect(String),
+}
+
+/// Custom processing of beacons before they are published, registered with
+/// [`IotCoreClient::register_hook`](crate::iotcore::IotCoreClient::register_hook).
+pub type BeaconHook = Box<dyn Fn(&RuuviBluetoothBeacon) -> BeaconAction + Send>;
+
+// runs hooks in the order they were registered, each seeing the beacon returned by previous ones.
+//  returns the beacon to publish with the subfolder it was redirected to, if any
+pub fn apply_hooks(
+    hooks: &[BeaconHook],
+    mut beacon: RuuviBluetoothBeacon,
+) -> Option<(RuuviBluetoothBeacon, Option<String>)> {
+    let mut redirect = None;
+    for hook in hooks {
+        match hook(&beacon) {
+            BeaconAction::Publish => {}
+            BeaconAction::Replace(replacement) => beacon = replacement,
+            BeaconAction::Drop => {
+                debug!("Beacon from '{}' dropped by hook", beacon.address);
+                return None;
+            }
+            BeaconAction::Redirect(subfolder) => redirect = Some(subfolder),
+        }
+    }
+    Some((beacon, redirect))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_hooks, BeaconAction, BeaconHook};
+    use crate::mock::beacon;
+
+    #[test]
+    fn no_hooks() {
+        let (published, redirect) = apply_hooks(&[], beacon("AA:BB:CC:DD:EE:01", 1)).unwrap();
+        assert_eq!(published.sequence, 1);
+        assert!(redirect.is_none());
+    }
+
+    #[test]
+    fn hooks_are_chained() {
+        let hooks: Vec<BeaconHook> = vec![
+            Box::new(|beacon| {
+                let mut beacon = beacon.clone();
+                beacon.data.as_mut().unwrap()["room"] = json!("kitchen");
+                BeaconAction::Replace(beacon)
+            }),
+            Box::new(
+                |beacon| match beacon.data.as_ref().unwrap()["room"].as_str() {
+                    Some(room) => BeaconAction::Redirect(room.to_string()),
+                    None => BeaconAction::Publish,
+                },
+            ),
+            Box::new(|_| BeaconAction::Publish),
+        ];
+        let (published, redirect) = apply_hooks(&hooks, beacon("AA:BB:CC:DD:EE:01", 1)).unwrap();
+        assert_eq!(published.data.unwrap()["room"], "kitchen");
+        assert_eq!(redirect.as_deref(), Some("kitchen"));
+    }
+
+    #[test]
+    fn dropped_beacon() {
+        let hooks: Vec<BeaconHook> = vec![
+            Box::new(|beacon| {
+                if beacon.sequence % 2 == 0 {
+                    BeaconAction::Drop
+                } else {
+                    BeaconAction::Publish
+                }
+            }),
+            Box::new(|_| panic!("hooks after a dropping one are not called")),
+        ];
+        assert!(apply_hooks(&hooks, beacon("AA:BB:CC:DD:EE:01", 2)).is_none());
+    }
+}
+
+// eof
@@ -0,0 +1,1330 @@
+// exercises IoT Core client and scanner together with mock broker and scanner, without Bluetooth
+//  hardware or network
+use crossbeam::channel;
+use crossbeam::thread;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::broadcast::Broadcast;
+use crate::configfile::AppConfig;
+use crate::hooks::{BeaconAction, BeaconHook};
+use crate::iotcore::{payload_hash, CollectConfig, IotCoreClient};
+use crate::metrics::Metrics;
+use crate::mock::{beacon, temp_path, MockBroker, MockPublisher, MockScanner, SharedBroker};
+use crate::publisher::IncomingMessage;
+use crate::scanner::{RuuviBluetoothBeacon, Scanner};
+use crate::status::SharedStatus;
+
+const CONFIG_TOPIC: &str = "/devices/test-gateway/config";
+const STATE_TOPIC: &str = "/devices/test-gateway/state";
+const COMMAND_TOPIC: &str = "/devices/test-gateway/commands";
+const ERRORS_TOPIC: &str = "/devices/test-gateway/errors";
+const DIAGNOSTICS_TOPIC: &str = "/devices/test-gateway/events/diagnostics";
+const GATEWAY_EVENT_TOPIC: &str = "/devices/test-gateway/events";
+const STATS_TOPIC: &str = "/devices/test-gateway/events/stats";
+
+const TAG: &str = "AA:BB:CC:DD:EE:01";
+const TAG_ATTACH_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-01/attach";
+const TAG_DETACH_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-01/detach";
+const TAG_EVENT_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-01/events";
+const TAG_STATE_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-01/state";
+
+const OTHER_TAG: &str = "AA:BB:CC:DD:EE:02";
+const OTHER_TAG_ATTACH_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-02/attach";
+const OTHER_TAG_EVENT_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-02/events";
+const TAG_ALERT_TOPIC: &str = "/devices/AA-BB-CC-DD-EE-01/events/alerts";
+
+fn appconfig() -> AppConfig {
+    serde_yaml::from_str(
+        r#"
+identity:
+  public_key: "test.crt"
+  private_key: "test.key"
+iotcore:
+  device_id: "test-gateway"
+  project_id: "test-project"
+  region: "europe-west1"
+  registry: "test-registry"
+  collectconfig_file: ""
+"#,
+    )
+    .unwrap()
+}
+
+// polls the broker until condition holds or a few seconds have passed
+fn wait_for<F: Fn(&MockBroker) -> bool>(broker: &SharedBroker, condition: F) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(5) {
+        if condition(&broker.lock().unwrap()) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+// acknowledgements of collect configs published by the client
+fn config_acks(broker: &MockBroker) -> Vec<serde_json::Value> {
+    broker
+        .published_to(STATE_TOPIC)
+        .iter()
+        .filter_map(|state| state.get("config_ack").cloned())
+        .collect()
+}
+
+// collect config states published by the client, leaving out the startup report
+fn collecting_states(broker: &MockBroker) -> Vec<bool> {
+    broker
+        .published_to(STATE_TOPIC)
+        .iter()
+        .filter_map(|state| state["collecting"].as_bool())
+        .collect()
+}
+
+// runs client and scanner until test has finished and gateway is shut down. returns result of
+//  the test and exit values of the client and the scanner
+fn run_gateway<T, F>(
+    broker: &SharedBroker,
+    beacons: Vec<RuuviBluetoothBeacon>,
+    hooks: Vec<BeaconHook>,
+    test: F,
+) -> (T, bool, bool)
+where
+    F: FnOnce() -> T,
+{
+    run_gateway_with(appconfig(), broker, beacons, hooks, test)
+}
+
+fn run_gateway_with<T, F>(
+    appconfig: AppConfig,
+    broker: &SharedBroker,
+    beacons: Vec<RuuviBluetoothBeacon>,
+    hooks: Vec<BeaconHook>,
+    test: F,
+) -> (T, bool, bool)
+where
+    F: FnOnce() -> T,
+{
+    let (event_s, event_r) = channel::unbounded();
+    let cnc = Broadcast::default();
+    let (_local_command_s, local_command_r) = channel::unbounded();
+    let metrics = Arc::new(Metrics::default());
+    let status = SharedStatus::default();
+    let mut iotcore = IotCoreClient::build(
+        &appconfig,
+        Box::new(MockPublisher::build(broker)),
+        &event_r,
+        &cnc,
+        &metrics,
+        &local_command_r,
+        &status,
+    )
+    .unwrap();
+    for hook in hooks {
+        iotcore.register_hook(hook);
+    }
+    let mut scanner = MockScanner::build(beacons, &event_s, &cnc.subscribe());
+
+    thread::scope(|scope| {
+        let client = scope.spawn(move |_| iotcore.start_client().unwrap());
+        let scanner = scope.spawn(move |_| scanner.start_scanner().unwrap());
+        let result = test();
+        broker
+            .lock()
+            .unwrap()
+            .send(COMMAND_TOPIC, json!({ "command": "shutdown" }));
+        (result, client.join().unwrap(), scanner.join().unwrap())
+    })
+    .unwrap()
+}
+
+#[test]
+fn beacons_are_relayed_after_config() {
+    let broker = MockBroker::shared();
+    let ((before_config, published), client_exit, scanner_exit) =
+        run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+            // scanner does not relay beacons before collect config has been received
+            std::thread::sleep(Duration::from_millis(500));
+            let before_config = !broker
+                .lock()
+                .unwrap()
+                .published_to(TAG_ATTACH_TOPIC)
+                .is_empty();
+            broker
+                .lock()
+                .unwrap()
+                .send(CONFIG_TOPIC, json!({ "collecting": true }));
+            let published = wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            });
+            (before_config, published)
+        });
+    assert!(!before_config);
+    assert!(published);
+    assert!(client_exit);
+    assert!(scanner_exit);
+
+    let broker = broker.lock().unwrap();
+    assert_eq!(broker.connects, 1);
+    assert_eq!(collecting_states(&broker), vec![true]);
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["address"], TAG);
+    assert_eq!(events[0]["data"]["temperature"], 21.5);
+    // tx_power is not in the default data fields
+    assert!(events[0]["data"].get("tx_power").is_none());
+}
+
+#[test]
+fn default_config_is_used_until_config_arrives() {
+    let broker = MockBroker::shared();
+    // IoT Core delivers an empty config document when none has been set
+    broker.lock().unwrap().incoming.push_back(IncomingMessage {
+        topic: CONFIG_TOPIC.to_string(),
+        payload: "".to_string(),
+    });
+    let mut appconfig = appconfig();
+    appconfig.default_collectconfig =
+        Some(serde_json::from_value(json!({ "collecting": true, "compact": true })).unwrap());
+    let ((published, paused), _, _) =
+        run_gateway_with(appconfig, &broker, vec![beacon(TAG, 1)], vec![], || {
+            let published = wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            });
+            broker
+                .lock()
+                .unwrap()
+                .send(CONFIG_TOPIC, json!({ "collecting": false }));
+            let paused = wait_for(&broker, |broker| {
+                collecting_states(broker).last() == Some(&false)
+            });
+            (published, paused)
+        });
+    assert!(published);
+    assert!(paused);
+
+    let broker = broker.lock().unwrap();
+    assert_eq!(collecting_states(&broker), vec![true, false]);
+    // only the config from IoT Core is acknowledged
+    let acks = config_acks(&broker);
+    assert_eq!(acks.len(), 1);
+    assert_eq!(acks[0]["status"], "accepted");
+}
+
+#[test]
+fn saved_config_is_used_after_restart() {
+    let file = temp_path("collectconfig.json");
+    let mut config_value = serde_yaml::to_value(appconfig()).unwrap();
+    config_value["iotcore"]["collectconfig_file"] = file.display().to_string().into();
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true, "compact": true }));
+    let (published, _, _) = run_gateway_with(
+        serde_yaml::from_value(config_value.clone()).unwrap(),
+        &broker,
+        vec![beacon(TAG, 1)],
+        vec![],
+        || {
+            wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            })
+        },
+    );
+    assert!(published);
+
+    // after a restart beacons are published before IoT Core delivers the config again
+    let broker = MockBroker::shared();
+    let (published, _, _) = run_gateway_with(
+        serde_yaml::from_value(config_value).unwrap(),
+        &broker,
+        vec![beacon(TAG, 2)],
+        vec![],
+        || {
+            wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            })
+        },
+    );
+    assert!(published);
+    std::fs::remove_file(file).unwrap();
+
+    let broker = broker.lock().unwrap();
+    assert_eq!(collecting_states(&broker), vec![true]);
+    assert!(config_acks(&broker).is_empty());
+}
+
+#[test]
+fn device_is_attached_before_publishing() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let (published, _, _) = run_gateway(
+        &broker,
+        vec![beacon(TAG, 1), beacon(TAG, 2)],
+        vec![],
+        || {
+            wait_for(&broker, |broker| {
+                broker.published_to(TAG_EVENT_TOPIC).len() == 2
+            })
+        },
+    );
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let tag_topics: Vec<&str> = broker
+        .published
+        .iter()
+        .map(|(topic, _)| topic.as_str())
+        .filter(|topic| topic.starts_with("/devices/AA-BB-CC-DD-EE-01/"))
+        .collect();
+    // attached once and detached on shutdown
+    assert_eq!(
+        tag_topics,
+        vec![
+            TAG_ATTACH_TOPIC,
+            TAG_EVENT_TOPIC,
+            TAG_EVENT_TOPIC,
+            TAG_DETACH_TOPIC
+        ]
+    );
+}
+
+#[test]
+fn beacons_are_batched() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "collection_size": 3 }),
+    );
+    let beacons = (1..=4).map(|sequence| beacon(TAG, sequence)).collect();
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    // fourth beacon waits in the queue for the batch to fill up
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events.len(), 1);
+    let sequences: Vec<u64> = events[0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|beacon| beacon["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![1, 2, 3]);
+}
+
+#[test]
+fn batch_age_counts_from_queueing_of_old_beacons() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "collection_size": 3, "max_batch_age": 60 }),
+    );
+    // replayed and history beacons keep the timestamps they were measured at
+    let beacons = (1..=3)
+        .map(|sequence| {
+            let mut beacon = beacon(TAG, sequence);
+            beacon.timestamp = chrono::Utc::now() - chrono::Duration::hours(1);
+            beacon
+        })
+        .collect();
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    // the first beacon is not flushed as a batch of its own
+    let broker = broker.lock().unwrap();
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn bursts_of_beacons_are_relayed_without_delay() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    // a round of the client loop takes 100ms, so taking one beacon per round would need
+    //  ten seconds for the burst
+    let beacons = (1..=100).map(|sequence| beacon(TAG, sequence)).collect();
+    let ((published, elapsed), _, _) = run_gateway(&broker, beacons, vec![], || {
+        let started = Instant::now();
+        let published = wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() == 100
+        });
+        (published, started.elapsed())
+    });
+    assert!(published);
+    assert!(elapsed < Duration::from_secs(2));
+}
+
+#[test]
+fn batches_stay_within_payload_size() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({
+            "collecting": true,
+            "collection_size": 100,
+            "max_payload_bytes": 800,
+            "compact": true,
+        }),
+    );
+    let beacons = (1..=8).map(|sequence| beacon(TAG, sequence)).collect();
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() >= 2
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let payloads: Vec<&String> = broker
+        .published
+        .iter()
+        .filter(|(topic, _)| topic == TAG_EVENT_TOPIC)
+        .map(|(_, payload)| payload)
+        .collect();
+    for payload in payloads {
+        assert!(payload.len() <= 800);
+        let batch: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert!(batch.as_array().unwrap().len() >= 2);
+    }
+}
+
+#[test]
+fn beacons_are_numbered_per_tag() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let beacons = vec![beacon(TAG, 10), beacon(OTHER_TAG, 11), beacon(TAG, 12)];
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() == 2
+                && broker.published_to(OTHER_TAG_EVENT_TOPIC).len() == 1
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let tag_sequences = |topic| -> Vec<(u64, u64)> {
+        broker
+            .published_to(topic)
+            .iter()
+            .map(|beacon| {
+                (
+                    beacon["sequence"].as_u64().unwrap(),
+                    beacon["tag_sequence"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    };
+    assert_eq!(tag_sequences(TAG_EVENT_TOPIC), vec![(10, 1), (12, 2)]);
+    assert_eq!(tag_sequences(OTHER_TAG_EVENT_TOPIC), vec![(11, 1)]);
+}
+
+#[test]
+fn alerts_are_published_to_alerts_subfolder() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let mut appconfig = appconfig();
+    appconfig.alerts = serde_json::from_value(json!({
+        "rules": [{ "metric": "temperature", "operator": "above", "threshold": 25.0 }],
+    }))
+    .unwrap();
+    let mut hot = beacon(TAG, 2);
+    hot.data.as_mut().unwrap()["temperature"] = json!(26.0);
+    let beacons = vec![beacon(TAG, 1), hot.clone(), hot, beacon(TAG, 4)];
+    let (published, _, _) = run_gateway_with(appconfig, &broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() == 4
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let alerts = broker.published_to(TAG_ALERT_TOPIC);
+    assert_eq!(alerts.len(), 2);
+    assert_eq!(alerts[0]["alert"]["state"], "triggered");
+    assert_eq!(alerts[0]["alert"]["value"], 26.0);
+    assert_eq!(alerts[1]["alert"]["state"], "cleared");
+}
+
+#[test]
+fn alert_rules_of_collect_config_replace_local_ones() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({
+            "collecting": true,
+            "alert_rules": [{ "metric": "temperature", "operator": "above", "threshold": 21.0 }],
+        }),
+    );
+    let mut appconfig = appconfig();
+    appconfig.alerts = serde_json::from_value(json!({
+        "rules": [{ "metric": "temperature", "operator": "above", "threshold": 25.0 }],
+    }))
+    .unwrap();
+    let beacons = vec![beacon(TAG, 1), beacon(TAG, 2)];
+    let (published, _, _) = run_gateway_with(appconfig, &broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() == 2
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let alerts = broker.published_to(TAG_ALERT_TOPIC);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["alert"]["state"], "triggered");
+    assert_eq!(alerts[0]["alert"]["threshold"], 21.0);
+}
+
+#[test]
+fn tags_with_low_battery_are_sampled_and_alerted() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({
+            "collecting": true,
+            "battery": {
+                "tiers": [{ "below": 2500.0, "interval": 3600 }],
+                "critical": 2400.0,
+            },
+        }),
+    );
+    let beacons = (1..=2)
+        .map(|sequence| {
+            let mut low = beacon(TAG, sequence);
+            low.data.as_mut().unwrap()["powerinfo"] = json!(2300.0);
+            low
+        })
+        .collect();
+    let (alerted, _, _) = run_gateway(&broker, beacons, vec![], || {
+        let alerted = wait_for(&broker, |broker| {
+            !broker.published_to(TAG_ALERT_TOPIC).is_empty()
+        });
+        std::thread::sleep(Duration::from_millis(500));
+        alerted
+    });
+    assert!(alerted);
+
+    let broker = broker.lock().unwrap();
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events.len(), 1);
+    let alerts = broker.published_to(TAG_ALERT_TOPIC);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["alert"]["metric"], "powerinfo");
+    assert_eq!(alerts[0]["alert"]["state"], "triggered");
+}
+
+#[test]
+fn movement_of_skipped_beacons_is_summarized() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({
+            "collecting": true,
+            "data_fields": ["temperature", "movement"],
+            "battery": { "tiers": [{ "below": 2500.0, "interval": 60 }] },
+        }),
+    );
+    let started = chrono::Utc::now();
+    let beacons = [(0, 1000.0), (10, 3000.0), (70, 1000.0)]
+        .iter()
+        .enumerate()
+        .map(|(index, (seconds, z))| {
+            let mut moving = beacon(TAG, index as u64 + 1);
+            moving.timestamp = started + chrono::Duration::seconds(*seconds);
+            let data = moving.data.as_mut().unwrap();
+            data["powerinfo"] = json!(2300.0);
+            data["acceleration"] = json!({ "on_x_axis": 0.0, "on_y_axis": 0.0, "on_z_axis": z });
+            moving
+        })
+        .collect();
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(TAG_EVENT_TOPIC).len() == 2
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events[0]["data"]["movement"]["samples"], 1);
+    // second published beacon covers the one skipped before it
+    assert_eq!(events[1]["data"]["movement"]["samples"], 2);
+    assert_eq!(events[1]["data"]["movement"]["peak_acceleration"], 3000.0);
+    assert!(events[1]["data"].get("powerinfo").is_none());
+}
+
+#[test]
+fn oldest_beacons_are_dropped_from_full_queue() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "collection_size": 10, "max_batch_age": 2 }),
+    );
+    let mut appconfig = appconfig();
+    appconfig.queue = serde_json::from_value(json!({ "max_beacons_per_tag": 2 })).unwrap();
+    let beacons = (1..=5).map(|sequence| beacon(TAG, sequence)).collect();
+    let (published, _, _) = run_gateway_with(appconfig, &broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    let sequences: Vec<u64> = events[0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|beacon| beacon["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![4, 5]);
+}
+
+#[test]
+fn beacons_of_unbound_devices_are_dropped() {
+    let broker = MockBroker::shared();
+    {
+        let mut broker = broker.lock().unwrap();
+        broker
+            .rejected_topics
+            .insert(OTHER_TAG_ATTACH_TOPIC.to_string());
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+    }
+    let beacons = vec![beacon(OTHER_TAG, 1), beacon(TAG, 1)];
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    assert!(broker.published_to(OTHER_TAG_ATTACH_TOPIC).is_empty());
+    assert!(broker.published_to(OTHER_TAG_EVENT_TOPIC).is_empty());
+    assert_eq!(broker.published_to(TAG_EVENT_TOPIC).len(), 1);
+}
+
+#[test]
+fn attach_is_backed_off_after_device_error() {
+    let broker = MockBroker::shared();
+    {
+        let mut broker = broker.lock().unwrap();
+        broker.send(
+            ERRORS_TOPIC,
+            json!({
+                "error_type": "GATEWAY_DEVICE_NOT_FOUND",
+                "description": "Device is not bound to the gateway",
+                "device_id": "AA-BB-CC-DD-EE-02",
+                "message_id": 1,
+            }),
+        );
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+    }
+    let beacons = vec![beacon(OTHER_TAG, 1), beacon(TAG, 1)];
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    // error is delivered only if the client has subscribed to the errors topic
+    assert!(broker.incoming.is_empty());
+    assert!(broker.published_to(OTHER_TAG_ATTACH_TOPIC).is_empty());
+    assert!(broker.published_to(OTHER_TAG_EVENT_TOPIC).is_empty());
+}
+
+#[test]
+fn rebind_command_clears_attach_backoff() {
+    let broker = MockBroker::shared();
+    {
+        let mut broker = broker.lock().unwrap();
+        broker.send(
+            ERRORS_TOPIC,
+            json!({
+                "error_type": "GATEWAY_DEVICE_NOT_FOUND",
+                "device_id": "AA-BB-CC-DD-EE-02",
+            }),
+        );
+        broker.send(COMMAND_TOPIC, json!({ "command": "rebind" }));
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+    }
+    let (published, _, _) = run_gateway(&broker, vec![beacon(OTHER_TAG, 1)], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(OTHER_TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+    assert_eq!(
+        broker
+            .lock()
+            .unwrap()
+            .published_to(OTHER_TAG_ATTACH_TOPIC)
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn state_of_attached_tags_is_published() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "tag_state_interval": 1 }),
+    );
+    let mut tag = beacon(TAG, 1);
+    tag.data.as_mut().unwrap()["powerinfo"] = json!(2977);
+    let (published, _, _) = run_gateway(&broker, vec![tag], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_STATE_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let state = &broker.published_to(TAG_STATE_TOPIC)[0];
+    assert_eq!(state["battery_voltage"], 2.977);
+    assert_eq!(state["tx_power"], 4);
+    assert!(state["last_seen"].is_string());
+}
+
+#[test]
+fn gateway_stats_are_published() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "stats_interval": 1 }),
+    );
+    let beacons = vec![beacon(TAG, 1), beacon(TAG, 2), beacon(OTHER_TAG, 1)];
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            broker
+                .published_to(STATS_TOPIC)
+                .iter()
+                .any(|stats| stats["stats"]["tags"][OTHER_TAG] == 1)
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let stats = broker
+        .published_to(STATS_TOPIC)
+        .into_iter()
+        .find(|stats| stats["stats"]["tags"][OTHER_TAG] == 1)
+        .unwrap();
+    assert_eq!(stats["stats"]["tags"][TAG], 2);
+    assert!(stats["stats"]["interval_start"].is_string());
+}
+
+#[test]
+fn connection_is_reported_and_lost_connection_recorded() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let (recorded, _, _) = run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        });
+        {
+            let mut broker = broker.lock().unwrap();
+            broker.connected = false;
+            broker.lost_reason = Some("connection reset by peer".to_string());
+        }
+        // the reason is taken by the client when it notices the lost connection
+        let recorded = wait_for(&broker, |broker| broker.lost_reason.is_none());
+        broker.lock().unwrap().connected = true;
+        recorded
+    });
+    assert!(recorded);
+
+    let broker = broker.lock().unwrap();
+    let report = broker
+        .published_to(STATE_TOPIC)
+        .into_iter()
+        .find(|state| state["software"].is_string())
+        .unwrap();
+    assert_eq!(report["connection"]["endpoint"], "mock:8883");
+    assert_eq!(report["connection"]["connack"], "Success");
+    assert_eq!(report["connection"]["connects"], 1);
+    assert!(report["connection"]["connect_duration_ms"].is_u64());
+    assert!(report["connection"]["connected_since"].is_string());
+}
+
+#[test]
+fn paused_collecting_is_resumed_by_command() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": false }));
+    let ((paused, published_while_paused, resumed), _, _) =
+        run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+            let paused = wait_for(&broker, |broker| collecting_states(broker) == vec![false]);
+            // beacon is relayed by scanner right after config, but not published
+            std::thread::sleep(Duration::from_millis(500));
+            let published_while_paused = !broker
+                .lock()
+                .unwrap()
+                .published_to(TAG_ATTACH_TOPIC)
+                .is_empty();
+            broker
+                .lock()
+                .unwrap()
+                .send(COMMAND_TOPIC, json!({ "command": "collect" }));
+            let resumed = wait_for(&broker, |broker| {
+                collecting_states(broker) == vec![false, true]
+            });
+            (paused, published_while_paused, resumed)
+        });
+    assert!(paused);
+    assert!(!published_while_paused);
+    assert!(resumed);
+    assert!(broker
+        .lock()
+        .unwrap()
+        .published_to(TAG_EVENT_TOPIC)
+        .is_empty());
+}
+
+#[test]
+fn beacons_buffered_during_pause_are_published_on_resume() {
+    let file = temp_path("pause_buffer.jsonl");
+    let mut config_value = serde_yaml::to_value(appconfig()).unwrap();
+    config_value["iotcore"]["pause_buffer_file"] = file.display().to_string().into();
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": false, "pause": { "mode": "buffer" } }),
+    );
+    let ((buffered, published), _, _) = run_gateway_with(
+        serde_yaml::from_value(config_value).unwrap(),
+        &broker,
+        vec![beacon(TAG, 1)],
+        vec![],
+        || {
+            wait_for(&broker, |broker| collecting_states(broker) == vec![false]);
+            std::thread::sleep(Duration::from_millis(500));
+            let buffered = file.exists()
+                && broker
+                    .lock()
+                    .unwrap()
+                    .published_to(TAG_EVENT_TOPIC)
+                    .is_empty();
+            broker
+                .lock()
+                .unwrap()
+                .send(COMMAND_TOPIC, json!({ "command": "collect" }));
+            let published = wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            });
+            (buffered, published)
+        },
+    );
+    assert!(buffered);
+    assert!(published);
+    assert!(!file.exists());
+    let broker = broker.lock().unwrap();
+    assert_eq!(broker.published_to(TAG_EVENT_TOPIC)[0]["sequence"], 1);
+}
+
+#[test]
+fn beacons_over_rate_limits_are_buffered_and_published_in_order() {
+    let file = temp_path("rate_limit_buffer.jsonl");
+    let mut config_value = serde_yaml::to_value(appconfig()).unwrap();
+    config_value["iotcore"]["max_messages_per_second"] = 2.0.into();
+    config_value["iotcore"]["rate_limit_buffer_file"] = file.display().to_string().into();
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let beacons = (1..=6).map(|sequence| beacon(TAG, sequence)).collect();
+    let ((buffered, published), _, _) = run_gateway_with(
+        serde_yaml::from_value(config_value).unwrap(),
+        &broker,
+        beacons,
+        vec![],
+        || {
+            let buffered = wait_for(&broker, |_| file.exists());
+            // two messages a second take a few seconds for all of them
+            let all_published =
+                |broker: &MockBroker| broker.published_to(TAG_EVENT_TOPIC).len() == 6;
+            let published = wait_for(&broker, all_published) || wait_for(&broker, all_published);
+            (buffered, published)
+        },
+    );
+    assert!(buffered);
+    assert!(published);
+    assert!(!file.exists());
+    let broker = broker.lock().unwrap();
+    let sequences: Vec<u64> = broker
+        .published_to(TAG_EVENT_TOPIC)
+        .iter()
+        .map(|beacon| beacon["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn collecting_follows_schedule() {
+    let broker = MockBroker::shared();
+    // the year 2000 has passed, so the schedule keeps collecting paused
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "schedule": { "cron": ["* * * * * * 2000"] } }),
+    );
+    let ((paused, resumed), _, _) = run_gateway(&broker, vec![], vec![], || {
+        let paused = wait_for(&broker, |broker| {
+            collecting_states(broker) == vec![true, false]
+        });
+        // window ending at its start spans the whole day
+        broker.lock().unwrap().send(
+            CONFIG_TOPIC,
+            json!({ "collecting": true, "schedule": { "windows": ["00:00-00:00"] } }),
+        );
+        let resumed = wait_for(&broker, |broker| {
+            collecting_states(broker) == vec![true, false, true]
+        });
+        (paused, resumed)
+    });
+    assert!(paused);
+    assert!(resumed);
+
+    let broker = broker.lock().unwrap();
+    let transitions: Vec<serde_json::Value> = broker
+        .published_to(STATE_TOPIC)
+        .iter()
+        .filter_map(|state| state["schedule"].get("collecting").cloned())
+        .collect();
+    assert_eq!(transitions, vec![json!(false), json!(true)]);
+}
+
+#[test]
+fn shutdown_detaches_devices_and_disconnects() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let (attached, client_exit, scanner_exit) =
+        run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+            wait_for(&broker, |broker| {
+                !broker.published_to(TAG_ATTACH_TOPIC).is_empty()
+            })
+        });
+    assert!(attached);
+    // shutdown is a clean exit for both threads instead of a restart
+    assert!(client_exit);
+    assert!(scanner_exit);
+
+    let broker = broker.lock().unwrap();
+    assert!(!broker.connected);
+    assert_eq!(broker.published_to(TAG_DETACH_TOPIC).len(), 1);
+    assert!(broker.incoming.is_empty());
+}
+
+#[test]
+fn hooks_modify_drop_and_redirect_beacons() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "collection_size": 2 }),
+    );
+    let hooks: Vec<BeaconHook> = vec![
+        Box::new(|beacon| match beacon.address.as_str() {
+            OTHER_TAG => BeaconAction::Drop,
+            _ => BeaconAction::Publish,
+        }),
+        Box::new(|beacon| {
+            let mut beacon = beacon.clone();
+            beacon.data.as_mut().unwrap()["humidity"] = json!(99.0);
+            BeaconAction::Replace(beacon)
+        }),
+        Box::new(|beacon| match beacon.sequence {
+            2 => BeaconAction::Redirect("alerts".to_string()),
+            _ => BeaconAction::Publish,
+        }),
+    ];
+    let beacons = vec![
+        beacon(TAG, 1),
+        beacon(OTHER_TAG, 1),
+        beacon(TAG, 2),
+        beacon(TAG, 3),
+    ];
+    let (published, _, _) = run_gateway(&broker, beacons, hooks, || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    assert!(broker.published_to(OTHER_TAG_ATTACH_TOPIC).is_empty());
+    // redirected beacon is published on its own instead of being queued
+    let alerts = broker.published_to(TAG_ALERT_TOPIC);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0]["sequence"], 2);
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    assert_eq!(events.len(), 1);
+    let sequences: Vec<u64> = events[0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|beacon| beacon["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![1, 3]);
+    assert_eq!(events[0][0]["data"]["humidity"], 99.0);
+}
+
+#[test]
+fn beacons_of_all_tags_are_published_in_envelope() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "envelope_interval": 1 }),
+    );
+    let beacons = vec![beacon(TAG, 1), beacon(OTHER_TAG, 1), beacon(TAG, 2)];
+    let envelope_size = |broker: &MockBroker| -> usize {
+        broker
+            .published_to(GATEWAY_EVENT_TOPIC)
+            .iter()
+            .flat_map(|envelope| envelope["tags"].as_object().unwrap().values().cloned())
+            .map(|beacons| beacons.as_array().unwrap().len())
+            .sum()
+    };
+    let (published, _, _) = run_gateway(&broker, beacons, vec![], || {
+        wait_for(&broker, |broker| envelope_size(broker) == 3)
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    // tags are not attached, their beacons are published by the gateway
+    assert!(broker.published_to(TAG_ATTACH_TOPIC).is_empty());
+    assert!(broker.published_to(TAG_EVENT_TOPIC).is_empty());
+    let envelopes = broker.published_to(GATEWAY_EVENT_TOPIC);
+    assert_eq!(envelopes[0]["gateway"], "test-gateway");
+    let sequences: Vec<u64> = envelopes
+        .iter()
+        .flat_map(|envelope| {
+            envelope["tags"][TAG]
+                .as_array()
+                .cloned()
+                .unwrap_or_default()
+        })
+        .map(|beacon| beacon["sequence"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sequences, vec![1, 2]);
+}
+
+#[test]
+fn payloads_are_fingerprinted() {
+    let broker = MockBroker::shared();
+    let config = json!({ "collecting": true, "fingerprint": true });
+    broker.lock().unwrap().send(CONFIG_TOPIC, config.clone());
+    let (published, _, _) = run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let event = &broker.published_to(TAG_EVENT_TOPIC)[0];
+    assert_eq!(event["fingerprint"]["gateway"], "test-gateway");
+    assert_eq!(event["fingerprint"]["version"], env!("CARGO_PKG_VERSION"));
+    let collectconfig: CollectConfig = serde_json::from_value(config).unwrap();
+    assert_eq!(event["fingerprint"]["config_hash"], collectconfig.hash());
+    assert_eq!(event["payload"]["address"], TAG);
+}
+
+#[test]
+fn floats_are_rounded_to_precision() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({
+            "collecting": true,
+            "data_fields": ["temperature", "humidity", "atmospheric_pressure", "acceleration"],
+            "precision": { "temperature": 1, "humidity": 0, "acceleration.on_x_axis": 2 },
+        }),
+    );
+    let mut tag = beacon(TAG, 1);
+    tag.data = Some(json!({
+        "temperature": 21.456_789,
+        "humidity": 45.678,
+        "atmospheric_pressure": 100_312,
+        "acceleration": { "on_x_axis": 0.123_456, "on_y_axis": 0.5 },
+    }));
+    let (published, _, _) = run_gateway(&broker, vec![tag], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let event = &broker.published_to(TAG_EVENT_TOPIC)[0];
+    assert_eq!(event["data"]["temperature"], 21.5);
+    assert_eq!(event["data"]["humidity"], 46.0);
+    assert_eq!(event["data"]["atmospheric_pressure"], 100_312);
+    assert_eq!(event["data"]["acceleration"]["on_x_axis"], 0.12);
+    assert_eq!(event["data"]["acceleration"]["on_y_axis"], 0.5);
+}
+
+#[test]
+fn payloads_are_renamed_and_compact() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "field_naming": "camelCase", "compact": true }),
+    );
+    let (published, _, _) = run_gateway(&broker, vec![beacon(TAG, 1)], vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let (_, payload) = broker
+        .published
+        .iter()
+        .find(|(topic, _)| topic == TAG_EVENT_TOPIC)
+        .unwrap();
+    assert!(!payload.contains('\n'));
+    let event: serde_json::Value = serde_json::from_str(payload).unwrap();
+    assert_eq!(event["data"]["measurementSequenceNumber"], 1);
+    assert!(event["receivedMonotonicMs"].is_number());
+    // other messages published under the collect config are compact too
+    let (_, state) = broker
+        .published
+        .iter()
+        .find(|(topic, state)| topic == STATE_TOPIC && state.contains("\"compact\""))
+        .unwrap();
+    assert!(!state.contains('\n'));
+}
+
+#[test]
+fn local_timestamps_are_added() {
+    let broker = MockBroker::shared();
+    broker
+        .lock()
+        .unwrap()
+        .send(CONFIG_TOPIC, json!({ "collecting": true }));
+    let mut appconfig = appconfig();
+    appconfig.clock = serde_json::from_value(json!({ "timezone": "Europe/Helsinki" })).unwrap();
+    let (published, _, _) =
+        run_gateway_with(appconfig, &broker, vec![beacon(TAG, 1)], vec![], || {
+            wait_for(&broker, |broker| {
+                !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+            })
+        });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let event = &broker.published_to(TAG_EVENT_TOPIC)[0];
+    let timestamp: chrono::DateTime<chrono::Utc> =
+        serde_json::from_value(event["timestamp"].clone()).unwrap();
+    let local_timestamp: chrono::DateTime<chrono::FixedOffset> =
+        serde_json::from_value(event["local_timestamp"].clone()).unwrap();
+    assert_eq!(local_timestamp, timestamp);
+    assert!(local_timestamp.offset().local_minus_utc() >= 2 * 60 * 60);
+}
+
+#[test]
+fn site_metadata_is_merged() {
+    let broker = MockBroker::shared();
+    broker.lock().unwrap().send(
+        CONFIG_TOPIC,
+        json!({ "collecting": true, "collection_size": 2 }),
+    );
+    let mut appconfig = appconfig();
+    appconfig.site = serde_json::from_value(json!({
+        "name": "Headquarters",
+        "floor": "2",
+        "latitude": 60.17,
+        "longitude": 24.94,
+    }))
+    .unwrap();
+    let beacons = vec![beacon(TAG, 1), beacon(TAG, 2)];
+    let (published, _, _) = run_gateway_with(appconfig, &broker, beacons, vec![], || {
+        wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+        })
+    });
+    assert!(published);
+
+    let broker = broker.lock().unwrap();
+    let events = broker.published_to(TAG_EVENT_TOPIC);
+    for beacon in events[0].as_array().unwrap() {
+        assert_eq!(
+            beacon["site"],
+            json!({
+                "name": "Headquarters",
+                "floor": "2",
+                "latitude": 60.17,
+                "longitude": 24.94,
+            })
+        );
+    }
+}
+
+#[test]
+fn configs_are_acknowledged() {
+    let broker = MockBroker::shared();
+    {
+        let mut broker = broker.lock().unwrap();
+        broker.send(CONFIG_TOPIC, json!({ "collecting": "yes" }));
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+    }
+    let (acknowledged, _, _) = run_gateway(&broker, vec![], vec![], || {
+        wait_for(&broker, |broker| config_acks(broker).len() == 2)
+    });
+    assert!(acknowledged);
+
+    let broker = broker.lock().unwrap();
+    let acks = config_acks(&broker);
+    assert_eq!(acks[0]["status"], "rejected");
+    assert_eq!(
+        acks[0]["version"],
+        payload_hash(&json!({ "collecting": "yes" }).to_string())
+    );
+    assert!(acks[0]["reason"].as_str().unwrap().contains("invalid type"));
+    let config: CollectConfig = serde_json::from_value(json!({ "collecting": true })).unwrap();
+    assert_eq!(acks[1]["status"], "accepted");
+    assert_eq!(acks[1]["version"], config.hash());
+    assert!(acks[1]["timestamp"].is_string());
+    assert!(acks[1].get("reason").is_none());
+    // acknowledgement follows the collect config it acknowledges in state
+    assert!(broker.published_to(STATE_TOPIC).last().unwrap()["config_ack"].is_object());
+}
+
+#[test]
+fn invalid_commands_are_reported() {
+    let broker = MockBroker::shared();
+    let invalid_commands = vec![
+        json!({ "command": "restart" }),
+        json!({ "comand": "pause" }),
+        json!({ "command": "duty_cycle", "duty_cycle": { "scan_window": 60, "scan_period": 10 } }),
+        json!(["pause"]),
+    ];
+    {
+        let mut broker = broker.lock().unwrap();
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+        for command in &invalid_commands {
+            broker.send(COMMAND_TOPIC, command.clone());
+        }
+    }
+    let (reported, _, _) = run_gateway(&broker, vec![], vec![], || {
+        wait_for(&broker, |broker| {
+            broker.published_to(DIAGNOSTICS_TOPIC).len() == invalid_commands.len()
+        })
+    });
+    assert!(reported);
+
+    let broker = broker.lock().unwrap();
+    let errors: Vec<serde_json::Value> = broker
+        .published_to(DIAGNOSTICS_TOPIC)
+        .into_iter()
+        .map(|error| error["command_error"].clone())
+        .collect();
+    assert_eq!(
+        errors[0]["payload_hash"],
+        payload_hash(&invalid_commands[0].to_string())
+    );
+    assert!(errors[0]["reason"]
+        .as_str()
+        .unwrap()
+        .starts_with("unknown command \"restart\""));
+    assert_eq!(errors[1]["reason"], "unknown field \"comand\"");
+    assert!(errors[2]["reason"]
+        .as_str()
+        .unwrap()
+        .contains("scan_window"));
+    assert_eq!(errors[3]["reason"], "command must be a JSON object");
+    // gateway kept collecting, invalid commands were ignored
+    assert_eq!(collecting_states(&broker), vec![true]);
+}
+
+#[test]
+fn failing_config_is_rolled_back() {
+    let broker = MockBroker::shared();
+    let bad_config = json!({ "collecting": true, "event_subfolder": "broken" });
+    {
+        let mut broker = broker.lock().unwrap();
+        broker
+            .rejected_topics
+            .insert(format!("{}/broken", TAG_EVENT_TOPIC));
+        broker.send(CONFIG_TOPIC, json!({ "collecting": true }));
+        broker.send(CONFIG_TOPIC, bad_config.clone());
+    }
+    let beacons = (1..=6).map(|sequence| beacon(TAG, sequence)).collect();
+    let (rolled_back, _, _) = run_gateway(&broker, beacons, vec![], || {
+        let rolled_back = wait_for(&broker, |broker| {
+            !broker.published_to(TAG_EVENT_TOPIC).is_empty()
+                && broker
+                    .published_to(STATE_TOPIC)
+                    .iter()
+                    .any(|state| state.get("config_rollback").is_some())
+        });
+        // rolled back config is not applied again when it is delivered again
+        broker
+            .lock()
+            .unwrap()
+            .send(CONFIG_TOPIC, bad_config.clone());
+        let ignored = wait_for(&broker, |broker| {
+            config_acks(broker)
+                .iter()
+                .any(|ack| ack["status"] == "rejected")
+        });
+        rolled_back && ignored
+    });
+    assert!(rolled_back);
+
+    let broker = broker.lock().unwrap();
+    let states = broker.published_to(STATE_TOPIC);
+    let report = states
+        .iter()
+        .find_map(|state| state.get("config_rollback"))
+        .unwrap();
+    let bad_config: CollectConfig = serde_json::from_value(bad_config).unwrap();
+    assert_eq!(report["rejected_version"], bad_config.hash());
+    assert!(report["failures"].as_u64().unwrap() >= 3);
+    // collect config restored after the report is the last one published to state
+    let last_state = states
+        .iter()
+        .rev()
+        .find(|state| state.get("collecting").is_some())
+        .unwrap();
+    assert_eq!(last_state["collecting"], true);
+    assert!(last_state["event_subfolder"].is_null());
+}
+
+// eof
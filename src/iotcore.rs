@@ -1,25 +1,53 @@
-use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use color_eyre::eyre::Report;
 use crossbeam::channel;
 use eui48::{MacAddress, MacAddressFormat};
-use paho_mqtt as mqtt;
 use serde::{Deserialize, Serialize};
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::mpsc::Receiver;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{thread, time};
 
-use crate::configfile::AppConfig;
-use crate::jwt::IotCoreAuthToken;
-use crate::scanner::RuuviBluetoothBeacon;
+use crate::actuators::{ActuatorState, Actuators};
+use crate::alerts::{Alert, Alerts, Notifier};
+use crate::battery::{BatteryConfig, BatterySampler};
+use crate::broadcast::Broadcast;
+use crate::clock::{self, ClockDrift};
+use crate::configfile::{AlertRule, AppConfig, Eviction, IotCoreConfig, QueueConfig, UpdateConfig};
+use crate::csvexport::CsvExport;
+use crate::decoder::IBEACON_DECODER;
+use crate::diagnostics::StartupReport;
+use crate::fatal::{self, FatalError};
+use crate::gpsd::Gps;
+use crate::hooks::{self, BeaconHook};
+use crate::metrics::Metrics;
+use crate::movement::MovementTracker;
+use crate::naming;
+use crate::pausebuffer::{PauseBuffer, PauseConfig, PauseMode, ResumeAction};
+use crate::publisher::{Publisher, RateLimiter};
+use crate::rollback::ConfigRollback;
+use crate::ruuvistation;
+use crate::scanner::{self, RuuviBluetoothBeacon, SharedBeacon};
+use crate::schedule::{Schedule, ScheduleConfig};
+use crate::scripting::PayloadScripts;
+use crate::sinks::{Sink, Sinks};
+use crate::stats::StatsCollector;
+use crate::status::{SharedStatus, TagStatus};
+use crate::updater::{self, Release, ReleaseCheck};
+use crate::watermark::Watermarks;
 
+/// Message relayed from the IoT Core client to the scanner over the CNC channel.
 #[derive(Debug, Clone)]
 pub enum IOTCoreCNCMessageKind {
     COMMAND(Option<CNCCommandMessage>),
-    CONFIG(Option<CollectConfig>),
+    CONFIG(Option<Box<CollectConfig>>),
 }
 
+/// Command sent to the commands topic of the gateway.
 #[derive(Debug, Deserialize, Clone)]
 pub enum CNCCommand {
     #[serde(rename = "collect")]
@@ -30,18 +58,189 @@ pub enum CNCCommand {
     SHUTDOWN,
     #[serde(rename = "reset")]
     RESET,
+    #[serde(rename = "duty_cycle")]
+    DUTYCYCLE,
+    #[serde(rename = "rebind")]
+    REBIND,
+    #[serde(rename = "update")]
+    UPDATE,
 }
 
+// commands in the form they are sent to the commands topic
+const CNC_COMMANDS: [&str; 7] = [
+    "collect",
+    "pause",
+    "shutdown",
+    "reset",
+    "duty_cycle",
+    "rebind",
+    "update",
+];
+
+/// Payload of a message in the commands topic of the gateway.
 #[derive(Debug, Deserialize, Clone)]
 pub struct CNCCommandMessage {
     pub command: CNCCommand,
+    /// Parameter of duty_cycle command, scanning is continuous when omitted
+    #[serde(default)]
+    pub duty_cycle: Option<DutyCycleConfig>,
+}
+
+impl CNCCommandMessage {
+    /// Parses and validates a command received from IoT Core. Returns a description of the
+    /// problem that can be reported back to the sender if the command is not valid.
+    pub fn parse(payload: &str) -> Result<CNCCommandMessage, String> {
+        let value: serde_json::Value = serde_json::from_str(payload)
+            .map_err(|error| format!("command is not valid JSON: {}", error))?;
+        let object = value
+            .as_object()
+            .ok_or_else(|| "command must be a JSON object".to_string())?;
+        if let Some(field) = object
+            .keys()
+            .find(|field| *field != "command" && *field != "duty_cycle")
+        {
+            return Err(format!("unknown field \"{}\"", field));
+        }
+        match object.get("command") {
+            Some(serde_json::Value::String(command))
+                if CNC_COMMANDS.contains(&command.as_str()) => {}
+            Some(serde_json::Value::String(command)) => {
+                return Err(format!(
+                    "unknown command \"{}\", expected one of: {}",
+                    command,
+                    CNC_COMMANDS.join(", ")
+                ))
+            }
+            Some(_) => return Err("\"command\" must be a string".to_string()),
+            None => return Err("missing field \"command\"".to_string()),
+        }
+        let command: CNCCommandMessage =
+            serde_json::from_value(value).map_err(|error| format!("invalid command: {}", error))?;
+        match &command.duty_cycle {
+            Some(_) if !matches!(command.command, CNCCommand::DUTYCYCLE) => {
+                Err("\"duty_cycle\" is only valid with the duty_cycle command".to_string())
+            }
+            Some(duty_cycle)
+                if duty_cycle.scan_window == 0
+                    || duty_cycle.scan_window > duty_cycle.scan_period =>
+            {
+                Err(
+                    "\"scan_window\" must be positive and not longer than \"scan_period\""
+                        .to_string(),
+                )
+            }
+            _ => Ok(command),
+        }
+    }
+}
+
+/// Scanning for `scan_time` seconds every `scan_period` seconds.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub struct DutyCycleConfig {
+    pub scan_window: u64,
+    pub scan_period: u64,
+}
+impl DutyCycleConfig {
+    /// Seconds the radio is idle in each period
+    pub fn idle_time(&self) -> u64 {
+        self.scan_period.saturating_sub(self.scan_window)
+    }
 }
 
+/// Selection and recovery of the Bluetooth adapter, part of collect config.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
 pub struct BluetoothConfig {
+    #[serde(default)]
     pub adapter_index: usize,
+    /// Adapter name (e.g. "hci1") or MAC address where "*" matches any characters. takes
+    /// precedence over adapter_index
+    pub adapter: Option<String>,
+    /// Deeper reset of the adapter when the scanner keeps failing
+    pub hard_reset: Option<HardResetMode>,
+    hard_reset_after: Option<u32>,
+    advertisement_monitor: Option<bool>,
+}
+impl BluetoothConfig {
+    /// Whether advertisements are filtered by a BlueZ Advertisement Monitor instead of received
+    /// from an unfiltered scan
+    pub fn advertisement_monitor(&self) -> bool {
+        self.advertisement_monitor.unwrap_or(false)
+    }
+
+    /// Consecutive scanner failures (stuck data or scan errors) before hard reset is performed
+    pub fn hard_reset_after(&self) -> u32 {
+        self.hard_reset_after.unwrap_or(2)
+    }
+}
+
+/// How a failing Bluetooth adapter is reset.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum HardResetMode {
+    #[serde(rename = "hci")]
+    HCI,
+    #[serde(rename = "rfkill")]
+    RFKILL,
+}
+
+/// Periodic sync of measurements logged by Ruuvi tags, part of collect config.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub struct HistoryConfig {
+    pub tags: Vec<String>,
+    interval: Option<u64>,
+    max_age: Option<u64>,
+    timeout: Option<u64>,
+}
+impl HistoryConfig {
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(60 * 60)
+    }
+
+    /// How far back logged measurements are read when a tag has not been synced before
+    pub fn max_age(&self) -> i64 {
+        self.max_age.unwrap_or(24 * 60 * 60) as i64
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(60)
+    }
+}
+
+/// Whether raw manufacturer data is published with or instead of decoded data.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RawDataMode {
+    #[serde(rename = "none")]
+    NONE,
+    #[serde(rename = "include")]
+    INCLUDE,
+    #[serde(rename = "only")]
+    ONLY,
+}
+
+/// JSON schema of published beacons.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum PayloadFormat {
+    #[serde(rename = "default")]
+    DEFAULT,
+    /// Gateway payload of Ruuvi Station
+    #[serde(rename = "ruuvistation")]
+    RUUVISTATION,
+}
+
+/// Naming of the fields of published beacons.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum FieldNaming {
+    /// Field names as they are, e.g. atmospheric_pressure
+    #[serde(rename = "snake_case")]
+    SNAKE,
+    /// E.g. atmosphericPressure
+    #[serde(rename = "camelCase")]
+    CAMEL,
+    /// Short keys of common fields, e.g. p for atmospheric_pressure
+    #[serde(rename = "short")]
+    SHORT,
 }
 
+/// Gateway configuration received from the config topic of the gateway in IoT Core.
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
 pub struct CollectConfig {
     collecting: bool,
@@ -49,33 +248,292 @@ pub struct CollectConfig {
     pub stuck_data_threshold: Option<i64>,
     no_beacons_threshold: Option<u64>,
     collection_size: Option<usize>,
+    max_payload_bytes: Option<usize>,
+    max_batch_age: Option<u64>,
+    tag_state_interval: Option<u64>,
+    envelope_interval: Option<u64>,
+    stats_interval: Option<u64>,
+    data_fields: Option<Vec<String>>,
+    precision: Option<BTreeMap<String, u32>>,
+    raw_data: Option<RawDataMode>,
+    payload_format: Option<PayloadFormat>,
+    field_naming: Option<FieldNaming>,
+    compact: Option<bool>,
+    fingerprint: Option<bool>,
+    generic_beacon_subfolder: Option<String>,
+    alert_rules: Option<Vec<AlertRule>>,
     pub bluetooth: Option<BluetoothConfig>,
+    pub history: Option<HistoryConfig>,
+    pub duty_cycle: Option<DutyCycleConfig>,
+    schedule: Option<ScheduleConfig>,
+    pause: Option<PauseConfig>,
+    battery: Option<BatteryConfig>,
 }
 impl CollectConfig {
+    /// IBeacon advertisements are published to this subfolder, or not at all when unset
+    pub fn generic_beacon_subfolder(&self) -> Option<String> {
+        self.generic_beacon_subfolder.clone()
+    }
+
+    /// Decimals floats in the fields of beacon data are rounded to, by field
+    pub fn precision(&self) -> BTreeMap<String, u32> {
+        self.precision.clone().unwrap_or_default()
+    }
+
+    pub fn raw_data(&self) -> RawDataMode {
+        self.raw_data.unwrap_or(RawDataMode::NONE)
+    }
+
+    pub fn payload_format(&self) -> PayloadFormat {
+        self.payload_format.unwrap_or(PayloadFormat::DEFAULT)
+    }
+
+    /// Naming of the fields of beacon payloads in the default payload format
+    pub fn field_naming(&self) -> FieldNaming {
+        self.field_naming.unwrap_or(FieldNaming::SNAKE)
+    }
+
+    /// Messages are serialized without whitespace instead of pretty-printed
+    pub fn compact(&self) -> bool {
+        self.compact.unwrap_or(false)
+    }
+
+    /// Beacon payloads are wrapped with the gateway, software version and hash of the collect
+    /// config they were published with
+    pub fn fingerprint(&self) -> bool {
+        self.fingerprint.unwrap_or(false)
+    }
+
+    /// Alert rules replacing the ones of the "alerts" section while the config is active, None
+    /// keeps the local ones
+    pub fn alert_rules(&self) -> Option<Vec<AlertRule>> {
+        self.alert_rules.clone()
+    }
+
+    /// Tx_power is left out by default to keep the payload backwards compatible
+    pub fn data_fields(&self) -> Vec<String> {
+        match &self.data_fields {
+            Some(data_fields) => data_fields.clone(),
+            None => vec![
+                "temperature",
+                "humidity",
+                "atmospheric_pressure",
+                "acceleration",
+                "powerinfo",
+                "movement_counter",
+                "measurement_sequence_number",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+
+    /// Beacons are not received while the scanner is idle in duty-cycle mode
     pub fn no_beacons_threshold(&self) -> u64 {
-        self.no_beacons_threshold.unwrap_or(58)
+        let idle_time = match &self.duty_cycle {
+            Some(duty_cycle) => duty_cycle.idle_time(),
+            None => 0,
+        };
+        self.no_beacons_threshold.unwrap_or(58) + idle_time
     }
 
     pub fn collection_size(&self) -> usize {
         self.collection_size.unwrap_or(0)
     }
+
+    /// IoT Core rejects messages larger than 256 KB
+    pub fn max_payload_bytes(&self) -> usize {
+        self.max_payload_bytes.unwrap_or(256 * 1024)
+    }
+
+    /// Partially filled queues of tags that stopped broadcasting are published after this
+    pub fn max_batch_age(&self) -> u64 {
+        self.max_batch_age.unwrap_or(10 * 60)
+    }
+
+    /// State of attached tags is published this often, or not at all when unset or zero
+    pub fn tag_state_interval(&self) -> Option<u64> {
+        self.tag_state_interval.filter(|interval| *interval > 0)
+    }
+
+    /// Beacons of all tags are published together in an envelope of the gateway this often
+    /// instead of on behalf of each tag, unless unset or zero
+    pub fn envelope_interval(&self) -> Option<u64> {
+        self.envelope_interval.filter(|interval| *interval > 0)
+    }
+
+    /// Statistics of the gateway are published this often, or not at all when unset or zero
+    pub fn stats_interval(&self) -> Option<u64> {
+        self.stats_interval.filter(|interval| *interval > 0)
+    }
+
+    /// Windows of time beacons are collected in, None collects at all times
+    pub fn schedule(&self) -> Result<Option<Schedule>, Report> {
+        self.schedule.as_ref().map(Schedule::build).transpose()
+    }
+
+    /// Beacons received while collecting is paused are discarded unless set to be buffered
+    pub fn pause(&self) -> PauseConfig {
+        self.pause.clone().unwrap_or_default()
+    }
+
+    /// Beacons of tags with low batteries are published less often when tiers are set
+    pub fn battery(&self) -> BatteryConfig {
+        self.battery.clone().unwrap_or_default()
+    }
+
+    /// Version of the config as hash of its JSON serialization
+    pub fn hash(&self) -> String {
+        payload_hash(&serde_json::to_string(self).unwrap())
+    }
+}
+
+/// FNV-1a hash of a payload as hex string. Unlike the hasher of the standard library it is
+/// stable across restarts and builds, so it can be compared to the one computed by an operator.
+pub fn payload_hash(payload: &str) -> String {
+    let hash = payload.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
 }
 
+// approximate memory used by a queued beacon, decoded data is estimated by its length in JSON
+fn beacon_memory(beacon: &RuuviBluetoothBeacon) -> usize {
+    std::mem::size_of::<RuuviBluetoothBeacon>()
+        + beacon.raw.capacity()
+        + beacon.address.capacity()
+        + beacon
+            .decoder
+            .as_ref()
+            .map_or(0, |decoder| decoder.capacity())
+        + beacon
+            .extensions
+            .as_ref()
+            .map_or(0, |extensions| extensions.capacity())
+        + beacon.source.as_ref().map_or(0, |source| source.capacity())
+        + beacon
+            .data
+            .as_ref()
+            .map_or(0, |data| data.to_string().len())
+}
+
+// rounds floats in the data to the decimals configured for their fields, dots separate fields
+//  of nested objects like in alert rules
+fn round_fields(data: &mut serde_json::Value, precision: &BTreeMap<String, u32>) {
+    for (field, decimals) in precision {
+        let mut value = Some(&mut *data);
+        for name in field.split('.') {
+            value = value.and_then(|value| value.get_mut(name));
+        }
+        if let Some(value) = value.filter(|value| value.is_f64()) {
+            let scale = 10f64.powi(*decimals as i32);
+            *value = json!((value.as_f64().unwrap() * scale).round() / scale);
+        }
+    }
+}
+
+// health of a tag published to the state topic of the tag. btleplug does not report signal
+//  strength, so RSSI is not included
+fn tag_state(beacon: &RuuviBluetoothBeacon, clock_drift_ms: Option<i64>) -> serde_json::Value {
+    let data = beacon.data.as_ref();
+    json!({
+        "last_seen": beacon.timestamp,
+        "clock_drift_ms": clock_drift_ms,
+        "battery_voltage": data
+            .and_then(|data| data["powerinfo"].as_f64())
+            .map(|powerinfo| powerinfo / 1000.0),
+        "tx_power": data.and_then(|data| data["tx_power"].as_i64()),
+        "source": beacon.source,
+    })
+}
+
+// attaching a tag that failed to attach is retried after this, doubled on every consecutive
+//  failure up to the maximum
+const ATTACH_BACKOFF_MIN: Duration = Duration::from_secs(30);
+const ATTACH_BACKOFF_MAX: Duration = Duration::from_secs(3600);
+
+// beacons received after the rate limit buffer has this many are discarded
+const RATE_LIMIT_BUFFER_MAX_BEACONS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct AttachRetry {
+    failures: u32,
+    retry_at: Instant,
+}
+
+// error IoT Core reports on the errors topic of the gateway, e.g. for a message published on behalf
+//  of a device that is not bound to the gateway
+#[derive(Debug, Deserialize)]
+struct IotCoreError {
+    error_type: Option<String>,
+    description: Option<String>,
+    device_id: Option<String>,
+}
+
+/// Publishes beacons received from a [`Scanner`](crate::scanner::Scanner) to IoT Core on behalf
+/// of the tags attached to the gateway and reacts to config and commands from IoT Core.
 pub struct IotCoreClient {
-    ssl_opts: mqtt::SslOptions,
-    conn_opts: mqtt::ConnectOptions,
-    client: mqtt::Client,
+    iotcore_config: IotCoreConfig,
+    client: Box<dyn Publisher>,
     channel_receiver: channel::Receiver<RuuviBluetoothBeacon>,
-    cnc_sender: channel::Sender<IOTCoreCNCMessageKind>,
-    jwt_factory: IotCoreAuthToken,
+    cnc_broadcast: Broadcast<IOTCoreCNCMessageKind>,
+    local_command_receiver: channel::Receiver<CNCCommandMessage>,
     config_topic: String,
     state_topic: String,
     command_topic_root: String,
-    consumer: Receiver<Option<mqtt::message::Message>>,
+    errors_topic: String,
+    diagnostics_topic: String,
+    stats_topic: String,
     collectconfig: Option<CollectConfig>,
+    // used until IoT Core delivers a collect config
+    default_collectconfig: Option<CollectConfig>,
+    // last collect config received from IoT Core, kept over restarts
+    collectconfig_file: Option<PathBuf>,
+    rollback: ConfigRollback,
+    // schedule of the active collect config and whether it was collecting when last checked
+    schedule: Option<Schedule>,
+    scheduled: Option<bool>,
     last_pause: Option<Instant>,
+    pause_buffer: PauseBuffer,
+    rate_limiter: Option<RateLimiter>,
+    // beacons received while publishing is over the rate limits, published as the limits allow
+    rate_limit_buffer: PauseBuffer,
     last_seen: Instant,
-    discovered_tags: HashMap<MacAddress, Vec<RuuviBluetoothBeacon>>,
+    last_queue_flush: Instant,
+    last_tag_state: Instant,
+    last_stats: Instant,
+    stats: StatsCollector,
+    last_envelope: Instant,
+    envelope: VecDeque<SharedBeacon>,
+    tag_sequences: HashMap<String, u64>,
+    watermarks: Watermarks,
+    queue_config: QueueConfig,
+    last_eviction_warning: Option<Instant>,
+    // queued beacons of each tag with the time they were queued, which the age of a batch is
+    //  counted from as replayed and history beacons keep their original timestamps
+    discovered_tags: HashMap<MacAddress, VecDeque<(Instant, SharedBeacon)>>,
+    // payload size of the queue of each tag, counted as beacons are queued so that the queue is
+    //  only serialized when it is published
+    queue_bytes: HashMap<MacAddress, usize>,
+    attach_retries: HashMap<MacAddress, AttachRetry>,
+    hooks: Vec<BeaconHook>,
+    scripts: Option<PayloadScripts>,
+    csv_export: Option<CsvExport>,
+    timezone: Option<chrono_tz::Tz>,
+    clock_drifts: Option<channel::Receiver<ClockDrift>>,
+    site: Option<serde_json::Value>,
+    gps: Option<Gps>,
+    sinks: Sinks,
+    alerts: Alerts,
+    battery_sampler: BatterySampler,
+    movement: MovementTracker,
+    actuators: Actuators,
+    update_config: UpdateConfig,
+    release_checks: Option<channel::Receiver<ReleaseCheck>>,
+    startup_report: Option<StartupReport>,
+    metrics: Arc<Metrics>,
+    status: SharedStatus,
 }
 
 impl IotCoreClient {
@@ -84,92 +542,145 @@ impl IotCoreClient {
         debug!("outbound mqtt topic: {}", topic);
         trace!("outbound mqtt message: {}", message);
 
-        let msg = message.as_bytes().to_vec();
         // fullfill IoT Core's odd JWT based authentication needs by disconnecting & connecting with new one
         //   when needed
-        if !self.jwt_factory.is_valid(60) || !self.client.is_connected() {
+        if self.client.credentials_expiring() || !self.client.is_connected() {
             warn!(
                 "JWT token has/is about to expire or we have no connection. Initiating reconnect."
             );
-            self.disconnect()?;
-            self.conn_opts = mqtt::ConnectOptionsBuilder::new()
-                .user_name("not_used")
-                .password(&self.jwt_factory.renew()?)
-                .ssl_options(self.ssl_opts.clone())
-                .finalize();
+            let reason = if self.client.is_connected() {
+                "JWT token expiring".to_string()
+            } else {
+                self.client
+                    .lost_reason()
+                    .unwrap_or_else(|| "connection lost".to_string())
+            };
+            self.disconnect(&reason)?;
             self.connect()?;
         }
 
-        // create message and send it
-        let mqtt_msg = mqtt::MessageBuilder::new()
-            .topic(topic)
-            .payload(msg)
-            .qos(mqtt::QOS_1)
-            .finalize();
-
-        match self.client.publish(mqtt_msg) {
-            Ok(retval) => retval,
-            Err(error) => {
-                return Err(eyre!("Error while publishing to MQTT")
-                    .with_section(move || error.to_string().header("Reason:")))
-            }
-        };
+        if let Err(error) = self.client.publish(&topic, message.as_bytes()) {
+            Metrics::inc(&self.metrics.publish_errors);
+            return Err(error);
+        }
+        if let Some(rate_limiter) = self.rate_limiter.as_mut() {
+            rate_limiter.take(message.len());
+        }
 
         Ok(())
     }
 
-    fn disconnect(&mut self) -> Result<(), Report> {
+    fn disconnect(&mut self, reason: &str) -> Result<(), Report> {
         trace!("in disconnect");
         if self.client.is_connected() {
             warn!("Disconnecting from MQTT broker");
         }
-        match self.client.disconnect(None) {
-            Ok(_) => Ok(()),
-            Err(error) => {
-                if self.client.is_connected() {
-                    Err(eyre!("Error while disconnecting MQTT broker")
-                        .with_section(move || error.to_string().header("Reason:")))
-                } else {
-                    warn!("There was an error while disconnecting MQTT broker, but we are apparently disconnected anyway: {}", error);
-                    Ok(())
-                }
-            }
+        self.record_disconnect(reason.to_string());
+        self.client.disconnect()
+    }
+
+    // end of a connection is recorded once, whether the gateway or the broker ended it
+    fn record_disconnect(&self, reason: String) {
+        let mut status = self.status.lock().unwrap();
+        let connection = &mut status.connection;
+        if let Some(connected_since) = connection.connected_since.take() {
+            let now = chrono::Utc::now();
+            info!(
+                "Disconnected from IoT core service after {} s: {}",
+                (now - connected_since).num_seconds(),
+                reason
+            );
+            connection.disconnected_at = Some(now);
+            connection.disconnect_reason = Some(reason);
         }
     }
 
     fn connect(&mut self) -> Result<(), Report> {
         trace!("in connect");
         // connect to the mqtt broker
-        match self.client.connect(self.conn_opts.clone()) {
-            Ok(_) => info!("Connected to IoT core service"),
-            Err(error) => {
-                return Err(eyre!("Error while connecting to IoT core service")
-                    .with_section(move || error.to_string().header("Reason:")))
+        let started = Instant::now();
+        let result = self.client.connect();
+        let connect_duration_ms = started.elapsed().as_millis() as u64;
+        let details = self.client.connect_details();
+        {
+            let mut status = self.status.lock().unwrap();
+            let connection = &mut status.connection;
+            if let Err(error) = &result {
+                let failure = details.failure.unwrap_or_else(|| error.to_string());
+                info!(
+                    "Connecting to IoT core service failed after {} ms: {}",
+                    connect_duration_ms, failure
+                );
+                connection.failures += 1;
+                connection.last_failure = Some(failure);
+                connection.last_failure_code = fatal::code_of(error);
+                connection.last_failure_at = Some(chrono::Utc::now());
+            } else {
+                info!(
+                    "Connected to IoT core service at {} in {} ms, CONNACK {}, session present: {}",
+                    details.endpoint.as_deref().unwrap_or("unknown endpoint"),
+                    connect_duration_ms,
+                    details.connack.as_deref().unwrap_or("unknown"),
+                    details
+                        .session_present
+                        .map_or("unknown".to_string(), |present| present.to_string())
+                );
+                if let Some(tls) = &details.tls {
+                    info!(
+                        "TLS handshake: {} with {}, ALPN {}, {} peer certificates, {} ms",
+                        tls.version,
+                        tls.cipher_suite,
+                        tls.alpn.as_deref().unwrap_or("none"),
+                        tls.peer_certificates,
+                        tls.handshake_ms
+                    );
+                }
+                connection.connects += 1;
+                connection.endpoint = details.endpoint;
+                connection.connected_since = Some(chrono::Utc::now());
+                connection.connect_duration_ms = Some(connect_duration_ms);
+                connection.connack = details.connack;
+                connection.session_present = details.session_present;
+                connection.tls = details.tls;
             }
-        };
+        }
+        result?;
+        Metrics::inc(&self.metrics.mqtt_connects);
 
         // subscribe to command and control channels
-        match self.client.subscribe_many(
-            &[
-                self.config_topic.to_string(),
-                format!("{}/#", self.command_topic_root.to_string()),
-            ],
-            &[mqtt::QOS_1, mqtt::QOS_1],
-        ) {
-            Ok(_) => {}
-            Err(error) => {
-                return Err(
-                    eyre!("Error while subscribing to command and control topics")
-                        .with_section(move || error.to_string().header("Reason:")),
-                )
-            }
-        }
+        self.client.subscribe(&[
+            self.config_topic.to_string(),
+            format!("{}/#", self.command_topic_root.to_string()),
+            self.errors_topic.to_string(),
+        ])?;
 
         self.reattach_discovered_devices();
+        self.publish_startup_report();
 
         Ok(())
     }
 
+    fn publish_startup_report(&mut self) {
+        trace!("in publish_startup_report");
+        // report is published only once, after the first succesful connect
+        if let Some(mut report) = self.startup_report.take() {
+            report.refresh_clock_status();
+            report.set_connection(self.status.lock().unwrap().connection.clone());
+            match self.publish_message(self.state_topic.clone(), self.json(&report)) {
+                Ok(_) => {
+                    info!("Published startup report to state topic.");
+                    if report.previous_failure().is_some() {
+                        FatalError::clear(Path::new(fatal::FATAL_ERROR_FILE));
+                    }
+                }
+                Err(error) => {
+                    warn!("Unable to publish startup report: {}", error);
+                    self.startup_report = Some(report);
+                }
+            }
+        }
+    }
+
     fn set_collecting_state(&mut self, enabled: bool) -> Result<(), Report> {
         trace!("in set_collecting_state");
         debug!("set_collecting_state({})", enabled);
@@ -177,11 +688,9 @@ impl IotCoreClient {
             let mut newconfig = collectconfig.clone();
             newconfig.collecting = enabled;
             self.collectconfig = Some(newconfig);
+            self.status.lock().unwrap().collecting = enabled;
             debug!("collectconfig is now: {:?}", self.collectconfig);
-            self.publish_message(
-                self.state_topic.clone(),
-                serde_json::to_string_pretty(&self.collectconfig).unwrap(),
-            )?;
+            self.publish_message(self.state_topic.clone(), self.json(&self.collectconfig))?;
         } else {
             error!(
                 "No collect config defined. Unable to change collect state to: {}",
@@ -189,35 +698,798 @@ impl IotCoreClient {
             );
         }
 
-        Ok(())
+        Ok(())
+    }
+
+    fn enable_collecting(&mut self) -> Result<(), Report> {
+        trace!("in enable_collecting");
+        let retval = self.set_collecting_state(true);
+        if retval.is_ok() {
+            self.last_pause = None;
+            self.resume_buffered();
+        }
+        retval
+    }
+
+    fn disable_collecting(&mut self) -> Result<(), Report> {
+        trace!("in disable_collecting");
+        let retval = self.set_collecting_state(false);
+        if retval.is_ok() {
+            self.last_pause = Some(Instant::now());
+        }
+        retval
+    }
+
+    // keeps a beacon received while collecting is paused, if the collect config buffers them
+    fn buffer_beacon(&mut self, beacon: &RuuviBluetoothBeacon, redirect: Option<&str>) {
+        trace!("in buffer_beacon");
+        let pause = self.collectconfig.as_ref().unwrap().pause();
+        if pause.mode() != PauseMode::BUFFER {
+            return;
+        }
+        let full = self.pause_buffer.len() >= pause.max_beacons();
+        match self.pause_buffer.push(beacon, redirect, pause.max_beacons()) {
+            Ok(_) if self.pause_buffer.len() == pause.max_beacons() => warn!(
+                "Pause buffer is full with {} beacons, further beacons are discarded until collecting is resumed.",
+                self.pause_buffer.len()
+            ),
+            Ok(_) => {}
+            Err(error) if full => trace!("{}", error),
+            Err(error) => warn!("{}", error),
+        }
+    }
+
+    // publishes or discards the beacons buffered during pause as the collect config says, they
+    //  are kept while the gateway is standby
+    fn resume_buffered(&mut self) {
+        trace!("in resume_buffered");
+        if self.pause_buffer.is_empty() || self.status.lock().unwrap().standby {
+            return;
+        }
+        let on_resume = self
+            .collectconfig
+            .as_ref()
+            .map(|collectconfig| collectconfig.pause().on_resume());
+        if on_resume != Some(ResumeAction::FLUSH) {
+            info!(
+                "Discarding {} beacons buffered during pause.",
+                self.pause_buffer.len()
+            );
+            self.pause_buffer.clear();
+            return;
+        }
+        let buffered = self.pause_buffer.take();
+        info!(
+            "Publishing {} beacons buffered during pause.",
+            buffered.len()
+        );
+        for (beacon, redirect) in buffered {
+            let address = match MacAddress::from_str(&beacon.address) {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
+            let mut beacon = Arc::new(beacon);
+            self.publish_within_limits(&mut beacon, address, redirect.as_deref());
+        }
+    }
+
+    // publishes the beacon, or keeps it in the rate limit buffer while publishing is over the
+    //  rate limits. beacons already in the buffer go first, so a new one is buffered after them
+    fn publish_within_limits(
+        &mut self,
+        msg: &mut SharedBeacon,
+        address: MacAddress,
+        redirect: Option<&str>,
+    ) {
+        trace!("in publish_within_limits");
+        if self.rate_limiter.is_none() {
+            self.publish_beacon(msg, address, redirect);
+            return;
+        }
+        let size = self.beacon_payload_size(msg);
+        if self.rate_limit_buffer.is_empty()
+            && !self.rate_limiter.as_mut().unwrap().is_limited(size)
+        {
+            self.publish_beacon(msg, address, redirect);
+            return;
+        }
+        if self.rate_limit_buffer.is_empty() {
+            debug!("Publish rate limit reached, buffering beacons until publishing is within the limits.");
+        }
+        let full = self.rate_limit_buffer.len() >= RATE_LIMIT_BUFFER_MAX_BEACONS;
+        match self
+            .rate_limit_buffer
+            .push(msg.as_ref(), redirect, RATE_LIMIT_BUFFER_MAX_BEACONS)
+        {
+            Ok(_) if self.rate_limit_buffer.len() == RATE_LIMIT_BUFFER_MAX_BEACONS => warn!(
+                "Rate limit buffer is full with {} beacons, further beacons are discarded until publishing catches up.",
+                self.rate_limit_buffer.len()
+            ),
+            Ok(_) => {}
+            Err(error) if full => trace!("{}", error),
+            Err(error) => warn!("{}", error),
+        }
+    }
+
+    // publishes beacons from the rate limit buffer in the order they were received for as long
+    //  as publishing is within the rate limits, the rest are put back for later
+    fn publish_rate_limited(&mut self) {
+        trace!("in publish_rate_limited");
+        if self.rate_limit_buffer.is_empty() {
+            return;
+        }
+        let mut buffered: VecDeque<(RuuviBluetoothBeacon, Option<String>)> =
+            self.rate_limit_buffer.take().into();
+        while let Some((beacon, redirect)) = buffered.pop_front() {
+            let address = match MacAddress::from_str(&beacon.address) {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
+            let mut beacon = Arc::new(beacon);
+            let size = self.beacon_payload_size(&beacon);
+            if self
+                .rate_limiter
+                .as_mut()
+                .map_or(false, |rate_limiter| rate_limiter.is_limited(size))
+            {
+                let beacon = Arc::try_unwrap(beacon).unwrap_or_else(|beacon| (*beacon).clone());
+                buffered.push_front((beacon, redirect));
+                break;
+            }
+            self.publish_beacon(&mut beacon, address, redirect.as_deref());
+        }
+        debug!("{} beacons left in rate limit buffer.", buffered.len());
+        if let Err(error) = self.rate_limit_buffer.put_back(buffered.into()) {
+            warn!("{}", error);
+        }
+    }
+
+    // activates a collect config and relays it to the scanner
+    fn activate_collectconfig(&mut self, collectconfig: CollectConfig) -> Result<(), Report> {
+        trace!("in activate_collectconfig");
+        let collecting = collectconfig.collecting;
+        // alert of batteries entering the critical band is raised like the other alerts
+        let mut alert_rules = collectconfig.alert_rules();
+        if let Some(rule) = collectconfig.battery().critical_rule() {
+            let mut rules = alert_rules.unwrap_or_else(|| self.alerts.local_rules().to_vec());
+            rules.push(rule);
+            alert_rules = Some(rules);
+        }
+        self.alerts.set_rules(alert_rules);
+        // a config that pauses collecting is not resumed by its schedule
+        self.schedule = match collectconfig.schedule() {
+            Ok(schedule) if collecting => schedule,
+            Ok(_) => None,
+            Err(error) => {
+                error!("Ignoring schedule of collect config: {}", error);
+                None
+            }
+        };
+        self.scheduled = None;
+        self.collectconfig = Some(collectconfig);
+        debug!("New collect config activated is '{:?}'", self.collectconfig);
+        // data fields and format of the new config change the size of queued beacons
+        self.count_queue_bytes();
+        if !collecting {
+            self.disable_collecting()?;
+        } else {
+            self.enable_collecting()?;
+        }
+        // send config to CNC channel
+        self.cnc_broadcast.send(IOTCoreCNCMessageKind::CONFIG(
+            self.collectconfig.clone().map(Box::new),
+        ));
+        Ok(())
+    }
+
+    // acknowledges a collect config received from IoT Core in the state of the gateway, so that
+    //  the rollout of a config can be followed across gateways
+    fn publish_config_ack(&mut self, version: &str, rejected_reason: Option<String>) {
+        trace!("in publish_config_ack");
+        let mut ack = json!({
+            "version": version,
+            "status": if rejected_reason.is_some() { "rejected" } else { "accepted" },
+            "timestamp": chrono::Utc::now(),
+        });
+        if let Some(reason) = rejected_reason {
+            ack["reason"] = json!(reason);
+        }
+        if let Err(error) = self.publish_message(
+            self.state_topic.clone(),
+            self.json(&json!({ "config_ack": ack })),
+        ) {
+            warn!("Unable to acknowledge collect config: {}", error);
+        }
+    }
+
+    // keeps the collect config over restarts
+    fn save_collectconfig(&self, collectconfig: &CollectConfig) {
+        trace!("in save_collectconfig");
+        if let Some(file) = &self.collectconfig_file {
+            if let Err(error) =
+                fs::write(file, serde_json::to_string_pretty(collectconfig).unwrap())
+            {
+                warn!(
+                    "Unable to save collect config to {}: {}",
+                    file.display(),
+                    error
+                );
+            }
+        }
+    }
+
+    // collect config saved in the previous run, unless it has been rolled back since
+    fn saved_collectconfig(&self) -> Option<CollectConfig> {
+        trace!("in saved_collectconfig");
+        let file = self.collectconfig_file.as_ref()?;
+        let json = fs::read_to_string(file).ok()?;
+        match serde_json::from_str::<CollectConfig>(&json) {
+            Ok(collectconfig) if !self.rollback.is_rejected(&collectconfig) => Some(collectconfig),
+            Ok(_) => None,
+            Err(error) => {
+                warn!(
+                    "Ignoring saved collect config in {}: {}",
+                    file.display(),
+                    error
+                );
+                None
+            }
+        }
+    }
+
+    // applies a collect config received from IoT Core and acknowledges it
+    fn handle_config(&mut self, payload: &str) -> Result<(), Report> {
+        trace!("in handle_config");
+        // IoT Core delivers an empty document when no config has been set for the gateway
+        if payload.trim().is_empty() {
+            info!("No collect config set for the gateway in IoT Core.");
+            return Ok(());
+        }
+        // we received new config, decode it
+        let new_collectconfig: CollectConfig = match serde_json::from_str(payload) {
+            Ok(config) => config,
+            Err(error) => {
+                error!("Unable to parse new collect config: {}", error);
+                self.publish_config_ack(&payload_hash(payload), Some(error.to_string()));
+                return Ok(());
+            }
+        };
+        let version = new_collectconfig.hash();
+        if self.rollback.is_rejected(&new_collectconfig) {
+            warn!(
+                "Ignoring collect config version {} that has been rolled back.",
+                version
+            );
+            self.publish_config_ack(
+                &version,
+                Some("rolled back after repeated failures".to_string()),
+            );
+            return Ok(());
+        }
+        if let Err(error) = new_collectconfig.schedule() {
+            error!("Invalid schedule in new collect config: {}", error);
+            self.publish_config_ack(&version, Some(error.to_string()));
+            return Ok(());
+        }
+        if Some(&new_collectconfig) != self.collectconfig.as_ref() {
+            self.rollback.applied(&new_collectconfig, self.failures());
+            self.save_collectconfig(&new_collectconfig);
+            self.activate_collectconfig(new_collectconfig)?;
+        } else {
+            debug!("Not replacing active collect config with identical one.");
+        }
+        self.publish_config_ack(&version, None);
+        Ok(())
+    }
+
+    // reports a command that was ignored to the diagnostics subfolder, so that the operator
+    //  sending it finds out
+    fn publish_command_error(&mut self, payload: &str, reason: String) {
+        trace!("in publish_command_error");
+        let error = json!({
+            "command_error": {
+                "payload_hash": payload_hash(payload),
+                "reason": reason,
+                "timestamp": chrono::Utc::now(),
+            }
+        });
+        if let Err(error) = self.publish_message(self.diagnostics_topic.clone(), self.json(&error))
+        {
+            warn!("Unable to report CNC command error: {}", error);
+        }
+    }
+
+    // reports a failed update to the diagnostics subfolder
+    fn publish_update_error(&mut self, reason: String) {
+        trace!("in publish_update_error");
+        let error = json!({
+            "update_error": {
+                "reason": reason,
+                "timestamp": chrono::Utc::now(),
+            }
+        });
+        if let Err(error) = self.publish_message(self.diagnostics_topic.clone(), self.json(&error))
+        {
+            warn!("Unable to report update error: {}", error);
+        }
+    }
+
+    // failures that count against a collect config on probation
+    fn failures(&self) -> u64 {
+        self.metrics.publish_errors.load(Ordering::Relaxed)
+            + self.metrics.scanner_restarts.load(Ordering::Relaxed)
+    }
+
+    // rolls back a collect config that has caused too many failures since it was applied
+    fn check_rollback(&mut self) -> Result<(), Report> {
+        trace!("in check_rollback");
+        if let Some(rollback) = self.rollback.check(self.failures()) {
+            error!(
+                "Collect config version {} caused {} failures. Rolling back to version {}.",
+                rollback.rejected.hash(),
+                rollback.failures,
+                rollback.restored.hash()
+            );
+            if let Err(error) =
+                self.publish_message(self.state_topic.clone(), self.json(&rollback.report()))
+            {
+                warn!("Unable to publish config rollback report: {}", error);
+            }
+            self.save_collectconfig(&rollback.restored);
+            self.activate_collectconfig(rollback.restored)?;
+        }
+        Ok(())
+    }
+
+    // pauses and resumes collecting as the schedule of the collect config opens and closes, and
+    //  reports the transitions to the state of the gateway. commands received in between take
+    //  effect until the next transition
+    fn check_schedule(&mut self) -> Result<(), Report> {
+        trace!("in check_schedule");
+        let collecting = match &self.schedule {
+            Some(schedule) => schedule.collecting_at(&chrono::Utc::now(), self.timezone.as_ref()),
+            None => return Ok(()),
+        };
+        if self.scheduled == Some(collecting) {
+            return Ok(());
+        }
+        self.scheduled = Some(collecting);
+        let active = matches!(&self.collectconfig, Some(collectconfig) if collectconfig.collecting);
+        if collecting == active {
+            debug!("collecting is already as scheduled: {}", collecting);
+        } else if collecting {
+            info!("Collection schedule opened, resuming collecting beacons.");
+            self.enable_collecting()?;
+        } else {
+            info!("Collection schedule closed, pausing collecting beacons.");
+            self.disable_collecting()?;
+        }
+        let transition = json!({
+            "schedule": {
+                "collecting": collecting,
+                "timestamp": chrono::Utc::now(),
+            }
+        });
+        if let Err(error) = self.publish_message(self.state_topic.clone(), self.json(&transition)) {
+            warn!(
+                "Unable to publish collection schedule transition: {}",
+                error
+            );
+        }
+        Ok(())
+    }
+
+    // reacts to a CNC command and returns Some(exit) if client needs to stop where exit
+    //  signals a clean shutdown instead of a restart
+    fn handle_command(&mut self, command: &CNCCommandMessage) -> Result<Option<bool>, Report> {
+        trace!("in handle_command");
+        match command.command {
+            CNCCommand::COLLECT => {
+                info!("CNC command received: COLLECT beacons");
+                self.enable_collecting()?;
+            }
+            CNCCommand::PAUSE => {
+                warn!("CNC command received: PAUSE collecting beacons");
+                self.disable_collecting()?;
+            }
+            CNCCommand::SHUTDOWN => {
+                warn!("CNC command received: SHUTDOWN software");
+                self.detach_devices();
+                return Ok(Some(true));
+            }
+            CNCCommand::RESET => {
+                warn!("CNC command received: RESET software");
+                self.disconnect("reset command")?;
+                // send the current collect configuration to cnc channel so that
+                //  bluetooth thread can use it after it recovers
+                self.cnc_broadcast.send(IOTCoreCNCMessageKind::CONFIG(
+                    self.collectconfig.clone().map(Box::new),
+                ));
+                return Ok(Some(false));
+            }
+            CNCCommand::DUTYCYCLE => {
+                info!(
+                    "CNC command received: DUTYCYCLE scanning {:?}",
+                    command.duty_cycle
+                );
+                // scanner receives the command through cnc channel, here it is only recorded
+                //  into collect config and published in state
+                if let Some(collectconfig) = &self.collectconfig {
+                    let mut newconfig = collectconfig.clone();
+                    newconfig.duty_cycle = command.duty_cycle.clone();
+                    self.collectconfig = Some(newconfig);
+                    self.publish_message(self.state_topic.clone(), self.json(&self.collectconfig))?;
+                }
+            }
+            CNCCommand::REBIND => {
+                info!("CNC command received: REBIND retrying attach of unbound tags");
+                self.attach_retries.clear();
+                for tag_status in self.status.lock().unwrap().tags.values_mut() {
+                    tag_status.unbound = false;
+                }
+            }
+            CNCCommand::UPDATE => {
+                warn!("CNC command received: UPDATE software");
+                return Ok(self.install_update(None));
+            }
+        };
+
+        Ok(None)
+    }
+
+    // installs the release, or the one configured for the update command, and returns
+    //  Some(true) if the client needs to shut down for the new version to be started
+    fn install_update(&mut self, release: Option<Release>) -> Option<bool> {
+        trace!("in install_update");
+        if let Err(error) = updater::update(&self.update_config, release.as_ref()) {
+            // the gateway keeps running the current version
+            error!("Update failed: {}", error);
+            self.publish_update_error(error.to_string());
+            return None;
+        }
+        info!("Update installed, shutting down to restart with the new version.");
+        self.status.lock().unwrap().updated = true;
+        // scanner ignores the update command, so it is told to shut down as well
+        self.cnc_broadcast
+            .send(IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+                command: CNCCommand::SHUTDOWN,
+                duty_cycle: None,
+            })));
+        self.detach_devices();
+        Some(true)
+    }
+
+    // reports the drift of the clock in the state of the gateway, and in states of tags from
+    //  now on, so that consumers can assess the quality of timestamps
+    fn handle_clock_drift(&mut self, clock_drift: ClockDrift) {
+        trace!("in handle_clock_drift");
+        debug!("clock drift is {} ms", clock_drift.clock_drift_ms);
+        self.status.lock().unwrap().clock_drift_ms = Some(clock_drift.clock_drift_ms);
+        if let Err(error) =
+            self.publish_message(self.state_topic.clone(), self.json(&clock_drift.payload()))
+        {
+            warn!("Unable to report clock drift: {}", error);
+        }
+    }
+
+    // reports the versions found by the release checker in the state of the gateway, and
+    //  installs a release rolled out to the gateway if configured to
+    fn handle_release_check(&mut self, release_check: ReleaseCheck) -> Option<bool> {
+        trace!("in handle_release_check");
+        if let Some(available) = &release_check.state.available {
+            info!(
+                "Version {} is available, rolled out to this gateway: {}",
+                available, release_check.state.rolled_out
+            );
+        }
+        if let Err(error) = self.publish_message(
+            self.state_topic.clone(),
+            self.json(&release_check.state.payload()),
+        ) {
+            warn!("Unable to report software versions: {}", error);
+        }
+        match release_check.release {
+            Some(release) if self.update_config.auto_install() => {
+                self.install_update(Some(release))
+            }
+            _ => None,
+        }
+    }
+
+    // errors of messages published on behalf of tags are reported asynchronously, so a failed
+    //  attach shows up here instead of as a failed publish
+    fn handle_error(&mut self, payload: &str) {
+        trace!("in handle_error");
+        let iotcore_error: IotCoreError = match serde_json::from_str(payload) {
+            Ok(iotcore_error) => iotcore_error,
+            Err(error) => {
+                error!("Unable to parse IoT Core error '{}': {}", payload, error);
+                return;
+            }
+        };
+        error!(
+            "IoT Core reported error {} for device '{}': {}",
+            iotcore_error.error_type.as_deref().unwrap_or("UNKNOWN"),
+            iotcore_error.device_id.as_deref().unwrap_or("unknown"),
+            iotcore_error.description.as_deref().unwrap_or("")
+        );
+        // errors of the gateway itself (e.g. quota exceeded) have nothing to back off from
+        let address = match iotcore_error
+            .device_id
+            .and_then(|device_id| MacAddress::parse_str(&device_id).ok())
+        {
+            Some(address) => address,
+            None => return,
+        };
+        let backoff = self.schedule_attach_retry(&address);
+        warn!(
+            "Ruuvi tag ({}) marked unbound. Attaching it is retried in {} seconds.",
+            address
+                .to_string(MacAddressFormat::Canonical)
+                .to_uppercase(),
+            backoff.as_secs()
+        );
+        // queued beacons can not be published before the tag has been attached again
+        self.discovered_tags.remove(&address);
+        self.queue_bytes.remove(&address);
+        self.update_tag_status(&address, |tag_status| tag_status.unbound = true);
+    }
+
+    // returns the time until the next attach attempt of the tag
+    fn schedule_attach_retry(&mut self, address: &MacAddress) -> Duration {
+        let failures = self
+            .attach_retries
+            .get(address)
+            .map_or(0, |retry| retry.failures)
+            + 1;
+        let backoff =
+            (ATTACH_BACKOFF_MIN * 2u32.pow((failures - 1).min(16))).min(ATTACH_BACKOFF_MAX);
+        self.attach_retries.insert(
+            *address,
+            AttachRetry {
+                failures,
+                retry_at: Instant::now() + backoff,
+            },
+        );
+        backoff
+    }
+
+    fn update_tag_status<F: FnOnce(&mut TagStatus)>(&self, address: &MacAddress, update: F) {
+        let mut status = self.status.lock().unwrap();
+        let tag_status = status
+            .tags
+            .entry(
+                address
+                    .to_string(MacAddressFormat::Canonical)
+                    .to_uppercase(),
+            )
+            .or_default();
+        update(tag_status);
+    }
+
+    // publish is acknowledged by the broker when publish_message returns, so this is the end
+    //  of the pipeline for latency measurements
+    fn record_published(&mut self, beacons: &[SharedBeacon]) {
+        self.watermarks.update(beacons);
+        self.metrics
+            .beacons_published
+            .fetch_add(beacons.len() as u64, Ordering::Relaxed);
+        let now = chrono::Utc::now();
+        for beacon in beacons {
+            let latency = (now - beacon.timestamp).num_milliseconds().max(0);
+            self.metrics
+                .publish_latency
+                .observe(latency as f64 / 1000.0);
+        }
+    }
+
+    // drops a beacon from the queue of the tag, or from queues of all tags and the envelope, if
+    //  there are more beacons than allowed. beacons are dropped oldest or newest first
+    fn enforce_queue_limits(&mut self, address: Option<&MacAddress>) {
+        trace!("in enforce_queue_limits");
+        let eviction = self.queue_config.eviction();
+        let max_beacons_per_tag = self.queue_config.max_beacons_per_tag();
+        let mut evicted = 0;
+        // beacons dropped from the queues of tags, to take them out of the payload sizes
+        let mut dropped: Vec<(MacAddress, SharedBeacon)> = Vec::new();
+        if let Some(address) = address {
+            if let Some(queue) = self.discovered_tags.get_mut(address) {
+                while queue.len() > max_beacons_per_tag {
+                    let beacon = match eviction {
+                        Eviction::OLDEST => queue.pop_front(),
+                        Eviction::NEWEST => queue.pop_back(),
+                    };
+                    dropped.extend(beacon.map(|(_, beacon)| (*address, beacon)));
+                    evicted += 1;
+                }
+            }
+        }
+        while self.queued_beacons() > self.queue_config.max_beacons() {
+            // gateway sequence tells the order beacons were received in
+            let mut candidates: Vec<(u64, Option<MacAddress>)> = self
+                .discovered_tags
+                .iter()
+                .filter_map(|(address, queue)| {
+                    let beacon = match eviction {
+                        Eviction::OLDEST => queue.front(),
+                        Eviction::NEWEST => queue.back(),
+                    };
+                    beacon.map(|(_, beacon)| (beacon.sequence, Some(*address)))
+                })
+                .collect();
+            let envelope_beacon = match eviction {
+                Eviction::OLDEST => self.envelope.front(),
+                Eviction::NEWEST => self.envelope.back(),
+            };
+            candidates.extend(envelope_beacon.map(|beacon| (beacon.sequence, None)));
+            let candidate = match eviction {
+                Eviction::OLDEST => candidates.into_iter().min_by_key(|(sequence, _)| *sequence),
+                Eviction::NEWEST => candidates.into_iter().max_by_key(|(sequence, _)| *sequence),
+            };
+            let beacon = match candidate {
+                Some((_, Some(address))) => {
+                    let queue = self.discovered_tags.get_mut(&address).unwrap();
+                    match eviction {
+                        Eviction::OLDEST => queue.pop_front(),
+                        Eviction::NEWEST => queue.pop_back(),
+                    }
+                    .map(|(_, beacon)| beacon)
+                }
+                Some((_, None)) => match eviction {
+                    Eviction::OLDEST => self.envelope.pop_front(),
+                    Eviction::NEWEST => self.envelope.pop_back(),
+                },
+                None => break,
+            };
+            if let (Some((_, Some(address))), Some(beacon)) = (candidate, beacon) {
+                dropped.push((address, beacon));
+            }
+            evicted += 1;
+        }
+        for (address, beacon) in dropped {
+            let size = self.beacon_payload_size(&beacon);
+            if let Some(bytes) = self.queue_bytes.get_mut(&address) {
+                *bytes = bytes.saturating_sub(size);
+            }
+        }
+        if evicted == 0 {
+            return;
+        }
+        self.metrics
+            .queue_evictions
+            .fetch_add(evicted, Ordering::Relaxed);
+        debug!("Dropped {} beacons from full queues.", evicted);
+        // warnings are limited to one a minute while queues stay full
+        let warn = match self.last_eviction_warning {
+            Some(last_warning) => last_warning.elapsed() >= Duration::from_secs(60),
+            None => true,
+        };
+        if warn {
+            warn!(
+                "Beacon queues are full ({} beacons). Dropping {} beacons.",
+                self.queued_beacons(),
+                match eviction {
+                    Eviction::OLDEST => "oldest",
+                    Eviction::NEWEST => "newest",
+                }
+            );
+            self.last_eviction_warning = Some(Instant::now());
+        }
+    }
+
+    fn queued_beacons(&self) -> usize {
+        self.discovered_tags
+            .values()
+            .map(|queue| queue.len())
+            .sum::<usize>()
+            + self.envelope.len()
     }
 
-    fn enable_collecting(&mut self) -> Result<(), Report> {
-        trace!("in enable_collecting");
-        let retval = self.set_collecting_state(true);
-        if retval.is_ok() {
-            self.last_pause = None;
+    fn update_queue_metrics(&self) {
+        let memory: usize = self
+            .discovered_tags
+            .values()
+            .flatten()
+            .map(|(_, beacon)| beacon)
+            .chain(self.envelope.iter())
+            .map(|beacon| beacon_memory(beacon))
+            .sum();
+        Metrics::set(&self.metrics.queued_beacons, self.queued_beacons() as u64);
+        Metrics::set(&self.metrics.queue_memory_bytes, memory as u64);
+    }
+
+    // assigns the next sequence number of the tag to a beacon that is queued for publishing.
+    //  beacons are published in this order, retries included, so a gap means lost beacons. the
+    //  beacon is not shared yet, so it is not copied
+    fn sequence(&mut self, beacon: &mut SharedBeacon) {
+        // numbering continues from the last published beacon of the tag
+        let watermark = self.watermarks.get(&beacon.address);
+        let tag_sequence = self
+            .tag_sequences
+            .entry(beacon.address.clone())
+            .or_insert(watermark);
+        *tag_sequence += 1;
+        Arc::make_mut(beacon).tag_sequence = Some(*tag_sequence);
+    }
+
+    // publishes the alert on behalf of the tag to the alerts subfolder of its events topic, unless
+    //  the gateway is not publishing beacons either
+    fn publish_alert(
+        &mut self,
+        address: &MacAddress,
+        beacon: &RuuviBluetoothBeacon,
+        alert: &Alert,
+    ) {
+        trace!("in publish_alert");
+        info!(
+            "Alert on {} of '{}' {:?}: {} {:?} {}",
+            alert.metric, alert.address, alert.state, alert.value, alert.operator, alert.threshold
+        );
+        let collecting = match &self.collectconfig {
+            Some(collectconfig) => collectconfig.collecting,
+            None => false,
+        };
+        if self.status.lock().unwrap().standby || !collecting || !self.try_attach_device(address) {
+            return;
+        }
+        let topic = self
+            .device_event_topic(address, beacon, Some(self.alerts.subfolder()))
+            .unwrap();
+        if let Err(error) = self.publish_message(topic, alert.payload().to_string()) {
+            error!("Error on publishing alert to MQTT: '{}'", error);
         }
-        retval
     }
 
-    fn disable_collecting(&mut self) -> Result<(), Report> {
-        trace!("in disable_collecting");
-        let retval = self.set_collecting_state(false);
-        if retval.is_ok() {
-            self.last_pause = Some(Instant::now());
+    // reports the switched actuator in the state of the gateway, the pin stays switched even if
+    //  the report is lost
+    fn publish_actuator_state(&mut self, state: &ActuatorState) {
+        trace!("in publish_actuator_state");
+        info!(
+            "Actuator '{}' switched {} by {} {} of '{}'",
+            state.name,
+            if state.on { "on" } else { "off" },
+            state.metric,
+            state.value,
+            state.address
+        );
+        if let Err(error) =
+            self.publish_message(self.state_topic.clone(), self.json(&state.payload()))
+        {
+            warn!("Unable to report actuator state: {}", error);
         }
-        retval
     }
 
+    fn update_publish_status(&self, address: &MacAddress, published: bool) {
+        self.update_tag_status(address, |tag_status| {
+            tag_status.last_publish = Some(chrono::Utc::now());
+            tag_status.last_publish_ok = Some(published);
+        });
+    }
+
+    /// Runs until shut down. Returns true on shutdown and false when the client and the scanner
+    /// should be restarted.
     pub fn start_client(&mut self) -> Result<bool, Report> {
         trace!("in start_client");
         // cycle connection state
         if self.client.is_connected() {
             trace!("Entering to start_client() from unclean restart.");
-            self.disconnect()?;
+            self.disconnect("client restart")?;
         }
         self.connect()?;
+        // the collect config of the previous run, or else the local default, is used until IoT
+        //  Core delivers one, if ever
+        if self.collectconfig.is_none() {
+            if let Some(collectconfig) = self.saved_collectconfig() {
+                info!(
+                    "Using collect config version {} saved in previous run until IoT Core delivers one.",
+                    collectconfig.hash()
+                );
+                self.activate_collectconfig(collectconfig)?;
+            } else if let Some(collectconfig) = self.default_collectconfig.clone() {
+                info!("Using local default collect config until IoT Core delivers one.");
+                self.activate_collectconfig(collectconfig)?;
+            }
+        }
 
         self.last_seen = Instant::now();
         // loop messages and wait for a ready signal
@@ -234,189 +1506,802 @@ impl IotCoreClient {
                     self.collectconfig.as_ref().unwrap().no_beacons_threshold()
                 );
                 // emit reset signal to the cnc channel
-                self.cnc_sender
+                self.cnc_broadcast
                     .send(IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
                         command: CNCCommand::RESET,
-                    })))
-                    .unwrap(); // TODO: fix unwrap
-                               // exit cleanly and issue restart from main loop
+                        duty_cycle: None,
+                    })));
+                // exit cleanly and issue restart from main loop
                 if self.client.is_connected() {
-                    self.disconnect()?;
+                    self.disconnect("no beacons detected")?;
                 }
                 return Ok(false);
             }
 
             // check into the subscriptions if there are any incoming cnc messages
-            if let Ok(Some(msg)) = self.consumer.try_recv() {
+            if let Some(msg) = self.client.try_receive() {
                 trace!("incoming CNC message: '{:?}'", msg);
 
-                if msg.topic() == self.config_topic {
-                    // we received new config, decode it
-                    let new_collectconfig: Option<CollectConfig> =
-                        match serde_json::from_str(&msg.payload_str()) {
-                            Ok(config) => Some(config),
-                            Err(error) => {
-                                error!("Unable to parse new collect config: {}", error);
-                                None
-                            }
-                        };
-                    if new_collectconfig != self.collectconfig && new_collectconfig.is_some() {
-                        self.collectconfig = new_collectconfig;
-                        debug!("New collect config activated is '{:?}'", self.collectconfig);
-                        if !&self.collectconfig.as_ref().unwrap().collecting {
-                            self.disable_collecting()?;
-                        } else {
-                            self.enable_collecting()?;
-                        }
-                        // send config to CNC channel
-                        self.cnc_sender
-                            .send(IOTCoreCNCMessageKind::CONFIG(self.collectconfig.clone()))
-                            .unwrap(); // TODO: fix unwrap
-                    } else {
-                        debug!("Not replacing active collect config with identical one.");
-                    }
-                } else if msg.topic().starts_with(&self.command_topic_root) {
+                if msg.topic == self.config_topic {
+                    self.handle_config(&msg.payload)?;
+                } else if msg.topic.starts_with(&self.command_topic_root) {
                     // command was sent into root or subfolder of command channel
                     // TODO: implement subfolder support
                     let command: Option<CNCCommandMessage> =
-                        match serde_json::from_str(&msg.payload_str()) {
+                        match CNCCommandMessage::parse(&msg.payload) {
                             Ok(command) => Some(command),
-                            Err(error) => {
-                                error!("Unable to parse CNC command: {}", error);
+                            Err(reason) => {
+                                error!("Unable to parse CNC command: {}", reason);
+                                self.publish_command_error(&msg.payload, reason);
                                 None
                             }
                         };
                     // also publish the command to CNC channel
-                    self.cnc_sender
-                        .send(IOTCoreCNCMessageKind::COMMAND(command.clone()))
-                        .unwrap(); // TODO: fix unwrap
+                    self.cnc_broadcast
+                        .send(IOTCoreCNCMessageKind::COMMAND(command.clone()));
                     if let Some(command) = command {
                         // react locally to the message as well
-                        match command.command {
-                            CNCCommand::COLLECT => {
-                                info!("CNC command received: COLLECT beacons");
-                                self.enable_collecting()?;
-                            }
-                            CNCCommand::PAUSE => {
-                                warn!("CNC command received: PAUSE collecting beacons");
-                                self.disable_collecting()?;
-                            }
-                            CNCCommand::SHUTDOWN => {
-                                warn!("CNC command received: SHUTDOWN software");
-                                self.detach_devices();
+                        if let Some(exit) = self.handle_command(&command)? {
+                            if exit {
                                 break;
                             }
-                            CNCCommand::RESET => {
-                                warn!("CNC command received: RESET software");
-                                self.disconnect()?;
-                                // send the current collect configuration to cnc channel so that
-                                //  bluetooth thread can use it after it recovers
-                                self.cnc_sender
-                                    .send(IOTCoreCNCMessageKind::CONFIG(self.collectconfig.clone()))
-                                    .unwrap(); // TODO: fix unwrap
-                                return Ok(false);
-                            }
-                        };
+                            return Ok(false);
+                        }
                     }
+                } else if msg.topic == self.errors_topic {
+                    self.handle_error(&msg.payload);
                 } else {
                     debug!("Unimplemented CNC topic in received message.");
                 }
             }
 
-            // check into the channel to see if there are beacons to relay to the mqtt broker
-            if let Ok(msg) = self.channel_receiver.try_recv() {
-                debug!("new incoming ruuvi tag beacon from bt thread: {:?}", msg);
-                // update the last_seen counter to verify internally that we are doing work
-                self.last_seen = Instant::now();
+            // check if the release checker found a new release
+            let release_check = self
+                .release_checks
+                .as_ref()
+                .and_then(|release_checks| release_checks.try_recv().ok());
+            if let Some(release_check) = release_check {
+                if self.handle_release_check(release_check).is_some() {
+                    break;
+                }
+            }
+
+            // record the drift of the clock measured by the drift monitor
+            let clock_drift = self
+                .clock_drifts
+                .as_ref()
+                .and_then(|clock_drifts| clock_drifts.try_recv().ok());
+            if let Some(clock_drift) = clock_drift {
+                self.handle_clock_drift(clock_drift);
+            }
+
+            // check if commands were issued locally on the gateway (e.g. from web dashboard)
+            if let Ok(command) = self.local_command_receiver.try_recv() {
+                debug!("incoming local command: '{:?}'", command);
+                // relay the command to CNC channel as if it was received from IoT Core
+                self.cnc_broadcast
+                    .send(IOTCoreCNCMessageKind::COMMAND(Some(command.clone())));
+                if let Some(exit) = self.handle_command(&command)? {
+                    if exit {
+                        break;
+                    }
+                    return Ok(false);
+                }
+            }
+
+            // relay all beacons received since the previous round, so that bursts of beacons are
+            //  not held back by the pause between rounds
+            while let Ok(msg) = self.channel_receiver.try_recv() {
+                self.receive_beacon(msg)?;
+            }
+
+            self.check_rollback()?;
+            self.check_schedule()?;
 
-                let address = MacAddress::from_str(&msg.address).unwrap();
+            // check once a second for partial batches that have waited for too long
+            if self.last_queue_flush.elapsed() >= Duration::from_secs(1) {
+                if let Some(collectconfig) = &self.collectconfig {
+                    if collectconfig.collecting
+                        && self.client.is_connected()
+                        && !self.status.lock().unwrap().standby
+                    {
+                        self.flush_stale_queues();
+                        self.publish_rate_limited();
+                    }
+                }
+                self.update_queue_metrics();
+                self.last_queue_flush = Instant::now();
+            }
 
-                let mut queue: Vec<RuuviBluetoothBeacon> = match self.discovered_tags.get(&address)
+            let tag_state_interval = self
+                .collectconfig
+                .as_ref()
+                .and_then(|collectconfig| collectconfig.tag_state_interval());
+            if let Some(interval) = tag_state_interval {
+                if self.last_tag_state.elapsed() >= Duration::from_secs(interval)
+                    && self.client.is_connected()
+                    && !self.status.lock().unwrap().standby
                 {
-                    Some(queue) => queue.to_vec(),
-                    None => Vec::new(),
-                };
+                    self.publish_tag_states();
+                    self.last_tag_state = Instant::now();
+                }
+            }
 
-                // submit the beacon to iotcore if collecting them is enabled
-                if self.collectconfig.as_ref().unwrap().collecting {
-                    if self.try_attach_device(&address) {
-                        let topic = self.device_event_topic(&address).unwrap();
-
-                        if self.collectconfig.as_ref().unwrap().collection_size() <= 1 {
-                            trace!("publish individual beacon");
-                            match self
-                                .publish_message(topic, serde_json::to_string_pretty(&msg).unwrap())
-                            {
-                                Ok(_) => {}
-                                Err(error) => error!(
-                                    "Error on publishing message to MQTT: '{}'. Beacon lost.",
-                                    error
-                                ),
-                            };
-                        } else if queue.len()
-                            >= self.collectconfig.as_ref().unwrap().collection_size() - 1
-                        {
-                            trace!("publish beacon queue");
-                            queue.push(msg);
-                            debug!(
-                                "Message queue size for '{}': {}/{}",
-                                address,
-                                queue.len(),
-                                self.collectconfig.as_ref().unwrap().collection_size()
-                            );
-                            match self.publish_message(
-                                topic,
-                                serde_json::to_string_pretty(&queue).unwrap(),
-                            ) {
-                                Ok(_) => {
-                                    self.discovered_tags.insert(address, Vec::new());
-                                }
-                                Err(error) => error!(
-                                    "Error on publishing message queue to MQTT: '{}'. Will retry.",
-                                    error
-                                ),
-                            };
-                        } else {
-                            trace!("add beacon to queue");
-                            // add beacon to queue
-                            queue.push(msg);
-                            debug!(
-                                "Message queue size for '{}': {}/{}",
-                                address,
-                                queue.len(),
-                                self.collectconfig.as_ref().unwrap().collection_size()
+            let stats_interval = self
+                .collectconfig
+                .as_ref()
+                .and_then(|collectconfig| collectconfig.stats_interval());
+            if let Some(interval) = stats_interval {
+                if self.last_stats.elapsed() >= Duration::from_secs(interval)
+                    && self.client.is_connected()
+                {
+                    self.publish_stats();
+                    self.last_stats = Instant::now();
+                }
+            }
+
+            // beacons left in the envelope when envelopes are turned off are published right away
+            let envelope_interval = self
+                .collectconfig
+                .as_ref()
+                .and_then(|collectconfig| collectconfig.envelope_interval());
+            let envelope_due = match envelope_interval {
+                Some(interval) => self.last_envelope.elapsed() >= Duration::from_secs(interval),
+                None => true,
+            };
+            if envelope_due && self.client.is_connected() && !self.status.lock().unwrap().standby {
+                if !self.envelope.is_empty() {
+                    self.publish_envelopes();
+                }
+                self.last_envelope = Instant::now();
+            }
+
+            Metrics::set(
+                &self.metrics.discovered_tags,
+                self.discovered_tags.len() as u64,
+            );
+            self.refresh_status();
+
+            // sleep for a while to reduce amount of CPU burn and idle for a while
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        self.disconnect("shutdown")?;
+
+        Ok(true)
+    }
+
+    // relays a beacon from the scanner to alerts, actuators, queues and sinks
+    fn receive_beacon(&mut self, mut msg: RuuviBluetoothBeacon) -> Result<(), Report> {
+        trace!("in receive_beacon");
+        debug!("new incoming ruuvi tag beacon from bt thread: {:?}", msg);
+        // update the last_seen counter to verify internally that we are doing work
+        self.last_seen = Instant::now();
+        // replayed beacons keep the position they were recorded with
+        if msg.position.is_none() {
+            msg.position = self
+                .gps
+                .as_ref()
+                .and_then(|gps| gps.position())
+                .map(Box::new);
+        }
+        let received = hooks::apply_hooks(&self.hooks, msg);
+        // beacons are published by their tag address, so one that is not valid is skipped
+        let received =
+            received.and_then(|(msg, redirect)| match MacAddress::from_str(&msg.address) {
+                Ok(address) => Some((msg, redirect, address)),
+                Err(_) => {
+                    warn!("Skipping beacon with invalid tag address '{}'", msg.address);
+                    None
+                }
+            });
+        if let Some((msg, redirect, address)) = received {
+            // the beacon is shared with queues, sinks and gateway status from here on
+            let mut msg: SharedBeacon = Arc::new(msg);
+            // local export does not depend on collecting or the gateway being standby
+            if let Some(csv_export) = self.csv_export.as_mut() {
+                if let Err(error) = csv_export.write(&msg) {
+                    warn!("{}", error);
+                }
+            }
+            self.stats.received(&msg.address);
+            for alert in self.alerts.check(&msg) {
+                self.publish_alert(&address, &msg, &alert);
+            }
+            // actuators are switched even when nothing is published
+            for state in self.actuators.check(&msg) {
+                self.publish_actuator_state(&state);
+            }
+            // movement is accumulated also from beacons that are not published
+            self.movement.record(&msg);
+
+            // submit the beacon to iotcore if collecting them is enabled
+            if self.status.lock().unwrap().standby {
+                trace!("standby gateway does not publish beacons");
+            } else if self.collectconfig.is_none() {
+                debug!("No collect config received yet, beacon is not published.");
+            } else if !self
+                .battery_sampler
+                .sample(&self.collectconfig.as_ref().unwrap().battery(), &msg)
+            {
+                trace!("beacon of tag with low battery skipped");
+            } else if self.collectconfig.as_ref().unwrap().collecting {
+                self.publish_within_limits(&mut msg, address, redirect.as_deref());
+            } else {
+                trace!("beacon collection is paused");
+                self.buffer_beacon(&msg, redirect.as_deref());
+                if let Some(last_pause) = self.last_pause {
+                    if last_pause.elapsed() >= Duration::from_secs(4 * 60) {
+                        // we are paused, so to avoid timeout due to lack of published messages to broker we occasionally will need to
+                        //  publish our state to avoid that. as a short hand we essentially do a pause again.
+                        self.disable_collecting()?;
+                        warn!("Beacon collection is paused.");
+                    }
+                } else {
+                    error!("Beacon collection is paused, but paused state was not established correctly.")
+                }
+            }
+            // sinks and gateway status get the beacon last, so that it is not shared before
+            //  its tag sequence is assigned
+            self.sinks.send(&msg);
+            self.update_tag_status(&address, |tag_status| {
+                tag_status.last_beacon = Some(msg);
+            });
+        }
+        Ok(())
+    }
+
+    // publishes the beacon on behalf of its tag, or queues it into its batch or the envelope
+    fn publish_beacon(
+        &mut self,
+        msg: &mut SharedBeacon,
+        address: MacAddress,
+        redirect: Option<&str>,
+    ) {
+        trace!("in publish_beacon");
+        if self
+            .collectconfig
+            .as_ref()
+            .unwrap()
+            .data_fields()
+            .iter()
+            .any(|field| field == "movement")
+        {
+            if let Some(summary) = self.movement.take(&msg.address) {
+                if let Some(data) = Arc::make_mut(msg)
+                    .data
+                    .as_mut()
+                    .and_then(|data| data.as_object_mut())
+                {
+                    data.insert("movement".to_string(), json!(summary));
+                }
+            }
+        }
+        // redirected beacons are still published on behalf of the tag
+        if self
+            .collectconfig
+            .as_ref()
+            .unwrap()
+            .envelope_interval()
+            .is_some()
+            && redirect.is_none()
+        {
+            trace!("add beacon to envelope");
+            self.sequence(msg);
+            self.envelope.push_back(msg.clone());
+            self.enforce_queue_limits(None);
+        } else if self.try_attach_device(&address) {
+            // redirected beacons are published right away, out of order with the
+            //  queued beacons of the tag, so they are left without tag sequence
+            if redirect.is_none() {
+                self.sequence(msg);
+            }
+            let topic = self.device_event_topic(&address, msg, redirect).unwrap();
+
+            // redirected beacons do not share the topic of the queue of the tag
+            if self.collectconfig.as_ref().unwrap().collection_size() <= 1 || redirect.is_some() {
+                trace!("publish individual beacon");
+                match self.serialize_payload(msg) {
+                    Some(payload) => match self.publish_message(topic, payload) {
+                        Ok(_) => {
+                            self.record_published(std::slice::from_ref(msg));
+                            self.update_publish_status(&address, true);
+                        }
+                        Err(error) => {
+                            error!(
+                                "Error on publishing message to MQTT: '{}'. Beacon lost.",
+                                error
                             );
-                            // replace in hashmap the message queue with new extended one
-                            self.discovered_tags.insert(address, queue.to_vec());
+                            self.update_publish_status(&address, false);
                         }
-                    }
+                    },
+                    None => trace!("beacon filtered out by payload script"),
+                };
+            } else {
+                // the queue of the tag is taken for the time it is updated and put
+                //  back, instead of copying it
+                let mut queue = std::mem::take(self.discovered_tags.entry(address).or_default());
+                let mut bytes = self.queue_bytes.remove(&address).unwrap_or(0);
+                let size = self.beacon_payload_size(msg);
+                // flush the queue first if adding this beacon would take it over the
+                //  payload size limit
+                let flush_first = !queue.is_empty()
+                    && bytes + size > self.collectconfig.as_ref().unwrap().max_payload_bytes();
+                if flush_first {
+                    trace!("publish beacon queue before it grows too big");
+                }
+                if flush_first && !self.publish_queue(&address, &topic, &queue) {
+                    // queue is kept for retry, but this beacon does not fit into it
+                    warn!(
+                        "Beacon from '{}' does not fit into message queue. Beacon lost.",
+                        address
+                    );
                 } else {
-                    trace!("beacon collection is paused");
-                    if let Some(last_pause) = self.last_pause {
-                        if last_pause.elapsed() >= Duration::from_secs(4 * 60) {
-                            // we are paused, so to avoid timeout due to lack of published messages to broker we occasionally will need to
-                            //  publish our state to avoid that. as a short hand we essentially do a pause again.
-                            self.disable_collecting()?;
-                            warn!("Beacon collection is paused.");
+                    if flush_first {
+                        queue.clear();
+                        bytes = 0;
+                    }
+                    trace!("add beacon to queue");
+                    queue.push_back((Instant::now(), msg.clone()));
+                    bytes += size;
+                    debug!(
+                        "Message queue size for '{}': {}/{}",
+                        address,
+                        queue.len(),
+                        self.collectconfig.as_ref().unwrap().collection_size()
+                    );
+                    if self.batch_ready(&queue, bytes) {
+                        trace!("publish beacon queue");
+                        if self.publish_queue(&address, &topic, &queue) {
+                            queue.clear();
+                            bytes = 0;
                         }
-                    } else {
-                        error!("Beacon collection is paused, but paused state was not established correctly.")
                     }
                 }
+                self.discovered_tags.insert(address, queue);
+                self.queue_bytes.insert(address, bytes);
+                self.enforce_queue_limits(Some(&address));
             }
+        }
+    }
 
-            // sleep for a while to reduce amount of CPU burn and idle for a while
-            thread::sleep(time::Duration::from_millis(100));
+    // serializes a beacon or a queue of beacons with only the configured data fields in the
+    //  configured payload format. returns None if payload scripts or the format left out all beacons
+    fn serialize_payload<T: Serialize>(&self, payload: &T) -> Option<String> {
+        self.payload_value(payload)
+            .map(|value| self.json(&self.renamed(self.fingerprinted(self.with_site(value)))))
+    }
+
+    // serializes a message pretty-printed, or compact if the collect config asks for it
+    fn json<T: Serialize>(&self, message: &T) -> String {
+        match &self.collectconfig {
+            Some(collectconfig) if collectconfig.compact() => {
+                serde_json::to_string(message).unwrap()
+            }
+            _ => serde_json::to_string_pretty(message).unwrap(),
         }
+    }
 
-        self.disconnect()?;
+    // renames the fields of a beacon payload to the configured naming. payloads in the Ruuvi
+    //  Station format follow its own schema
+    fn renamed(&self, value: serde_json::Value) -> serde_json::Value {
+        match &self.collectconfig {
+            Some(collectconfig) if collectconfig.payload_format() == PayloadFormat::DEFAULT => {
+                naming::rename_fields(value, collectconfig.field_naming())
+            }
+            _ => value,
+        }
+    }
 
-        Ok(true)
+    // adds the metadata of the site to the payload, or to each beacon of a collection
+    fn with_site(&self, mut value: serde_json::Value) -> serde_json::Value {
+        if let Some(site) = &self.site {
+            let mut objects: Vec<&mut serde_json::Value> = match value.as_array_mut() {
+                Some(queue) => queue.iter_mut().collect(),
+                None => vec![&mut value],
+            };
+            for object in objects
+                .iter_mut()
+                .filter_map(|object| object.as_object_mut())
+            {
+                object.insert("site".to_string(), site.clone());
+            }
+        }
+        value
+    }
+
+    // wraps a payload with the gateway, software version and collect config it is published
+    //  with, so that anomalies can be traced to them during rollouts
+    fn fingerprinted(&self, value: serde_json::Value) -> serde_json::Value {
+        match &self.collectconfig {
+            Some(collectconfig) if collectconfig.fingerprint() => json!({
+                "fingerprint": {
+                    "gateway": self
+                        .iotcore_config
+                        .gateway_id()
+                        .unwrap_or_else(|| self.iotcore_config.device_id.clone()),
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "config_hash": collectconfig.hash(),
+                },
+                "payload": value,
+            }),
+            _ => value,
+        }
+    }
+
+    fn payload_value<T: Serialize>(&self, payload: &T) -> Option<serde_json::Value> {
+        let mut value = serde_json::to_value(payload).unwrap();
+        // before data and raw data are left out, as the dedup hint is derived from them
+        if let Some(gateway_id) = self.iotcore_config.gateway_id() {
+            let mut beacons: Vec<&mut serde_json::Value> = match value.as_array_mut() {
+                Some(queue) => queue.iter_mut().collect(),
+                None => vec![&mut value],
+            };
+            for beacon in beacons.iter_mut().filter(|beacon| beacon.is_object()) {
+                let dedup_id = scanner::dedup_id(beacon);
+                let beacon = beacon.as_object_mut().unwrap();
+                beacon.insert("gateway_id".to_string(), json!(gateway_id));
+                beacon.insert("dedup_id".to_string(), json!(dedup_id));
+            }
+        }
+        if let Some(collectconfig) = &self.collectconfig {
+            let data_fields = collectconfig.data_fields();
+            let raw_data = collectconfig.raw_data();
+            let precision = collectconfig.precision();
+            let mut beacons: Vec<&mut serde_json::Value> = match value.as_array_mut() {
+                Some(queue) => queue.iter_mut().collect(),
+                None => vec![&mut value],
+            };
+            for beacon in beacons
+                .iter_mut()
+                .filter_map(|beacon| beacon.as_object_mut())
+            {
+                match raw_data {
+                    RawDataMode::NONE => beacon.remove("raw"),
+                    RawDataMode::INCLUDE => None,
+                    // beacons without raw data (e.g. logged measurements) keep their data
+                    RawDataMode::ONLY if beacon["raw"] != "" => beacon.remove("data"),
+                    RawDataMode::ONLY => None,
+                };
+                if let Some(data) = beacon.get_mut("data") {
+                    round_fields(data, &precision);
+                }
+                // data fields can only be selected for Ruuvi tag data formats
+                let decoder = beacon.get("decoder").and_then(|decoder| decoder.as_str());
+                if !matches!(decoder, Some(decoder) if decoder.starts_with("ruuvi_")) {
+                    continue;
+                }
+                if let Some(data) = beacon.get_mut("data").and_then(|data| data.as_object_mut()) {
+                    let excluded: Vec<String> = data
+                        .keys()
+                        .filter(|field| !data_fields.contains(field))
+                        .cloned()
+                        .collect();
+                    for field in excluded {
+                        data.remove(&field);
+                    }
+                }
+            }
+        }
+        if let Some(timezone) = &self.timezone {
+            let mut beacons: Vec<&mut serde_json::Value> = match value.as_array_mut() {
+                Some(queue) => queue.iter_mut().collect(),
+                None => vec![&mut value],
+            };
+            for beacon in beacons
+                .iter_mut()
+                .filter_map(|beacon| beacon.as_object_mut())
+            {
+                let timestamp = beacon
+                    .get("timestamp")
+                    .and_then(|timestamp| serde_json::from_value(timestamp.clone()).ok());
+                if let Some(timestamp) = timestamp {
+                    beacon.insert(
+                        "local_timestamp".to_string(),
+                        json!(clock::local_timestamp(&timestamp, timezone)),
+                    );
+                }
+            }
+        }
+        if let Some(scripts) = &self.scripts {
+            value = match value {
+                serde_json::Value::Array(queue) => {
+                    let queue: Vec<serde_json::Value> = queue
+                        .into_iter()
+                        .filter_map(|beacon| scripts.transform(beacon))
+                        .collect();
+                    if queue.is_empty() {
+                        return None;
+                    }
+                    serde_json::Value::Array(queue)
+                }
+                beacon => scripts.transform(beacon)?,
+            };
+        }
+        let payload_format = self
+            .collectconfig
+            .as_ref()
+            .map(|collectconfig| collectconfig.payload_format());
+        if payload_format == Some(PayloadFormat::RUUVISTATION) {
+            let device_id = self
+                .iotcore_config
+                .gateway_id()
+                .unwrap_or_else(|| self.iotcore_config.device_id.clone());
+            value = ruuvistation::to_ruuvistation(&device_id, value)?;
+        }
+        Some(value)
+    }
+
+    fn envelope_payload(&self, tags: &serde_json::Map<String, serde_json::Value>) -> String {
+        let gateway = self
+            .iotcore_config
+            .gateway_id()
+            .unwrap_or_else(|| self.iotcore_config.device_id.clone());
+        self.json(&self.renamed(self.fingerprinted(self.with_site(json!({
+            "gateway": gateway,
+            "timestamp": chrono::Utc::now(),
+            "tags": tags,
+        })))))
+    }
+
+    // publishes beacons of all tags collected since the previous envelope keyed by tag address,
+    //  in as few messages as the payload size limit allows
+    fn publish_envelopes(&mut self) {
+        trace!("in publish_envelopes");
+        let mut queues: BTreeMap<String, Vec<SharedBeacon>> = BTreeMap::new();
+        for beacon in std::mem::take(&mut self.envelope) {
+            if self.watermarks.is_published(&beacon) {
+                continue;
+            }
+            queues
+                .entry(beacon.address.clone())
+                .or_default()
+                .push(beacon);
+        }
+        let max_payload_bytes = self.collectconfig.as_ref().unwrap().max_payload_bytes();
+        let mut tags = serde_json::Map::new();
+        let mut beacons = Vec::new();
+        for (address, queue) in queues {
+            let value = match self.payload_value(&queue) {
+                Some(value) => value,
+                None => continue,
+            };
+            tags.insert(address.clone(), value);
+            if !beacons.is_empty() && self.envelope_payload(&tags).len() > max_payload_bytes {
+                let value = tags.remove(&address).unwrap();
+                self.publish_envelope(&tags, beacons);
+                tags = serde_json::Map::new();
+                tags.insert(address, value);
+                beacons = Vec::new();
+            }
+            beacons.extend(queue);
+        }
+        if !tags.is_empty() {
+            self.publish_envelope(&tags, beacons);
+        }
+    }
+
+    fn publish_envelope(
+        &mut self,
+        tags: &serde_json::Map<String, serde_json::Value>,
+        beacons: Vec<SharedBeacon>,
+    ) {
+        trace!("in publish_envelope");
+        let addresses: Vec<MacAddress> = tags
+            .keys()
+            .filter_map(|address| MacAddress::from_str(address).ok())
+            .collect();
+        let payload = self.envelope_payload(tags);
+        match self.publish_message(self.gateway_event_topic(), payload) {
+            Ok(_) => {
+                debug!("Published envelope of {} tags.", tags.len());
+                self.record_published(&beacons);
+                for address in addresses {
+                    self.update_publish_status(&address, true);
+                }
+            }
+            Err(error) => {
+                error!(
+                    "Error on publishing envelope to MQTT: '{}'. Will retry.",
+                    error
+                );
+                for address in addresses {
+                    self.update_publish_status(&address, false);
+                }
+                self.envelope.extend(beacons);
+            }
+        }
+    }
+
+    // bytes a beacon adds to the payload of its queue. it is serialized as a batch of its own,
+    //  brackets and envelope of the batch included, so the sum over a queue is a little more
+    //  than the payload of the queue and the payload stays within the limit
+    fn beacon_payload_size(&self, beacon: &SharedBeacon) -> usize {
+        self.serialize_payload(&[beacon])
+            .map_or(0, |payload| payload.len())
+    }
+
+    // counts the payload sizes of all queues again, e.g. after the payload format changed
+    fn count_queue_bytes(&mut self) {
+        trace!("in count_queue_bytes");
+        self.queue_bytes = self
+            .discovered_tags
+            .iter()
+            .map(|(address, queue)| {
+                let bytes = queue
+                    .iter()
+                    .map(|(_, beacon)| self.beacon_payload_size(beacon))
+                    .sum::<usize>();
+                (*address, bytes)
+            })
+            .collect();
+    }
+
+    // batch is published when it is full, big or old, whichever comes first
+    fn batch_ready(&self, queue: &VecDeque<(Instant, SharedBeacon)>, bytes: usize) -> bool {
+        trace!("in batch_ready");
+        let collectconfig = self.collectconfig.as_ref().unwrap();
+        if queue.len() >= collectconfig.collection_size() {
+            return true;
+        }
+        if bytes >= collectconfig.max_payload_bytes() {
+            debug!("Message queue reached maximum payload size.");
+            return true;
+        }
+        if self.batch_too_old(queue) {
+            debug!("Message queue reached maximum batch age.");
+            return true;
+        }
+        false
+    }
+
+    fn batch_too_old(&self, queue: &VecDeque<(Instant, SharedBeacon)>) -> bool {
+        let max_batch_age = self.collectconfig.as_ref().unwrap().max_batch_age();
+        match queue.front() {
+            Some((queued, _)) => queued.elapsed() >= Duration::from_secs(max_batch_age),
+            None => false,
+        }
+    }
+
+    // publishes queues that have been waiting for too long, so that beacons from tags that went
+    //  quiet are not kept in memory forever
+    fn flush_stale_queues(&mut self) {
+        trace!("in flush_stale_queues");
+        let stale: Vec<MacAddress> = self
+            .discovered_tags
+            .iter()
+            .filter(|(_, queue)| self.batch_too_old(queue))
+            .map(|(address, _)| *address)
+            .collect();
+        for address in stale {
+            let queue = std::mem::take(self.discovered_tags.get_mut(&address).unwrap());
+            let topic = self
+                .device_event_topic(&address, &queue[0].1, None)
+                .unwrap();
+            info!(
+                "Publishing {} queued beacons of '{}' that reached maximum batch age.",
+                queue.len(),
+                address
+            );
+            if self.publish_queue(&address, &topic, &queue) {
+                self.queue_bytes.remove(&address);
+            } else {
+                self.discovered_tags.insert(address, queue);
+            }
+        }
+    }
+
+    // publishes the statistics of the interval, which are lost if publishing fails
+    fn publish_stats(&mut self) {
+        trace!("in publish_stats");
+        let stats = self.stats.take(&self.metrics);
+        debug!(
+            "{} beacons received from {} tags",
+            stats.beacons_received,
+            stats.tags.len()
+        );
+        if let Err(error) =
+            self.publish_message(self.stats_topic.clone(), self.json(&stats.payload()))
+        {
+            warn!("Unable to publish statistics: {}", error);
+        }
+    }
+
+    // publishes last seen time and battery voltage of attached tags to their own state topics, so
+    //  that tag health shows up in IoT Core
+    fn publish_tag_states(&mut self) {
+        trace!("in publish_tag_states");
+        let tags: Vec<MacAddress> = self.discovered_tags.keys().cloned().collect();
+        for address in tags {
+            let key = address
+                .to_string(MacAddressFormat::Canonical)
+                .to_uppercase();
+            let (last_beacon, clock_drift_ms) = {
+                let status = self.status.lock().unwrap();
+                let last_beacon = status
+                    .tags
+                    .get(&key)
+                    .and_then(|tag_status| tag_status.last_beacon.clone());
+                (last_beacon, status.clock_drift_ms)
+            };
+            if let Some(last_beacon) = last_beacon {
+                let state = self.json(&tag_state(&last_beacon, clock_drift_ms));
+                if let Err(error) = self.publish_message(self.device_state_topic(&address), state) {
+                    warn!("Unable to publish state of Ruuvi tag ({}): {}", key, error);
+                }
+            }
+        }
+    }
+
+    fn publish_queue(
+        &mut self,
+        address: &MacAddress,
+        topic: &str,
+        queue: &VecDeque<(Instant, SharedBeacon)>,
+    ) -> bool {
+        trace!("in publish_queue");
+        // beacons published already, e.g. before a reconnect, are not published again
+        let queue: Vec<SharedBeacon> = queue
+            .iter()
+            .map(|(_, beacon)| beacon)
+            .filter(|beacon| !self.watermarks.is_published(beacon))
+            .cloned()
+            .collect();
+        if queue.is_empty() {
+            debug!(
+                "Beacons of '{}' in queue have been published already.",
+                address
+            );
+            return true;
+        }
+        let payload = match self.serialize_payload(&queue) {
+            Some(payload) => payload,
+            // nothing left to publish after payload scripts
+            None => return true,
+        };
+        match self.publish_message(topic.to_string(), payload) {
+            Ok(_) => {
+                self.record_published(&queue);
+                self.update_publish_status(address, true);
+                true
+            }
+            Err(error) => {
+                error!(
+                    "Error on publishing message queue to MQTT: '{}'. Will retry.",
+                    error
+                );
+                self.update_publish_status(address, false);
+                false
+            }
+        }
+    }
+
+    fn refresh_status(&mut self) {
+        if !self.client.is_connected() {
+            if let Some(reason) = self.client.lost_reason() {
+                self.record_disconnect(reason);
+            }
+        }
+        let mut status = self.status.lock().unwrap();
+        status.connected = self.client.is_connected();
+        for (address, queue) in self.discovered_tags.iter() {
+            let key = address
+                .to_string(MacAddressFormat::Canonical)
+                .to_uppercase();
+            if let Some(tag_status) = status.tags.get_mut(&key) {
+                tag_status.queue_depth = queue.len();
+            }
+        }
     }
 
     fn try_attach_device(&mut self, address: &MacAddress) -> bool {
         trace!("in try_attach_device");
         if self.client.is_connected() && self.discovered_tags.get(address).is_none() {
+            if matches!(self.attach_retries.get(address), Some(retry) if Instant::now() < retry.retry_at)
+            {
+                trace!("attaching unbound tag is backed off");
+                return false;
+            }
             // try to attach a newly discovered beacon owner to this gateway
             //  (succesful only if bound)
             match self.publish_message(self.device_attach_topic(&address), "{}".to_string()) {
@@ -427,11 +2312,16 @@ impl IotCoreClient {
                             .to_string(MacAddressFormat::Canonical)
                             .to_uppercase()
                     );
-                    self.discovered_tags.insert(*address, Vec::new());
+                    self.discovered_tags.insert(*address, VecDeque::new());
+                    self.queue_bytes.remove(address);
+                    self.attach_retries.remove(address);
+                    self.update_tag_status(address, |tag_status| tag_status.unbound = false);
                 }
                 Err(error) => {
-                    warn!("Discovered Ruuvi tag ({}) attachment to gateway failed (possibly not bound): {}", 
+                    let backoff = self.schedule_attach_retry(address);
+                    warn!("Discovered Ruuvi tag ({}) attachment to gateway failed (possibly not bound), retrying in {} seconds: {}",
                         address.to_string(MacAddressFormat::Canonical).to_uppercase(),
+                        backoff.as_secs(),
                         error);
                     return false;
                 }
@@ -444,7 +2334,8 @@ impl IotCoreClient {
     fn reattach_discovered_devices(&mut self) {
         trace!("in reattach_discovered_devices");
         if self.client.is_connected() {
-            for (tag, _) in self.discovered_tags.clone().iter() {
+            let tags: Vec<MacAddress> = self.discovered_tags.keys().cloned().collect();
+            for tag in tags.iter() {
                 match self.publish_message(self.device_attach_topic(&tag), "{}".to_string()) {
                     Ok(_) => info!(
                         "Discovered Ruuvi tag ({}) reattached to gateway succesfully.",
@@ -453,6 +2344,7 @@ impl IotCoreClient {
                     Err(error) => {
                         // remove the tag from associated list as it failed this time around
                         self.discovered_tags.remove(tag);
+                        self.queue_bytes.remove(tag);
                         warn!(
                             "Discovered Ruuvi tag ({}) reattached to gateway failed: {}",
                             tag.to_string(MacAddressFormat::Canonical).to_uppercase(),
@@ -467,7 +2359,8 @@ impl IotCoreClient {
     fn detach_devices(&mut self) {
         trace!("in detach_devices");
         if self.client.is_connected() {
-            for (tag, _) in self.discovered_tags.clone().iter() {
+            let tags: Vec<MacAddress> = self.discovered_tags.keys().cloned().collect();
+            for tag in tags.iter() {
                 match self.publish_message(self.device_detach_topic(&tag), "{}".to_string()) {
                     Ok(_) => info!(
                         "Discovered Ruuvi tag ({}) detached from gateway succesfully.",
@@ -483,11 +2376,29 @@ impl IotCoreClient {
         }
     }
 
-    fn device_event_topic(&self, address: &MacAddress) -> Option<String> {
+    fn device_event_topic(
+        &self,
+        address: &MacAddress,
+        beacon: &RuuviBluetoothBeacon,
+        redirect: Option<&str>,
+    ) -> Option<String> {
         trace!("in device_event_topic");
         let mut retval: Option<String> = None;
         if let Some(collectconfig) = &self.collectconfig {
-            retval = match &collectconfig.event_subfolder {
+            // generic beacons are kept apart from Ruuvi tag beacons
+            let subfolder = if let Some(redirect) = redirect {
+                Some(redirect.to_string())
+            } else if beacon.decoder.as_deref() == Some(IBEACON_DECODER) {
+                collectconfig.generic_beacon_subfolder()
+            } else {
+                collectconfig.event_subfolder.clone()
+            };
+            // "{gateway_id}" in subfolder keeps beacons relayed by different gateways apart
+            let subfolder = subfolder.map(|folder| match self.iotcore_config.gateway_id() {
+                Some(gateway_id) => folder.replace("{gateway_id}", &gateway_id),
+                None => folder,
+            });
+            retval = match subfolder {
                 Some(folder) => Some(format!(
                     "/devices/{}/events/{}",
                     address
@@ -506,6 +2417,27 @@ impl IotCoreClient {
         retval
     }
 
+    // events topic of the gateway itself, for envelopes of beacons of several tags
+    fn gateway_event_topic(&self) -> String {
+        let subfolder = self
+            .collectconfig
+            .as_ref()
+            .and_then(|collectconfig| collectconfig.event_subfolder.clone());
+        let topic = match subfolder {
+            Some(folder) => format!(
+                "/devices/{}/events/{}",
+                self.iotcore_config.device_id,
+                match self.iotcore_config.gateway_id() {
+                    Some(gateway_id) => folder.replace("{gateway_id}", &gateway_id),
+                    None => folder,
+                }
+            ),
+            None => format!("/devices/{}/events", self.iotcore_config.device_id),
+        };
+        debug!("gateway event topic: {}", topic);
+        topic
+    }
+
     fn device_attach_topic(&self, address: &MacAddress) -> String {
         let topic = format!(
             "/devices/{}/attach",
@@ -517,6 +2449,15 @@ impl IotCoreClient {
         topic
     }
 
+    fn device_state_topic(&self, address: &MacAddress) -> String {
+        format!(
+            "/devices/{}/state",
+            address
+                .to_string(MacAddressFormat::Canonical)
+                .to_uppercase()
+        )
+    }
+
     fn device_detach_topic(&self, address: &MacAddress) -> String {
         let topic = format!(
             "/devices/{}/detach",
@@ -528,91 +2469,107 @@ impl IotCoreClient {
         topic
     }
 
+    /// Registers a hook that can modify, drop or redirect beacons before they are published.
+    /// Hooks are run in the order they were registered.
+    pub fn register_hook(&mut self, hook: BeaconHook) {
+        trace!("in register_hook");
+        self.hooks.push(hook);
+    }
+
+    /// Registers a sink that receives beacons alongside IoT Core in batches of at most
+    /// batch_size beacons, delivered at the latest batch_interval after the first one was
+    /// received. Like local export, sinks receive beacons even when not collecting.
+    pub fn register_sink(
+        &mut self,
+        sink: Box<dyn Sink>,
+        batch_size: usize,
+        batch_interval: Duration,
+    ) {
+        trace!("in register_sink");
+        self.sinks.start(sink, batch_size, batch_interval);
+    }
+
+    /// Registers a notifier that receives alerts raised by the alert rules, like the notifiers
+    /// configured in the "alerts" section.
+    pub fn register_notifier(&mut self, notifier: Box<dyn Notifier>) {
+        trace!("in register_notifier");
+        self.alerts.start(notifier);
+    }
+
     pub fn build(
         appconfig: &AppConfig,
+        publisher: Box<dyn Publisher>,
         r: &channel::Receiver<RuuviBluetoothBeacon>,
-        cnc_s: &channel::Sender<IOTCoreCNCMessageKind>,
+        cnc: &Broadcast<IOTCoreCNCMessageKind>,
+        metrics: &Arc<Metrics>,
+        local_command_r: &channel::Receiver<CNCCommandMessage>,
+        status: &SharedStatus,
     ) -> Result<IotCoreClient, Report> {
         trace!("in build");
-        let create_opts = mqtt::CreateOptionsBuilder::new()
-            .client_id(appconfig.iotcore.client_id())
-            .mqtt_version(mqtt::types::MQTT_VERSION_3_1_1)
-            .server_uri("ssl://mqtt.googleapis.com:8883")
-            .persistence(mqtt::PersistenceType::None)
-            .finalize();
-
-        let mut cli = match mqtt::Client::new(create_opts) {
-            Ok(cli) => cli,
-            Err(error) => {
-                return Err(eyre!("Unable to create Paho MQTT client instance")
-                    .with_section(move || error.to_string().header("Reason:")))
-            }
-        };
-        cli.set_timeout(Duration::from_secs(5));
-
-        let mut ssl_options_builder = mqtt::SslOptionsBuilder::new();
-        ssl_options_builder.ssl_version(mqtt::SslVersion::Tls_1_2);
-        if appconfig.identity.ca_certs.is_some() {
-            match ssl_options_builder.trust_store(appconfig.identity.ca_certs.as_ref().unwrap()) {
-                Ok(options_builder) => options_builder,
-                Err(error) => {
-                    return Err(eyre!("Unable to use CA certificates in mqtt client")
-                        .with_section(move || error.to_string().header("Reason:")))
-                }
-            };
-        }
-        match ssl_options_builder.key_store(&appconfig.identity.public_key) {
-            Ok(options_builder) => options_builder,
-            Err(error) => {
-                return Err(eyre!("Unable to use public key in mqtt client")
-                    .with_section(move || error.to_string().header("Reason:")))
-            }
-        };
-        match ssl_options_builder.private_key(&appconfig.identity.private_key) {
-            Ok(options_builder) => options_builder,
-            Err(error) => {
-                return Err(eyre!("Unable to use private key in mqtt client")
-                    .with_section(move || error.to_string().header("Reason:")))
-            }
-        };
-        let ssl_options = ssl_options_builder.finalize();
-
-        let jwt_factory = IotCoreAuthToken::build(appconfig);
-        let jwt_token = match jwt_factory.issue_new() {
-            Ok(token) => token,
-            Err(error) => {
-                return Err(eyre!("Unable to issue original JWT token")
-                    .with_section(move || error.to_string().header("Reason:")))
-            }
-        };
-
-        let conn_opts = mqtt::ConnectOptionsBuilder::new()
-            .user_name("not_used")
-            .password(jwt_token)
-            .ssl_options(ssl_options.clone())
-            .keep_alive_interval(Duration::from_secs(5 * 60))
-            .finalize();
-
-        // thru mspc relay incoming messages from cnc topics
-        let consumer = cli.start_consuming();
-
         let device_id = appconfig.iotcore.device_id.clone();
+        let timezone = clock::timezone(&appconfig.clock)?;
 
         Ok(IotCoreClient {
-            ssl_opts: ssl_options,
-            conn_opts,
-            client: cli,
-            jwt_factory,
+            iotcore_config: appconfig.iotcore.clone(),
+            client: publisher,
             channel_receiver: r.clone(),
-            cnc_sender: cnc_s.clone(),
+            cnc_broadcast: cnc.clone(),
             config_topic: format!("/devices/{}/config", device_id),
             state_topic: format!("/devices/{}/state", device_id),
             command_topic_root: format!("/devices/{}/commands", device_id),
-            consumer,
+            errors_topic: format!("/devices/{}/errors", device_id),
+            diagnostics_topic: format!(
+                "/devices/{}/events/{}",
+                device_id,
+                appconfig.iotcore.diagnostics_subfolder()
+            ),
+            stats_topic: format!(
+                "/devices/{}/events/{}",
+                device_id,
+                appconfig.iotcore.stats_subfolder()
+            ),
             collectconfig: None,
+            default_collectconfig: appconfig.default_collectconfig.clone(),
+            collectconfig_file: appconfig.iotcore.collectconfig_file(),
+            rollback: ConfigRollback::build(&appconfig.rollback),
+            schedule: None,
+            scheduled: None,
             last_pause: None,
+            pause_buffer: PauseBuffer::build(appconfig.iotcore.pause_buffer_file()),
+            rate_limiter: RateLimiter::build(&appconfig.iotcore),
+            rate_limit_buffer: PauseBuffer::build(appconfig.iotcore.rate_limit_buffer_file()),
             last_seen: Instant::now(),
+            last_queue_flush: Instant::now(),
+            last_tag_state: Instant::now(),
+            last_stats: Instant::now(),
+            stats: StatsCollector::new(metrics),
+            last_envelope: Instant::now(),
+            envelope: VecDeque::new(),
+            tag_sequences: HashMap::new(),
+            watermarks: Watermarks::build(appconfig.iotcore.watermark_file()),
+            queue_config: appconfig.queue.clone(),
+            last_eviction_warning: None,
             discovered_tags: HashMap::new(),
+            queue_bytes: HashMap::new(),
+            attach_retries: HashMap::new(),
+            hooks: Vec::new(),
+            scripts: PayloadScripts::build(&appconfig.scripting)?,
+            csv_export: CsvExport::build(&appconfig.csv, timezone)?,
+            timezone,
+            clock_drifts: clock::start_drift_monitor(&appconfig.clock),
+            site: appconfig.site.metadata(),
+            gps: Gps::start(&appconfig.gpsd),
+            sinks: Sinks::build(&appconfig.sinks, &appconfig.iotcore.device_id)?,
+            alerts: Alerts::build(&appconfig.alerts, &appconfig.iotcore.device_id)?,
+            battery_sampler: BatterySampler::default(),
+            movement: MovementTracker::default(),
+            actuators: Actuators::build(&appconfig.actuators)?,
+            update_config: appconfig.update.clone(),
+            release_checks: updater::start_checker(&appconfig.update, &device_id),
+            startup_report: Some(StartupReport::build(appconfig)),
+            metrics: metrics.clone(),
+            local_command_receiver: local_command_r.clone(),
+            status: status.clone(),
         })
     }
 }
@@ -1,16 +1,20 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
 use frank_jwt::{encode, Algorithm};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::configfile::AppConfig;
+use crate::fatal::ErrorCode;
 
 #[derive(Debug, Serialize)]
 pub struct JWTHeaders;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JWTPayload {
     iat: u64,
     exp: u64,
@@ -29,20 +33,61 @@ impl JWTPayload {
             aud: String::from(audience),
         }
     }
+
+    fn is_valid(&self, threshold: u64) -> bool {
+        trace!("in is_valid");
+        let now = SystemTime::now();
+        let secs_since_epoc = now.duration_since(UNIX_EPOCH).unwrap();
+
+        secs_since_epoc.as_secs() + threshold < self.exp
+    }
 }
 
+// private key file the cached token was signed with, a rotated key changes its modification
+//  time or size
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct KeyFingerprint {
+    path: PathBuf,
+    modified: u64,
+    size: u64,
+}
+
+impl KeyFingerprint {
+    fn read(private_key: &Path) -> Option<KeyFingerprint> {
+        let metadata = fs::metadata(private_key).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(KeyFingerprint {
+            path: private_key.to_path_buf(),
+            modified: modified.as_nanos() as u64,
+            size: metadata.len(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedToken {
+    token: String,
+    payload: JWTPayload,
+    // caches written before keys were fingerprinted are not reused
+    #[serde(default)]
+    key: Option<KeyFingerprint>,
+}
+
+/// Issues and renews the JWT tokens used as password when connecting to IoT Core.
 pub struct IotCoreAuthToken {
     headers: JWTHeaders,
     payload: JWTPayload,
     private_key: PathBuf,
     audience: String,
     lifetime: u64,
+    cache_file: Option<PathBuf>,
+    token: Option<String>,
 }
 
 impl IotCoreAuthToken {
     pub fn build(appconfig: &AppConfig) -> IotCoreAuthToken {
         trace!("in build");
-        IotCoreAuthToken {
+        let mut factory = IotCoreAuthToken {
             headers: JWTHeaders,
             payload: JWTPayload::new(
                 &appconfig.iotcore.project_id,
@@ -51,37 +96,114 @@ impl IotCoreAuthToken {
             private_key: Path::new(&appconfig.identity.private_key).to_path_buf(),
             audience: appconfig.iotcore.project_id.clone(),
             lifetime: appconfig.identity.token_lifetime(),
+            cache_file: appconfig.identity.token_cache(),
+            token: None,
+        };
+        factory.load_cached_token();
+        factory
+    }
+
+    fn load_cached_token(&mut self) {
+        trace!("in load_cached_token");
+        let cache_file = match &self.cache_file {
+            Some(cache_file) => cache_file,
+            None => return,
+        };
+        let cached: CachedToken = match fs::read_to_string(cache_file) {
+            Ok(json) => match serde_json::from_str(&json) {
+                Ok(cached) => cached,
+                Err(error) => {
+                    warn!("Ignoring unparseable JWT token cache: {}", error);
+                    return;
+                }
+            },
+            Err(error) => {
+                debug!("No JWT token cache available: {}", error);
+                return;
+            }
+        };
+
+        if cached.payload.aud != self.audience {
+            debug!("Cached JWT token was issued for another audience. Not using it.");
+        } else if cached.key.is_none() || cached.key != KeyFingerprint::read(&self.private_key) {
+            info!("Cached JWT token was signed with another private key. Not using it.");
+        } else if !cached.payload.is_valid(60) {
+            debug!("Cached JWT token has expired / is expiring within the threshold.");
+        } else {
+            info!("Reusing cached JWT token from {}", cache_file.display());
+            self.payload = cached.payload;
+            self.token = Some(cached.token);
+        }
+    }
+
+    fn store_cached_token(&self, token: &str) -> Result<(), Report> {
+        trace!("in store_cached_token");
+        let cache_file = match &self.cache_file {
+            Some(cache_file) => cache_file,
+            None => return Ok(()),
+        };
+        let cached = CachedToken {
+            token: token.to_string(),
+            payload: self.payload.clone(),
+            key: KeyFingerprint::read(&self.private_key),
+        };
+        // the token is a credential, so keep it readable only by us
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(cache_file)
+            .and_then(|mut file| file.write_all(json!(cached).to_string().as_bytes()));
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Unable to write JWT token cache")
+                .with_section(move || cache_file.display().to_string().header("File name:"))
+                .with_section(move || error.to_string().header("Reason:"))),
         }
     }
 
-    pub fn issue_new(&self) -> Result<String, Report> {
+    pub fn issue_new(&mut self) -> Result<String, Report> {
         trace!("in issue_new");
+        if let Some(token) = &self.token {
+            if self.payload.is_valid(60) {
+                debug!("Using previously issued JWT token.");
+                return Ok(token.clone());
+            }
+        }
+
         let token = match encode(
             json!(self.headers),
             &self.private_key,
             &json!(self.payload),
             Algorithm::RS256,
         ) {
-            Ok(jwt) => Ok(jwt),
-            Err(error) => Err(eyre!("Unable to issue new JWT token")
-                .with_section(move || error.to_string().header("Reason:"))),
+            Ok(jwt) => jwt,
+            Err(error) => {
+                return Err(ErrorCode::JWT_SIGN_FAIL
+                    .report("Unable to issue new JWT token")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
         };
         debug!("JWT token is: {:?}", token);
-        token
+        if let Err(error) = self.store_cached_token(&token) {
+            warn!("{}", error);
+        }
+        self.token = Some(token.clone());
+        Ok(token)
     }
 
     pub fn renew(&mut self) -> Result<String, Report> {
         trace!("in renew");
         self.payload = JWTPayload::new(&self.audience, &self.lifetime);
+        self.token = None;
         self.issue_new()
     }
 
+    /// Whether the current token is still valid after `threshold` seconds.
     pub fn is_valid(&self, threshold: u64) -> bool {
         trace!("in is_valid");
-        let now = SystemTime::now();
-        let secs_since_epoc = now.duration_since(UNIX_EPOCH).unwrap();
-
-        if secs_since_epoc.as_secs() > self.payload.exp - threshold {
+        if !self.payload.is_valid(threshold) {
             debug!("JWT token has expired / is expiring within the threshold.");
             return false;
         }
@@ -91,4 +213,75 @@ impl IotCoreAuthToken {
     }
 }
 
+/// Signs a token for another audience than IoT Core with the private key, e.g. the URL of a
+/// server the gateway authenticates to. it is not cached, so it is signed on every call.
+pub fn sign_token(private_key: &Path, audience: &str, lifetime: u64) -> Result<String, Report> {
+    trace!("in sign_token");
+    match encode(
+        json!(JWTHeaders),
+        &private_key.to_path_buf(),
+        &json!(JWTPayload::new(audience, &lifetime)),
+        Algorithm::RS256,
+    ) {
+        Ok(jwt) => Ok(jwt),
+        Err(error) => Err(ErrorCode::JWT_SIGN_FAIL
+            .report("Unable to issue new JWT token")
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+/// Signs a throwaway token with the private key to check that it can sign the tokens IoT Core is
+/// connected with.
+pub fn check_private_key(private_key: &Path) -> Result<(), String> {
+    trace!("in check_private_key");
+    if let Err(error) = fs::metadata(private_key) {
+        return Err(error.to_string());
+    }
+    match encode(
+        json!(JWTHeaders),
+        &private_key.to_path_buf(),
+        &json!(JWTPayload::new("check", &60)),
+        Algorithm::RS256,
+    ) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IotCoreAuthToken, JWTHeaders, JWTPayload};
+    use crate::mock::temp_path;
+    use std::fs;
+
+    #[test]
+    fn cached_token_of_rotated_key_is_not_reused() {
+        let private_key = temp_path("jwt-rsa_private.pem");
+        let cache_file = temp_path("jwt-token_cache.json");
+        fs::write(&private_key, "old key").unwrap();
+        let factory = || IotCoreAuthToken {
+            headers: JWTHeaders,
+            payload: JWTPayload::new("ruuvi-project", &3600),
+            private_key: private_key.clone(),
+            audience: "ruuvi-project".to_string(),
+            lifetime: 3600,
+            cache_file: Some(cache_file.clone()),
+            token: None,
+        };
+        factory().store_cached_token("cached token").unwrap();
+
+        let mut reloaded = factory();
+        reloaded.load_cached_token();
+        assert_eq!(reloaded.token, Some("cached token".to_string()));
+
+        fs::write(&private_key, "rotated key").unwrap();
+        let mut rotated = factory();
+        rotated.load_cached_token();
+        assert_eq!(rotated.token, None);
+
+        fs::remove_file(&private_key).ok();
+        fs::remove_file(&cache_file).ok();
+    }
+}
+
 // eof
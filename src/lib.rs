@@ -0,0 +1,147 @@
+//! Relays Ruuvi tag (and other Bluetooth LE) beacons to Google Cloud IoT Core over MQTT.
+//!
+//! The `ruuvi2iotcore` binary is a thin wrapper around this library. Other applications can embed
+//! the same pipeline: a [`Scanner`] relays [`RuuviBluetoothBeacon`]s through a channel to an
+//! [`IotCoreClient`], which publishes them through a [`Publisher`] according to the
+//! [`CollectConfig`] received from IoT Core. Messages take three routes, so no end can take
+//! messages meant for another:
+//!
+//! * beacons from the scanner to the IoT Core client,
+//! * collect configs and commands from IoT Core, relayed by the client, to every subscriber of
+//!   a [`Broadcast`](broadcast::Broadcast), e.g. the scanner,
+//! * commands from the control socket and web dashboard to the IoT Core client.
+//!
+//! ```no_run
+//! use crossbeam::channel::unbounded;
+//! use ruuvi2iotcore::broadcast::Broadcast;
+//! use ruuvi2iotcore::{publisher, AppConfig, BluetoothScanner, IotCoreClient, Scanner};
+//! use std::path::Path;
+//! use std::sync::Arc;
+//!
+//! # fn main() -> Result<(), color_eyre::eyre::Report> {
+//! let appconfig = AppConfig::read_config(Path::new("ruuvi2iotcore.yaml"))?;
+//! let cnc = Broadcast::default();
+//! let (event_s, event_r) = unbounded();
+//! let (_local_command_s, local_command_r) = unbounded();
+//! let metrics = Arc::new(ruuvi2iotcore::metrics::Metrics::default());
+//! let status = ruuvi2iotcore::status::SharedStatus::default();
+//!
+//! let mut scanner = BluetoothScanner::build(&event_s, &cnc.subscribe(), &metrics)?;
+//! let mut client = IotCoreClient::build(
+//!     &appconfig,
+//!     publisher::build(&appconfig)?,
+//!     &event_r,
+//!     &cnc,
+//!     &metrics,
+//!     &local_command_r,
+//!     &status,
+//! )?;
+//! std::thread::spawn(move || scanner.start_scanner());
+//! client.start_client()?;
+//! # Ok(())
+//! # }
+//! ```
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_json;
+
+#[cfg(not(any(feature = "paho", feature = "rustls")))]
+compile_error!("an MQTT backend is needed, enable the \"rustls\" or \"paho\" feature");
+
+pub mod actuators;
+pub mod advmonitor;
+pub mod alerts;
+#[cfg(feature = "amqp")]
+pub mod amqp;
+#[cfg(feature = "bacnet")]
+pub mod bacnet;
+pub mod battery;
+pub mod bigquery;
+#[cfg(feature = "paho")]
+pub mod bridge;
+pub mod broadcast;
+pub mod chat;
+pub mod clock;
+pub mod configfile;
+pub mod control;
+#[cfg(feature = "paho")]
+pub mod coordination;
+pub mod csvexport;
+pub mod decoder;
+pub mod diagnostics;
+#[cfg(feature = "dns-discovery")]
+pub mod dnsconfig;
+pub mod doctor;
+pub mod email;
+pub mod endpoint;
+pub mod fatal;
+pub mod gpsd;
+pub mod grafana;
+pub mod hci;
+pub mod history;
+pub mod hooks;
+#[cfg(test)]
+mod integration_tests;
+pub mod iotcore;
+pub mod jwt;
+pub mod local;
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+pub mod monitoring;
+pub mod movement;
+pub mod naming;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "opcua")]
+pub mod opcua;
+pub mod pausebuffer;
+pub mod pipeline;
+pub mod proxy;
+pub mod publisher;
+pub mod recorder;
+pub mod remoteconfig;
+pub mod replay;
+pub mod rollback;
+#[cfg(feature = "rustls")]
+pub mod rumqtt;
+pub mod ruuvistation;
+pub mod scanner;
+pub mod schedule;
+pub mod scripting;
+pub mod secrets;
+pub mod serviceaccount;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+pub mod sinks;
+pub mod snmp;
+#[cfg(feature = "sparkplug")]
+pub mod sparkplug;
+pub mod stats;
+pub mod status;
+pub mod tls;
+pub mod udp;
+pub mod updater;
+pub mod watermark;
+#[cfg(feature = "webui")]
+pub mod webui;
+pub mod zabbix;
+
+pub use crate::alerts::Notifier;
+pub use crate::configfile::AppConfig;
+pub use crate::hooks::{BeaconAction, BeaconHook};
+pub use crate::iotcore::{
+    CNCCommand, CNCCommandMessage, CollectConfig, IOTCoreCNCMessageKind, IotCoreClient,
+};
+pub use crate::jwt::IotCoreAuthToken;
+#[cfg(feature = "paho")]
+pub use crate::publisher::MqttPublisher;
+pub use crate::publisher::Publisher;
+#[cfg(feature = "rustls")]
+pub use crate::rumqtt::RumqttPublisher;
+pub use crate::scanner::{BluetoothScanner, RuuviBluetoothBeacon, Scanner};
+pub use crate::sinks::Sink;
+
+// eof
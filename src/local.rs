@@ -0,0 +1,346 @@
+use color_eyre::eyre::Report;
+#[cfg(feature = "paho")]
+use color_eyre::{eyre::eyre, Section, SectionExt};
+#[cfg(feature = "paho")]
+use paho_mqtt as mqtt;
+#[cfg(feature = "paho")]
+use std::sync::mpsc::Receiver;
+#[cfg(feature = "paho")]
+use std::time::{Duration, Instant};
+
+use crate::configfile::AppConfig;
+#[cfg(feature = "paho")]
+use crate::configfile::LocalConfig;
+use crate::publisher::{ConnectDetails, IncomingMessage, Publisher};
+#[cfg(feature = "paho")]
+use crate::tls;
+
+// pause between attempts to connect to the local broker
+#[cfg(feature = "paho")]
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+// maps topics of the gateway between IoT Core and the local broker, e.g.
+//  /devices/gateway1/commands/# to ruuvi2iotcore/gateway1/commands/#
+struct TopicMap {
+    iotcore_root: String,
+    local_root: String,
+}
+
+impl TopicMap {
+    fn replace_root(topic: &str, from: &str, to: &str) -> Option<String> {
+        let rest = topic.strip_prefix(from)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(format!("{}{}", to, rest))
+        } else {
+            None
+        }
+    }
+
+    fn local_topic(&self, topic: &str) -> Option<String> {
+        TopicMap::replace_root(topic, &self.iotcore_root, &self.local_root)
+    }
+
+    // messages are received only through Paho
+    #[cfg(any(feature = "paho", test))]
+    fn iotcore_topic(&self, topic: &str) -> Option<String> {
+        TopicMap::replace_root(topic, &self.local_root, &self.iotcore_root)
+    }
+}
+
+// connection to the local broker commands and collect configs are received from
+#[cfg(feature = "paho")]
+struct LocalBroker {
+    config: LocalConfig,
+    client: mqtt::Client,
+    consumer: Receiver<Option<mqtt::Message>>,
+    last_connect: Option<Instant>,
+    subscriptions: Vec<String>,
+}
+
+#[cfg(feature = "paho")]
+impl LocalBroker {
+    fn connect(&mut self) -> Result<(), Report> {
+        trace!("in connect");
+        self.last_connect = Some(Instant::now());
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+        conn_opts.clean_session(true);
+        if let Some(username) = self.config.username() {
+            conn_opts.user_name(username);
+        }
+        if let Some(password) = self.config.password() {
+            conn_opts.password(password);
+        }
+        if tls::is_secure(&self.config.broker().unwrap_or_default()) {
+            conn_opts
+                .ssl_options(tls::ssl_options_builder(&self.config.tls, "local", None)?.finalize());
+        }
+        if let Err(error) = self.client.connect(conn_opts.finalize()) {
+            return Err(eyre!("Unable to connect to local MQTT broker")
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+        for topic in &self.subscriptions {
+            if let Err(error) = self.client.subscribe(topic, mqtt::QOS_1) {
+                let topic = topic.clone();
+                return Err(eyre!("Unable to subscribe to local topic")
+                    .with_section(move || topic.header("Topic:"))
+                    .with_section(move || error.to_string().header("Reason:")));
+            }
+        }
+        info!(
+            "Receiving commands and collect configs from local broker topics {:?}",
+            self.subscriptions
+        );
+        Ok(())
+    }
+
+    // (re)connects to the broker when needed, without waiting for it
+    fn ensure_connected(&mut self) -> bool {
+        if self.client.is_connected() {
+            return true;
+        }
+        let connect_due = match self.last_connect {
+            Some(last_connect) => last_connect.elapsed() >= RECONNECT_INTERVAL,
+            None => true,
+        };
+        if connect_due {
+            if let Err(error) = self.connect() {
+                error!("{}", error);
+            }
+        }
+        self.client.is_connected()
+    }
+
+    fn build(config: &LocalConfig, device_id: &str) -> Result<Option<LocalBroker>, Report> {
+        trace!("in build");
+        let broker = match config.broker() {
+            Some(broker) => broker,
+            None => return Ok(None),
+        };
+        let create_opts = mqtt::CreateOptionsBuilder::new()
+            .server_uri(broker)
+            .client_id(format!("{}-{}-local", env!("CARGO_PKG_NAME"), device_id))
+            .finalize();
+        let mut client = match mqtt::Client::new(create_opts) {
+            Ok(client) => client,
+            Err(error) => {
+                return Err(eyre!("Unable to create Paho MQTT client instance")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let consumer = client.start_consuming();
+        Ok(Some(LocalBroker {
+            config: config.clone(),
+            client,
+            consumer,
+            last_connect: None,
+            subscriptions: Vec::new(),
+        }))
+    }
+}
+
+/// Publisher of the local mode that runs without IoT Core. Nothing is published to IoT Core,
+/// beacons reach only the local sinks. The gateway state is relayed to a local MQTT broker, if
+/// one is set, and commands and collect configs are received from it as if they came from IoT
+/// Core.
+pub struct LocalPublisher {
+    topics: TopicMap,
+    connected: bool,
+    #[cfg(feature = "paho")]
+    broker: Option<LocalBroker>,
+}
+
+#[cfg(feature = "paho")]
+impl LocalPublisher {
+    // running does not depend on the local broker, it is reconnected when it can be reached
+    fn connect_local(&mut self) {
+        if let Some(broker) = self.broker.as_mut() {
+            broker.ensure_connected();
+        }
+    }
+
+    fn disconnect_local(&mut self) {
+        if let Some(broker) = &self.broker {
+            if broker.client.is_connected() {
+                broker.client.disconnect(None).ok();
+            }
+        }
+    }
+
+    fn relay_state(&mut self, topic: &str, payload: &[u8]) {
+        if let Some(broker) = self.broker.as_mut() {
+            if broker.ensure_connected() {
+                let local_topic = self.topics.local_topic(topic).unwrap();
+                let message = mqtt::Message::new_retained(local_topic, payload, mqtt::QOS_1);
+                if let Err(error) = broker.client.publish(message) {
+                    warn!("Unable to publish state to local broker: {}", error);
+                }
+            }
+        }
+    }
+
+    fn subscribe_local(&mut self, topics: Vec<String>) {
+        if let Some(broker) = self.broker.as_mut() {
+            broker.subscriptions = topics;
+            if broker.client.is_connected() {
+                for topic in &broker.subscriptions {
+                    broker.client.subscribe(topic, mqtt::QOS_1).ok();
+                }
+            }
+        }
+    }
+
+    fn receive_local(&mut self) -> Option<IncomingMessage> {
+        let broker = self.broker.as_mut()?;
+        if !broker.ensure_connected() {
+            return None;
+        }
+        while let Ok(Some(message)) = broker.consumer.try_recv() {
+            match self.topics.iotcore_topic(message.topic()) {
+                Some(topic) => {
+                    return Some(IncomingMessage {
+                        topic,
+                        payload: message.payload_str().to_string(),
+                    })
+                }
+                None => debug!("Ignoring message from local topic '{}'", message.topic()),
+            }
+        }
+        None
+    }
+}
+
+// without Paho there is no local broker to relay to
+#[cfg(not(feature = "paho"))]
+impl LocalPublisher {
+    fn connect_local(&mut self) {}
+
+    fn disconnect_local(&mut self) {}
+
+    fn relay_state(&mut self, _topic: &str, _payload: &[u8]) {}
+
+    fn subscribe_local(&mut self, _topics: Vec<String>) {}
+
+    fn receive_local(&mut self) -> Option<IncomingMessage> {
+        None
+    }
+}
+
+impl Publisher for LocalPublisher {
+    fn connect(&mut self) -> Result<(), Report> {
+        trace!("in connect");
+        self.connect_local();
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<(), Report> {
+        trace!("in disconnect");
+        self.disconnect_local();
+        self.connected = false;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn credentials_expiring(&self) -> bool {
+        false
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), Report> {
+        // only the state of the gateway is relayed, beacons are in the local sinks
+        if topic == format!("{}/state", self.topics.iotcore_root) {
+            self.relay_state(topic, payload);
+        } else {
+            trace!("local mode, not publishing to '{}'", topic);
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topics: &[String]) -> Result<(), Report> {
+        trace!("in subscribe");
+        let local_topics: Vec<String> = topics
+            .iter()
+            .filter_map(|topic| self.topics.local_topic(topic))
+            .collect();
+        self.subscribe_local(local_topics);
+        Ok(())
+    }
+
+    fn try_receive(&mut self) -> Option<IncomingMessage> {
+        self.receive_local()
+    }
+
+    fn connect_details(&self) -> ConnectDetails {
+        ConnectDetails {
+            endpoint: Some("local mode".to_string()),
+            ..ConnectDetails::default()
+        }
+    }
+}
+
+impl LocalPublisher {
+    pub fn build(appconfig: &AppConfig) -> Result<LocalPublisher, Report> {
+        trace!("in build");
+        let device_id = &appconfig.iotcore.device_id;
+        Ok(LocalPublisher {
+            topics: TopicMap {
+                iotcore_root: format!("/devices/{}", device_id),
+                local_root: appconfig.local.topic(device_id),
+            },
+            connected: false,
+            #[cfg(feature = "paho")]
+            broker: LocalBroker::build(&appconfig.local, device_id)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalPublisher, TopicMap};
+    use crate::configfile::AppConfig;
+    use crate::publisher::Publisher;
+
+    #[test]
+    fn topics_are_mapped_under_local_root() {
+        let topics = TopicMap {
+            iotcore_root: "/devices/gateway1".to_string(),
+            local_root: "home/ruuvi".to_string(),
+        };
+        assert_eq!(
+            topics.local_topic("/devices/gateway1/commands/#"),
+            Some("home/ruuvi/commands/#".to_string())
+        );
+        assert_eq!(
+            topics.iotcore_topic("home/ruuvi/config"),
+            Some("/devices/gateway1/config".to_string())
+        );
+        assert_eq!(topics.local_topic("/devices/gateway10/config"), None);
+        assert_eq!(topics.iotcore_topic("home/ruuvis/config"), None);
+    }
+
+    #[test]
+    fn nothing_is_published_without_broker() {
+        let appconfig: AppConfig = serde_yaml::from_str(
+            r#"
+iotcore:
+  device_id: "gateway1"
+local:
+  enabled: true
+"#,
+        )
+        .unwrap();
+        let mut publisher = LocalPublisher::build(&appconfig).unwrap();
+        publisher.connect().unwrap();
+        assert!(publisher.is_connected());
+        publisher
+            .subscribe(&["/devices/gateway1/config".to_string()])
+            .unwrap();
+        publisher.publish("/devices/gateway1/state", b"{}").unwrap();
+        assert!(publisher.try_receive().is_none());
+        assert!(!publisher.credentials_expiring());
+    }
+}
+
+// eof
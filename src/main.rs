@@ -1,14 +1,7 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate serde_json;
-
-pub mod configfile;
-pub mod iotcore;
-pub mod jwt;
-pub mod scanner;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
 use crossbeam::channel::unbounded;
 use crossbeam::thread;
@@ -16,10 +9,163 @@ use directories::ProjectDirs;
 use dotenv::dotenv;
 use std::env;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "bacnet")]
+use ruuvi2iotcore::bacnet::BacnetServer;
+#[cfg(feature = "paho")]
+use ruuvi2iotcore::bridge::MqttBridge;
+use ruuvi2iotcore::broadcast::Broadcast;
+use ruuvi2iotcore::configfile::{AppConfig, SinkKind};
+use ruuvi2iotcore::control::{self, ControlServer};
+#[cfg(feature = "paho")]
+use ruuvi2iotcore::coordination::Coordinator;
+use ruuvi2iotcore::doctor::{self, Verdict};
+use ruuvi2iotcore::fatal::{fatal, ErrorCode};
+use ruuvi2iotcore::hci;
+use ruuvi2iotcore::iotcore::IotCoreClient;
+use ruuvi2iotcore::metrics::{Metrics, MetricsPusher};
+#[cfg(feature = "opcua")]
+use ruuvi2iotcore::opcua::OpcUaServer;
+use ruuvi2iotcore::pipeline::{self, Dispatcher, PipelineInstance};
+use ruuvi2iotcore::scanner::{BluetoothScanner, Scanner};
+#[cfg(feature = "simulator")]
+use ruuvi2iotcore::simulator::Simulator;
+use ruuvi2iotcore::status::GatewayStatus;
+use ruuvi2iotcore::udp::UdpListener;
+#[cfg(feature = "webui")]
+use ruuvi2iotcore::webui::WebUi;
+use ruuvi2iotcore::{clock, publisher, recorder, remoteconfig, replay, secrets};
+
+// fails when a subsystem is configured or asked for on command line but the binary was built
+//  without its feature
+fn check_features(appconfig: &AppConfig, matches: &ArgMatches) -> Result<(), Report> {
+    trace!("in check_features");
+    let subsystems = [
+        (
+            "MQTT bridge",
+            "paho",
+            cfg!(feature = "paho"),
+            appconfig.bridge.broker().is_some(),
+        ),
+        (
+            "Commands from local broker",
+            "paho",
+            cfg!(feature = "paho"),
+            appconfig.local.enabled() && appconfig.local.broker().is_some(),
+        ),
+        (
+            "Gateway coordination",
+            "paho",
+            cfg!(feature = "paho"),
+            appconfig.coordination.broker().is_some(),
+        ),
+        (
+            "Pushing metrics",
+            "prometheus",
+            cfg!(feature = "prometheus"),
+            appconfig.metrics.push_url().is_some(),
+        ),
+        (
+            "Web dashboard",
+            "webui",
+            cfg!(feature = "webui"),
+            appconfig.webui.port().is_some(),
+        ),
+        (
+            "BACnet/IP server",
+            "bacnet",
+            cfg!(feature = "bacnet"),
+            appconfig.bacnet.port().is_some(),
+        ),
+        (
+            "OPC UA server",
+            "opcua",
+            cfg!(feature = "opcua"),
+            appconfig.opcua.port().is_some(),
+        ),
+        (
+            "Simulating Ruuvi tags",
+            "simulator",
+            cfg!(feature = "simulator"),
+            matches.is_present("simulate"),
+        ),
+        (
+            "NATS sink",
+            "nats",
+            cfg!(feature = "nats"),
+            appconfig
+                .sinks
+                .iter()
+                .any(|sink| sink.kind() == SinkKind::NATS),
+        ),
+        (
+            "AMQP sink",
+            "amqp",
+            cfg!(feature = "amqp"),
+            appconfig
+                .sinks
+                .iter()
+                .any(|sink| sink.kind() == SinkKind::AMQP),
+        ),
+        (
+            "Sparkplug sink",
+            "sparkplug",
+            cfg!(feature = "sparkplug"),
+            appconfig
+                .sinks
+                .iter()
+                .any(|sink| sink.kind() == SinkKind::SPARKPLUG),
+        ),
+    ];
+    for (subsystem, feature, compiled, configured) in subsystems {
+        if configured && !compiled {
+            return Err(
+                eyre!("{} is not compiled in", subsystem).with_section(move || {
+                    format!("cargo build --features {}", feature).header("Build with:")
+                }),
+            );
+        }
+    }
+    Ok(())
+}
+
+// runs the IoT Core client until it is shut down
+fn run_client(iotcore: &mut IotCoreClient) {
+    loop {
+        trace!("in MQTT thread loop");
+        match iotcore.start_client() {
+            Ok(exit) => {
+                if exit {
+                    break;
+                } else {
+                    info!("Restarting IoT Core client due to internal state change.");
+                }
+            }
+            Err(error) => error!("Restarting iotcore client due to error: {}", error),
+        };
+    }
+}
 
-use crate::configfile::AppConfig;
-use crate::iotcore::IotCoreClient;
-use crate::scanner::BluetoothScanner;
+// runs the Bluetooth scanner until it is shut down
+fn run_scanner(scanner: &mut BluetoothScanner, metrics: &Metrics) {
+    loop {
+        trace!("in BT thread loop");
+        match scanner.start_scanner() {
+            Ok(exit) => {
+                if exit {
+                    break;
+                } else {
+                    info!("Restarting Bluetooth scanner due to internal state change.");
+                }
+            }
+            Err(error) => error!("Restarting bluetooth scanner due to error: {}", error),
+        };
+        Metrics::inc(&metrics.scanner_restarts);
+    }
+}
 
 fn main() -> Result<(), Report> {
     // initialize error handling
@@ -73,9 +219,96 @@ fn main() -> Result<(), Report> {
                 .conflicts_with("logging")
                 .global(true),
         )
+        .arg(
+            Arg::with_name("replay") // relay beacons from a file instead of scanning
+                .long("replay")
+                .help("Publish beacons recorded in a JSON lines or pcap file instead of scanning.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay-speed")
+                .long("replay-speed")
+                .help("Speed of replay relative to recorded speed. 0 replays as fast as possible.")
+                .default_value("1")
+                .requires("replay"),
+        )
+        .arg(
+            Arg::with_name("simulate") // relay synthetic beacons instead of scanning
+                .long("simulate")
+                .help("Publish beacons of N virtual Ruuvi tags with synthetic data instead of scanning.")
+                .takes_value(true)
+                .value_name("N")
+                .conflicts_with("replay"),
+        )
+        .arg(
+            Arg::with_name("discover") // registry settings from DNS
+                .long("discover")
+                .help("Discover IoT Core project, region and registry from DNS TXT records of the domain.")
+                .takes_value(true)
+                .value_name("DOMAIN"),
+        )
+        .subcommand(
+            SubCommand::with_name("encrypt")
+                .about("Encrypt a value to be used as a secret in config file.")
+                .arg(
+                    Arg::with_name("value")
+                        .help("Value to encrypt. Read from standard input if not given, which keeps it out of shell history and process list."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("record")
+                .about("Record beacons into a file for replay or debugging without publishing them.")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .help("File to record into. Defaults to a timestamped file in working directory.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .short("d")
+                        .help("Stop recording after given seconds.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("adapter")
+                        .long("adapter")
+                        .short("a")
+                        .help("Name or MAC address pattern of Bluetooth adapter to use.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check permissions, Bluetooth adapter, configuration, keys, IoT Core reachability and clock."),
+        )
+        .subcommand(
+            SubCommand::with_name("control")
+                .about("Send a command to a running instance through the control socket.")
+                .arg(
+                    Arg::with_name("command")
+                        .help("Command to send.")
+                        .possible_values(&[
+                            "collect", "pause", "shutdown", "reset", "rebind", "status",
+                        ])
+                        .required(true),
+                ),
+        )
         // from App instance parse all matches to determine selected commandline arguments and options
         .get_matches();
 
+    // encrypt a secret for config file and exit
+    if let Some(encrypt_matches) = matches.subcommand_matches("encrypt") {
+        let value = match encrypt_matches.value_of("value") {
+            Some(value) => value.to_string(),
+            None => secrets::read_plaintext()?,
+        };
+        println!("{}", secrets::encrypt(&value)?);
+        return Ok(());
+    }
+
     // change working directory to configured path
     let working_dir_path = Path::new(matches.value_of("workdir").unwrap());
     match env::set_current_dir(working_dir_path) {
@@ -93,6 +326,21 @@ fn main() -> Result<(), Report> {
         }
     }
 
+    // check the gateway and exit, without logging so that the report is not mixed with log lines
+    if matches.subcommand_matches("doctor").is_some() {
+        let checks = doctor::run(Path::new(matches.value_of("config").unwrap()));
+        let colors = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+        print!("{}", doctor::report(&checks, colors));
+        let failed = checks
+            .iter()
+            .filter(|check| check.verdict == Verdict::FAIL)
+            .count();
+        if failed > 0 {
+            return Err(eyre!("{} of {} checks failed", failed, checks.len()));
+        }
+        return Ok(());
+    }
+
     // read logging configuration (if present)
     if matches.is_present("logging") {
         let logging_config_path = Path::new(matches.value_of("logging").unwrap());
@@ -117,55 +365,330 @@ fn main() -> Result<(), Report> {
         env!("CARGO_PKG_VERSION")
     );
 
+    // record beacons without connecting to IoT Core and exit
+    if let Some(record_matches) = matches.subcommand_matches("record") {
+        let duration = match record_matches.value_of("duration") {
+            Some(duration) => match duration.parse::<u64>() {
+                Ok(duration) => Some(Duration::from_secs(duration)),
+                Err(error) => {
+                    return Err(eyre!("Invalid recording duration")
+                        .with_section(move || error.to_string().header("Reason:")))
+                }
+            },
+            None => None,
+        };
+        let output = record_matches
+            .value_of("output")
+            .map(|output| output.to_string())
+            .unwrap_or_else(recorder::default_output);
+        return recorder::record(
+            Path::new(&output),
+            duration,
+            record_matches
+                .value_of("adapter")
+                .map(|adapter| adapter.to_string()),
+        );
+    }
+
     // read configuration
-    let appconfig = AppConfig::read_config(Path::new(matches.value_of("config").unwrap()))?;
-    debug!("appconfig is '{:?}'", appconfig);
+    let mut appconfig = match AppConfig::read_config(Path::new(matches.value_of("config").unwrap()))
+    {
+        Ok(appconfig) => appconfig,
+        // a command relayed to a running instance is not a failure of the gateway
+        Err(error) if matches.subcommand_matches("control").is_some() => return Err(error),
+        Err(error) => return Err(fatal(None, ErrorCode::CONFIG_INVALID, error)),
+    };
+    debug!("appconfig is '{:?}'", secrets::Redacted(&appconfig));
+
+    // relay a command to running instance and exit
+    if let Some(control_matches) = matches.subcommand_matches("control") {
+        println!(
+            "{}",
+            control::send_command(
+                &appconfig.control,
+                control_matches.value_of("command").unwrap()
+            )?
+        );
+        return Ok(());
+    }
+
+    // failures from here on are published to the state topic, or with the next startup report
+    //  when IoT Core cannot be reached. registry settings from DNS override the ones in
+    //  configuration file
+    appconfig
+        .discover(matches.value_of("discover"))
+        .map_err(|error| fatal(None, ErrorCode::CONFIG_INVALID, error))?;
+    // and centrally managed configuration overrides both
+    let appconfig = remoteconfig::apply(appconfig)
+        .map_err(|error| fatal(None, ErrorCode::CONFIG_INVALID, error))?;
+    check_features(&appconfig, &matches)
+        .map_err(|error| fatal(Some(&appconfig), ErrorCode::CONFIG_INVALID, error))?;
+
+    // read beacons to replay before connecting anywhere so that errors in the file surface early
+    let replay = match matches.value_of("replay") {
+        Some(replay_file) => {
+            let speed_arg = matches.value_of("replay-speed").unwrap().to_string();
+            let speed: f64 = match speed_arg.parse() {
+                Ok(speed) if speed >= 0.0 => speed,
+                _ => {
+                    return Err(eyre!("Invalid replay speed")
+                        .with_section(move || speed_arg.header("Replay speed:")))
+                }
+            };
+            Some((replay::read_replay_file(Path::new(replay_file))?, speed))
+        }
+        None => None,
+    };
+    #[cfg(feature = "simulator")]
+    let simulate = match matches.value_of("simulate") {
+        Some(tags_arg) => match tags_arg.parse::<usize>() {
+            Ok(tags) if tags > 0 => Some(tags),
+            _ => {
+                let tags_arg = tags_arg.to_string();
+                return Err(eyre!("Invalid number of simulated tags")
+                    .with_section(move || tags_arg.header("Simulated tags:")));
+            }
+        },
+        None => None,
+    };
 
-    let (cnc_s, cnc_r) = unbounded();
+    // JWT tokens issued with a clock far off are rejected by IoT Core, so wait for a sane clock
+    if appconfig.local.enabled() {
+        info!("Running in local mode without IoT Core.");
+    } else {
+        clock::wait_for_sane_clock(&appconfig.clock);
+    }
+
+    let cnc = Broadcast::default();
     let (event_s, event_r) = unbounded();
-    let mut scanner = BluetoothScanner::build(&event_s, &cnc_r)?;
-    let mut iotcore = IotCoreClient::build(&appconfig, &event_r, &cnc_s)?;
+    let (local_command_s, local_command_r) = unbounded();
+    let metrics = Arc::new(Metrics::default());
+    let running = Arc::new(AtomicBool::new(true));
+    let status = Arc::new(Mutex::new(GatewayStatus::default()));
+    // with pipelines the beacons of the scanner are dispatched between them and the top level
+    //  client
+    let pipelines = pipeline::build_pipelines(&appconfig)
+        .map_err(|error| fatal(Some(&appconfig), ErrorCode::CONFIG_INVALID, error))?;
+    let (scanned_s, scanned_r) = unbounded();
+    let mut dispatcher = Dispatcher::build(&scanned_r, Some(event_s.clone()), &running);
+    let mut pipeline_instances = Vec::new();
+    for pipeline in &pipelines {
+        info!("Starting pipeline '{}'.", pipeline.name);
+        pipeline_instances.push(
+            PipelineInstance::build(pipeline, &mut dispatcher, &metrics, &running).map_err(
+                |error| fatal(Some(&pipeline.appconfig), ErrorCode::CONFIG_INVALID, error),
+            )?,
+        );
+    }
+    let pipeline_shutdowns: Vec<_> = pipeline_instances
+        .iter()
+        .map(|instance| instance.shutdown_sender())
+        .collect();
+    let scanner_s = if pipelines.is_empty() {
+        event_s
+    } else {
+        scanned_s
+    };
+    let mut scanner = BluetoothScanner::build(&scanner_s, &cnc.subscribe(), &metrics)?;
+    // the bridge and gateway coordination connect to their brokers with the Paho client only
+    #[cfg(feature = "paho")]
+    let bridge = MqttBridge::build(&appconfig.bridge, &appconfig.iotcore.device_id)
+        .map_err(|error| fatal(Some(&appconfig), ErrorCode::CONFIG_INVALID, error))?;
+    let (udp_s, udp_r) = unbounded();
+    if appconfig.udp.port().is_some() {
+        scanner.register_udp_input(&udp_r);
+    }
+    // JWT signing failures are tagged by the publisher
+    let client = publisher::build(&appconfig)
+        .map_err(|error| fatal(Some(&appconfig), ErrorCode::CONFIG_INVALID, error))?;
+    let mut iotcore = IotCoreClient::build(
+        &appconfig,
+        client,
+        &event_r,
+        &cnc,
+        &metrics,
+        &local_command_r,
+        &status,
+    )
+    .map_err(|error| fatal(Some(&appconfig), ErrorCode::CONFIG_INVALID, error))?;
+    let pusher = MetricsPusher::build(
+        &appconfig.metrics,
+        &metrics,
+        &appconfig.iotcore.device_id,
+        &running,
+    );
+    #[cfg(feature = "webui")]
+    let webui = WebUi::build(&appconfig.webui, &status, &local_command_s, &running);
+    let control = ControlServer::build(&appconfig.control, &status, &local_command_s, &running);
+    let udp_listener = UdpListener::build(&appconfig.udp, &udp_s, &running);
+    #[cfg(feature = "bacnet")]
+    let bacnet = BacnetServer::build(
+        &appconfig.bacnet,
+        &appconfig.iotcore.device_id,
+        &status,
+        &running,
+    );
+    #[cfg(feature = "opcua")]
+    let opcua = OpcUaServer::build(
+        &appconfig.opcua,
+        &appconfig.iotcore.device_id,
+        &status,
+        &running,
+    );
+    #[cfg(feature = "paho")]
+    let mut coordinator = Coordinator::build(
+        &appconfig.coordination,
+        &appconfig
+            .iotcore
+            .gateway_id()
+            .unwrap_or_else(|| appconfig.iotcore.device_id.clone()),
+        &status,
+        &running,
+    );
 
     thread::scope(|scope| {
         // spawn the mqtt thread
         scope.spawn(move |_| {
-            loop {
-                trace!("in MQTT thread loop");
-                match iotcore.start_client() {
-                    Ok(exit) => {
-                        if exit {
-                            break;
-                        } else {
-                            info!("Restarting IoT Core client due to internal state change.");
-                        }
-                    }
-                    Err(error) => error!("Restarting iotcore client due to error: {}", error),
-                };
-            }
+            run_client(&mut iotcore);
             info!("Shutting down IotCore client thread.");
+            // signal pipelines and auxiliary threads to shut down as well
+            pipeline::shutdown(&pipeline_shutdowns);
+            running.store(false, Ordering::Relaxed);
         });
 
+        // spawn threads of pipelines and the dispatcher relaying beacons to them
+        if !pipeline_instances.is_empty() {
+            scope.spawn(move |_| {
+                dispatcher.start_dispatcher();
+            });
+        }
+        for instance in pipeline_instances {
+            let PipelineInstance {
+                name,
+                mut client,
+                scanner,
+                ..
+            } = instance;
+            let pipeline_metrics = metrics.clone();
+            if let Some((mut scanner, filter)) = scanner {
+                scope.spawn(move |_| {
+                    run_scanner(&mut scanner, &pipeline_metrics);
+                });
+                scope.spawn(move |_| {
+                    filter.start_dispatcher();
+                });
+            }
+            scope.spawn(move |_| {
+                run_client(&mut client);
+                info!("Shutting down pipeline '{}'.", name);
+            });
+        }
+
         // spawn bt scan thread
+        let scanner_status = status.clone();
         scope.spawn(move |_| {
-            loop {
-                trace!("in BT thread loop");
-                match scanner.start_scanner() {
-                    Ok(exit) => {
-                        if exit {
-                            break;
-                        } else {
-                            info!("Restarting Bluetooth scanner due to internal state change.");
-                        }
-                    }
-                    Err(error) => error!("Restarting bluetooth scanner due to error: {}", error),
-                };
+            if let Some((records, speed)) = replay {
+                if let Err(error) = scanner.start_replay(records, speed) {
+                    error!("{}", error);
+                }
+                info!("Shutting down replay thread.");
+                return;
+            }
+            #[cfg(feature = "simulator")]
+            if let Some(tags) = simulate {
+                info!("Simulating {} Ruuvi tags instead of scanning.", tags);
+                if let Err(error) = scanner.start_replay(Simulator::build(tags), 1.0) {
+                    error!("{}", error);
+                }
+                info!("Shutting down simulator thread.");
+                return;
             }
+            #[cfg(feature = "paho")]
+            if let Some(mut bridge) = bridge {
+                info!("Receiving advertisements from MQTT bridge instead of scanning.");
+                if let Err(error) = scanner.start_bridge(&mut bridge) {
+                    error!("{}", error);
+                }
+                info!("Shutting down MQTT bridge thread.");
+                return;
+            }
+            // scanning without the capabilities it needs would fail and be restarted over and
+            //  over, so the scanner waits for shutdown instead while the rest keeps running
+            if let Err(error) = hci::check_capabilities() {
+                eprintln!("{:?}", error);
+                error!(
+                    "{}. Grant the capabilities with \"{}\" or \"{}\" in the systemd unit and restart.",
+                    error,
+                    hci::setcap_command(),
+                    hci::SYSTEMD_CAPABILITIES
+                );
+                scanner_status.lock().unwrap().degraded = Some(error.to_string());
+                scanner.start_degraded(&error.to_string());
+                info!("Shutting down Bluetooth scanner thread.");
+                return;
+            }
+            run_scanner(&mut scanner, &metrics);
             info!("Shutting down Bluetooth scanner thread.");
         });
+
+        // spawn metrics push thread
+        scope.spawn(move |_| {
+            pusher.start_pusher();
+        });
+
+        // spawn web dashboard thread
+        #[cfg(feature = "webui")]
+        scope.spawn(move |_| {
+            if let Err(error) = webui.start_server() {
+                error!("{}", error);
+            }
+        });
+
+        // spawn control socket thread
+        scope.spawn(move |_| {
+            if let Err(error) = control.start_server() {
+                error!("{}", error);
+            }
+        });
+
+        // spawn UDP listener thread
+        scope.spawn(move |_| {
+            if let Err(error) = udp_listener.start_listener() {
+                error!("{}", error);
+            }
+        });
+
+        // spawn BACnet/IP server thread
+        #[cfg(feature = "bacnet")]
+        scope.spawn(move |_| {
+            if let Err(error) = bacnet.start_server() {
+                error!("{}", error);
+            }
+        });
+
+        // spawn OPC UA server thread
+        #[cfg(feature = "opcua")]
+        scope.spawn(move |_| {
+            if let Err(error) = opcua.start_server() {
+                error!("{}", error);
+            }
+        });
+
+        // spawn gateway coordination thread
+        #[cfg(feature = "paho")]
+        scope.spawn(move |_| {
+            if let Err(error) = coordinator.start_coordinator() {
+                error!("{}", error);
+            }
+        });
     })
     .unwrap();
 
     warn!("Shutting down {}", env!("CARGO_PKG_NAME"));
+    if status.lock().unwrap().updated {
+        // exit with failure status so that the service manager restarts the new version
+        std::process::exit(appconfig.update.exit_code());
+    }
     // return with Ok (success)
     Ok(())
 }
@@ -0,0 +1,419 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{thread, time};
+
+use crate::configfile::MetricsConfig;
+
+const METRIC_PREFIX: &str = "ruuvi2iotcore";
+// quantiles are calculated from this many most recent observations
+const LATENCY_WINDOW: usize = 1000;
+
+#[derive(Debug, Default)]
+struct LatencyWindow {
+    samples: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LatencySummary {
+    window: Mutex<LatencyWindow>,
+}
+
+impl LatencySummary {
+    pub fn observe(&self, seconds: f64) {
+        let mut window = self.window.lock().unwrap();
+        if window.samples.len() >= LATENCY_WINDOW {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(seconds);
+        window.sum += seconds;
+        window.count += 1;
+    }
+
+    // returns p50, p95 and p99 of the recent observations
+    pub fn quantiles(&self) -> Option<(f64, f64, f64)> {
+        let window = self.window.lock().unwrap();
+        if window.samples.is_empty() {
+            return None;
+        }
+        let mut samples: Vec<f64> = window.samples.iter().copied().collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let quantile = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+        Some((quantile(0.5), quantile(0.95), quantile(0.99)))
+    }
+
+    fn sum_and_count(&self) -> (f64, u64) {
+        let window = self.window.lock().unwrap();
+        (window.sum, window.count)
+    }
+}
+
+// statistics of a single Bluetooth adapter for diagnosing stuck Bluetooth stack
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct AdapterStats {
+    pub advertisements_seen: u64,
+    pub frames_decoded: u64,
+    pub decode_errors: u64,
+    pub hci_errors: u64,
+    pub last_reset: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub beacons_received: AtomicU64,
+    pub beacons_published: AtomicU64,
+    pub publish_errors: AtomicU64,
+    pub mqtt_connects: AtomicU64,
+    pub scanner_restarts: AtomicU64,
+    pub discovered_tags: AtomicU64,
+    pub queued_beacons: AtomicU64,
+    pub queue_memory_bytes: AtomicU64,
+    pub queue_evictions: AtomicU64,
+    pub publish_latency: LatencySummary,
+    pub adapters: Mutex<BTreeMap<String, AdapterStats>>,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, value: u64) {
+        gauge.store(value, Ordering::Relaxed);
+    }
+
+    pub fn update_adapter<F: FnOnce(&mut AdapterStats)>(&self, adapter: &str, update: F) {
+        let mut adapters = self.adapters.lock().unwrap();
+        update(adapters.entry(adapter.to_string()).or_default());
+    }
+
+    fn render_adapter_metric<F: Fn(&AdapterStats) -> u64>(
+        output: &mut String,
+        adapters: &BTreeMap<String, AdapterStats>,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: F,
+    ) {
+        writeln!(output, "# HELP {}_{} {}", METRIC_PREFIX, name, help).unwrap();
+        writeln!(output, "# TYPE {}_{} {}", METRIC_PREFIX, name, kind).unwrap();
+        for (adapter, stats) in adapters.iter() {
+            writeln!(
+                output,
+                "{}_{}{{adapter=\"{}\"}} {}",
+                METRIC_PREFIX,
+                name,
+                adapter,
+                value(stats)
+            )
+            .unwrap();
+        }
+    }
+
+    fn render_metric(output: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+        writeln!(output, "# HELP {}_{} {}", METRIC_PREFIX, name, help).unwrap();
+        writeln!(output, "# TYPE {}_{} {}", METRIC_PREFIX, name, kind).unwrap();
+        writeln!(output, "{}_{} {}", METRIC_PREFIX, name, value).unwrap();
+    }
+
+    // renders the metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        trace!("in render");
+        let mut output = String::new();
+        Metrics::render_metric(
+            &mut output,
+            "beacons_received_total",
+            "counter",
+            "Ruuvi tag beacons decoded by the Bluetooth scanner.",
+            self.beacons_received.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "beacons_published_total",
+            "counter",
+            "Ruuvi tag beacons published to IoT Core.",
+            self.beacons_published.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "publish_errors_total",
+            "counter",
+            "Failed MQTT publish attempts.",
+            self.publish_errors.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "mqtt_connects_total",
+            "counter",
+            "Connections made to IoT Core MQTT broker.",
+            self.mqtt_connects.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "scanner_restarts_total",
+            "counter",
+            "Restarts of the Bluetooth scanner.",
+            self.scanner_restarts.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "discovered_tags",
+            "gauge",
+            "Ruuvi tags currently attached to the gateway.",
+            self.discovered_tags.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "queued_beacons",
+            "gauge",
+            "Beacons waiting in queues to be published.",
+            self.queued_beacons.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "queue_memory_bytes",
+            "gauge",
+            "Approximate memory used by beacons waiting in queues.",
+            self.queue_memory_bytes.load(Ordering::Relaxed),
+        );
+        Metrics::render_metric(
+            &mut output,
+            "queue_evictions_total",
+            "counter",
+            "Beacons dropped from full queues.",
+            self.queue_evictions.load(Ordering::Relaxed),
+        );
+        Metrics::render_summary(
+            &mut output,
+            "publish_latency_seconds",
+            "Time from beacon reception to acknowledged MQTT publish.",
+            &self.publish_latency,
+        );
+        let adapters = self.adapters.lock().unwrap();
+        Metrics::render_adapter_metric(
+            &mut output,
+            &adapters,
+            "adapter_advertisements_total",
+            "counter",
+            "Bluetooth advertisements seen by the adapter.",
+            |stats| stats.advertisements_seen,
+        );
+        Metrics::render_adapter_metric(
+            &mut output,
+            &adapters,
+            "adapter_frames_decoded_total",
+            "counter",
+            "Beacon frames decoded from advertisements seen by the adapter.",
+            |stats| stats.frames_decoded,
+        );
+        Metrics::render_adapter_metric(
+            &mut output,
+            &adapters,
+            "adapter_decode_errors_total",
+            "counter",
+            "Advertisements of known sensors that could not be decoded.",
+            |stats| stats.decode_errors,
+        );
+        Metrics::render_adapter_metric(
+            &mut output,
+            &adapters,
+            "adapter_hci_errors_total",
+            "counter",
+            "Errors returned by the adapter.",
+            |stats| stats.hci_errors,
+        );
+        Metrics::render_adapter_metric(
+            &mut output,
+            &adapters,
+            "adapter_last_reset_timestamp_seconds",
+            "gauge",
+            "Time of the last reset of the adapter.",
+            |stats| match stats.last_reset {
+                Some(last_reset) => last_reset.timestamp() as u64,
+                None => 0,
+            },
+        );
+        output
+    }
+
+    // one line summary per Bluetooth adapter for the log
+    pub fn adapter_summaries(&self) -> Vec<String> {
+        trace!("in adapter_summaries");
+        let adapters = self.adapters.lock().unwrap();
+        adapters
+            .iter()
+            .map(|(adapter, stats)| {
+                format!(
+                    "Bluetooth adapter {}: {} advertisements seen, {} frames decoded, {} decode errors, {} HCI errors, last reset {}",
+                    adapter,
+                    stats.advertisements_seen,
+                    stats.frames_decoded,
+                    stats.decode_errors,
+                    stats.hci_errors,
+                    match stats.last_reset {
+                        Some(last_reset) => last_reset.to_rfc3339(),
+                        None => "never".to_string(),
+                    }
+                )
+            })
+            .collect()
+    }
+
+    fn render_summary(output: &mut String, name: &str, help: &str, summary: &LatencySummary) {
+        writeln!(output, "# HELP {}_{} {}", METRIC_PREFIX, name, help).unwrap();
+        writeln!(output, "# TYPE {}_{} summary", METRIC_PREFIX, name).unwrap();
+        if let Some((p50, p95, p99)) = summary.quantiles() {
+            for (quantile, value) in &[("0.5", p50), ("0.95", p95), ("0.99", p99)] {
+                writeln!(
+                    output,
+                    "{}_{}{{quantile=\"{}\"}} {}",
+                    METRIC_PREFIX, name, quantile, value
+                )
+                .unwrap();
+            }
+        }
+        let (sum, count) = summary.sum_and_count();
+        writeln!(output, "{}_{}_sum {}", METRIC_PREFIX, name, sum).unwrap();
+        writeln!(output, "{}_{}_count {}", METRIC_PREFIX, name, count).unwrap();
+    }
+
+    // one line summary of pipeline throughput and latency for the log
+    pub fn summary(&self, published_since: u64, interval: Duration) -> String {
+        trace!("in summary");
+        let throughput = published_since as f64 / interval.as_secs_f64();
+        match self.publish_latency.quantiles() {
+            Some((p50, p95, p99)) => format!(
+                "{} beacons published in {} seconds ({:.2}/s), publish latency p50 {:.3}s, p95 {:.3}s, p99 {:.3}s",
+                published_since,
+                interval.as_secs(),
+                throughput,
+                p50,
+                p95,
+                p99
+            ),
+            None => format!(
+                "{} beacons published in {} seconds ({:.2}/s)",
+                published_since,
+                interval.as_secs(),
+                throughput
+            ),
+        }
+    }
+}
+
+pub struct MetricsPusher {
+    config: MetricsConfig,
+    metrics: Arc<Metrics>,
+    instance: String,
+    running: Arc<AtomicBool>,
+}
+
+impl MetricsPusher {
+    fn push(&self, url: &str) -> Result<(), Report> {
+        trace!("in push");
+        let push_url = format!(
+            "{}/metrics/job/{}/instance/{}",
+            url.trim_end_matches('/'),
+            self.config.push_job(),
+            self.instance
+        );
+        debug!("pushing metrics to: {}", push_url);
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        let mut request = agent.put(&push_url);
+        if let Some(username) = self.config.push_username() {
+            let credentials = format!(
+                "{}:{}",
+                username,
+                self.config.push_password().unwrap_or_default()
+            );
+            request = request.set(
+                "Authorization",
+                &format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        match request.send_string(&self.metrics.render()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Unable to push metrics to push gateway")
+                .with_section(move || push_url.header("URL:"))
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+
+    pub fn start_pusher(&self) {
+        trace!("in start_pusher");
+        #[cfg(feature = "prometheus")]
+        let url = self.config.push_url();
+        #[cfg(not(feature = "prometheus"))]
+        let url: Option<String> = None;
+        match &url {
+            Some(url) => info!(
+                "Pushing metrics to {} every {} seconds",
+                url,
+                self.config.push_interval()
+            ),
+            None => debug!("No push gateway configured for metrics."),
+        }
+        let log_interval = Duration::from_secs(self.config.log_interval());
+
+        let mut last_push: Option<Instant> = None;
+        let mut last_log = Instant::now();
+        let mut last_published = 0;
+        while self.running.load(Ordering::Relaxed) {
+            if let Some(url) = &url {
+                let push_due = match last_push {
+                    Some(last_push) => {
+                        last_push.elapsed() >= Duration::from_secs(self.config.push_interval())
+                    }
+                    None => true,
+                };
+                if push_due {
+                    if let Err(error) = self.push(url) {
+                        warn!("{}", error);
+                    }
+                    last_push = Some(Instant::now());
+                }
+            }
+            if self.config.log_interval() > 0 && last_log.elapsed() >= log_interval {
+                let published = self.metrics.beacons_published.load(Ordering::Relaxed);
+                info!(
+                    "{}",
+                    self.metrics
+                        .summary(published - last_published, last_log.elapsed())
+                );
+                for adapter_summary in self.metrics.adapter_summaries() {
+                    info!("{}", adapter_summary);
+                }
+                last_published = published;
+                last_log = Instant::now();
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        info!("Shutting down metrics pusher.");
+    }
+
+    pub fn build(
+        config: &MetricsConfig,
+        metrics: &Arc<Metrics>,
+        instance: &str,
+        running: &Arc<AtomicBool>,
+    ) -> MetricsPusher {
+        trace!("in build");
+        MetricsPusher {
+            config: config.clone(),
+            metrics: metrics.clone(),
+            instance: instance.to_string(),
+            running: running.clone(),
+        }
+    }
+}
+
+// eof
@@ -0,0 +1,222 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::configfile::SinkConfig;
+use crate::scanner::{RuuviBluetoothBeacon, SharedBeacon};
+use crate::serviceaccount::ServiceAccount;
+use crate::sinks::Sink;
+
+// API of Cloud Monitoring when the sink does not give another one
+const DEFAULT_API_URL: &str = "https://monitoring.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/monitoring.write";
+// metric types under custom.googleapis.com/ruuvi/ and the value of the metric in decoded
+//  beacon data
+const METRICS: [(&str, &str, f64); 3] = [
+    ("temperature", "temperature", 1.0),
+    ("humidity", "humidity", 1.0),
+    ("battery_voltage", "powerinfo", 0.001),
+];
+// time series written at most in a request
+const MAX_TIME_SERIES: usize = 200;
+// cloud monitoring accepts a point of a time series at most every five seconds
+const MIN_POINT_INTERVAL: i64 = 5;
+
+/// Sink writing temperature, humidity and battery voltage of each tag as custom metrics of
+/// Cloud Monitoring, authenticated as a service account.
+pub struct MonitoringSink {
+    url: String,
+    project_id: String,
+    device_id: String,
+    account: ServiceAccount,
+    // time of the last point written of each tag
+    written: HashMap<String, chrono::DateTime<chrono::Utc>>,
+    agent: ureq::Agent,
+}
+
+impl MonitoringSink {
+    fn time_series(&self, beacon: &RuuviBluetoothBeacon) -> Vec<serde_json::Value> {
+        let data = beacon.data.clone().unwrap_or(serde_json::Value::Null);
+        METRICS
+            .iter()
+            .filter_map(|(metric, field, scale)| {
+                let value = data[field].as_f64()? * scale;
+                Some(json!({
+                    "metric": {
+                        "type": format!("custom.googleapis.com/ruuvi/{}", metric),
+                        "labels": { "address": beacon.address, "gateway": self.device_id },
+                    },
+                    "resource": {
+                        "type": "generic_node",
+                        "labels": {
+                            "project_id": self.project_id,
+                            "location": "global",
+                            "namespace": env!("CARGO_PKG_NAME"),
+                            "node_id": self.device_id,
+                        },
+                    },
+                    "points": [{
+                        "interval": { "endTime": beacon.timestamp.to_rfc3339() },
+                        "value": { "doubleValue": value },
+                    }],
+                }))
+            })
+            .collect()
+    }
+
+    fn write(&mut self, time_series: &[serde_json::Value]) -> Result<(), String> {
+        trace!("in write");
+        let token = self.account.access_token(&self.agent)?;
+        let body = json!({ "timeSeries": time_series });
+        match self
+            .agent
+            .post(&self.url)
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())
+        {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => {
+                if status == 401 {
+                    self.account.revoke();
+                }
+                Err(format!(
+                    "{}: {}",
+                    status,
+                    response.into_string().unwrap_or_default()
+                ))
+            }
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    pub fn build(config: &SinkConfig, device_id: &str) -> Result<MonitoringSink, Report> {
+        trace!("in build");
+        let key_file = match config.service_account_key() {
+            Some(key_file) => key_file,
+            None => {
+                return Err(eyre!(
+                    "No service account key given for Cloud Monitoring sink"
+                ))
+            }
+        };
+        let account = ServiceAccount::read(&key_file, SCOPE)?;
+        let project_id = match config.project_id().or_else(|| account.project_id()) {
+            Some(project_id) => project_id,
+            None => return Err(eyre!("No project id given for Cloud Monitoring sink")),
+        };
+        Ok(MonitoringSink {
+            url: format!(
+                "{}/v3/projects/{}/timeSeries",
+                config
+                    .url()
+                    .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+                    .trim_end_matches('/'),
+                project_id
+            ),
+            project_id,
+            device_id: device_id.to_string(),
+            account,
+            written: HashMap::new(),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout()))
+                .build(),
+        })
+    }
+}
+
+impl Sink for MonitoringSink {
+    fn name(&self) -> String {
+        format!("Cloud Monitoring of project {}", self.project_id)
+    }
+
+    fn deliver(&mut self, beacons: &[SharedBeacon]) -> Result<(), Report> {
+        trace!("in deliver");
+        // a request can hold only one point of a time series, so only the latest beacon of
+        //  each tag is written, and only if the previous point is old enough
+        let mut latest: HashMap<&str, &RuuviBluetoothBeacon> = HashMap::new();
+        for beacon in beacons {
+            if let Some(written) = self.written.get(&beacon.address) {
+                if (beacon.timestamp - *written).num_seconds() < MIN_POINT_INTERVAL {
+                    continue;
+                }
+            }
+            latest.insert(&beacon.address, beacon);
+        }
+        let mut time_series = Vec::new();
+        for beacon in latest.values() {
+            time_series.extend(self.time_series(beacon));
+        }
+        for chunk in time_series.chunks(MAX_TIME_SERIES) {
+            if let Err(reason) = self.write(chunk) {
+                let project_id = self.project_id.clone();
+                return Err(eyre!("Unable to write metrics to Cloud Monitoring")
+                    .with_section(move || project_id.header("Project:"))
+                    .with_section(move || reason.header("Reason:")));
+            }
+        }
+        for (address, beacon) in latest {
+            self.written.insert(address.to_string(), beacon.timestamp);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MonitoringSink;
+    use crate::configfile::SinkConfig;
+    use crate::mock::{beacon, serve, service_account_key, shared_beacon, HttpRequest};
+    use crate::sinks::Sink;
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn tag_metrics_are_written_to_cloud_monitoring() {
+        let token = json!({ "access_token": "ya29.token", "expires_in": 3600 }).to_string();
+        let (url, server) = serve(vec![(200, token), (200, "{}".to_string())]);
+        let key_file = service_account_key(&format!("{}/token", url));
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "monitoring",
+            "url": url,
+            "service_account_key": key_file,
+        }))
+        .unwrap();
+        let mut sink = MonitoringSink::build(&config, "test-gateway").unwrap();
+        let mut tag = beacon("AA:BB:CC:DD:EE:01", 2);
+        tag.data.as_mut().unwrap()["powerinfo"] = json!(2977);
+        let batch = [
+            shared_beacon("AA:BB:CC:DD:EE:01", 1),
+            Arc::new(tag),
+            shared_beacon("AA:BB:CC:DD:EE:02", 3),
+        ];
+        sink.deliver(&batch).unwrap();
+        // points of the tags were just written, so these are skipped without a request
+        sink.deliver(&[shared_beacon("AA:BB:CC:DD:EE:01", 4)])
+            .unwrap();
+        fs::remove_file(key_file).unwrap();
+
+        let requests: Vec<HttpRequest> = server.iter().collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].line,
+            "POST /v3/projects/ruuvi-project/timeSeries HTTP/1.1"
+        );
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        let time_series = body["timeSeries"].as_array().unwrap();
+        // battery voltage is only known for the first tag
+        assert_eq!(time_series.len(), 5);
+        let battery = time_series
+            .iter()
+            .find(|series| {
+                series["metric"]["type"] == "custom.googleapis.com/ruuvi/battery_voltage"
+            })
+            .unwrap();
+        assert_eq!(battery["metric"]["labels"]["address"], "AA:BB:CC:DD:EE:01");
+        assert_eq!(battery["metric"]["labels"]["gateway"], "test-gateway");
+        assert_eq!(battery["resource"]["labels"]["node_id"], "test-gateway");
+        assert_eq!(battery["points"][0]["value"]["doubleValue"], 2.977);
+    }
+}
+
+// eof
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::alerts::metric_value;
+use crate::scanner::RuuviBluetoothBeacon;
+
+/// Movement of a tag in the beacons received since its last published beacon, published as
+/// the "movement" data field. Beacons left out by sampling or hooks count too, so vibration
+/// monitoring gets the peaks of the beacons it does not receive.
+#[derive(Debug, Default, Clone, Serialize, PartialEq)]
+pub struct MovementSummary {
+    /// Largest total acceleration in mG
+    pub peak_acceleration: Option<f64>,
+    /// Movements detected by the tag
+    pub movement_delta: u64,
+    /// Beacons the summary covers
+    pub samples: u64,
+}
+
+// summary being accumulated for a tag, and the movement counter of its latest beacon
+#[derive(Default)]
+struct TagMovement {
+    summary: MovementSummary,
+    last_counter: Option<u64>,
+}
+
+/// Total acceleration of the beacon in mG, if it has one.
+pub fn acceleration_total(beacon: &RuuviBluetoothBeacon) -> Option<f64> {
+    let x = metric_value(beacon, "acceleration.on_x_axis")?;
+    let y = metric_value(beacon, "acceleration.on_y_axis")?;
+    let z = metric_value(beacon, "acceleration.on_z_axis")?;
+    Some((x * x + y * y + z * z).sqrt().round())
+}
+
+/// Accumulates the movement of tags between their published beacons.
+#[derive(Default)]
+pub struct MovementTracker {
+    tags: HashMap<String, TagMovement>,
+}
+
+impl MovementTracker {
+    pub fn record(&mut self, beacon: &RuuviBluetoothBeacon) {
+        let acceleration = acceleration_total(beacon);
+        let counter = metric_value(beacon, "movement_counter").map(|counter| counter as u64);
+        if acceleration.is_none() && counter.is_none() {
+            return;
+        }
+        let tag = self.tags.entry(beacon.address.clone()).or_default();
+        tag.summary.samples += 1;
+        if let Some(acceleration) = acceleration {
+            let peak = tag.summary.peak_acceleration.get_or_insert(acceleration);
+            *peak = peak.max(acceleration);
+        }
+        if let Some(counter) = counter {
+            // the counter of a tag is a byte that wraps around
+            if let Some(last_counter) = tag.last_counter {
+                tag.summary.movement_delta += counter.wrapping_sub(last_counter) % 256;
+            }
+            tag.last_counter = Some(counter);
+        }
+    }
+
+    /// Summary of the tag since it was taken last time.
+    pub fn take(&mut self, address: &str) -> Option<MovementSummary> {
+        let tag = self.tags.get_mut(address)?;
+        if tag.summary.samples == 0 {
+            return None;
+        }
+        Some(std::mem::take(&mut tag.summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MovementTracker;
+    use crate::mock::beacon;
+
+    #[test]
+    fn movement_is_summarized_between_takes() {
+        let mut tracker = MovementTracker::default();
+        let mut record = |sequence: u64, x: f64, counter: u64| {
+            let mut tag = beacon("AA:BB:CC:DD:EE:01", sequence);
+            let data = tag.data.as_mut().unwrap();
+            data["acceleration"] = json!({ "on_x_axis": x, "on_y_axis": 0.0, "on_z_axis": 1000.0 });
+            data["movement_counter"] = json!(counter);
+            tracker.record(&tag);
+        };
+        record(1, 0.0, 254);
+        record(2, 2000.0, 255);
+        record(3, 0.0, 1);
+
+        let summary = tracker.take("AA:BB:CC:DD:EE:01").unwrap();
+        assert_eq!(summary.samples, 3);
+        assert_eq!(summary.peak_acceleration, Some(2236.0));
+        assert_eq!(summary.movement_delta, 3);
+        assert_eq!(tracker.take("AA:BB:CC:DD:EE:01"), None);
+
+        // counting continues from the last beacon before the take
+        let mut tag = beacon("AA:BB:CC:DD:EE:01", 4);
+        tag.data.as_mut().unwrap()["movement_counter"] = json!(3);
+        tracker.record(&tag);
+        let summary = tracker.take("AA:BB:CC:DD:EE:01").unwrap();
+        assert_eq!(summary.movement_delta, 2);
+        assert_eq!(summary.peak_acceleration, None);
+    }
+}
+
+// eof
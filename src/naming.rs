@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+
+use crate::iotcore::FieldNaming;
+
+// short keys of the fields of beacons and Ruuvi tag data, other fields keep their names
+const SHORT_KEYS: [(&str, &str); 20] = [
+    ("address", "mac"),
+    ("timestamp", "ts"),
+    ("decoder", "dec"),
+    ("data", "d"),
+    ("raw", "r"),
+    ("sequence", "seq"),
+    ("tag_sequence", "tseq"),
+    ("received_monotonic_ms", "mono"),
+    ("temperature", "t"),
+    ("humidity", "h"),
+    ("atmospheric_pressure", "p"),
+    ("acceleration", "acc"),
+    ("on_x_axis", "x"),
+    ("on_y_axis", "y"),
+    ("on_z_axis", "z"),
+    ("acceleration_total", "acct"),
+    ("powerinfo", "bat"),
+    ("movement_counter", "mov"),
+    ("measurement_sequence_number", "mseq"),
+    ("tx_power", "tx"),
+];
+
+fn camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for character in key.chars() {
+        if character == '_' {
+            upper = !camel.is_empty();
+        } else if upper {
+            camel.extend(character.to_uppercase());
+            upper = false;
+        } else {
+            camel.push(character);
+        }
+    }
+    camel
+}
+
+fn short_key(key: &str) -> String {
+    match SHORT_KEYS.iter().find(|(long, _)| *long == key) {
+        Some((_, short)) => short.to_string(),
+        None => key.to_string(),
+    }
+}
+
+/// Renames the fields of objects in the payload, including nested ones, to the naming.
+pub fn rename_fields(value: serde_json::Value, naming: FieldNaming) -> serde_json::Value {
+    let rename: fn(&str) -> String = match naming {
+        FieldNaming::SNAKE => return value,
+        FieldNaming::CAMEL => camel_case,
+        FieldNaming::SHORT => short_key,
+    };
+    rename_with(value, rename)
+}
+
+fn rename_with(value: serde_json::Value, rename: fn(&str) -> String) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => serde_json::Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| (rename(&key), rename_with(value, rename)))
+                .collect(),
+        ),
+        serde_json::Value::Array(array) => serde_json::Value::Array(
+            array
+                .into_iter()
+                .map(|value| rename_with(value, rename))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// Numeric fields of decoded beacon data by name, names of nested fields joined with the
+/// separator, e.g. acceleration_on_x_axis with "_".
+pub fn numeric_fields(value: &serde_json::Value, separator: &str) -> BTreeMap<String, f64> {
+    let mut fields = BTreeMap::new();
+    collect_numeric("", value, separator, &mut fields);
+    fields
+}
+
+fn collect_numeric(
+    prefix: &str,
+    value: &serde_json::Value,
+    separator: &str,
+    fields: &mut BTreeMap<String, f64>,
+) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                let name = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}{}{}", prefix, separator, key)
+                };
+                collect_numeric(&name, value, separator, fields);
+            }
+        }
+        serde_json::Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                fields.insert(prefix.to_string(), number);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{numeric_fields, rename_fields};
+    use crate::iotcore::FieldNaming;
+    use crate::mock::beacon;
+
+    #[test]
+    fn fields_are_renamed() {
+        let mut tag = serde_json::to_value(beacon("AA:BB:CC:DD:EE:01", 1)).unwrap();
+        tag["data"]["atmospheric_pressure"] = json!(100_300);
+        tag["data"]["acceleration"] = json!({ "on_x_axis": -4, "on_y_axis": 8, "on_z_axis": 1016 });
+        tag["site"] = json!({ "floor_name": "2nd" });
+        let queue = json!([tag]);
+
+        let camel = rename_fields(queue.clone(), FieldNaming::CAMEL);
+        assert_eq!(camel[0]["data"]["atmosphericPressure"], 100_300);
+        assert_eq!(camel[0]["data"]["acceleration"]["onXAxis"], -4);
+        assert_eq!(camel[0]["data"]["measurementSequenceNumber"], 1);
+        assert_eq!(camel[0]["receivedMonotonicMs"], 1000);
+        assert_eq!(camel[0]["address"], "AA:BB:CC:DD:EE:01");
+
+        let short = rename_fields(queue.clone(), FieldNaming::SHORT);
+        assert_eq!(short[0]["mac"], "AA:BB:CC:DD:EE:01");
+        assert_eq!(short[0]["d"]["t"], 21.5);
+        assert_eq!(short[0]["d"]["acc"]["z"], 1016);
+        assert_eq!(short[0]["d"]["tx"], 4);
+        // fields without a short key keep their names
+        assert_eq!(short[0]["site"]["floor_name"], "2nd");
+
+        assert_eq!(rename_fields(queue.clone(), FieldNaming::SNAKE), queue);
+    }
+
+    #[test]
+    fn nested_numeric_fields_are_joined() {
+        let data = json!({ "temperature": 21.5, "acceleration": { "on_x_axis": -4 }, "name": "x" });
+        let fields = numeric_fields(&data, "/");
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields["temperature"], 21.5);
+        assert_eq!(fields["acceleration/on_x_axis"], -4.0);
+        assert!(numeric_fields(&data, "_").contains_key("acceleration_on_x_axis"));
+    }
+}
+
+// eof
@@ -0,0 +1,353 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::configfile::SinkConfig;
+use crate::scanner::SharedBeacon;
+use crate::sinks::{self, Sink};
+
+// port of NATS servers when the URL does not give one
+const DEFAULT_PORT: u16 = 4222;
+
+// host and port of a nats:// URL
+fn parse_url(url: &str) -> Result<(String, u16), String> {
+    let address = match url.strip_prefix("nats://") {
+        Some(address) => address.trim_end_matches('/'),
+        None => return Err("only nats:// URLs are supported, TLS is not".to_string()),
+    };
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => Ok((host.to_string(), port)),
+            Err(_) => Err(format!("invalid port {}", port)),
+        },
+        None => Ok((address.to_string(), DEFAULT_PORT)),
+    }
+}
+
+// connection speaking the NATS client protocol, which is line based text apart from payloads
+struct Connection {
+    stream: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.stream
+            .get_mut()
+            .write_all(data)
+            .map_err(|error| error.to_string())
+    }
+
+    // next protocol line from the server. keep-alive pings of the server are answered on the
+    //  way and errors it reports are returned as such
+    fn read_line(&mut self) -> Result<String, String> {
+        loop {
+            let mut line = String::new();
+            match self.stream.read_line(&mut line) {
+                Ok(0) => return Err("connection closed by the server".to_string()),
+                Ok(_) => {}
+                Err(error) => return Err(error.to_string()),
+            }
+            let line = line.trim_end();
+            if line == "PING" {
+                self.write(b"PONG\r\n")?;
+            } else if let Some(error) = line.strip_prefix("-ERR ") {
+                return Err(error.trim_matches('\'').to_string());
+            } else if line != "+OK" {
+                return Ok(line.to_string());
+            }
+        }
+    }
+
+    // waits for the server to answer a ping, which it does after it has processed everything
+    //  sent before it
+    fn flush(&mut self) -> Result<(), String> {
+        self.write(b"PING\r\n")?;
+        while self.read_line()? != "PONG" {}
+        Ok(())
+    }
+
+    fn publish(
+        &mut self,
+        subject: &str,
+        reply: Option<&str>,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        let mut message = match reply {
+            Some(reply) => format!("PUB {} {} {}\r\n", subject, reply, payload.len()),
+            None => format!("PUB {} {}\r\n", subject, payload.len()),
+        }
+        .into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+        self.write(&message)
+    }
+
+    // subject and payload of the next message received on a subscription
+    fn next_message(&mut self) -> Result<(String, Vec<u8>), String> {
+        loop {
+            let line = self.read_line()?;
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[0] != "MSG" {
+                continue;
+            }
+            let length: usize = match fields[fields.len() - 1].parse() {
+                Ok(length) => length,
+                Err(_) => return Err(format!("invalid message from the server: {}", line)),
+            };
+            // the payload is followed by a line break
+            let mut payload = vec![0; length + 2];
+            if let Err(error) = self.stream.read_exact(&mut payload) {
+                return Err(error.to_string());
+            }
+            payload.truncate(length);
+            return Ok((fields[1].to_string(), payload));
+        }
+    }
+}
+
+/// Sink publishing each beacon to a NATS server on a subject of its tag. With JetStream each
+/// message is published with a reply subject and the batch is delivered once the stream has
+/// acknowledged storing all of its messages.
+pub struct NatsSink {
+    url: String,
+    host: String,
+    port: u16,
+    connect_options: serde_json::Value,
+    subject: String,
+    jetstream: bool,
+    device_id: String,
+    timeout: Duration,
+    inbox: String,
+    connection: Option<Connection>,
+}
+
+impl NatsSink {
+    fn connect(&self) -> Result<Connection, String> {
+        trace!("in connect");
+        let address = match (self.host.as_str(), self.port).to_socket_addrs() {
+            Ok(mut addresses) => match addresses.next() {
+                Some(address) => address,
+                None => return Err(format!("unable to resolve {}", self.host)),
+            },
+            Err(error) => return Err(error.to_string()),
+        };
+        let stream = TcpStream::connect_timeout(&address, self.timeout)
+            .map_err(|error| error.to_string())?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|error| error.to_string())?;
+        let mut connection = Connection {
+            stream: BufReader::new(stream),
+        };
+
+        let info = connection.read_line()?;
+        let info: serde_json::Value = match info.strip_prefix("INFO ") {
+            Some(info) => serde_json::from_str(info).map_err(|error| error.to_string())?,
+            None => return Err(format!("unexpected greeting from the server: {}", info)),
+        };
+        if info["tls_required"] == true {
+            return Err("the server requires TLS, which is not supported".to_string());
+        }
+        connection.write(format!("CONNECT {}\r\n", self.connect_options).as_bytes())?;
+        if self.jetstream {
+            connection.write(format!("SUB {}.* 1\r\n", self.inbox).as_bytes())?;
+        }
+        // the server reports errors, e.g. of authentication, before it answers the ping
+        connection.flush()?;
+        debug!("connected to NATS server {}", self.url);
+        Ok(connection)
+    }
+
+    fn publish(&mut self, beacons: &[SharedBeacon]) -> Result<(), String> {
+        trace!("in publish");
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        let connection = self.connection.as_mut().unwrap();
+        for (index, beacon) in beacons.iter().enumerate() {
+            let subject = sinks::topic(&self.subject, &self.device_id, &beacon.address);
+            let payload = serde_json::to_vec(beacon).unwrap();
+            if !self.jetstream {
+                connection.publish(&subject, None, &payload)?;
+                continue;
+            }
+            let reply = format!("{}.{}", self.inbox, index);
+            connection.publish(&subject, Some(&reply), &payload)?;
+            loop {
+                let (reply_subject, ack) = connection.next_message()?;
+                if reply_subject != reply {
+                    continue;
+                }
+                let ack: serde_json::Value = match serde_json::from_slice(&ack) {
+                    Ok(ack) => ack,
+                    Err(error) => return Err(format!("invalid JetStream ack: {}", error)),
+                };
+                if let Some(error) = ack.get("error") {
+                    return Err(format!(
+                        "JetStream did not store the message on {}: {}",
+                        subject, error["description"]
+                    ));
+                }
+                break;
+            }
+        }
+        if !self.jetstream {
+            connection.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn build(config: &SinkConfig, device_id: &str) -> Result<NatsSink, Report> {
+        trace!("in build");
+        let url = match config.url() {
+            Some(url) => url,
+            None => return Err(eyre!("No URL given for NATS sink")),
+        };
+        let (host, port) = match parse_url(&url) {
+            Ok(address) => address,
+            Err(reason) => {
+                return Err(eyre!("Invalid NATS URL")
+                    .with_section(move || url.header("URL:"))
+                    .with_section(move || reason.header("Reason:")))
+            }
+        };
+        let mut connect_options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "ruuvi2iotcore",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+        });
+        if let Some(bearer_token) = config.bearer_token() {
+            connect_options["auth_token"] = json!(bearer_token);
+        }
+        if let Some(username) = config.username() {
+            connect_options["user"] = json!(username);
+            connect_options["pass"] = json!(config.password().unwrap_or_default());
+        }
+        Ok(NatsSink {
+            url,
+            host,
+            port,
+            connect_options,
+            subject: config.subject(),
+            jetstream: config.jetstream(),
+            device_id: device_id.to_string(),
+            timeout: Duration::from_secs(config.timeout()),
+            inbox: format!("_INBOX.{:016x}", rand::random::<u64>()),
+            connection: None,
+        })
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    fn deliver(&mut self, beacons: &[SharedBeacon]) -> Result<(), Report> {
+        trace!("in deliver");
+        match self.publish(beacons) {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                // reconnected on the next delivery
+                self.connection = None;
+                let url = self.url.clone();
+                Err(eyre!("Unable to deliver beacons to NATS")
+                    .with_section(move || url.header("URL:"))
+                    .with_section(move || reason.header("Reason:")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_url, NatsSink};
+    use crate::configfile::SinkConfig;
+    use crate::mock::{serve_lines, shared_beacon};
+    use crate::sinks::Sink;
+    use crossbeam::channel;
+    use std::io::{BufRead, Read, Write};
+
+    // NATS server acknowledging messages published with a reply subject like JetStream does,
+    //  and relaying the protocol lines and published messages it received
+    fn serve() -> (String, channel::Receiver<String>) {
+        let (sender, receiver) = channel::unbounded();
+        let (address, _) = serve_lines(1, move |reader| {
+            reader
+                .get_mut()
+                .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+                .unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let fields: Vec<String> = line.split_whitespace().map(String::from).collect();
+                match fields[0].as_str() {
+                    "PING" => reader.get_mut().write_all(b"PONG\r\n").unwrap(),
+                    "PUB" => {
+                        let length: usize = fields[fields.len() - 1].parse().unwrap();
+                        let mut payload = vec![0; length + 2];
+                        reader.read_exact(&mut payload).unwrap();
+                        if fields.len() == 4 {
+                            let ack = "{\"stream\":\"RUUVI\",\"seq\":1}";
+                            let reply = format!("MSG {} 1 {}\r\n{}\r\n", fields[2], ack.len(), ack);
+                            reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                        }
+                        let payload = String::from_utf8_lossy(&payload[..length]).to_string();
+                        sender.send(format!("{} {}", fields[1], payload)).unwrap();
+                    }
+                    _ => sender.send(line.trim_end().to_string()).unwrap(),
+                }
+            }
+        });
+        (format!("nats://{}", address), receiver)
+    }
+
+    #[test]
+    fn nats_sink_publishes_to_jetstream() {
+        assert_eq!(
+            parse_url("nats://localhost").unwrap(),
+            ("localhost".to_string(), 4222)
+        );
+        assert!(parse_url("tls://localhost:4222").is_err());
+
+        let (url, received) = serve();
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "nats",
+            "url": url,
+            "subject": "ruuvi.{device_id}.{address}",
+            "jetstream": true,
+            "bearer_token": "secret",
+        }))
+        .unwrap();
+        let mut sink = NatsSink::build(&config, "test-gateway").unwrap();
+        sink.deliver(&[
+            shared_beacon("AA:BB:CC:DD:EE:01", 1),
+            shared_beacon("AA:BB:CC:DD:EE:02", 2),
+        ])
+        .unwrap();
+
+        let connect = received.recv().unwrap();
+        let options: serde_json::Value =
+            serde_json::from_str(connect.strip_prefix("CONNECT ").unwrap()).unwrap();
+        assert_eq!(options["auth_token"], "secret");
+        assert!(received.recv().unwrap().starts_with("SUB _INBOX."));
+        let published = received.recv().unwrap();
+        let (subject, payload) = published.split_once(' ').unwrap();
+        assert_eq!(subject, "ruuvi.test-gateway.AABBCCDDEE01");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["address"], "AA:BB:CC:DD:EE:01");
+        assert!(received
+            .recv()
+            .unwrap()
+            .starts_with("ruuvi.test-gateway.AABBCCDDEE02 "));
+    }
+}
+
+// eof
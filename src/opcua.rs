@@ -0,0 +1,1458 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configfile::OpcUaConfig;
+use crate::naming;
+use crate::status::SharedStatus;
+
+const NAMESPACE_URI: &str = "urn:ruuvi2iotcore";
+const SECURITY_POLICY_NONE: &str = "http://opcfoundation.org/UA/SecurityPolicy#None";
+const TRANSPORT_PROFILE: &str = "http://opcfoundation.org/UA-Profile/Transport/uatcp-uasc-uabinary";
+const BUFFER_SIZE: u32 = 65535;
+// 100 ns ticks from 1601-01-01, the epoch of OPC UA, to the Unix epoch
+const EPOCH_OFFSET: i64 = 116_444_736_000_000_000;
+
+// binary encoding ids of requests and responses
+const OPEN_SECURE_CHANNEL_REQUEST: u32 = 446;
+const OPEN_SECURE_CHANNEL_RESPONSE: u32 = 449;
+const SERVICE_FAULT: u32 = 397;
+const FIND_SERVERS_REQUEST: u32 = 422;
+const FIND_SERVERS_RESPONSE: u32 = 425;
+const GET_ENDPOINTS_REQUEST: u32 = 428;
+const GET_ENDPOINTS_RESPONSE: u32 = 431;
+const CREATE_SESSION_REQUEST: u32 = 461;
+const CREATE_SESSION_RESPONSE: u32 = 464;
+const ACTIVATE_SESSION_REQUEST: u32 = 467;
+const ACTIVATE_SESSION_RESPONSE: u32 = 470;
+const CLOSE_SESSION_REQUEST: u32 = 473;
+const CLOSE_SESSION_RESPONSE: u32 = 476;
+const BROWSE_REQUEST: u32 = 527;
+const BROWSE_RESPONSE: u32 = 530;
+const READ_REQUEST: u32 = 631;
+const READ_RESPONSE: u32 = 634;
+
+// status codes
+const GOOD: u32 = 0;
+const BAD_SERVICE_UNSUPPORTED: u32 = 0x800b_0000;
+const BAD_DECODING_ERROR: u32 = 0x8007_0000;
+const BAD_NODE_ID_UNKNOWN: u32 = 0x8034_0000;
+const BAD_ATTRIBUTE_ID_INVALID: u32 = 0x8035_0000;
+const BAD_SECURITY_POLICY_REJECTED: u32 = 0x8055_0000;
+const BAD_WAITING_FOR_INITIAL_DATA: u32 = 0x8032_0000;
+const BAD_TOO_MANY_SESSIONS: u32 = 0x8056_0000;
+
+// nodes of namespace 0
+const ROOT_FOLDER: u32 = 84;
+const OBJECTS_FOLDER: u32 = 85;
+const SERVER: u32 = 2253;
+const NAMESPACE_ARRAY: u32 = 2255;
+const SERVER_STATUS_CURRENT_TIME: u32 = 2258;
+const SERVER_STATUS_STATE: u32 = 2259;
+// reference types
+const REFERENCES: u32 = 31;
+const HIERARCHICAL_REFERENCES: u32 = 33;
+const HAS_CHILD: u32 = 34;
+const ORGANIZES: u32 = 35;
+const AGGREGATES: u32 = 44;
+const HAS_PROPERTY: u32 = 46;
+const HAS_COMPONENT: u32 = 47;
+// type definitions
+const BASE_OBJECT_TYPE: u32 = 58;
+const FOLDER_TYPE: u32 = 61;
+const BASE_DATA_VARIABLE_TYPE: u32 = 63;
+const PROPERTY_TYPE: u32 = 68;
+const SERVER_TYPE: u32 = 2004;
+// data types
+const DOUBLE: u32 = 11;
+const STRING: u32 = 12;
+const UTC_TIME: u32 = 294;
+const SERVER_STATE: u32 = 852;
+
+// attributes
+const ATTRIBUTE_NODE_ID: u32 = 1;
+const ATTRIBUTE_NODE_CLASS: u32 = 2;
+const ATTRIBUTE_BROWSE_NAME: u32 = 3;
+const ATTRIBUTE_DISPLAY_NAME: u32 = 4;
+const ATTRIBUTE_DESCRIPTION: u32 = 5;
+const ATTRIBUTE_WRITE_MASK: u32 = 6;
+const ATTRIBUTE_USER_WRITE_MASK: u32 = 7;
+const ATTRIBUTE_EVENT_NOTIFIER: u32 = 12;
+const ATTRIBUTE_VALUE: u32 = 13;
+const ATTRIBUTE_DATA_TYPE: u32 = 14;
+const ATTRIBUTE_VALUE_RANK: u32 = 15;
+const ATTRIBUTE_ARRAY_DIMENSIONS: u32 = 16;
+const ATTRIBUTE_ACCESS_LEVEL: u32 = 17;
+const ATTRIBUTE_USER_ACCESS_LEVEL: u32 = 18;
+const ATTRIBUTE_MINIMUM_SAMPLING_INTERVAL: u32 = 19;
+const ATTRIBUTE_HISTORIZING: u32 = 20;
+
+// node classes
+const NODE_CLASS_OBJECT: u32 = 1;
+const NODE_CLASS_VARIABLE: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+enum NodeId {
+    Numeric(u16, u32),
+    String(u16, String),
+    // guid and opaque node ids are not used by the server, only skipped in requests
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Variant {
+    Boolean(bool),
+    Byte(u8),
+    Int32(i32),
+    UInt32(u32),
+    Double(f64),
+    DateTime(chrono::DateTime<chrono::Utc>),
+    NodeId(NodeId),
+    QualifiedName(u16, String),
+    LocalizedText(String),
+    StringArray(Vec<String>),
+    UInt32Array(Vec<u32>),
+}
+
+fn ticks(time: &chrono::DateTime<chrono::Utc>) -> i64 {
+    time.timestamp_millis() * 10_000 + EPOCH_OFFSET
+}
+
+// encodes values of the OPC UA binary encoding, all little endian
+#[derive(Default)]
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn f64(&mut self, value: f64) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    fn datetime(&mut self, time: &chrono::DateTime<chrono::Utc>) -> &mut Self {
+        self.data.extend(ticks(time).to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => {
+                self.i32(value.len() as i32);
+                self.data.extend(value.as_bytes());
+            }
+            None => {
+                self.i32(-1);
+            }
+        }
+        self
+    }
+
+    fn byte_string(&mut self, value: Option<&[u8]>) -> &mut Self {
+        match value {
+            Some(value) => {
+                self.i32(value.len() as i32);
+                self.data.extend(value);
+            }
+            None => {
+                self.i32(-1);
+            }
+        }
+        self
+    }
+
+    fn node_id(&mut self, node_id: &NodeId) -> &mut Self {
+        match node_id {
+            NodeId::Numeric(0, id) if *id < 256 => self.u8(0x00).u8(*id as u8),
+            NodeId::Numeric(namespace, id) if *namespace < 256 && *id < 65536 => {
+                self.u8(0x01).u8(*namespace as u8).u16(*id as u16)
+            }
+            NodeId::Numeric(namespace, id) => self.u8(0x02).u16(*namespace).u32(*id),
+            NodeId::String(namespace, id) => self.u8(0x03).u16(*namespace).string(Some(id)),
+            NodeId::Other => self.u8(0x00).u8(0),
+        }
+    }
+
+    fn qualified_name(&mut self, namespace: u16, name: &str) -> &mut Self {
+        self.u16(namespace).string(Some(name))
+    }
+
+    fn localized_text(&mut self, text: &str) -> &mut Self {
+        // text without a locale
+        self.u8(0x02).string(Some(text))
+    }
+
+    fn null_extension_object(&mut self) -> &mut Self {
+        self.u8(0x00).u8(0x00).u8(0x00)
+    }
+
+    fn response_header(&mut self, request_handle: u32, status: u32) -> &mut Self {
+        self.datetime(&chrono::Utc::now())
+            .u32(request_handle)
+            .u32(status)
+            // no diagnostics, string table or additional header
+            .u8(0x00)
+            .i32(-1)
+            .null_extension_object()
+    }
+
+    fn variant(&mut self, value: &Variant) -> &mut Self {
+        match value {
+            Variant::Boolean(value) => self.u8(1).u8(*value as u8),
+            Variant::Byte(value) => self.u8(3).u8(*value),
+            Variant::Int32(value) => self.u8(6).i32(*value),
+            Variant::UInt32(value) => self.u8(7).u32(*value),
+            Variant::Double(value) => self.u8(11).f64(*value),
+            Variant::DateTime(value) => self.u8(13).datetime(value),
+            Variant::NodeId(value) => self.u8(17).node_id(value),
+            Variant::QualifiedName(namespace, name) => self.u8(20).qualified_name(*namespace, name),
+            Variant::LocalizedText(text) => self.u8(21).localized_text(text),
+            Variant::StringArray(values) => {
+                self.u8(12 | 0x80).i32(values.len() as i32);
+                for value in values {
+                    self.string(Some(value));
+                }
+                self
+            }
+            Variant::UInt32Array(values) => {
+                self.u8(7 | 0x80).i32(values.len() as i32);
+                for value in values {
+                    self.u32(*value);
+                }
+                self
+            }
+        }
+    }
+}
+
+// decodes values of requests, None when the request ends too early
+struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn byte_string(&mut self) -> Option<Option<&'a [u8]>> {
+        match self.i32()? {
+            length if length < 0 => Some(None),
+            length => Some(Some(self.bytes(length as usize)?)),
+        }
+    }
+
+    fn string(&mut self) -> Option<Option<String>> {
+        Some(
+            self.byte_string()?
+                .map(|bytes| String::from_utf8_lossy(bytes).to_string()),
+        )
+    }
+
+    fn node_id(&mut self) -> Option<NodeId> {
+        let encoding = self.u8()?;
+        let node_id = match encoding & 0x3f {
+            0x00 => NodeId::Numeric(0, self.u8()? as u32),
+            0x01 => NodeId::Numeric(self.u8()? as u16, self.u16()? as u32),
+            0x02 => NodeId::Numeric(self.u16()?, self.u32()?),
+            0x03 => NodeId::String(self.u16()?, self.string()?.unwrap_or_default()),
+            0x04 => {
+                self.bytes(18)?;
+                NodeId::Other
+            }
+            0x05 => {
+                self.u16()?;
+                self.byte_string()?;
+                NodeId::Other
+            }
+            _ => return None,
+        };
+        // expanded node ids may have a namespace uri and server index
+        if encoding & 0x80 != 0 {
+            self.string()?;
+        }
+        if encoding & 0x40 != 0 {
+            self.u32()?;
+        }
+        Some(node_id)
+    }
+
+    fn extension_object(&mut self) -> Option<()> {
+        self.node_id()?;
+        match self.u8()? {
+            0x00 => Some(()),
+            _ => {
+                self.byte_string()?;
+                Some(())
+            }
+        }
+    }
+
+    // request handle of the request header
+    fn request_header(&mut self) -> Option<u32> {
+        self.node_id()?;
+        self.bytes(8)?;
+        let request_handle = self.u32()?;
+        self.u32()?;
+        self.string()?;
+        self.u32()?;
+        self.extension_object()?;
+        Some(request_handle)
+    }
+
+    fn array<T>(&mut self, mut item: impl FnMut(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        let count = self.i32()?.max(0) as usize;
+        (0..count).map(|_| item(self)).collect()
+    }
+}
+
+// nodes of the address space: a few standard ones of namespace 0 that clients read on connect,
+//  and a gateway, tag and metric tree in namespace 1
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Root,
+    Objects,
+    Server,
+    NamespaceArray,
+    CurrentTime,
+    State,
+    Gateway(String),
+    Tag(String, String),
+    Metric(String, String, String),
+}
+
+impl Node {
+    fn from_id(node_id: &NodeId) -> Option<Node> {
+        match node_id {
+            NodeId::Numeric(0, ROOT_FOLDER) => Some(Node::Root),
+            NodeId::Numeric(0, OBJECTS_FOLDER) => Some(Node::Objects),
+            NodeId::Numeric(0, SERVER) => Some(Node::Server),
+            NodeId::Numeric(0, NAMESPACE_ARRAY) => Some(Node::NamespaceArray),
+            NodeId::Numeric(0, SERVER_STATUS_CURRENT_TIME) => Some(Node::CurrentTime),
+            NodeId::Numeric(0, SERVER_STATUS_STATE) => Some(Node::State),
+            NodeId::String(1, path) => {
+                let parts: Vec<&str> = path.splitn(3, '/').collect();
+                match parts[..] {
+                    [gateway] => Some(Node::Gateway(gateway.to_string())),
+                    [gateway, tag] => Some(Node::Tag(gateway.to_string(), tag.to_string())),
+                    [gateway, tag, metric] => Some(Node::Metric(
+                        gateway.to_string(),
+                        tag.to_string(),
+                        metric.to_string(),
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> NodeId {
+        match self {
+            Node::Root => NodeId::Numeric(0, ROOT_FOLDER),
+            Node::Objects => NodeId::Numeric(0, OBJECTS_FOLDER),
+            Node::Server => NodeId::Numeric(0, SERVER),
+            Node::NamespaceArray => NodeId::Numeric(0, NAMESPACE_ARRAY),
+            Node::CurrentTime => NodeId::Numeric(0, SERVER_STATUS_CURRENT_TIME),
+            Node::State => NodeId::Numeric(0, SERVER_STATUS_STATE),
+            Node::Gateway(gateway) => NodeId::String(1, gateway.clone()),
+            Node::Tag(gateway, tag) => NodeId::String(1, format!("{}/{}", gateway, tag)),
+            Node::Metric(gateway, tag, metric) => {
+                NodeId::String(1, format!("{}/{}/{}", gateway, tag, metric))
+            }
+        }
+    }
+
+    fn browse_name(&self) -> (u16, String) {
+        match self {
+            Node::Root => (0, "Root".to_string()),
+            Node::Objects => (0, "Objects".to_string()),
+            Node::Server => (0, "Server".to_string()),
+            Node::NamespaceArray => (0, "NamespaceArray".to_string()),
+            Node::CurrentTime => (0, "CurrentTime".to_string()),
+            Node::State => (0, "State".to_string()),
+            Node::Gateway(name) | Node::Tag(_, name) | Node::Metric(_, _, name) => {
+                (1, name.clone())
+            }
+        }
+    }
+
+    fn node_class(&self) -> u32 {
+        match self {
+            Node::NamespaceArray | Node::CurrentTime | Node::State | Node::Metric(..) => {
+                NODE_CLASS_VARIABLE
+            }
+            _ => NODE_CLASS_OBJECT,
+        }
+    }
+
+    fn type_definition(&self) -> u32 {
+        match self {
+            Node::Root | Node::Objects => FOLDER_TYPE,
+            Node::Server => SERVER_TYPE,
+            Node::NamespaceArray => PROPERTY_TYPE,
+            Node::Gateway(_) | Node::Tag(..) => BASE_OBJECT_TYPE,
+            _ => BASE_DATA_VARIABLE_TYPE,
+        }
+    }
+
+    fn data_type(&self) -> u32 {
+        match self {
+            Node::NamespaceArray => STRING,
+            Node::CurrentTime => UTC_TIME,
+            Node::State => SERVER_STATE,
+            _ => DOUBLE,
+        }
+    }
+}
+
+// the latest values of a tag and when they were received
+struct TagValues {
+    gateway: String,
+    address: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    metrics: BTreeMap<String, f64>,
+}
+
+// secure channel of a connection, only with security policy None
+struct Channel {
+    id: u32,
+    token_id: u32,
+    sequence_number: u32,
+    endpoint_url: String,
+}
+
+/// OPC UA server presenting the gateways, their tags and the metrics of the tags as a node
+/// tree with the latest values, for shop-floor data collection.
+#[derive(Clone)]
+pub struct OpcUaServer {
+    config: OpcUaConfig,
+    device_id: String,
+    status: SharedStatus,
+    running: Arc<AtomicBool>,
+    channels: Arc<AtomicU32>,
+    // clients being served
+    sessions: Arc<AtomicUsize>,
+}
+
+impl OpcUaServer {
+    // latest values of the tags, grouped by the gateway that received them
+    fn tags(&self) -> Vec<TagValues> {
+        let status = self.status.lock().unwrap();
+        status
+            .tags
+            .iter()
+            .filter_map(|(address, tag)| {
+                let beacon = tag.last_beacon.as_ref()?;
+                let values = naming::numeric_fields(beacon.data.as_ref()?, "_");
+                Some(TagValues {
+                    gateway: beacon
+                        .source
+                        .clone()
+                        .unwrap_or_else(|| self.device_id.clone()),
+                    address: address.clone(),
+                    timestamp: beacon.timestamp,
+                    metrics: values,
+                })
+            })
+            .collect()
+    }
+
+    fn tag(&self, gateway: &str, address: &str) -> Option<TagValues> {
+        self.tags()
+            .into_iter()
+            .find(|tag| tag.gateway == gateway && tag.address == address)
+    }
+
+    fn exists(&self, node: &Node) -> bool {
+        match node {
+            Node::Gateway(gateway) => self.tags().iter().any(|tag| &tag.gateway == gateway),
+            Node::Tag(gateway, address) => self.tag(gateway, address).is_some(),
+            Node::Metric(gateway, address, metric) => match self.tag(gateway, address) {
+                Some(tag) => tag.metrics.contains_key(metric),
+                None => false,
+            },
+            _ => true,
+        }
+    }
+
+    // forward references of the node with their reference type
+    fn children(&self, node: &Node) -> Vec<(u32, Node)> {
+        match node {
+            Node::Root => vec![(ORGANIZES, Node::Objects)],
+            Node::Objects => {
+                let mut children = vec![(ORGANIZES, Node::Server)];
+                let mut gateways: Vec<String> =
+                    self.tags().into_iter().map(|tag| tag.gateway).collect();
+                gateways.sort();
+                gateways.dedup();
+                children.extend(
+                    gateways
+                        .into_iter()
+                        .map(|gateway| (ORGANIZES, Node::Gateway(gateway))),
+                );
+                children
+            }
+            Node::Server => vec![(HAS_PROPERTY, Node::NamespaceArray)],
+            Node::Gateway(gateway) => self
+                .tags()
+                .into_iter()
+                .filter(|tag| &tag.gateway == gateway)
+                .map(|tag| (ORGANIZES, Node::Tag(tag.gateway, tag.address)))
+                .collect(),
+            Node::Tag(gateway, address) => match self.tag(gateway, address) {
+                Some(tag) => tag
+                    .metrics
+                    .keys()
+                    .map(|metric| {
+                        (
+                            HAS_COMPONENT,
+                            Node::Metric(gateway.clone(), address.clone(), metric.clone()),
+                        )
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    // value of the variable node and its source timestamp
+    fn value(&self, node: &Node) -> Option<(Variant, Option<chrono::DateTime<chrono::Utc>>)> {
+        match node {
+            Node::NamespaceArray => Some((
+                Variant::StringArray(vec![
+                    "http://opcfoundation.org/UA/".to_string(),
+                    NAMESPACE_URI.to_string(),
+                ]),
+                None,
+            )),
+            Node::CurrentTime => Some((Variant::DateTime(chrono::Utc::now()), None)),
+            // running
+            Node::State => Some((Variant::Int32(0), None)),
+            Node::Metric(gateway, address, metric) => {
+                let tag = self.tag(gateway, address)?;
+                Some((
+                    Variant::Double(*tag.metrics.get(metric)?),
+                    Some(tag.timestamp),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    // attribute of the node as a data value
+    fn read_attribute(
+        &self,
+        encoder: &mut Encoder,
+        node_id: &NodeId,
+        attribute: u32,
+        timestamps: u32,
+    ) {
+        let node = match Node::from_id(node_id) {
+            Some(node) if self.exists(&node) => node,
+            _ => {
+                encoder.u8(0x02).u32(BAD_NODE_ID_UNKNOWN);
+                return;
+            }
+        };
+        let variable = node.node_class() == NODE_CLASS_VARIABLE;
+        let (namespace, name) = node.browse_name();
+        let value = match attribute {
+            ATTRIBUTE_NODE_ID => Variant::NodeId(node.id()),
+            ATTRIBUTE_NODE_CLASS => Variant::Int32(node.node_class() as i32),
+            ATTRIBUTE_BROWSE_NAME => Variant::QualifiedName(namespace, name),
+            ATTRIBUTE_DISPLAY_NAME => Variant::LocalizedText(name),
+            ATTRIBUTE_DESCRIPTION => Variant::LocalizedText(match &node {
+                Node::Gateway(gateway) => format!("Gateway {}", gateway),
+                Node::Tag(_, address) => format!("Ruuvi tag {}", address),
+                Node::Metric(_, address, metric) => format!("{} of Ruuvi tag {}", metric, address),
+                _ => String::new(),
+            }),
+            ATTRIBUTE_WRITE_MASK | ATTRIBUTE_USER_WRITE_MASK => Variant::UInt32(0),
+            ATTRIBUTE_EVENT_NOTIFIER if !variable => Variant::Byte(0),
+            ATTRIBUTE_VALUE if variable => match self.value(&node) {
+                Some((value, source_timestamp)) => {
+                    // source timestamp is returned for source or both, server timestamp for
+                    //  server or both
+                    let source = source_timestamp.filter(|_| timestamps == 0 || timestamps == 2);
+                    let server = timestamps == 1 || timestamps == 2;
+                    encoder.u8(0x01
+                        | if source.is_some() { 0x04 } else { 0 }
+                        | if server { 0x08 } else { 0 });
+                    encoder.variant(&value);
+                    if let Some(source) = source {
+                        encoder.datetime(&source);
+                    }
+                    if server {
+                        encoder.datetime(&chrono::Utc::now());
+                    }
+                    return;
+                }
+                None => {
+                    encoder.u8(0x02).u32(BAD_WAITING_FOR_INITIAL_DATA);
+                    return;
+                }
+            },
+            ATTRIBUTE_DATA_TYPE if variable => {
+                Variant::NodeId(NodeId::Numeric(0, node.data_type()))
+            }
+            ATTRIBUTE_VALUE_RANK if variable => {
+                Variant::Int32(if node == Node::NamespaceArray { 1 } else { -1 })
+            }
+            ATTRIBUTE_ARRAY_DIMENSIONS if variable => Variant::UInt32Array(Vec::new()),
+            // current read
+            ATTRIBUTE_ACCESS_LEVEL | ATTRIBUTE_USER_ACCESS_LEVEL if variable => Variant::Byte(1),
+            ATTRIBUTE_MINIMUM_SAMPLING_INTERVAL if variable => Variant::Double(0.0),
+            ATTRIBUTE_HISTORIZING if variable => Variant::Boolean(false),
+            _ => {
+                encoder.u8(0x02).u32(BAD_ATTRIBUTE_ID_INVALID);
+                return;
+            }
+        };
+        encoder.u8(0x01).variant(&value);
+    }
+
+    fn read(&self, request: &mut Decoder, response: &mut Encoder) -> Option<()> {
+        let request_handle = request.request_header()?;
+        // max age
+        request.bytes(8)?;
+        let timestamps = request.u32()?;
+        let nodes = request.array(|request| {
+            let node_id = request.node_id()?;
+            let attribute = request.u32()?;
+            // index range and data encoding
+            request.string()?;
+            request.u16()?;
+            request.string()?;
+            Some((node_id, attribute))
+        })?;
+        response
+            .response_header(request_handle, GOOD)
+            .i32(nodes.len() as i32);
+        for (node_id, attribute) in nodes {
+            self.read_attribute(response, &node_id, attribute, timestamps);
+        }
+        // no diagnostic infos
+        response.i32(-1);
+        Some(())
+    }
+
+    // whether references of the type are asked for by the reference type of a browse
+    fn reference_matches(requested: &NodeId, include_subtypes: bool, reference: u32) -> bool {
+        match requested {
+            NodeId::Numeric(0, 0) => true,
+            NodeId::Numeric(0, requested) if *requested == reference => true,
+            NodeId::Numeric(0, REFERENCES) | NodeId::Numeric(0, HIERARCHICAL_REFERENCES) => {
+                include_subtypes
+            }
+            NodeId::Numeric(0, HAS_CHILD) | NodeId::Numeric(0, AGGREGATES) => {
+                include_subtypes && (reference == HAS_COMPONENT || reference == HAS_PROPERTY)
+            }
+            _ => false,
+        }
+    }
+
+    fn browse(&self, request: &mut Decoder, response: &mut Encoder) -> Option<()> {
+        let request_handle = request.request_header()?;
+        // view and requested max references per node, all references are always returned
+        request.node_id()?;
+        request.bytes(12)?;
+        request.u32()?;
+        let descriptions = request.array(|request| {
+            let node_id = request.node_id()?;
+            let direction = request.u32()?;
+            let reference_type = request.node_id()?;
+            let include_subtypes = request.u8()? != 0;
+            let node_class_mask = request.u32()?;
+            let result_mask = request.u32()?;
+            Some((
+                node_id,
+                direction,
+                reference_type,
+                include_subtypes,
+                node_class_mask,
+                result_mask,
+            ))
+        })?;
+        response
+            .response_header(request_handle, GOOD)
+            .i32(descriptions.len() as i32);
+        for (node_id, direction, reference_type, include_subtypes, node_class_mask, result_mask) in
+            descriptions
+        {
+            let node = match Node::from_id(&node_id) {
+                Some(node) if self.exists(&node) => node,
+                _ => {
+                    response.u32(BAD_NODE_ID_UNKNOWN).byte_string(None).i32(-1);
+                    continue;
+                }
+            };
+            // only forward references are browsed
+            let references: Vec<(u32, Node)> = if direction == 1 {
+                Vec::new()
+            } else {
+                self.children(&node)
+                    .into_iter()
+                    .filter(|(reference, child)| {
+                        OpcUaServer::reference_matches(
+                            &reference_type,
+                            include_subtypes,
+                            *reference,
+                        ) && (node_class_mask == 0 || node_class_mask & child.node_class() != 0)
+                    })
+                    .collect()
+            };
+            response
+                .u32(GOOD)
+                .byte_string(None)
+                .i32(references.len() as i32);
+            for (reference, child) in references {
+                let (namespace, name) = child.browse_name();
+                let field = |bit: u32| result_mask & bit != 0;
+                response.node_id(&if field(0x01) {
+                    NodeId::Numeric(0, reference)
+                } else {
+                    NodeId::Numeric(0, 0)
+                });
+                response.u8(1);
+                response.node_id(&child.id());
+                response.qualified_name(
+                    if field(0x08) { namespace } else { 0 },
+                    if field(0x08) { &name } else { "" },
+                );
+                if field(0x10) {
+                    response.localized_text(&name);
+                } else {
+                    response.u8(0x00);
+                }
+                response.u32(if field(0x04) { child.node_class() } else { 0 });
+                response.node_id(&if field(0x20) {
+                    NodeId::Numeric(0, child.type_definition())
+                } else {
+                    NodeId::Numeric(0, 0)
+                });
+            }
+        }
+        // no diagnostic infos
+        response.i32(-1);
+        Some(())
+    }
+
+    fn application_description(&self, encoder: &mut Encoder, endpoint_url: &str) {
+        encoder
+            .string(Some(&format!("{}:{}", NAMESPACE_URI, self.device_id)))
+            .string(Some(NAMESPACE_URI))
+            .localized_text(&format!("ruuvi2iotcore {}", self.device_id))
+            // server
+            .u32(0)
+            .string(None)
+            .string(None)
+            .i32(1)
+            .string(Some(endpoint_url));
+    }
+
+    fn endpoint_description(&self, encoder: &mut Encoder, endpoint_url: &str) {
+        encoder.string(Some(endpoint_url));
+        self.application_description(encoder, endpoint_url);
+        encoder
+            .byte_string(None)
+            // security mode none
+            .u32(1)
+            .string(Some(SECURITY_POLICY_NONE))
+            // anonymous user token policy
+            .i32(1)
+            .string(Some("anonymous"))
+            .u32(0)
+            .string(None)
+            .string(None)
+            .string(None)
+            .string(Some(TRANSPORT_PROFILE))
+            .u8(0);
+    }
+
+    // encodes the response of the service request, None when the request cannot be decoded
+    fn service(&self, channel: &Channel, body: &[u8]) -> Option<(u32, Vec<u8>)> {
+        let mut request = Decoder {
+            data: body,
+            position: 0,
+        };
+        let request_type = match request.node_id()? {
+            NodeId::Numeric(0, request_type) => request_type,
+            _ => return None,
+        };
+        let mut response = Encoder::default();
+        let response_type = match request_type {
+            FIND_SERVERS_REQUEST => {
+                let request_handle = request.request_header()?;
+                response.response_header(request_handle, GOOD).i32(1);
+                self.application_description(&mut response, &channel.endpoint_url);
+                FIND_SERVERS_RESPONSE
+            }
+            GET_ENDPOINTS_REQUEST => {
+                let request_handle = request.request_header()?;
+                response.response_header(request_handle, GOOD).i32(1);
+                self.endpoint_description(&mut response, &channel.endpoint_url);
+                GET_ENDPOINTS_RESPONSE
+            }
+            CREATE_SESSION_REQUEST => {
+                let request_handle = request.request_header()?;
+                let mut nonce = [0; 32];
+                SystemRandom::new().fill(&mut nonce).ok()?;
+                let session = self.channels.fetch_add(1, Ordering::Relaxed);
+                response
+                    .response_header(request_handle, GOOD)
+                    .node_id(&NodeId::Numeric(1, session))
+                    .node_id(&NodeId::Numeric(1, session))
+                    .f64(3_600_000.0)
+                    .byte_string(Some(&nonce))
+                    .byte_string(None)
+                    .i32(1);
+                self.endpoint_description(&mut response, &channel.endpoint_url);
+                response
+                    .i32(0)
+                    .string(None)
+                    .byte_string(None)
+                    .u32(BUFFER_SIZE);
+                CREATE_SESSION_RESPONSE
+            }
+            ACTIVATE_SESSION_REQUEST => {
+                let request_handle = request.request_header()?;
+                let mut nonce = [0; 32];
+                SystemRandom::new().fill(&mut nonce).ok()?;
+                response
+                    .response_header(request_handle, GOOD)
+                    .byte_string(Some(&nonce))
+                    .i32(0)
+                    .i32(0);
+                ACTIVATE_SESSION_RESPONSE
+            }
+            CLOSE_SESSION_REQUEST => {
+                let request_handle = request.request_header()?;
+                response.response_header(request_handle, GOOD);
+                CLOSE_SESSION_RESPONSE
+            }
+            BROWSE_REQUEST => {
+                self.browse(&mut request, &mut response)?;
+                BROWSE_RESPONSE
+            }
+            READ_REQUEST => {
+                self.read(&mut request, &mut response)?;
+                READ_RESPONSE
+            }
+            _ => {
+                debug!("unsupported OPC UA service request {}", request_type);
+                let request_handle = request.request_header().unwrap_or(0);
+                response.response_header(request_handle, BAD_SERVICE_UNSUPPORTED);
+                SERVICE_FAULT
+            }
+        };
+        Some((response_type, response.data))
+    }
+
+    // reads a message of the connection, None once the server is shutting down or the client
+    //  closed the connection
+    fn read_message(&self, stream: &mut TcpStream) -> Result<Option<(String, Vec<u8>)>, String> {
+        let mut header = [0; 8];
+        let mut read = 0;
+        let waiting = Instant::now();
+        while read < header.len() {
+            match stream.read(&mut header[read..]) {
+                Ok(0) => return Ok(None),
+                Ok(length) => read += length,
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    if !self.running.load(Ordering::Relaxed) {
+                        return Ok(None);
+                    }
+                    // idle clients are disconnected so that they do not hold a session forever
+                    if waiting.elapsed() >= Duration::from_secs(self.config.idle_timeout()) {
+                        return Err(format!(
+                            "no message in {} seconds",
+                            self.config.idle_timeout()
+                        ));
+                    }
+                }
+                Err(error) => return Err(error.to_string()),
+            }
+        }
+        let message_type = String::from_utf8_lossy(&header[..4]).to_string();
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        if !(8..=BUFFER_SIZE as usize).contains(&size) {
+            return Err(format!("invalid message size {}", size));
+        }
+        let mut body = vec![0; size - 8];
+        stream
+            .read_exact(&mut body)
+            .map_err(|error| error.to_string())?;
+        Ok(Some((message_type, body)))
+    }
+
+    fn send(stream: &mut TcpStream, message_type: &str, body: &[u8]) -> Result<(), String> {
+        let mut message = message_type.as_bytes().to_vec();
+        message.extend(((body.len() + 8) as u32).to_le_bytes());
+        message.extend(body);
+        stream
+            .write_all(&message)
+            .map_err(|error| error.to_string())
+    }
+
+    fn send_error(stream: &mut TcpStream, status: u32, reason: &str) -> Result<(), String> {
+        let mut body = Encoder::default();
+        body.u32(status).string(Some(reason));
+        OpcUaServer::send(stream, "ERRF", &body.data)
+    }
+
+    /// Serves a client until it disconnects or the server shuts down
+    pub fn handle_connection(&self, mut stream: TcpStream) -> Result<(), String> {
+        trace!("in handle_connection");
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .map_err(|error| error.to_string())?;
+        let mut channel = Channel {
+            id: 0,
+            token_id: 1,
+            sequence_number: 0,
+            endpoint_url: String::new(),
+        };
+        while let Some((message_type, body)) = self.read_message(&mut stream)? {
+            let mut message = Decoder {
+                data: &body,
+                position: 0,
+            };
+            match message_type.as_str() {
+                "HELF" => {
+                    // protocol version and buffer sizes of the client are not limiting
+                    message.bytes(20).ok_or("invalid hello")?;
+                    channel.endpoint_url = message.string().flatten().unwrap_or_default();
+                    let mut acknowledge = Encoder::default();
+                    acknowledge
+                        .u32(0)
+                        .u32(BUFFER_SIZE)
+                        .u32(BUFFER_SIZE)
+                        .u32(BUFFER_SIZE)
+                        .u32(1);
+                    OpcUaServer::send(&mut stream, "ACKF", &acknowledge.data)?;
+                }
+                "OPNF" => {
+                    message.u32().ok_or("invalid open")?;
+                    let policy = message.string().flatten().unwrap_or_default();
+                    if policy != SECURITY_POLICY_NONE {
+                        OpcUaServer::send_error(
+                            &mut stream,
+                            BAD_SECURITY_POLICY_REJECTED,
+                            "only security policy None is supported",
+                        )?;
+                        return Ok(());
+                    }
+                    message.byte_string().ok_or("invalid open")?;
+                    message.byte_string().ok_or("invalid open")?;
+                    message.u32().ok_or("invalid open")?;
+                    let request_id = message.u32().ok_or("invalid open")?;
+                    if message.node_id() != Some(NodeId::Numeric(0, OPEN_SECURE_CHANNEL_REQUEST)) {
+                        return Err("invalid open".to_string());
+                    }
+                    let request_handle = message.request_header().ok_or("invalid open")?;
+                    // protocol version, request type and security mode
+                    message.bytes(12).ok_or("invalid open")?;
+                    message.byte_string().ok_or("invalid open")?;
+                    let lifetime = message.u32().ok_or("invalid open")?;
+                    if channel.id == 0 {
+                        channel.id = self.channels.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        // renewal of the channel
+                        channel.token_id += 1;
+                    }
+                    channel.sequence_number += 1;
+                    let mut response = Encoder::default();
+                    response
+                        .u32(channel.id)
+                        .string(Some(SECURITY_POLICY_NONE))
+                        .byte_string(None)
+                        .byte_string(None)
+                        .u32(channel.sequence_number)
+                        .u32(request_id)
+                        .node_id(&NodeId::Numeric(0, OPEN_SECURE_CHANNEL_RESPONSE))
+                        .response_header(request_handle, GOOD)
+                        .u32(0)
+                        .u32(channel.id)
+                        .u32(channel.token_id)
+                        .datetime(&chrono::Utc::now())
+                        .u32(lifetime)
+                        .byte_string(None);
+                    OpcUaServer::send(&mut stream, "OPNF", &response.data)?;
+                }
+                "MSGF" => {
+                    // secure channel id, token id and sequence number
+                    message.bytes(12).ok_or("invalid message")?;
+                    let request_id = message.u32().ok_or("invalid message")?;
+                    let (response_type, body) =
+                        match self.service(&channel, &body[message.position..]) {
+                            Some(response) => response,
+                            None => {
+                                OpcUaServer::send_error(
+                                    &mut stream,
+                                    BAD_DECODING_ERROR,
+                                    "unable to decode request",
+                                )?;
+                                return Ok(());
+                            }
+                        };
+                    channel.sequence_number += 1;
+                    let mut response = Encoder::default();
+                    response
+                        .u32(channel.id)
+                        .u32(channel.token_id)
+                        .u32(channel.sequence_number)
+                        .u32(request_id)
+                        .node_id(&NodeId::Numeric(0, response_type));
+                    response.data.extend(body);
+                    OpcUaServer::send(&mut stream, "MSGF", &response.data)?;
+                }
+                "CLOF" => return Ok(()),
+                _ => {
+                    OpcUaServer::send_error(
+                        &mut stream,
+                        BAD_DECODING_ERROR,
+                        "chunked messages are not supported",
+                    )?;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // serves the client in a thread of its own, unless the maximum number of clients are
+    //  already connected
+    fn open_session(&self, mut stream: TcpStream, client: SocketAddr) {
+        trace!("in open_session");
+        if self.sessions.load(Ordering::Relaxed) >= self.config.max_sessions() {
+            warn!(
+                "Rejecting OPC UA client {}, {} clients are already connected.",
+                client,
+                self.config.max_sessions()
+            );
+            stream.set_nonblocking(false).ok();
+            OpcUaServer::send_error(&mut stream, BAD_TOO_MANY_SESSIONS, "too many clients").ok();
+            return;
+        }
+        debug!("OPC UA client connected from {}", client);
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        let server = self.clone();
+        thread::spawn(move || {
+            if let Err(error) = stream
+                .set_nonblocking(false)
+                .map_err(|error| error.to_string())
+                .and_then(|_| server.handle_connection(stream))
+            {
+                warn!("OPC UA connection of {} failed: {}", client, error);
+            }
+            server.sessions.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn start_server(&self) -> Result<(), Report> {
+        trace!("in start_server");
+        let port = match self.config.port() {
+            Some(port) => port,
+            None => {
+                debug!("OPC UA server not enabled.");
+                return Ok(());
+            }
+        };
+        let address = format!("{}:{}", self.config.address(), port);
+        let listener = match TcpListener::bind(&address).and_then(|listener| {
+            // wake up regularly to notice shutdown
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        }) {
+            Ok(listener) => listener,
+            Err(error) => {
+                return Err(eyre!("Unable to start OPC UA server")
+                    .with_section(move || address.header("Listen address:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        info!("OPC UA server listening at opc.tcp://{}", address);
+
+        while self.running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, client)) => self.open_session(stream, client),
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100))
+                }
+                Err(error) => error!("Unable to accept OPC UA connection: {}", error),
+            }
+        }
+        info!("Shutting down OPC UA server.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &OpcUaConfig,
+        device_id: &str,
+        status: &SharedStatus,
+        running: &Arc<AtomicBool>,
+    ) -> OpcUaServer {
+        trace!("in build");
+        OpcUaServer {
+            config: config.clone(),
+            device_id: device_id.to_string(),
+            status: status.clone(),
+            running: running.clone(),
+            channels: Arc::new(AtomicU32::new(1)),
+            sessions: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, Encoder, NodeId, OpcUaServer};
+    use crate::configfile::OpcUaConfig;
+    use crate::mock::beacon;
+    use crate::status::{GatewayStatus, TagStatus};
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    // client side of a secure channel with security policy None
+    struct Client {
+        stream: TcpStream,
+        request_id: u32,
+    }
+
+    impl Client {
+        fn exchange(&mut self, message_type: &str, body: &[u8]) -> (String, Vec<u8>) {
+            let mut message = message_type.as_bytes().to_vec();
+            message.extend(((body.len() + 8) as u32).to_le_bytes());
+            message.extend(body);
+            self.stream.write_all(&message).unwrap();
+            let mut header = [0; 8];
+            self.stream.read_exact(&mut header).unwrap();
+            let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+            let mut body = vec![0; size - 8];
+            self.stream.read_exact(&mut body).unwrap();
+            (String::from_utf8_lossy(&header[..4]).to_string(), body)
+        }
+
+        // sends the request and returns the type of the response, its service result and the
+        //  response after the response header
+        fn call(&mut self, request_type: u32, request: &[u8]) -> (NodeId, u32, Vec<u8>) {
+            self.request_id += 1;
+            let mut message = Encoder::default();
+            message
+                .u32(1)
+                .u32(1)
+                .u32(self.request_id)
+                .u32(self.request_id)
+                .node_id(&NodeId::Numeric(0, request_type))
+                // request header
+                .node_id(&NodeId::Numeric(0, 0))
+                .datetime(&chrono::Utc::now())
+                .u32(self.request_id)
+                .u32(0)
+                .string(None)
+                .u32(0)
+                .null_extension_object();
+            message.data.extend(request);
+            let (message_type, body) = self.exchange("MSGF", &message.data);
+            assert_eq!(message_type, "MSGF");
+            let mut response = Decoder {
+                data: &body,
+                position: 16,
+            };
+            let response_type = response.node_id().unwrap();
+            // response header: timestamp, request handle, service result, diagnostics, string
+            //  table and additional header
+            response.bytes(8).unwrap();
+            assert_eq!(response.u32(), Some(self.request_id));
+            let status = response.u32().unwrap();
+            response.bytes(8).unwrap();
+            (response_type, status, body[response.position..].to_vec())
+        }
+
+        // sends the request and returns the successful response after the response header
+        fn request(&mut self, request_type: u32, request: &[u8]) -> Vec<u8> {
+            let (response_type, status, body) = self.call(request_type, request);
+            assert_eq!(response_type, NodeId::Numeric(0, request_type + 3));
+            assert_eq!(status, 0);
+            body
+        }
+
+        // forward references of the node as browse names and node ids
+        fn browse(&mut self, node_id: &NodeId) -> Vec<(String, NodeId)> {
+            let mut request = Encoder::default();
+            request
+                .node_id(&NodeId::Numeric(0, 0))
+                .datetime(&chrono::Utc::now())
+                .u32(0)
+                .u32(0)
+                .i32(1)
+                .node_id(node_id)
+                .u32(0)
+                .node_id(&NodeId::Numeric(0, 33))
+                .u8(1)
+                .u32(0)
+                .u32(0x3f);
+            let body = self.request(527, &request.data);
+            let mut response = Decoder {
+                data: &body,
+                position: 0,
+            };
+            assert_eq!(response.i32(), Some(1));
+            assert_eq!(response.u32(), Some(0));
+            response.byte_string().unwrap();
+            response
+                .array(|response| {
+                    response.node_id()?;
+                    response.u8()?;
+                    let node_id = response.node_id()?;
+                    response.u16()?;
+                    let name = response.string()?.unwrap();
+                    response.u8()?;
+                    response.string()?;
+                    response.u32()?;
+                    response.node_id()?;
+                    Some((name, node_id))
+                })
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn tag_tree_is_browsed_and_read_over_opc_ua() {
+        let status = Arc::new(Mutex::new(GatewayStatus::default()));
+        let mut forwarded = beacon("AA:BB:CC:DD:EE:02", 2);
+        forwarded.source = Some("collector".to_string());
+        for tag in [beacon("AA:BB:CC:DD:EE:01", 1), forwarded] {
+            status.lock().unwrap().tags.insert(
+                tag.address.clone(),
+                TagStatus {
+                    last_beacon: Some(Arc::new(tag)),
+                    ..Default::default()
+                },
+            );
+        }
+        let config: OpcUaConfig = serde_json::from_value(json!({ "port": 4840 })).unwrap();
+        let server = OpcUaServer::build(
+            &config,
+            "test-gateway",
+            &status,
+            &Arc::new(AtomicBool::new(true)),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.handle_connection(stream).unwrap();
+        });
+        let mut client = Client {
+            stream: TcpStream::connect(address).unwrap(),
+            request_id: 0,
+        };
+
+        let mut hello = Encoder::default();
+        hello
+            .u32(0)
+            .u32(65535)
+            .u32(65535)
+            .u32(0)
+            .u32(0)
+            .string(Some(&format!("opc.tcp://{}", address)));
+        assert_eq!(client.exchange("HELF", &hello.data).0, "ACKF");
+        let mut open = Encoder::default();
+        open.u32(0)
+            .string(Some(super::SECURITY_POLICY_NONE))
+            .byte_string(None)
+            .byte_string(None)
+            .u32(1)
+            .u32(1)
+            .node_id(&NodeId::Numeric(0, 446))
+            .node_id(&NodeId::Numeric(0, 0))
+            .datetime(&chrono::Utc::now())
+            .u32(1)
+            .u32(0)
+            .string(None)
+            .u32(0)
+            .null_extension_object()
+            .u32(0)
+            .u32(0)
+            .u32(1)
+            .byte_string(None)
+            .u32(600_000);
+        assert_eq!(client.exchange("OPNF", &open.data).0, "OPNF");
+        client.request(461, &[]);
+        client.request(467, &[]);
+
+        let objects = client.browse(&NodeId::Numeric(0, 85));
+        let names: Vec<&str> = objects.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Server", "collector", "test-gateway"]);
+        let tags = client.browse(&objects[2].1);
+        assert_eq!(
+            tags,
+            [(
+                "AA:BB:CC:DD:EE:01".to_string(),
+                NodeId::String(1, "test-gateway/AA:BB:CC:DD:EE:01".to_string())
+            )]
+        );
+        let metrics = client.browse(&tags[0].1);
+        let names: Vec<&str> = metrics.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "humidity",
+                "measurement_sequence_number",
+                "temperature",
+                "tx_power"
+            ]
+        );
+
+        // value of the temperature with the time it was received, and an unknown node
+        let mut read = Encoder::default();
+        read.f64(0.0)
+            .u32(0)
+            .i32(2)
+            .node_id(&metrics[2].1)
+            .u32(13)
+            .string(None)
+            .u16(0)
+            .string(None)
+            .node_id(&NodeId::String(
+                1,
+                "test-gateway/AA:BB:CC:DD:EE:02".to_string(),
+            ))
+            .u32(13)
+            .string(None)
+            .u16(0)
+            .string(None);
+        let body = client.request(631, &read.data);
+        let mut response = Decoder {
+            data: &body,
+            position: 0,
+        };
+        assert_eq!(response.i32(), Some(2));
+        assert_eq!(response.u8(), Some(0x05));
+        assert_eq!(response.u8(), Some(11));
+        assert_eq!(
+            f64::from_le_bytes(response.bytes(8).unwrap().try_into().unwrap()),
+            21.5
+        );
+        response.bytes(8).unwrap();
+        assert_eq!(response.u8(), Some(0x02));
+        assert_eq!(response.u32(), Some(super::BAD_NODE_ID_UNKNOWN));
+
+        // other services are not supported
+        let (response_type, status, _) = client.call(787, &[]);
+        assert_eq!(response_type, NodeId::Numeric(0, 397));
+        assert_eq!(status, super::BAD_SERVICE_UNSUPPORTED);
+
+        client.stream.write_all(b"CLOF\x08\x00\x00\x00").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn clients_over_max_sessions_are_rejected() {
+        let config: OpcUaConfig =
+            serde_json::from_value(json!({ "port": 4840, "max_sessions": 1, "idle_timeout": 1 }))
+                .unwrap();
+        let server = OpcUaServer::build(
+            &config,
+            "test-gateway",
+            &Arc::new(Mutex::new(GatewayStatus::default())),
+            &Arc::new(AtomicBool::new(true)),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            let (accepted, client) = listener.accept().unwrap();
+            server.open_session(accepted, client);
+            Client {
+                stream,
+                request_id: 0,
+            }
+        };
+        let mut hello = Encoder::default();
+        hello
+            .u32(0)
+            .u32(65535)
+            .u32(65535)
+            .u32(0)
+            .u32(0)
+            .string(Some(&format!("opc.tcp://{}", address)));
+
+        let mut first = connect();
+        assert_eq!(first.exchange("HELF", &hello.data).0, "ACKF");
+        // the server answers with an error and closes the connection
+        let mut rejected = Vec::new();
+        connect().stream.read_to_end(&mut rejected).unwrap();
+        assert_eq!(&rejected[..4], b"ERRF");
+        assert_eq!(
+            u32::from_le_bytes(rejected[8..12].try_into().unwrap()),
+            super::BAD_TOO_MANY_SESSIONS
+        );
+
+        // the idle client is disconnected, which makes room for another one
+        let mut closed = [0; 1];
+        assert_eq!(first.stream.read(&mut closed).unwrap(), 0);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(connect().exchange("HELF", &hello.data).0, "ACKF");
+    }
+}
+
+// eof
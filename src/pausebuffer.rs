@@ -0,0 +1,228 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::scanner::RuuviBluetoothBeacon;
+
+/// What is done with beacons received while collecting is paused, part of collect config.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, PartialOrd)]
+pub struct PauseConfig {
+    mode: Option<PauseMode>,
+    on_resume: Option<ResumeAction>,
+    max_beacons: Option<usize>,
+}
+impl PauseConfig {
+    pub fn mode(&self) -> PauseMode {
+        self.mode.unwrap_or(PauseMode::DISCARD)
+    }
+
+    pub fn on_resume(&self) -> ResumeAction {
+        self.on_resume.unwrap_or(ResumeAction::FLUSH)
+    }
+
+    /// Beacons received after the buffer has this many are discarded
+    pub fn max_beacons(&self) -> usize {
+        self.max_beacons.unwrap_or(100_000)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum PauseMode {
+    #[serde(rename = "discard")]
+    DISCARD,
+    /// Beacons are kept in the pause buffer file
+    #[serde(rename = "buffer")]
+    BUFFER,
+}
+
+/// What is done with the buffered beacons when collecting is resumed.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ResumeAction {
+    /// Beacons are published as if they had just been received
+    #[serde(rename = "flush")]
+    FLUSH,
+    #[serde(rename = "discard")]
+    DISCARD,
+}
+
+// line of the buffer file
+#[derive(Debug, Deserialize, Serialize)]
+struct BufferedBeacon {
+    beacon: RuuviBluetoothBeacon,
+    redirect: Option<String>,
+}
+
+/// Beacons received while collecting is paused, with the subfolders hooks redirected them to.
+/// They are appended to a JSON lines file so that a long pause does not grow the memory of the
+/// gateway and the beacons are not lost if the gateway restarts before collecting is resumed.
+/// Beacons received while publishing is over the rate limits are buffered the same way.
+pub struct PauseBuffer {
+    file: PathBuf,
+    buffered: usize,
+}
+
+impl PauseBuffer {
+    pub fn build(file: PathBuf) -> PauseBuffer {
+        trace!("in build");
+        // beacons buffered in an earlier run are kept until collecting is resumed
+        let buffered = fs::read_to_string(&file)
+            .map(|content| content.lines().filter(|line| !line.is_empty()).count())
+            .unwrap_or(0);
+        if buffered > 0 {
+            info!("{} beacons buffered in {}", buffered, file.display());
+        }
+        PauseBuffer { file, buffered }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffered
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffered == 0
+    }
+
+    /// Appends the beacon to the buffer, unless the buffer has max_beacons already.
+    pub fn push(
+        &mut self,
+        beacon: &RuuviBluetoothBeacon,
+        redirect: Option<&str>,
+        max_beacons: usize,
+    ) -> Result<(), Report> {
+        trace!("in push");
+        if self.buffered >= max_beacons {
+            return Err(eyre!("Beacon buffer is full, beacon discarded")
+                .with_section(move || max_beacons.to_string().header("Max beacons:")));
+        }
+        let line = serde_json::to_string(&BufferedBeacon {
+            beacon: beacon.clone(),
+            redirect: redirect.map(String::from),
+        })
+        .unwrap();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        match result {
+            Ok(_) => {
+                self.buffered += 1;
+                Ok(())
+            }
+            Err(error) => {
+                let file = self.file.display().to_string();
+                Err(eyre!("Unable to write to beacon buffer")
+                    .with_section(move || file.header("File name:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        }
+    }
+
+    /// Buffered beacons in the order they were received, emptying the buffer.
+    pub fn take(&mut self) -> Vec<(RuuviBluetoothBeacon, Option<String>)> {
+        trace!("in take");
+        let content = fs::read_to_string(&self.file).unwrap_or_default();
+        self.clear();
+        content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match serde_json::from_str::<BufferedBeacon>(line) {
+                Ok(buffered) => Some((buffered.beacon, buffered.redirect)),
+                Err(error) => {
+                    warn!(
+                        "Ignoring unreadable beacon in {}: {}",
+                        self.file.display(),
+                        error
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Puts beacons taken from the buffer back in front of the buffered ones.
+    pub fn put_back(
+        &mut self,
+        beacons: Vec<(RuuviBluetoothBeacon, Option<String>)>,
+    ) -> Result<(), Report> {
+        trace!("in put_back");
+        if beacons.is_empty() {
+            return Ok(());
+        }
+        let count = beacons.len();
+        let mut content = String::new();
+        for (beacon, redirect) in beacons {
+            content.push_str(&serde_json::to_string(&BufferedBeacon { beacon, redirect }).unwrap());
+            content.push('\n');
+        }
+        content.push_str(&fs::read_to_string(&self.file).unwrap_or_default());
+        match fs::write(&self.file, content) {
+            Ok(_) => {
+                self.buffered += count;
+                Ok(())
+            }
+            Err(error) => {
+                let file = self.file.display().to_string();
+                Err(eyre!("Unable to write to beacon buffer")
+                    .with_section(move || file.header("File name:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        trace!("in clear");
+        if self.file.exists() {
+            if let Err(error) = fs::remove_file(&self.file) {
+                warn!("Unable to remove {}: {}", self.file.display(), error);
+            }
+        }
+        self.buffered = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PauseBuffer;
+    use crate::mock::{beacon, temp_path};
+
+    #[test]
+    fn beacons_are_buffered_over_restarts() {
+        let file = temp_path("pause_buffer.jsonl");
+        let mut buffer = PauseBuffer::build(file.clone());
+        assert!(buffer.is_empty());
+        buffer
+            .push(&beacon("AA:BB:CC:DD:EE:01", 1), None, 2)
+            .unwrap();
+        buffer
+            .push(&beacon("AA:BB:CC:DD:EE:02", 2), Some("alarms"), 2)
+            .unwrap();
+        assert!(buffer
+            .push(&beacon("AA:BB:CC:DD:EE:01", 3), None, 2)
+            .is_err());
+
+        let mut buffer = PauseBuffer::build(file.clone());
+        assert_eq!(buffer.len(), 2);
+        let buffered = buffer.take();
+        assert_eq!(buffered.len(), 2);
+        assert_eq!(buffered[0].0.sequence, 1);
+        assert_eq!(buffered[0].1, None);
+        assert_eq!(buffered[1].1, Some("alarms".to_string()));
+        assert!(buffer.is_empty());
+        assert!(!file.exists());
+
+        // beacons put back are taken before the ones buffered after them
+        buffer
+            .push(&beacon("AA:BB:CC:DD:EE:01", 3), None, 2)
+            .unwrap();
+        buffer.put_back(buffered[1..].to_vec()).unwrap();
+        assert_eq!(buffer.len(), 2);
+        let buffered = buffer.take();
+        assert_eq!(buffered[0].0.sequence, 2);
+        assert_eq!(buffered[1].0.sequence, 3);
+    }
+}
+
+// eof
@@ -0,0 +1,319 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::broadcast::Broadcast;
+use crate::configfile::{AppConfig, PipelineConfig};
+use crate::iotcore::{CNCCommand, CNCCommandMessage, IotCoreClient};
+use crate::metrics::Metrics;
+use crate::publisher;
+use crate::remoteconfig;
+use crate::scanner::{BluetoothScanner, RuuviBluetoothBeacon};
+use crate::status::SharedStatus;
+
+/// Addresses of the tags a pipeline relays, any tag when empty.
+#[derive(Debug, Clone, Default)]
+pub struct TagFilter {
+    addresses: HashSet<String>,
+}
+
+impl TagFilter {
+    pub fn build(addresses: &[String]) -> TagFilter {
+        TagFilter {
+            addresses: addresses
+                .iter()
+                .map(|address| address.to_uppercase())
+                .collect(),
+        }
+    }
+
+    pub fn matches(&self, address: &str) -> bool {
+        self.addresses.is_empty() || self.addresses.contains(&address.to_uppercase())
+    }
+}
+
+/// Pipeline with the top level configuration it was merged into.
+pub struct Pipeline {
+    pub name: String,
+    pub appconfig: AppConfig,
+    pub adapter: Option<String>,
+    pub filter: TagFilter,
+}
+
+impl Pipeline {
+    pub fn build(appconfig: &AppConfig, config: &PipelineConfig) -> Result<Pipeline, Report> {
+        trace!("in build");
+        let name = config.name.clone();
+        if config.adapter().is_none() && config.tags().is_empty() {
+            return Err(eyre!("Pipeline needs an adapter or tags")
+                .with_section(move || name.header("Pipeline:")));
+        }
+        let mut config_value = serde_yaml::to_value(appconfig).unwrap();
+        if let serde_yaml::Value::Mapping(mapping) = &mut config_value {
+            mapping.remove(&serde_yaml::Value::from("pipelines"));
+        }
+        remoteconfig::merge(
+            &mut config_value,
+            serde_yaml::to_value(&config.sections).unwrap(),
+        );
+        match serde_yaml::from_value(config_value) {
+            Ok(pipeline_appconfig) => Ok(Pipeline {
+                name: config.name.clone(),
+                appconfig: pipeline_appconfig,
+                adapter: config.adapter(),
+                filter: TagFilter::build(&config.tags()),
+            }),
+            Err(error) => Err(eyre!("Unable to parse pipeline configuration")
+                .with_section(move || name.header("Pipeline:"))
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+}
+
+/// Pipelines of the configuration. Each one connects as a device of its own, so their device ids
+/// must differ from each other and from the top level one.
+pub fn build_pipelines(appconfig: &AppConfig) -> Result<Vec<Pipeline>, Report> {
+    trace!("in build_pipelines");
+    let mut device_ids = HashSet::new();
+    device_ids.insert(appconfig.iotcore.device_id.clone());
+    let mut pipelines = Vec::new();
+    for config in &appconfig.pipelines {
+        let pipeline = Pipeline::build(appconfig, config)?;
+        let device_id = pipeline.appconfig.iotcore.device_id.clone();
+        if !device_ids.insert(device_id.clone()) {
+            let name = pipeline.name;
+            return Err(eyre!("Device id of pipeline is already in use")
+                .with_section(move || name.header("Pipeline:"))
+                .with_section(move || device_id.header("Device id:")));
+        }
+        pipelines.push(pipeline);
+    }
+    Ok(pipelines)
+}
+
+/// Relays beacons of a scanner to the first pipeline whose tags they are from, or to the default
+/// pipeline.
+pub struct Dispatcher {
+    receiver: Receiver<RuuviBluetoothBeacon>,
+    routes: Vec<(TagFilter, Sender<RuuviBluetoothBeacon>)>,
+    default: Option<Sender<RuuviBluetoothBeacon>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Dispatcher {
+    pub fn build(
+        receiver: &Receiver<RuuviBluetoothBeacon>,
+        default: Option<Sender<RuuviBluetoothBeacon>>,
+        running: &Arc<AtomicBool>,
+    ) -> Dispatcher {
+        Dispatcher {
+            receiver: receiver.clone(),
+            routes: Vec::new(),
+            default,
+            running: running.clone(),
+        }
+    }
+
+    pub fn route(&mut self, filter: TagFilter, sender: Sender<RuuviBluetoothBeacon>) {
+        self.routes.push((filter, sender));
+    }
+
+    fn dispatch(&self, beacon: RuuviBluetoothBeacon) {
+        let route = self
+            .routes
+            .iter()
+            .find(|(filter, _)| filter.matches(&beacon.address))
+            .map(|(_, sender)| sender);
+        match route.or(self.default.as_ref()) {
+            Some(sender) => {
+                sender.send(beacon).ok();
+            }
+            None => trace!(
+                "beacon from '{}' is not relayed by pipeline",
+                beacon.address
+            ),
+        }
+    }
+
+    pub fn start_dispatcher(&self) {
+        trace!("in start_dispatcher");
+        while self.running.load(Ordering::Relaxed) {
+            match self.receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(beacon) => self.dispatch(beacon),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+/// Pipeline built into its IoT Core client, and scanner with its dispatcher when it has an
+/// adapter of its own.
+pub struct PipelineInstance {
+    pub name: String,
+    pub client: IotCoreClient,
+    pub scanner: Option<(BluetoothScanner, Dispatcher)>,
+    commands: Sender<CNCCommandMessage>,
+}
+
+impl PipelineInstance {
+    /// Builds the pipeline. Pipelines without an adapter get beacons from the dispatcher of the
+    /// top level scanner.
+    pub fn build(
+        pipeline: &Pipeline,
+        dispatcher: &mut Dispatcher,
+        metrics: &Arc<Metrics>,
+        running: &Arc<AtomicBool>,
+    ) -> Result<PipelineInstance, Report> {
+        trace!("in build");
+        let (event_s, event_r) = channel::unbounded();
+        let cnc = Broadcast::default();
+        let (command_s, command_r) = channel::unbounded();
+        let scanner = match &pipeline.adapter {
+            Some(adapter) => {
+                let (scanned_s, scanned_r) = channel::unbounded();
+                let mut scanner = BluetoothScanner::build(&scanned_s, &cnc.subscribe(), metrics)?;
+                scanner.bind_adapter(adapter);
+                let mut filter = Dispatcher::build(&scanned_r, None, running);
+                filter.route(pipeline.filter.clone(), event_s);
+                Some((scanner, filter))
+            }
+            None => {
+                // collect configs and commands of the pipeline have no scanner to reach
+                dispatcher.route(pipeline.filter.clone(), event_s);
+                None
+            }
+        };
+        let client = IotCoreClient::build(
+            &pipeline.appconfig,
+            publisher::build(&pipeline.appconfig)?,
+            &event_r,
+            &cnc,
+            metrics,
+            &command_r,
+            &SharedStatus::default(),
+        )?;
+        Ok(PipelineInstance {
+            name: pipeline.name.clone(),
+            client,
+            scanner,
+            commands: command_s,
+        })
+    }
+
+    /// Sender of the command that shuts the pipeline down along with the rest of the gateway.
+    pub fn shutdown_sender(&self) -> Sender<CNCCommandMessage> {
+        self.commands.clone()
+    }
+}
+
+/// Shuts down pipelines through their local command channels.
+pub fn shutdown(senders: &[Sender<CNCCommandMessage>]) {
+    trace!("in shutdown");
+    for sender in senders {
+        sender
+            .send(CNCCommandMessage {
+                command: CNCCommand::SHUTDOWN,
+                duty_cycle: None,
+            })
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_pipelines, Dispatcher, TagFilter};
+    use crate::configfile::AppConfig;
+    use crate::mock::beacon;
+    use crossbeam::channel;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn appconfig(pipelines: &str) -> AppConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+iotcore:
+  device_id: "freezer-gateway"
+  project_id: "test-project"
+  region: "europe-west1"
+  registry: "test-registry"
+pipelines:
+{}
+"#,
+            pipelines
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn pipeline_sections_are_merged_over_top_level() {
+        let appconfig = appconfig(
+            r#"
+  - name: "office"
+    tags: ["aa:bb:cc:dd:ee:02"]
+    iotcore:
+      device_id: "office-gateway"
+    local:
+      enabled: true
+"#,
+        );
+        let pipelines = build_pipelines(&appconfig).unwrap();
+        assert_eq!(pipelines.len(), 1);
+        let pipeline = &pipelines[0];
+        assert_eq!(pipeline.name, "office");
+        assert_eq!(pipeline.adapter, None);
+        assert_eq!(pipeline.appconfig.iotcore.device_id, "office-gateway");
+        assert_eq!(pipeline.appconfig.iotcore.registry, "test-registry");
+        assert!(pipeline.appconfig.local.enabled());
+        assert!(!appconfig.local.enabled());
+        assert!(pipeline.appconfig.pipelines.is_empty());
+        assert!(pipeline.filter.matches("AA:BB:CC:DD:EE:02"));
+        assert!(!pipeline.filter.matches("AA:BB:CC:DD:EE:01"));
+    }
+
+    #[test]
+    fn invalid_pipelines_are_rejected() {
+        // a pipeline would connect with the device id of the top level one
+        assert!(build_pipelines(&appconfig(
+            r#"
+  - name: "office"
+    tags: ["AA:BB:CC:DD:EE:02"]
+"#
+        ))
+        .is_err());
+        // a pipeline without adapter or tags would relay nothing
+        assert!(build_pipelines(&appconfig(
+            r#"
+  - name: "office"
+    iotcore:
+      device_id: "office-gateway"
+"#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn beacons_are_dispatched_by_tag() {
+        let (scanned_s, scanned_r) = channel::unbounded();
+        let (default_s, default_r) = channel::unbounded();
+        let (office_s, office_r) = channel::unbounded();
+        let running = Arc::new(AtomicBool::new(true));
+        let mut dispatcher = Dispatcher::build(&scanned_r, Some(default_s), &running);
+        dispatcher.route(
+            TagFilter::build(&["AA:BB:CC:DD:EE:02".to_string()]),
+            office_s,
+        );
+        scanned_s.send(beacon("AA:BB:CC:DD:EE:01", 1)).unwrap();
+        scanned_s.send(beacon("AA:BB:CC:DD:EE:02", 2)).unwrap();
+        drop(scanned_s);
+        dispatcher.start_dispatcher();
+        assert_eq!(default_r.try_recv().unwrap().sequence, 1);
+        assert!(default_r.try_recv().is_err());
+        assert_eq!(office_r.try_recv().unwrap().sequence, 2);
+    }
+}
+
+// eof
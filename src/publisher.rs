@@ -0,0 +1,605 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+#[cfg(feature = "paho")]
+use paho_mqtt as mqtt;
+#[cfg(feature = "paho")]
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::configfile::{AppConfig, ClockConfig, IotCoreConfig};
+#[cfg(feature = "paho")]
+use crate::configfile::{ProxyKind, Transport};
+#[cfg(feature = "paho")]
+use crate::endpoint;
+use crate::endpoint::Endpoint;
+use crate::fatal::ErrorCode;
+#[cfg(feature = "paho")]
+use crate::jwt::IotCoreAuthToken;
+use crate::local::LocalPublisher;
+#[cfg(feature = "paho")]
+use crate::proxy::{self, Socks5Relay};
+#[cfg(all(feature = "rustls", not(feature = "paho")))]
+use crate::rumqtt::RumqttPublisher;
+use crate::status::TlsHandshake;
+#[cfg(feature = "paho")]
+use crate::tls::{self, TlsProbe};
+
+/// Message received from a subscribed topic.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Details of the current connection, reported for diagnostics.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectDetails {
+    pub endpoint: Option<String>,
+    pub connack: Option<String>,
+    pub session_present: Option<bool>,
+    pub tls: Option<TlsHandshake>,
+    // reason the latest connect failed
+    pub failure: Option<String>,
+}
+
+/// Connection to the MQTT broker used by IoT Core client. abstracted so that the client can be
+/// exercised in tests without network
+pub trait Publisher: Send {
+    fn connect(&mut self) -> Result<(), Report>;
+    fn disconnect(&mut self) -> Result<(), Report>;
+    fn is_connected(&self) -> bool;
+    /// Credentials of current connection are about to expire and a reconnect is needed
+    fn credentials_expiring(&self) -> bool;
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), Report>;
+    fn subscribe(&mut self, topics: &[String]) -> Result<(), Report>;
+    fn try_receive(&mut self) -> Option<IncomingMessage>;
+    /// Details of the connection made by the latest connect
+    fn connect_details(&self) -> ConnectDetails {
+        ConnectDetails::default()
+    }
+    /// Reason the broker or the network closed the connection, taken once
+    fn lost_reason(&mut self) -> Option<String> {
+        None
+    }
+}
+
+/// Endpoints of IoT Core in the configured order.
+pub fn configured_endpoints(iotcore: &IotCoreConfig) -> Result<Vec<Endpoint>, Report> {
+    trace!("in configured_endpoints");
+    let mut endpoints = Vec::new();
+    for configured in iotcore.endpoints() {
+        match Endpoint::parse(&configured, iotcore.port()) {
+            Some(endpoint) => endpoints.push(endpoint),
+            None => {
+                return Err(eyre!("Invalid IoT Core endpoint")
+                    .with_section(move || configured.header("Endpoint:")))
+            }
+        }
+    }
+    Ok(endpoints)
+}
+
+/// Error of a failed connection to IoT Core. IoT Core rejects tokens issued with a skewed clock
+/// with a generic auth error, so the clock is named as a possible cause when it is off. Code tells
+/// a rejected token from an unreachable service.
+pub fn connect_error(code: ErrorCode, reason: String, clock_config: &ClockConfig) -> Report {
+    let mut report = code
+        .report("Error while connecting to IoT core service")
+        .with_section(move || reason.header("Reason:"));
+    if let Err(reason) = clock::check_clock(clock_config) {
+        report = report.with_section(move || reason.header("Possible cause:"));
+    }
+    report
+}
+
+/// Connection to IoT Core through the MQTT backend the crate is built with: Paho with the "paho"
+/// feature, otherwise rumqttc with rustls. In local mode nothing is published to IoT Core.
+pub fn build(appconfig: &AppConfig) -> Result<Box<dyn Publisher>, Report> {
+    trace!("in build");
+    if appconfig.local.enabled() {
+        return Ok(Box::new(LocalPublisher::build(appconfig)?));
+    }
+    #[cfg(feature = "paho")]
+    let publisher = MqttPublisher::build(appconfig)?;
+    #[cfg(all(feature = "rustls", not(feature = "paho")))]
+    let publisher = RumqttPublisher::build(appconfig)?;
+    Ok(Box::new(publisher))
+}
+
+#[cfg(feature = "paho")]
+fn build_connect_options(
+    iotcore: &IotCoreConfig,
+    ssl_options: &mqtt::SslOptions,
+    http_proxy: Option<&str>,
+    server_uris: &[String],
+    jwt_token: &str,
+) -> mqtt::ConnectOptions {
+    trace!("in build_connect_options");
+    let mut conn_opts = mqtt::ConnectOptionsBuilder::new();
+    conn_opts
+        .server_uris(server_uris)
+        .user_name("not_used")
+        .password(jwt_token)
+        .ssl_options(ssl_options.clone())
+        .keep_alive_interval(Duration::from_secs(5 * 60))
+        .clean_session(iotcore.clean_session());
+    if let Some(http_proxy) = http_proxy {
+        conn_opts.http_proxy(http_proxy).https_proxy(http_proxy);
+    }
+    conn_opts.finalize()
+}
+
+// token bucket that refills continuously up to its capacity. the bucket may go into debt so that
+//  messages larger than the capacity can still be published once the bucket is full
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate)
+            .min(self.capacity);
+        self.updated = now;
+    }
+
+    // time to wait until there are enough tokens for the amount, or the bucket is full
+    fn wait_time(&mut self, amount: f64) -> Duration {
+        self.refill();
+        let missing = amount.min(self.capacity) - self.tokens;
+        if missing <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(missing / self.rate)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+}
+
+/// Global limit of messages per second and bytes per minute published to IoT Core. The limiter
+/// does not wait for tokens, beacons that are not yet within the limits are kept for later.
+pub struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn wait_time(&mut self, bytes: usize) -> Duration {
+        let messages = self
+            .messages
+            .as_mut()
+            .map(|bucket| bucket.wait_time(1.0))
+            .unwrap_or_default();
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map(|bucket| bucket.wait_time(bytes as f64))
+            .unwrap_or_default();
+        messages.max(bytes)
+    }
+
+    /// Whether a message of the size can not be published yet within the limits
+    pub fn is_limited(&mut self, bytes: usize) -> bool {
+        let wait_time = self.wait_time(bytes);
+        if wait_time > Duration::from_secs(0) {
+            trace!(
+                "Publish rate limit reached, next message fits in {} ms",
+                wait_time.as_millis()
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Counts a published message of the size against the limits
+    pub fn take(&mut self, bytes: usize) {
+        if let Some(bucket) = self.messages.as_mut() {
+            bucket.take(1.0);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(bytes as f64);
+        }
+    }
+
+    /// Rate limiter of the configured limits, none when publishing is not limited
+    pub fn build(iotcore: &IotCoreConfig) -> Option<RateLimiter> {
+        trace!("in build");
+        let messages = iotcore
+            .max_messages_per_second()
+            .map(|max_messages_per_second| {
+                TokenBucket::new(max_messages_per_second.max(1.0), max_messages_per_second)
+            });
+        let bytes = iotcore.max_bytes_per_minute().map(|max_bytes_per_minute| {
+            TokenBucket::new(
+                max_bytes_per_minute as f64,
+                max_bytes_per_minute as f64 / 60.0,
+            )
+        });
+        if messages.is_none() && bytes.is_none() {
+            return None;
+        }
+        info!(
+            "Publishing limited to {:?} messages per second and {:?} bytes per minute",
+            iotcore.max_messages_per_second(),
+            iotcore.max_bytes_per_minute()
+        );
+        Some(RateLimiter { messages, bytes })
+    }
+}
+
+/// Connection to IoT Core MQTT bridge authenticated with JWT tokens signed by the gateway key.
+#[cfg(feature = "paho")]
+pub struct MqttPublisher {
+    iotcore_config: IotCoreConfig,
+    ssl_opts: mqtt::SslOptions,
+    http_proxy: Option<String>,
+    endpoints: Vec<Endpoint>,
+    probe_endpoints: bool,
+    jwt_token: String,
+    client: mqtt::Client,
+    consumer: Receiver<Option<mqtt::message::Message>>,
+    jwt_factory: IotCoreAuthToken,
+    clock_config: ClockConfig,
+    tls_probe: Option<TlsProbe>,
+    details: ConnectDetails,
+    lost_reason: Option<String>,
+}
+
+#[cfg(feature = "paho")]
+impl MqttPublisher {
+    // TLS handshake with the endpoint, probed when endpoints are connected directly
+    fn probe_handshake(&self, endpoint: &Endpoint) -> Option<Result<TlsHandshake, String>> {
+        match &self.tls_probe {
+            Some(tls_probe) if self.probe_endpoints => {
+                Some(tls_probe.handshake(endpoint, Duration::from_secs(5)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "paho")]
+impl Publisher for MqttPublisher {
+    fn connect(&mut self) -> Result<(), Report> {
+        trace!("in connect");
+        // fullfill IoT Core's odd JWT based authentication needs by connecting with a new token
+        //   when needed
+        if !self.jwt_factory.is_valid(60) {
+            self.jwt_token = self.jwt_factory.renew()?;
+        }
+        // endpoints are tried in order until one of them accepts the connection
+        let endpoints = if self.probe_endpoints && self.endpoints.len() > 1 {
+            endpoint::order_by_reachability(&self.endpoints)
+        } else {
+            self.endpoints.clone()
+        };
+        let server_uris: Vec<String> = endpoints
+            .iter()
+            .map(|endpoint| endpoint.uri(self.iotcore_config.transport()))
+            .collect();
+        let conn_opts = build_connect_options(
+            &self.iotcore_config,
+            &self.ssl_opts,
+            self.http_proxy.as_deref(),
+            &server_uris,
+            &self.jwt_token,
+        );
+        self.details = ConnectDetails::default();
+        match self.client.connect(conn_opts) {
+            Ok(response) => {
+                self.lost_reason = None;
+                // Paho only reports a successful CONNACK
+                self.details.connack = Some("Success".to_string());
+                let connected = response.connect_response().and_then(|connect_response| {
+                    self.details.session_present = Some(connect_response.session_present);
+                    endpoints
+                        .iter()
+                        .zip(server_uris.iter())
+                        .find_map(|(endpoint, uri)| {
+                            if *uri == connect_response.server_uri {
+                                Some(endpoint.clone())
+                            } else {
+                                None
+                            }
+                        })
+                });
+                let endpoint = connected.unwrap_or_else(|| endpoints[0].clone());
+                self.details.endpoint = Some(endpoint.to_string());
+                match self.probe_handshake(&endpoint) {
+                    Some(Ok(handshake)) => self.details.tls = Some(handshake),
+                    Some(Err(reason)) => warn!("Unable to probe TLS handshake: {}", reason),
+                    None => {}
+                }
+                Ok(())
+            }
+            Err(error) => {
+                // Paho reports failed TLS handshakes without details, which the probe has
+                let mut reason = error.to_string();
+                if let Some(Err(handshake_error)) = self.probe_handshake(&endpoints[0]) {
+                    reason = format!(
+                        "{}, TLS handshake with {}: {}",
+                        reason, endpoints[0], handshake_error
+                    );
+                }
+                let code = match error {
+                    mqtt::Error::ConnectReturn(mqtt::ConnectReturnCode::BadUserNameOrPassword)
+                    | mqtt::Error::ConnectReturn(mqtt::ConnectReturnCode::NotAuthorized) => {
+                        ErrorCode::MQTT_AUTH_REJECTED
+                    }
+                    _ => ErrorCode::MQTT_UNREACHABLE,
+                };
+                self.details.failure = Some(reason.clone());
+                Err(connect_error(code, reason, &self.clock_config))
+            }
+        }
+    }
+
+    fn disconnect(&mut self) -> Result<(), Report> {
+        trace!("in disconnect");
+        match self.client.disconnect(None) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                if self.client.is_connected() {
+                    Err(eyre!("Error while disconnecting MQTT broker")
+                        .with_section(move || error.to_string().header("Reason:")))
+                } else {
+                    warn!("There was an error while disconnecting MQTT broker, but we are apparently disconnected anyway: {}", error);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn credentials_expiring(&self) -> bool {
+        !self.jwt_factory.is_valid(60)
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), Report> {
+        trace!("in publish");
+        let mqtt_msg = mqtt::MessageBuilder::new()
+            .topic(topic)
+            .payload(payload)
+            .qos(mqtt::QOS_1)
+            .finalize();
+
+        match self.client.publish(mqtt_msg) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Error while publishing to MQTT")
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+
+    fn subscribe(&mut self, topics: &[String]) -> Result<(), Report> {
+        trace!("in subscribe");
+        let qos = vec![mqtt::QOS_1; topics.len()];
+        match self.client.subscribe_many(topics, &qos) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(
+                eyre!("Error while subscribing to command and control topics")
+                    .with_section(move || error.to_string().header("Reason:")),
+            ),
+        }
+    }
+
+    fn try_receive(&mut self) -> Option<IncomingMessage> {
+        match self.consumer.try_recv() {
+            Ok(Some(msg)) => Some(IncomingMessage {
+                topic: msg.topic().to_string(),
+                payload: msg.payload_str().to_string(),
+            }),
+            // the consumer is given no message when the connection is lost
+            Ok(None) => {
+                if self.lost_reason.is_none() {
+                    self.lost_reason = Some("connection lost".to_string());
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn connect_details(&self) -> ConnectDetails {
+        self.details.clone()
+    }
+
+    fn lost_reason(&mut self) -> Option<String> {
+        self.lost_reason.take()
+    }
+}
+
+#[cfg(feature = "paho")]
+impl MqttPublisher {
+    pub fn build(appconfig: &AppConfig) -> Result<MqttPublisher, Report> {
+        trace!("in build");
+        // with file persistence in-flight messages survive restarts of the process
+        let persistence = match appconfig.iotcore.persistence_dir() {
+            Some(persistence_dir) => {
+                debug!("MQTT client persistence directory: {}", persistence_dir);
+                mqtt::PersistenceType::from(persistence_dir)
+            }
+            None => mqtt::PersistenceType::None,
+        };
+        if appconfig.iotcore.session_expiry().is_some() {
+            warn!("MQTT session expiry requires MQTT 5 and is ignored with MQTT 3.1.1.");
+        }
+
+        let mut endpoints = configured_endpoints(&appconfig.iotcore)?;
+        // IoT Core requires the "mqtt" protocol to be negotiated on port 443
+        let alpn_mqtt = appconfig.iotcore.transport() == Transport::TCP
+            && endpoints.iter().any(|endpoint| endpoint.port == 443);
+
+        // the MQTT client supports HTTP proxies, connections through SOCKS5 proxies go through a
+        //  local relay per endpoint. reachability of endpoints behind a proxy can not be probed
+        let mut http_proxy = None;
+        if appconfig.proxy.host().is_some() {
+            match appconfig.proxy.kind() {
+                ProxyKind::HTTP => {
+                    info!(
+                        "Connecting to IoT Core through HTTP proxy {}:{}",
+                        appconfig.proxy.host().unwrap(),
+                        appconfig.proxy.port()
+                    );
+                    http_proxy = proxy::http_proxy_url(&appconfig.proxy);
+                }
+                ProxyKind::SOCKS5 => {
+                    let mut relayed = Vec::new();
+                    for endpoint in endpoints {
+                        let relay =
+                            Socks5Relay::start(&appconfig.proxy, &endpoint.host, endpoint.port)?;
+                        relayed.push(Endpoint {
+                            host: "127.0.0.1".to_string(),
+                            port: relay.port,
+                        });
+                    }
+                    endpoints = relayed;
+                }
+            }
+        }
+
+        let create_opts = mqtt::CreateOptionsBuilder::new()
+            .client_id(appconfig.iotcore.client_id())
+            .mqtt_version(mqtt::types::MQTT_VERSION_3_1_1)
+            .server_uri(endpoints[0].uri(appconfig.iotcore.transport()))
+            .persistence(persistence)
+            .finalize();
+
+        let mut cli = match mqtt::Client::new(create_opts) {
+            Ok(cli) => cli,
+            Err(error) => {
+                return Err(eyre!("Unable to create Paho MQTT client instance")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        cli.set_timeout(Duration::from_secs(5));
+
+        let tls_config = match &appconfig.identity.ca_certs {
+            Some(ca_certs) => appconfig.iotcore.tls.with_ca_cert(ca_certs),
+            None => appconfig.iotcore.tls.clone(),
+        };
+        let mut ssl_options_builder =
+            tls::ssl_options_builder(&tls_config, "iotcore", Some("1.2"))?;
+        if alpn_mqtt && tls_config.alpn_protocols().is_empty() {
+            ssl_options_builder.alpn_protos(&["mqtt"]);
+        }
+        match ssl_options_builder.key_store(&appconfig.identity.public_key) {
+            Ok(options_builder) => options_builder,
+            Err(error) => {
+                return Err(eyre!("Unable to use public key in mqtt client")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        match ssl_options_builder.private_key(&appconfig.identity.private_key) {
+            Ok(options_builder) => options_builder,
+            Err(error) => {
+                return Err(eyre!("Unable to use private key in mqtt client")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let ssl_options = ssl_options_builder.finalize();
+        let tls_probe =
+            if appconfig.iotcore.transport() == Transport::TCP && tls_config.probe_handshake() {
+                let mut alpn_protocols = tls_config.alpn_protocols();
+                if alpn_mqtt && alpn_protocols.is_empty() {
+                    alpn_protocols.push("mqtt".to_string());
+                }
+                Some(TlsProbe::build(&tls_config, &alpn_protocols)?)
+            } else {
+                None
+            };
+
+        let mut jwt_factory = IotCoreAuthToken::build(appconfig);
+        let jwt_token = match jwt_factory.issue_new() {
+            Ok(token) => token,
+            Err(error) => {
+                return Err(ErrorCode::JWT_SIGN_FAIL
+                    .report("Unable to issue original JWT token")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+
+        // thru mspc relay incoming messages from cnc topics
+        let consumer = cli.start_consuming();
+
+        Ok(MqttPublisher {
+            iotcore_config: appconfig.iotcore.clone(),
+            ssl_opts: ssl_options,
+            probe_endpoints: appconfig.proxy.host().is_none(),
+            http_proxy,
+            endpoints,
+            jwt_token,
+            client: cli,
+            consumer,
+            jwt_factory,
+            clock_config: appconfig.clock.clone(),
+            tls_probe,
+            details: ConnectDetails::default(),
+            lost_reason: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use crate::configfile::IotCoreConfig;
+    use std::time::Duration;
+
+    #[test]
+    fn bursts_are_limited() {
+        let config: IotCoreConfig = serde_json::from_value(json!({
+            "device_id": "gateway",
+            "project_id": "project",
+            "region": "europe-west1",
+            "registry": "registry",
+            "max_messages_per_second": 10.0,
+            "max_bytes_per_minute": 6000,
+        }))
+        .unwrap();
+        let mut limiter = RateLimiter::build(&config).unwrap();
+        // a burst of a second worth of messages passes right away
+        for _ in 0..10 {
+            assert!(!limiter.is_limited(10));
+            limiter.take(10);
+        }
+        assert!(limiter.is_limited(10));
+        let wait_time = limiter.wait_time(10);
+        assert!(wait_time > Duration::from_millis(50) && wait_time <= Duration::from_millis(100));
+
+        // a message larger than a minute worth of bytes is limited until the bucket is full
+        let mut limiter = RateLimiter::build(&config).unwrap();
+        assert!(!limiter.is_limited(9000));
+        limiter.take(9000);
+        let wait_time = limiter.wait_time(9000);
+        assert!(wait_time > Duration::from_secs(89) && wait_time <= Duration::from_secs(90));
+
+        let config: IotCoreConfig = serde_json::from_value(json!({
+            "device_id": "gateway",
+            "project_id": "project",
+            "region": "europe-west1",
+            "registry": "registry",
+        }))
+        .unwrap();
+        assert!(RateLimiter::build(&config).is_none());
+    }
+}
+
+// eof
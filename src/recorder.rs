@@ -0,0 +1,106 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel::{unbounded, RecvTimeoutError};
+use crossbeam::thread;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::iotcore::{CNCCommand, CNCCommandMessage, CollectConfig, IOTCoreCNCMessageKind};
+use crate::metrics::Metrics;
+use crate::scanner::{BluetoothScanner, Scanner};
+
+// default name of the recording in working directory
+pub fn default_output() -> String {
+    format!(
+        "{}-{}.jsonl",
+        env!("CARGO_PKG_NAME"),
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    )
+}
+
+// scans and writes beacons with raw manufacturer data into a JSON lines file that can be
+//  replayed later, without connecting to IoT Core
+pub fn record(
+    output: &Path,
+    duration: Option<Duration>,
+    adapter: Option<String>,
+) -> Result<(), Report> {
+    trace!("in record");
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(output) {
+        Ok(file) => file,
+        Err(error) => {
+            let output = output.display().to_string();
+            return Err(eyre!("Unable to create recording file")
+                .with_section(move || output.header("File name:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+    };
+
+    let (cnc_s, cnc_r) = unbounded();
+    let (event_s, event_r) = unbounded();
+    let metrics = Arc::new(Metrics::default());
+    let mut scanner = BluetoothScanner::build(&event_s, &cnc_r, &metrics)?;
+    // raw data is always included so that decoding issues can be analyzed and beacons
+    //  decoded again on replay
+    let collectconfig: CollectConfig = serde_json::from_value(json!({
+        "collecting": true,
+        "raw_data": "include",
+        "bluetooth": { "adapter_index": 0, "adapter": adapter },
+    }))
+    .unwrap();
+    cnc_s
+        .send(IOTCoreCNCMessageKind::CONFIG(Some(Box::new(collectconfig))))
+        .unwrap();
+
+    info!("Recording beacons to {}", output.display());
+    let started = Instant::now();
+    let mut recorded: u64 = 0;
+    let result = thread::scope(|scope| {
+        scope.spawn(move |_| loop {
+            match scanner.start_scanner() {
+                Ok(true) => break,
+                Ok(false) => info!("Restarting Bluetooth scanner due to internal state change."),
+                Err(error) => error!("Restarting bluetooth scanner due to error: {}", error),
+            }
+        });
+
+        let result = loop {
+            if let Some(duration) = duration {
+                if started.elapsed() >= duration {
+                    break Ok(());
+                }
+            }
+            match event_r.recv_timeout(Duration::from_millis(100)) {
+                Ok(beacon) => {
+                    debug!("recorded beacon: {:?}", beacon);
+                    if let Err(error) =
+                        writeln!(file, "{}", serde_json::to_string(&beacon).unwrap())
+                    {
+                        break Err(eyre!("Unable to write to recording file")
+                            .with_section(move || error.to_string().header("Reason:")));
+                    }
+                    recorded += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            }
+        };
+
+        // stop the scanner
+        cnc_s
+            .send(IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+                command: CNCCommand::SHUTDOWN,
+                duty_cycle: None,
+            })))
+            .ok();
+        result
+    })
+    .unwrap();
+
+    println!("Recorded {} beacons to {}", recorded, output.display());
+    result
+}
+
+// eof
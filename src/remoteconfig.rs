@@ -0,0 +1,211 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::configfile::{AppConfig, RemoteConfig};
+use crate::jwt;
+use crate::secrets;
+
+// lifetime in seconds of the token authenticating the gateway to the server of the configuration
+const TOKEN_LIFETIME: u64 = 60;
+
+// objects in Cloud Storage are fetched through its HTTPS endpoint
+fn fetch_url(url: &str) -> String {
+    match url.strip_prefix("gs://") {
+        Some(object) => format!("https://storage.googleapis.com/{}", object),
+        None => url.to_string(),
+    }
+}
+
+// merges the overlay into the base, values in the overlay replace the ones in base except for
+//  mappings that are merged key by key
+pub fn merge(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// fetches the configuration with a short lived JWT token signed by the device key for the URL as
+//  a bearer token. the audience keeps the token from being accepted by IoT Core if the server
+//  passes it on. Cloud Storage rejects requests with tokens it did not issue, so objects there
+//  are fetched without one
+fn fetch(config: &RemoteConfig, url: &str, appconfig: &AppConfig) -> Result<String, Report> {
+    trace!("in fetch");
+    let fetch_url = fetch_url(url);
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout()))
+        .build();
+    let mut request = agent.get(&fetch_url);
+    if !url.starts_with("gs://") {
+        let token = jwt::sign_token(
+            Path::new(&appconfig.identity.private_key),
+            &fetch_url,
+            TOKEN_LIFETIME,
+        )?;
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let response = request.call();
+    match response.map(|response| response.into_string()) {
+        Ok(Ok(body)) => Ok(body),
+        Ok(Err(error)) => Err(eyre!("Unable to read remote configuration")
+            .with_section(move || error.to_string().header("Reason:"))),
+        Err(error) => Err(eyre!("Unable to fetch remote configuration")
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+fn parse(yaml: &str) -> Result<serde_yaml::Value, Report> {
+    let mut overlay: serde_yaml::Value = match serde_yaml::from_str(yaml) {
+        Ok(overlay) => overlay,
+        Err(error) => {
+            return Err(eyre!("Unable to parse remote configuration")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    secrets::decrypt_values(&mut overlay)?;
+    Ok(overlay)
+}
+
+// remote configuration from its URL, or from the local cache when it can not be fetched. the
+//  cache keeps the configuration as fetched, so secrets in it stay encrypted
+fn overlay(config: &RemoteConfig, url: &str, appconfig: &AppConfig) -> Option<serde_yaml::Value> {
+    trace!("in overlay");
+    let cache = config.cache();
+    let fetched =
+        fetch(config, url, appconfig).and_then(|yaml| parse(&yaml).map(|overlay| (yaml, overlay)));
+    match fetched {
+        Ok((yaml, overlay)) => {
+            info!("Fetched remote configuration from {}", url);
+            if let Err(error) = fs::write(&cache, yaml) {
+                warn!(
+                    "Unable to cache remote configuration to {}: {}",
+                    cache.display(),
+                    error
+                );
+            }
+            Some(overlay)
+        }
+        Err(error) => {
+            warn!("{}", error);
+            read_cache(&cache)
+        }
+    }
+}
+
+fn read_cache(cache: &Path) -> Option<serde_yaml::Value> {
+    trace!("in read_cache");
+    let yaml = match fs::read_to_string(cache) {
+        Ok(yaml) => yaml,
+        Err(error) => {
+            warn!(
+                "No cached remote configuration available, using local configuration only: {}",
+                error
+            );
+            return None;
+        }
+    };
+    match parse(&yaml) {
+        Ok(overlay) => {
+            info!("Using cached remote configuration from {}", cache.display());
+            Some(overlay)
+        }
+        Err(error) => {
+            warn!("Ignoring cached remote configuration: {}", error);
+            None
+        }
+    }
+}
+
+// local configuration with the remote configuration merged on top of it
+pub fn apply(appconfig: AppConfig) -> Result<AppConfig, Report> {
+    trace!("in apply");
+    let url = match appconfig.remote_config.url() {
+        Some(url) => url,
+        None => return Ok(appconfig),
+    };
+    // the request carries a token of the gateway, so it is not sent in plain text
+    if !fetch_url(&url).starts_with("https://") {
+        return Err(
+            eyre!("Remote configuration URL must use HTTPS or Cloud Storage")
+                .with_section(move || url.header("URL:")),
+        );
+    }
+    let overlay = match overlay(&appconfig.remote_config, &url, &appconfig) {
+        Some(overlay) => overlay,
+        None => return Ok(appconfig),
+    };
+    let mut config_value = serde_yaml::to_value(&appconfig).unwrap();
+    merge(&mut config_value, overlay);
+    match serde_yaml::from_value(config_value) {
+        Ok(config) => {
+            debug!(
+                "application configuration with remote overlay is: {:?}",
+                config
+            );
+            Ok(config)
+        }
+        Err(error) => Err(eyre!("Unable to apply remote configuration")
+            .with_section(move || url.header("URL:"))
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, fetch_url, merge};
+    use crate::configfile::AppConfig;
+
+    #[test]
+    fn overlay_is_merged() {
+        let mut base: serde_yaml::Value = serde_yaml::from_str(
+            "iotcore:\n  device_id: gateway\n  region: europe-west1\nmetrics:\n  push_interval: 60\n",
+        )
+        .unwrap();
+        let overlay: serde_yaml::Value =
+            serde_yaml::from_str("iotcore:\n  region: us-central1\ncsv:\n  directory: csv\n")
+                .unwrap();
+        merge(&mut base, overlay);
+        assert_eq!(base["iotcore"]["device_id"], "gateway");
+        assert_eq!(base["iotcore"]["region"], "us-central1");
+        assert_eq!(base["metrics"]["push_interval"], 60);
+        assert_eq!(base["csv"]["directory"], "csv");
+
+        assert_eq!(
+            fetch_url("gs://fleet-config/gateways.yaml"),
+            "https://storage.googleapis.com/fleet-config/gateways.yaml"
+        );
+    }
+
+    #[test]
+    fn plain_http_url_is_refused() {
+        let appconfig: AppConfig = serde_yaml::from_str(
+            r#"
+identity:
+  public_key: "test.crt"
+  private_key: "test.key"
+iotcore:
+  device_id: "test-gateway"
+  project_id: "test-project"
+  region: "europe-west1"
+  registry: "test-registry"
+remote_config:
+  url: "http://config.example.com/gateways.yaml"
+"#,
+        )
+        .unwrap();
+        assert!(apply(appconfig).is_err());
+    }
+}
+
+// eof
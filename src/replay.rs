@@ -0,0 +1,263 @@
+use btleplug::api::BDAddr;
+use chrono::{DateTime, TimeZone, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use eui48::MacAddress;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::scanner::RuuviBluetoothBeacon;
+
+// pcap link types of Bluetooth HCI captures (e.g. tcpdump -i bluetooth0)
+const LINKTYPE_BLUETOOTH_HCI_H4: u32 = 187;
+const LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR: u32 = 201;
+
+const HCI_EVENT_PACKET: u8 = 0x04;
+const HCI_LE_META_EVENT: u8 = 0x3e;
+const HCI_LE_ADVERTISING_REPORT: u8 = 0x02;
+const AD_MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+
+#[derive(Debug, Clone)]
+pub enum ReplayRecord {
+    // manufacturer data is decoded again as if it was just received
+    Advertisement {
+        timestamp: DateTime<Utc>,
+        address: BDAddr,
+        data: Vec<u8>,
+    },
+    // beacons without raw data are relayed as they are
+    Beacon(RuuviBluetoothBeacon),
+}
+
+impl ReplayRecord {
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            ReplayRecord::Advertisement { timestamp, .. } => *timestamp,
+            ReplayRecord::Beacon(beacon) => beacon.timestamp,
+        }
+    }
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// beacons are published by their tag address, so one that is not a MAC address is rejected
+pub fn beacon_record(beacon: RuuviBluetoothBeacon) -> Result<ReplayRecord, Report> {
+    if MacAddress::from_str(&beacon.address).is_err() {
+        let address = beacon.address;
+        return Err(
+            eyre!("Invalid tag address in beacon").with_section(move || address.header("Address:"))
+        );
+    }
+    let address = BDAddr::from_str(&beacon.address).ok();
+    Ok(match (address, from_hex(&beacon.raw)) {
+        (Some(address), Some(data)) if !data.is_empty() => ReplayRecord::Advertisement {
+            timestamp: beacon.timestamp,
+            address,
+            data,
+        },
+        _ => ReplayRecord::Beacon(beacon),
+    })
+}
+
+// each line holds a single beacon or a collection of beacons as published to IoT Core
+fn read_json_lines(content: &str) -> Result<Vec<ReplayRecord>, Report> {
+    trace!("in read_json_lines");
+    let mut records = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(eyre!("Unable to parse beacon in replay file")
+                    .with_section(move || (index + 1).to_string().header("Line:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        let beacons: Vec<RuuviBluetoothBeacon> = match value {
+            serde_json::Value::Array(_) => serde_json::from_value(value),
+            _ => serde_json::from_value(value).map(|beacon| vec![beacon]),
+        }
+        .map_err(|error| {
+            eyre!("Unable to parse beacon in replay file")
+                .with_section(move || (index + 1).to_string().header("Line:"))
+                .with_section(move || error.to_string().header("Reason:"))
+        })?;
+        for beacon in beacons {
+            let record = beacon_record(beacon).map_err(|error| {
+                error.with_section(move || (index + 1).to_string().header("Line:"))
+            })?;
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+// manufacturer data from advertising data structures (length, type, data)
+pub fn manufacturer_data(mut advertising_data: &[u8]) -> Option<Vec<u8>> {
+    while advertising_data.len() >= 2 {
+        let length = advertising_data[0] as usize;
+        if length == 0 || advertising_data.len() < length + 1 {
+            break;
+        }
+        if advertising_data[1] == AD_MANUFACTURER_SPECIFIC_DATA {
+            return Some(advertising_data[2..length + 1].to_vec());
+        }
+        advertising_data = &advertising_data[length + 1..];
+    }
+    None
+}
+
+// LE advertising report events of a captured HCI packet
+fn advertising_reports(packet: &[u8], timestamp: DateTime<Utc>, records: &mut Vec<ReplayRecord>) {
+    if packet.len() < 5
+        || packet[0] != HCI_EVENT_PACKET
+        || packet[1] != HCI_LE_META_EVENT
+        || packet[3] != HCI_LE_ADVERTISING_REPORT
+    {
+        return;
+    }
+    let reports = packet[4];
+    let mut report = &packet[5..];
+    for _ in 0..reports {
+        // event type, address type, address, data length, data and rssi
+        if report.len() < 9 {
+            return;
+        }
+        // address is little endian in HCI packets
+        let address: Vec<String> = report[2..8]
+            .iter()
+            .rev()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let address = BDAddr::from_str(&address.join(":")).unwrap();
+        let data_length = report[8] as usize;
+        if report.len() < 9 + data_length + 1 {
+            return;
+        }
+        if let Some(data) = manufacturer_data(&report[9..9 + data_length]) {
+            records.push(ReplayRecord::Advertisement {
+                timestamp,
+                address,
+                data,
+            });
+        }
+        report = &report[9 + data_length + 1..];
+    }
+}
+
+fn read_pcap(content: &[u8]) -> Result<Vec<ReplayRecord>, Report> {
+    trace!("in read_pcap");
+    if content.len() < 24 {
+        return Err(eyre!("Replay file is not a valid pcap file"));
+    }
+    let little_endian = content[0..4] == [0xd4, 0xc3, 0xb2, 0xa1];
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let link_type = read_u32(&content[20..24]);
+    let header_length = match link_type {
+        LINKTYPE_BLUETOOTH_HCI_H4 => 0,
+        // direction of the packet precedes the packet
+        LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR => 4,
+        _ => {
+            return Err(eyre!("Unsupported link type in pcap file")
+                .with_section(move || link_type.to_string().header("Link type:")))
+        }
+    };
+
+    let mut records = Vec::new();
+    let mut position = 24;
+    while position + 16 <= content.len() {
+        let seconds = read_u32(&content[position..position + 4]);
+        let microseconds = read_u32(&content[position + 4..position + 8]);
+        let length = read_u32(&content[position + 8..position + 12]) as usize;
+        position += 16;
+        if position + length > content.len() {
+            warn!("Truncated packet at the end of pcap file");
+            break;
+        }
+        if length > header_length {
+            let timestamp = Utc
+                .timestamp_opt(seconds as i64, (microseconds % 1_000_000) * 1000)
+                .unwrap();
+            advertising_reports(
+                &content[position + header_length..position + length],
+                timestamp,
+                &mut records,
+            );
+        }
+        position += length;
+    }
+    Ok(records)
+}
+
+// reads JSON lines beacon dumps and pcap captures of Bluetooth HCI traffic
+pub fn read_replay_file(path: &Path) -> Result<Vec<ReplayRecord>, Report> {
+    trace!("in read_replay_file");
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(error) => {
+            let path = path.display().to_string();
+            return Err(eyre!("Unable to read replay file")
+                .with_section(move || path.header("File name:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+    };
+    let mut records = if content.len() >= 4
+        && (content[0..4] == [0xa1, 0xb2, 0xc3, 0xd4] || content[0..4] == [0xd4, 0xc3, 0xb2, 0xa1])
+    {
+        read_pcap(&content)?
+    } else {
+        read_json_lines(&String::from_utf8_lossy(&content))?
+    };
+    records.sort_by_key(|record| record.timestamp());
+    info!(
+        "Read {} beacons to replay from {}",
+        records.len(),
+        path.display()
+    );
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_json_lines, ReplayRecord};
+
+    #[test]
+    fn json_lines_with_invalid_address_are_rejected() {
+        let beacon = json!({
+            "decoder": "ruuvi_v5",
+            "data": {"temperature": 21.5},
+            "raw": "",
+            "timestamp": "2021-03-01T00:00:00Z",
+            "address": "AA:BB:CC:DD:EE:01",
+            "sequence": 1,
+        });
+        let records = read_json_lines(&format!("{}\n\n{}", beacon, json!([beacon]))).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(records[0], ReplayRecord::Beacon(_)));
+
+        let mut invalid = beacon.clone();
+        invalid["address"] = json!("not a tag");
+        let error = read_json_lines(&format!("{}\n{}", beacon, invalid)).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid tag address in beacon");
+    }
+}
+
+// eof
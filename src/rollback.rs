@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::configfile::RollbackConfig;
+use crate::iotcore::CollectConfig;
+
+// hashes of rejected configs that are remembered, oldest are forgotten first
+const MAX_REJECTED: usize = 16;
+
+// state persisted into the state file
+#[derive(Debug, Deserialize, Serialize, Default)]
+struct RollbackState {
+    last_good: Option<CollectConfig>,
+    rejected: Vec<String>,
+}
+
+// config on probation after it has been applied
+#[derive(Debug)]
+struct Probation {
+    config: CollectConfig,
+    applied: Instant,
+    failures: u64,
+}
+
+// config that was rolled back and the one restored in its place
+#[derive(Debug)]
+pub struct Rollback {
+    pub rejected: CollectConfig,
+    pub restored: CollectConfig,
+    pub failures: u64,
+}
+
+impl Rollback {
+    // report of the rollback published to the state topic
+    pub fn report(&self) -> serde_json::Value {
+        json!({
+            "config_rollback": {
+                "rejected_version": self.rejected.hash(),
+                "restored_version": self.restored.hash(),
+                "failures": self.failures,
+                "timestamp": chrono::Utc::now(),
+            }
+        })
+    }
+}
+
+// tracks collect configs pushed from IoT Core. a new config is on probation until it has been
+//  active for the window without reaching the failure threshold, and is rolled back to the last
+//  known good one if it does not make it
+pub struct ConfigRollback {
+    config: RollbackConfig,
+    state: RollbackState,
+    probation: Option<Probation>,
+}
+
+impl ConfigRollback {
+    fn save(&self) {
+        trace!("in save");
+        if let Some(state_file) = self.config.state_file() {
+            if let Err(error) = fs::write(
+                &state_file,
+                serde_json::to_string_pretty(&self.state).unwrap(),
+            ) {
+                warn!(
+                    "Unable to save collect config state to {}: {}",
+                    state_file.display(),
+                    error
+                );
+            }
+        }
+    }
+
+    fn mark_good(&mut self, config: &CollectConfig) {
+        trace!("in mark_good");
+        info!("Collect config version {} is known good.", config.hash());
+        self.state.last_good = Some(config.clone());
+        self.save();
+    }
+
+    // previously rolled back configs are not applied again when IoT Core delivers them again,
+    //  e.g. on reconnect
+    pub fn is_rejected(&self, config: &CollectConfig) -> bool {
+        self.state.rejected.contains(&config.hash())
+    }
+
+    // puts a config received from IoT Core on probation, given the failures counted so far. the
+    //  first config without a known good one to return to is trusted as is
+    pub fn applied(&mut self, config: &CollectConfig, failures: u64) {
+        trace!("in applied");
+        self.probation = None;
+        if self.config.failure_threshold() == 0 {
+            return;
+        }
+        match &self.state.last_good {
+            Some(last_good) if last_good.hash() != config.hash() => {
+                info!(
+                    "Collect config version {} is on probation for {} seconds.",
+                    config.hash(),
+                    self.config.window()
+                );
+                self.probation = Some(Probation {
+                    config: config.clone(),
+                    applied: Instant::now(),
+                    failures,
+                });
+            }
+            _ => self.mark_good(config),
+        }
+    }
+
+    // given the failures counted so far, returns the rollback to do if the config on probation
+    //  has reached the failure threshold
+    pub fn check(&mut self, failures: u64) -> Option<Rollback> {
+        let probation = self.probation.as_ref()?;
+        let failures = failures.saturating_sub(probation.failures);
+        if failures >= self.config.failure_threshold() {
+            let probation = self.probation.take().unwrap();
+            self.state.rejected.push(probation.config.hash());
+            if self.state.rejected.len() > MAX_REJECTED {
+                self.state.rejected.remove(0);
+            }
+            self.save();
+            return Some(Rollback {
+                rejected: probation.config,
+                restored: self.state.last_good.clone().unwrap(),
+                failures,
+            });
+        }
+        if probation.applied.elapsed() >= Duration::from_secs(self.config.window()) {
+            let probation = self.probation.take().unwrap();
+            self.mark_good(&probation.config);
+        }
+        None
+    }
+
+    pub fn build(config: &RollbackConfig) -> ConfigRollback {
+        trace!("in build");
+        let state = match config.state_file() {
+            Some(state_file) => match fs::read_to_string(&state_file) {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|error| {
+                    warn!(
+                        "Ignoring collect config state in {}: {}",
+                        state_file.display(),
+                        error
+                    );
+                    RollbackState::default()
+                }),
+                Err(_) => RollbackState::default(),
+            },
+            None => RollbackState::default(),
+        };
+        ConfigRollback {
+            config: config.clone(),
+            state,
+            probation: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConfigRollback;
+    use crate::configfile::RollbackConfig;
+    use crate::iotcore::CollectConfig;
+    use crate::mock::temp_path;
+    use std::fs;
+
+    #[test]
+    fn state_survives_restart() {
+        let state_file = temp_path("rollback.json");
+        let config: RollbackConfig = serde_json::from_value(json!({
+            "state_file": state_file.display().to_string(),
+            "failure_threshold": 2,
+        }))
+        .unwrap();
+        let good: CollectConfig = serde_json::from_value(json!({ "collecting": true })).unwrap();
+        let bad: CollectConfig =
+            serde_json::from_value(json!({ "collecting": true, "collection_size": 10 })).unwrap();
+        assert_ne!(good.hash(), bad.hash());
+
+        let mut rollback = ConfigRollback::build(&config);
+        rollback.applied(&good, 5);
+        assert!(rollback.check(100).is_none());
+        rollback.applied(&bad, 100);
+        assert!(rollback.check(101).is_none());
+        let rolled_back = rollback.check(102).unwrap();
+        assert_eq!(rolled_back.restored, good);
+        assert_eq!(rolled_back.failures, 2);
+
+        let mut rollback = ConfigRollback::build(&config);
+        assert!(rollback.is_rejected(&bad));
+        assert!(!rollback.is_rejected(&good));
+        // the known good config is trusted right away
+        rollback.applied(&good, 0);
+        assert!(rollback.check(10).is_none());
+        fs::remove_file(state_file).unwrap();
+    }
+}
+
+// eof
@@ -0,0 +1,365 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel::{self, Receiver, Sender};
+use rumqttc::{
+    Client, ConnectReturnCode, Connection, ConnectionError, Event, MqttOptions, Packet, QoS,
+    SubscribeFilter, TlsConfiguration,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configfile::{AppConfig, ClockConfig, IotCoreConfig, Transport};
+use crate::endpoint::{self, Endpoint};
+use crate::fatal::ErrorCode;
+use crate::jwt::IotCoreAuthToken;
+use crate::publisher::{self, ConnectDetails, IncomingMessage, Publisher};
+use crate::tls::{self, TlsProbe};
+
+// time to wait for the broker to accept a connection or acknowledge a publish or subscribe
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+// IoT Core accepts messages of up to 256 KB
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+// events of a connection relayed from the thread driving its event loop
+enum ConnectionEvent {
+    // return code of the CONNACK and whether the broker had a session of the client
+    Connected(String, bool),
+    // return code of a CONNACK refusing the connection
+    Refused(ConnectReturnCode),
+    Acknowledged,
+    Message(IncomingMessage),
+    Lost(String),
+}
+
+// drives the event loop of a connection until the connection is lost or closed. rumqttc would
+//  reconnect on its own, but reconnecting is left to the IoT Core client which renews the token
+fn drive(mut connection: Connection, events: Sender<ConnectionEvent>, connected: Arc<AtomicBool>) {
+    trace!("in drive");
+    for event in connection.iter() {
+        let event = match event {
+            Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                connected.store(true, Ordering::Relaxed);
+                ConnectionEvent::Connected(format!("{:?}", connack.code), connack.session_present)
+            }
+            Ok(Event::Incoming(Packet::PubAck(_))) | Ok(Event::Incoming(Packet::SubAck(_))) => {
+                ConnectionEvent::Acknowledged
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                ConnectionEvent::Message(IncomingMessage {
+                    topic: publish.topic,
+                    payload: String::from_utf8_lossy(&publish.payload).to_string(),
+                })
+            }
+            Ok(_) => continue,
+            Err(ConnectionError::ConnectionRefused(code)) => {
+                events.send(ConnectionEvent::Refused(code)).ok();
+                break;
+            }
+            Err(error) => {
+                connected.store(false, Ordering::Relaxed);
+                events.send(ConnectionEvent::Lost(error.to_string())).ok();
+                break;
+            }
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+    connected.store(false, Ordering::Relaxed);
+}
+
+/// Connection to IoT Core MQTT bridge through rumqttc and rustls, which build without OpenSSL
+/// or the Paho C library. Authenticated with JWT tokens signed by the gateway key like the
+/// Paho backend.
+pub struct RumqttPublisher {
+    iotcore_config: IotCoreConfig,
+    tls_config: TlsConfiguration,
+    endpoints: Vec<Endpoint>,
+    jwt_token: String,
+    jwt_factory: IotCoreAuthToken,
+    clock_config: ClockConfig,
+    tls_probe: Option<TlsProbe>,
+    client: Option<Client>,
+    events: Option<Receiver<ConnectionEvent>>,
+    incoming: VecDeque<IncomingMessage>,
+    connected: Arc<AtomicBool>,
+    details: ConnectDetails,
+    lost_reason: Option<String>,
+    // an endpoint refused the token during the latest connect
+    auth_rejected: bool,
+}
+
+impl RumqttPublisher {
+    // waits for the broker to accept the connection or acknowledge the last request, messages
+    //  received meanwhile are kept for try_receive
+    fn wait_for_ack(&mut self) -> Result<(), String> {
+        let events = match &self.events {
+            Some(events) => events,
+            None => return Err("not connected".to_string()),
+        };
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        loop {
+            match events.recv_deadline(deadline) {
+                Ok(ConnectionEvent::Connected(connack, session_present)) => {
+                    self.details.connack = Some(connack);
+                    self.details.session_present = Some(session_present);
+                    return Ok(());
+                }
+                Ok(ConnectionEvent::Refused(code)) => {
+                    self.details.connack = Some(format!("{:?}", code));
+                    self.auth_rejected |= code == ConnectReturnCode::BadUserNamePassword
+                        || code == ConnectReturnCode::NotAuthorized;
+                    return Err(format!("connection refused with {:?}", code));
+                }
+                Ok(ConnectionEvent::Acknowledged) => return Ok(()),
+                Ok(ConnectionEvent::Message(message)) => self.incoming.push_back(message),
+                Ok(ConnectionEvent::Lost(reason)) => {
+                    self.lost_reason = Some(reason.clone());
+                    return Err(reason);
+                }
+                Err(_) => return Err("timed out waiting for the broker".to_string()),
+            }
+        }
+    }
+
+    fn connect_endpoint(&mut self, endpoint: &Endpoint) -> Result<(), String> {
+        trace!("in connect_endpoint");
+        let mut options = MqttOptions::new(
+            self.iotcore_config.client_id(),
+            endpoint.host.clone(),
+            endpoint.port,
+        );
+        options
+            .set_credentials("not_used", self.jwt_token.clone())
+            .set_transport(rumqttc::Transport::Tls(self.tls_config.clone()))
+            .set_keep_alive(Duration::from_secs(5 * 60))
+            .set_clean_session(self.iotcore_config.clean_session())
+            .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+        let (client, connection) = Client::new(options, 10);
+        let (events_s, events_r) = channel::unbounded();
+        let connected = Arc::new(AtomicBool::new(false));
+        let driver_connected = connected.clone();
+        thread::spawn(move || drive(connection, events_s, driver_connected));
+        self.client = Some(client);
+        self.events = Some(events_r);
+        self.connected = connected;
+        let result = self.wait_for_ack();
+        if result.is_err() {
+            // dropping the client stops the event loop thread
+            self.client = None;
+            self.events = None;
+        }
+        result
+    }
+
+    pub fn build(appconfig: &AppConfig) -> Result<RumqttPublisher, Report> {
+        trace!("in build");
+        // rumqttc is built without its proxy and WebSocket support, and certificates of
+        //  endpoints relayed through SOCKS5 can not be verified by rustls as the relay is
+        //  connected by its IP address
+        if appconfig.iotcore.transport() == Transport::WEBSOCKET {
+            return Err(
+                eyre!("WebSocket transport is not supported by the rustls backend")
+                    .with_section(|| "cargo build --features paho".header("Build with:")),
+            );
+        }
+        if appconfig.proxy.host().is_some() {
+            return Err(eyre!("Proxies are not supported by the rustls backend")
+                .with_section(|| "cargo build --features paho".header("Build with:")));
+        }
+        if appconfig.iotcore.persistence_dir().is_some() {
+            warn!("MQTT client persistence is not supported by the rustls backend and is ignored.");
+        }
+        if appconfig.iotcore.session_expiry().is_some() {
+            warn!("MQTT session expiry requires MQTT 5 and is ignored with MQTT 3.1.1.");
+        }
+
+        let endpoints = publisher::configured_endpoints(&appconfig.iotcore)?;
+        let tls_config = match &appconfig.identity.ca_certs {
+            Some(ca_certs) => appconfig.iotcore.tls.with_ca_cert(ca_certs),
+            None => appconfig.iotcore.tls.clone(),
+        };
+        // IoT Core requires the "mqtt" protocol to be negotiated on port 443
+        let mut alpn_protocols = tls_config.alpn_protocols();
+        if alpn_protocols.is_empty() && endpoints.iter().any(|endpoint| endpoint.port == 443) {
+            alpn_protocols.push("mqtt".to_string());
+        }
+        let tls_probe = if tls_config.probe_handshake() {
+            Some(TlsProbe::build(&tls_config, &alpn_protocols)?)
+        } else {
+            None
+        };
+        // client certificates are not used, IoT Core authenticates the gateway by the JWT token
+        let tls_config = TlsConfiguration::Simple {
+            ca: tls::pem_bundle(&tls_config)?,
+            alpn: if alpn_protocols.is_empty() {
+                None
+            } else {
+                Some(
+                    alpn_protocols
+                        .into_iter()
+                        .map(|alpn| alpn.into_bytes())
+                        .collect(),
+                )
+            },
+            client_auth: None,
+        };
+
+        let mut jwt_factory = IotCoreAuthToken::build(appconfig);
+        let jwt_token = match jwt_factory.issue_new() {
+            Ok(token) => token,
+            Err(error) => {
+                return Err(ErrorCode::JWT_SIGN_FAIL
+                    .report("Unable to issue original JWT token")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+
+        Ok(RumqttPublisher {
+            iotcore_config: appconfig.iotcore.clone(),
+            tls_config,
+            endpoints,
+            jwt_token,
+            jwt_factory,
+            clock_config: appconfig.clock.clone(),
+            tls_probe,
+            client: None,
+            events: None,
+            incoming: VecDeque::new(),
+            connected: Arc::new(AtomicBool::new(false)),
+            details: ConnectDetails::default(),
+            lost_reason: None,
+            auth_rejected: false,
+        })
+    }
+}
+
+impl Publisher for RumqttPublisher {
+    fn connect(&mut self) -> Result<(), Report> {
+        trace!("in connect");
+        // connect with a new token when needed, as with the Paho backend
+        if !self.jwt_factory.is_valid(60) {
+            self.jwt_token = self.jwt_factory.renew()?;
+        }
+        // endpoints are tried in order until one of them accepts the connection
+        let endpoints = if self.endpoints.len() > 1 {
+            endpoint::order_by_reachability(&self.endpoints)
+        } else {
+            self.endpoints.clone()
+        };
+        let mut reasons = Vec::new();
+        self.auth_rejected = false;
+        for endpoint in endpoints {
+            self.details = ConnectDetails::default();
+            match self.connect_endpoint(&endpoint) {
+                Ok(()) => {
+                    debug!("connected to IoT Core endpoint {}", endpoint);
+                    self.lost_reason = None;
+                    self.details.endpoint = Some(endpoint.to_string());
+                    if let Some(tls_probe) = &self.tls_probe {
+                        match tls_probe.handshake(&endpoint, ACK_TIMEOUT) {
+                            Ok(handshake) => self.details.tls = Some(handshake),
+                            Err(reason) => warn!("Unable to probe TLS handshake: {}", reason),
+                        }
+                    }
+                    return Ok(());
+                }
+                Err(reason) => reasons.push(format!("{}: {}", endpoint, reason)),
+            }
+        }
+        self.details.failure = Some(reasons.join(", "));
+        let code = if self.auth_rejected {
+            ErrorCode::MQTT_AUTH_REJECTED
+        } else {
+            ErrorCode::MQTT_UNREACHABLE
+        };
+        Err(publisher::connect_error(
+            code,
+            reasons.join(", "),
+            &self.clock_config,
+        ))
+    }
+
+    fn disconnect(&mut self) -> Result<(), Report> {
+        trace!("in disconnect");
+        self.connected.store(false, Ordering::Relaxed);
+        self.events = None;
+        if let Some(mut client) = self.client.take() {
+            if let Err(error) = client.disconnect() {
+                warn!("There was an error while disconnecting MQTT broker, but we are apparently disconnected anyway: {}", error);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    fn credentials_expiring(&self) -> bool {
+        !self.jwt_factory.is_valid(60)
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), Report> {
+        trace!("in publish");
+        let result = match self.client.as_mut() {
+            Some(client) => client
+                .publish(topic, QoS::AtLeastOnce, false, payload.to_vec())
+                .map_err(|error| error.to_string()),
+            None => Err("not connected".to_string()),
+        }
+        .and_then(|_| self.wait_for_ack());
+        result.map_err(|reason| {
+            eyre!("Error while publishing to MQTT").with_section(move || reason.header("Reason:"))
+        })
+    }
+
+    fn subscribe(&mut self, topics: &[String]) -> Result<(), Report> {
+        trace!("in subscribe");
+        let filters: Vec<SubscribeFilter> = topics
+            .iter()
+            .map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce))
+            .collect();
+        let result = match self.client.as_mut() {
+            Some(client) => client
+                .subscribe_many(filters)
+                .map_err(|error| error.to_string()),
+            None => Err("not connected".to_string()),
+        }
+        .and_then(|_| self.wait_for_ack());
+        result.map_err(|reason| {
+            eyre!("Error while subscribing to command and control topics")
+                .with_section(move || reason.header("Reason:"))
+        })
+    }
+
+    fn try_receive(&mut self) -> Option<IncomingMessage> {
+        if let Some(events) = &self.events {
+            while let Ok(event) = events.try_recv() {
+                match event {
+                    ConnectionEvent::Message(message) => self.incoming.push_back(message),
+                    ConnectionEvent::Lost(reason) => {
+                        warn!("Connection to IoT Core lost: {}", reason);
+                        self.lost_reason = Some(reason);
+                    }
+                    ConnectionEvent::Connected(..)
+                    | ConnectionEvent::Refused(_)
+                    | ConnectionEvent::Acknowledged => {}
+                }
+            }
+        }
+        self.incoming.pop_front()
+    }
+
+    fn connect_details(&self) -> ConnectDetails {
+        self.details.clone()
+    }
+
+    fn lost_reason(&mut self) -> Option<String> {
+        self.lost_reason.take()
+    }
+}
+
+// eof
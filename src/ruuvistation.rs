@@ -0,0 +1,105 @@
+use chrono::Utc;
+
+// data formats by the decoders of Ruuvi tags
+fn data_format(decoder: &str) -> Option<u8> {
+    match decoder {
+        "ruuvi_v3" => Some(3),
+        "ruuvi_v5" => Some(5),
+        _ => None,
+    }
+}
+
+// a beacon in the tag format of Ruuvi Station. units follow Ruuvi Station: acceleration in G,
+//  pressure in Pa and battery voltage in V
+fn tag(beacon: &serde_json::Value) -> Option<serde_json::Value> {
+    let data_format = data_format(beacon["decoder"].as_str()?)?;
+    let data = &beacon["data"];
+    let acceleration = |axis: &str| {
+        data["acceleration"][axis]
+            .as_f64()
+            .map(|acceleration| acceleration / 1000.0)
+    };
+    Some(json!({
+        "id": beacon["address"],
+        "dataFormat": data_format,
+        "updateAt": beacon["timestamp"],
+        "temperature": data["temperature"],
+        "humidity": data["humidity"],
+        "pressure": data["atmospheric_pressure"],
+        "accelX": acceleration("on_x_axis"),
+        "accelY": acceleration("on_y_axis"),
+        "accelZ": acceleration("on_z_axis"),
+        "voltage": data["powerinfo"].as_f64().map(|powerinfo| powerinfo / 1000.0),
+        "txPower": data["tx_power"],
+        "movementCounter": data["movement_counter"],
+        "measurementSequenceNumber": data["measurement_sequence_number"],
+        "rawDataBlob": beacon["raw"],
+    }))
+}
+
+// converts a beacon or a collection of beacons into the gateway payload of Ruuvi Station.
+//  beacons of other than Ruuvi tag data formats are left out
+pub fn to_ruuvistation(device_id: &str, value: serde_json::Value) -> Option<serde_json::Value> {
+    trace!("in to_ruuvistation");
+    let beacons = match value {
+        serde_json::Value::Array(beacons) => beacons,
+        beacon => vec![beacon],
+    };
+    let tags: Vec<serde_json::Value> = beacons.iter().filter_map(tag).collect();
+    if tags.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "deviceId": device_id,
+        "eventId": uuid(),
+        "time": Utc::now(),
+        "tags": tags,
+    }))
+}
+
+// random version 4 UUID identifying the event, as Ruuvi Station generates them
+fn uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_ruuvistation;
+    use crate::mock::beacon;
+
+    #[test]
+    fn ruuvistation_payload() {
+        let mut tag = serde_json::to_value(beacon("AA:BB:CC:DD:EE:01", 3)).unwrap();
+        tag["data"]["powerinfo"] = json!(2977);
+        tag["data"]["acceleration"] =
+            json!({"on_x_axis": -4.0, "on_y_axis": 1036.0, "on_z_axis": 12.0});
+        let mut ibeacon = tag.clone();
+        ibeacon["decoder"] = json!("ibeacon");
+
+        let payload = to_ruuvistation("gateway", json!([tag, ibeacon.clone()])).unwrap();
+        assert_eq!(payload["deviceId"], "gateway");
+        assert_eq!(payload["eventId"].as_str().unwrap().len(), 36);
+        let tags = payload["tags"].as_array().unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0]["id"], "AA:BB:CC:DD:EE:01");
+        assert_eq!(tags[0]["dataFormat"], 5);
+        assert_eq!(tags[0]["temperature"], 21.5);
+        assert_eq!(tags[0]["voltage"], 2.977);
+        assert_eq!(tags[0]["accelY"], 1.036);
+        assert_eq!(tags[0]["measurementSequenceNumber"], 3);
+        assert!(to_ruuvistation("gateway", ibeacon).is_none());
+    }
+}
+
+// eof
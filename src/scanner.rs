@@ -1,24 +1,183 @@
-use btleplug::api::{Central, CentralEvent, Peripheral};
+use btleplug::api::{BDAddr, Central, CentralEvent, Peripheral};
 use btleplug::bluez::{adapter::ConnectedAdapter, manager::Manager};
 use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
 use crossbeam::channel;
-use ruuvitag_dataformat::RuuviTagDataFormat5;
-use serde::Serialize;
+use ring::digest;
+use serde::{Deserialize, Serialize};
 use std::clone::Clone;
 use std::collections::HashMap;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Instant;
 use std::{thread, time};
-use structview::View;
 
-use crate::iotcore::{CNCCommand, IOTCoreCNCMessageKind};
+use crate::advmonitor::AdvertisementMonitor;
+#[cfg(feature = "paho")]
+use crate::bridge::MqttBridge;
+use crate::decoder::{BeaconDecoder, DecoderRegistry, IBeaconDecoder, IBEACON_DECODER};
+use crate::fatal::ErrorCode;
+use crate::gpsd::Position;
+use crate::hci;
+use crate::history::{self, HISTORY_DECODER};
+use crate::iotcore::{
+    CNCCommand, CollectConfig, DutyCycleConfig, HardResetMode, HistoryConfig,
+    IOTCoreCNCMessageKind, RawDataMode,
+};
+use crate::metrics::{AdapterStats, Metrics};
+use crate::replay::ReplayRecord;
+use crate::udp::UdpAdvertisement;
 
-#[derive(Debug, Serialize, Clone)]
+// advertisements without a measurement sequence number with the same payload within this many
+//  seconds are considered duplicates
+const DEDUP_WINDOW_SECONDS: i64 = 10;
+// interval of reminding in the log that scanning is disabled
+const DEGRADED_REMINDER_INTERVAL: time::Duration = time::Duration::from_secs(600);
+
+/// Beacon received from a Ruuvi tag or other Bluetooth LE sensor, as published to IoT Core.
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RuuviBluetoothBeacon {
-    pub data: RuuviTagDataFormat5,
+    /// Name of the decoder that decoded data
+    pub decoder: Option<String>,
+    /// Data is not decoded for unknown data formats
+    pub data: Option<serde_json::Value>,
+    /// Manufacturer data as hex string
+    #[serde(default)]
+    pub raw: String,
+    /// Trailing bytes appended by newer firmware as hex string
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<String>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub address: String,
+    /// Wall clock can jump (e.g. NTP step) so these allow ordering beacons reliably
+    #[serde(default)]
+    pub sequence: u64,
+    #[serde(default)]
+    pub received_monotonic_ms: u64,
+    /// Increases by one for every beacon of the tag queued for publishing, assigned by the IoT
+    /// Core client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_sequence: Option<u64>,
+    /// Collector that forwarded the beacon, none for beacons scanned locally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Position of a mobile gateway when the beacon was received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<Box<Position>>,
 }
 
+/// Beacon shared between queues, sinks and gateway status once it has been received, so that
+/// passing it around does not copy its data.
+pub type SharedBeacon = Arc<RuuviBluetoothBeacon>;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// "*" in pattern matches any characters
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.to_uppercase();
+    let value = value.to_uppercase();
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let mut remaining = value.as_str();
+    for (index, part) in parts.iter().enumerate() {
+        if index == 0 {
+            match remaining.strip_prefix(part) {
+                Some(rest) => remaining = rest,
+                None => return false,
+            }
+        } else if index == parts.len() - 1 {
+            return remaining.ends_with(part);
+        } else {
+            match remaining.find(part) {
+                Some(position) => remaining = &remaining[position + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+// Ruuvi data format 5 increments measurement sequence number on every measurement. value 65535
+//  is reserved for "not available"
+fn measurement_sequence(data: Option<&serde_json::Value>) -> Option<u64> {
+    match data?.get("measurement_sequence_number")?.as_u64()? {
+        65535 => None,
+        sequence => Some(sequence),
+    }
+}
+
+/// Hint for deduplicating a published beacon that is the same on every gateway that received
+/// the advertisement and after a restart of the gateway. Measurements of data format 5 are
+/// numbered by the tag, other advertisements are told apart by their payload within a window
+/// of DEDUP_WINDOW_SECONDS.
+pub fn dedup_id(beacon: &serde_json::Value) -> String {
+    let address = beacon["address"].as_str().unwrap_or_default();
+    if let Some(sequence) = measurement_sequence(beacon.get("data")) {
+        return format!("{}:{}", address, sequence);
+    }
+    let payload = match beacon["raw"].as_str() {
+        Some(raw) if !raw.is_empty() => raw.to_string(),
+        _ => beacon["data"].to_string(),
+    };
+    let digest = digest::digest(&digest::SHA256, payload.as_bytes());
+    let timestamp: Option<chrono::DateTime<chrono::Utc>> =
+        serde_json::from_value(beacon["timestamp"].clone()).ok();
+    let window = timestamp.map_or(0, |timestamp| timestamp.timestamp() / DEDUP_WINDOW_SECONDS);
+    format!("{}:{}:{}", address, to_hex(&digest.as_ref()[..8]), window)
+}
+
+// compares beacon to one received from the same tag stuck data threshold ago. if the measurement
+//  sequence has not advanced the Bluetooth stack is relaying stale data. tags without sequence
+//  number fall back to comparing raw data, which can false positive in very stable environments.
+//  other beacons (e.g. iBeacons) are static by nature and never considered stuck
+fn data_is_stuck(old_beacon: &RuuviBluetoothBeacon, beacon: &RuuviBluetoothBeacon) -> bool {
+    match (
+        measurement_sequence(old_beacon.data.as_ref()),
+        measurement_sequence(beacon.data.as_ref()),
+    ) {
+        (Some(old_sequence), Some(sequence)) => old_sequence == sequence,
+        _ => {
+            matches!(&beacon.decoder, Some(decoder) if decoder.starts_with("ruuvi_"))
+                && beacon.raw == old_beacon.raw
+        }
+    }
+}
+
+/// Names and addresses of Bluetooth adapters in the system.
+pub fn list_adapters() -> Result<Vec<String>, Report> {
+    trace!("in list_adapters");
+    let manager = match Manager::new() {
+        Ok(manager) => manager,
+        Err(error) => {
+            return Err(eyre!("Unable to initialize Bluetooth manager")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    let adapters = match manager.adapters() {
+        Ok(adapters) => adapters,
+        Err(error) => {
+            return Err(eyre!("Unable to list Bluetooth adapters")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+
+    Ok(adapters
+        .iter()
+        .map(|adapter| format!("{} ({})", adapter.name, adapter.addr))
+        .collect())
+}
+
+/// Source of beacons relayed to IoT Core client. abstracted so that the client can be exercised in
+/// tests without Bluetooth hardware
+pub trait Scanner: Send {
+    /// Returns true when scanner was shut down and false when it should be restarted
+    fn start_scanner(&mut self) -> Result<bool, Report>;
+}
+
+/// Scans for beacons with a Bluetooth adapter once collect config has been received.
 pub struct BluetoothScanner {
     bt_central: Option<ConnectedAdapter>,
     bt_receiver: Option<Receiver<CentralEvent>>,
@@ -26,6 +185,32 @@ pub struct BluetoothScanner {
     cnc_receiver: channel::Receiver<IOTCoreCNCMessageKind>,
     adapter_index: Option<usize>,
     stuck_data_threshold: Option<i64>,
+    metrics: Arc<Metrics>,
+    sequence: u64,
+    started: Instant,
+    raw_data: RawDataMode,
+    decoders: DecoderRegistry,
+    history: Option<HistoryConfig>,
+    adapter_pattern: Option<String>,
+    // adapter of a pipeline, used instead of the one in collect config
+    bound_adapter: Option<String>,
+    adapter_address: Option<BDAddr>,
+    adapter_name: Option<String>,
+    hard_reset: Option<HardResetMode>,
+    hard_reset_after: u32,
+    advertisement_monitor: bool,
+    // registered while the kernel scans for the advertisements of the decoders instead of btleplug
+    monitor: Option<AdvertisementMonitor>,
+    failures: u32,
+    last_adapter_check: Instant,
+    duty_cycle: Option<DutyCycleConfig>,
+    duty_cycle_started: Instant,
+    scan_idle: bool,
+    last_history_check: Option<Instant>,
+    last_history_sync: HashMap<String, chrono::DateTime<chrono::Utc>>,
+    // notifications of the peripherals of the reserved adapter, by tag address
+    history_notifications: HashMap<String, channel::Receiver<Vec<u8>>>,
+    udp_receiver: Option<channel::Receiver<UdpAdvertisement>>,
 }
 
 impl BluetoothScanner {
@@ -53,48 +238,63 @@ impl BluetoothScanner {
             }
         };
 
-        let mut adapter = match adapters.into_iter().nth(adapter_index) {
+        let selected = match &self.adapter_pattern {
+            Some(pattern) => adapters.into_iter().find(|adapter| {
+                matches_pattern(pattern, &adapter.name)
+                    || matches_pattern(pattern, &adapter.addr.to_string())
+            }),
+            None => adapters.into_iter().nth(adapter_index),
+        };
+        let mut adapter = match selected {
             Some(adapter) => adapter,
             None => {
-                return Err(
-                    eyre!("Configured Bluetooth adapter not found.").with_section(move || {
-                        adapter_index
-                            .to_string()
-                            .header("Configured adapter index:")
-                    }),
-                )
+                let configured = match &self.adapter_pattern {
+                    Some(pattern) => pattern.to_string(),
+                    None => adapter_index.to_string(),
+                };
+                return Err(ErrorCode::BT_ADAPTER_MISSING
+                    .report("Configured Bluetooth adapter not found.")
+                    .with_section(move || configured.header("Configured adapter:")));
             }
         };
+        self.adapter_address = Some(adapter.addr);
+        self.adapter_name = Some(adapter.name.clone());
 
         // reset the adapter -- clears out any errant state
         adapter = match manager.down(&adapter) {
             Ok(adapter) => adapter,
             Err(error) => {
+                self.adapter_stats(|stats| stats.hci_errors += 1);
                 return Err(eyre!("Unable to shutdown Bluetooth adapter")
-                    .with_section(move || error.to_string().header("Reason:")))
+                    .with_section(move || error.to_string().header("Reason:")));
             }
         };
         adapter = match manager.up(&adapter) {
             Ok(adapter) => adapter,
             Err(error) => {
+                self.adapter_stats(|stats| stats.hci_errors += 1);
                 return Err(eyre!("Unable to (re)start Bluetooth adapter")
-                    .with_section(move || error.to_string().header("Reason:")))
+                    .with_section(move || error.to_string().header("Reason:")));
             }
         };
+        self.adapter_stats(|stats| stats.last_reset = Some(chrono::Utc::now()));
 
         let central = match adapter.connect() {
             Ok(central) => central,
             Err(error) => {
+                self.adapter_stats(|stats| stats.hci_errors += 1);
                 return Err(eyre!("Unable to connect to Bluetooth adapter")
                     .with_section(move || {
                         adapter_index
                             .to_string()
                             .header("Configured adapter index:")
                     })
-                    .with_section(move || error.to_string().header("Reason:")))
+                    .with_section(move || error.to_string().header("Reason:")));
             }
         };
         self.bt_central = Some(central.clone());
+        // peripherals of the adapter are new, so their notification handlers are as well
+        self.history_notifications.clear();
 
         let receiver =
             match central.event_receiver() {
@@ -115,6 +315,7 @@ impl BluetoothScanner {
 
     fn release_adapter(&mut self) -> Result<(), Report> {
         trace!("in release_adapter");
+        self.monitor = None;
         if self.bt_central.is_some() {
             debug!("Releasing Bluetooth adapter.");
             match self.bt_central.as_ref().unwrap().stop_scan() {
@@ -132,16 +333,20 @@ impl BluetoothScanner {
         Ok(())
     }
 
-    fn start_scan(&self) -> Result<(), Report> {
+    fn start_scan(&mut self) -> Result<(), Report> {
         trace!("in start_scan");
         match self.bt_central {
             None => Err(eyre!("No Bluetooth adapter reserved for use")),
             Some(_) => {
+                if self.advertisement_monitor && self.register_monitor() {
+                    return Ok(());
+                }
                 // use only passive scan as we are interested in beacons only
                 self.bt_central.as_ref().unwrap().active(false);
                 match self.bt_central.as_ref().unwrap().start_scan() {
                     Ok(_) => info!("Started passive Bluetooth scan on configured adapter"),
                     Err(error) => {
+                        self.adapter_stats(|stats| stats.hci_errors += 1);
                         return Err(eyre!("Unable to start Bluetooth scan on adapter")
                             .with_section(move || {
                                 self.adapter_index
@@ -149,7 +354,7 @@ impl BluetoothScanner {
                                     .to_string()
                                     .header("Configured adapter index:")
                             })
-                            .with_section(move || error.to_string().header("Reason:")))
+                            .with_section(move || error.to_string().header("Reason:")));
                     }
                 };
                 Ok(())
@@ -157,14 +362,43 @@ impl BluetoothScanner {
         }
     }
 
-    fn stop_scan(&self) -> Result<(), Report> {
+    // the kernel scans passively for the advertisements of the decoders while a BlueZ
+    //  Advertisement Monitor is registered, and btleplug receives the reports it lets through.
+    //  false when the monitor can not be registered and btleplug has to scan instead
+    fn register_monitor(&mut self) -> bool {
+        trace!("in register_monitor");
+        let adapter_name = match &self.adapter_name {
+            Some(adapter_name) => adapter_name.clone(),
+            None => return false,
+        };
+        match AdvertisementMonitor::register(&adapter_name, &self.decoders.manufacturer_ids()) {
+            Ok(monitor) => {
+                self.monitor = Some(monitor);
+                info!("Started filtered Bluetooth scan on configured adapter");
+                true
+            }
+            Err(error) => {
+                warn!("{}", error);
+                warn!("Falling back to passive Bluetooth scan without advertisement monitor");
+                false
+            }
+        }
+    }
+
+    fn stop_scan(&mut self) -> Result<(), Report> {
         trace!("in stop_scan");
         match self.bt_central {
             None => Err(eyre!("No Bluetooth adapter reserved for use")),
             Some(_) => {
+                // the kernel stops its scan once the monitor is unregistered
+                if self.monitor.take().is_some() {
+                    info!("Stopped filtered Bluetooth scan on configured adapter");
+                    return Ok(());
+                }
                 match self.bt_central.as_ref().unwrap().stop_scan() {
                     Ok(_) => info!("Stopped passive Bluetooth scan on configured adapter"),
                     Err(error) => {
+                        self.adapter_stats(|stats| stats.hci_errors += 1);
                         return Err(eyre!("Unable to stop Bluetooth scan on adapter")
                             .with_section(move || {
                                 self.adapter_index
@@ -172,7 +406,7 @@ impl BluetoothScanner {
                                     .to_string()
                                     .header("Configured adapter index:")
                             })
-                            .with_section(move || error.to_string().header("Reason:")))
+                            .with_section(move || error.to_string().header("Reason:")));
                     }
                 };
                 Ok(())
@@ -180,23 +414,25 @@ impl BluetoothScanner {
         }
     }
 
-    pub fn start_scanner(&mut self) -> Result<bool, Report> {
-        trace!("in start_scanner");
+    fn run_scanner(&mut self) -> Result<bool, Report> {
+        trace!("in run_scanner");
         if self.adapter_index.is_some() {
             trace!("Entering to start_scanner() from unclean restart.");
             // i am restarting from main loop as I got here and I have some adapter index
             //  already configured
             match self.release_adapter() {
-                Ok(_) => match self.reserve_adapter() {
-                    Ok(_) => self.start_scan()?,
-                    Err(error) => {
-                        error!("{}", error);
-                        self.release_adapter()?;
-                        self.adapter_index = None;
-                        // force exit to main loop and restart in clean state
-                        return Ok(false);
+                Ok(_) => {
+                    self.hard_reset_adapter();
+                    match self.reserve_adapter() {
+                        Ok(_) => self.start_scan()?,
+                        Err(error) => {
+                            error!("{}", error);
+                            self.release_adapter()?;
+                            // adapter may have been unplugged. keep polling for it to return
+                            warn!("Waiting for Bluetooth adapter to become available.");
+                        }
                     }
-                },
+                }
                 Err(error) => {
                     error!("{}", error);
                     match self.release_adapter() {
@@ -231,6 +467,10 @@ impl BluetoothScanner {
                                 self.release_adapter()?;
                                 return Ok(false);
                             }
+                            CNCCommand::DUTYCYCLE => {
+                                info!("Scanner duty cycle changed to {:?}", command.duty_cycle);
+                                self.set_duty_cycle(command.duty_cycle)?;
+                            }
                             _ => warn!(
                                 "Unimplemented CNC message for Bluetooth scanner: {:?}",
                                 command
@@ -240,35 +480,76 @@ impl BluetoothScanner {
                     },
                     IOTCoreCNCMessageKind::CONFIG(collectconfig) => match collectconfig {
                         Some(collectconfig) => {
-                            let new_adapter_index = match collectconfig.bluetooth {
-                                Some(bluetooth) => bluetooth.adapter_index,
-                                None => 0,
-                            };
+                            self.configure_decoders(&collectconfig);
+                            self.history = collectconfig.history.clone();
+                            self.set_duty_cycle(collectconfig.duty_cycle.clone())?;
+                            self.advertisement_monitor = collectconfig
+                                .bluetooth
+                                .as_ref()
+                                .map_or(false, |bluetooth| bluetooth.advertisement_monitor());
+                            let (new_adapter_index, new_adapter_pattern) =
+                                match collectconfig.bluetooth {
+                                    Some(bluetooth) => {
+                                        self.hard_reset = bluetooth.hard_reset;
+                                        self.hard_reset_after = bluetooth.hard_reset_after();
+                                        (bluetooth.adapter_index, bluetooth.adapter)
+                                    }
+                                    None => {
+                                        self.hard_reset = None;
+                                        (0, None)
+                                    }
+                                };
+                            let new_adapter_pattern =
+                                self.bound_adapter.clone().or(new_adapter_pattern);
                             self.stuck_data_threshold = collectconfig.stuck_data_threshold;
                             if self.adapter_index.is_none() {
                                 trace!("Associate Bluetooth adapter for the first time");
                                 // associate the adapter
                                 self.adapter_index = Some(new_adapter_index);
-                                self.reserve_adapter()?;
-                            } else if self.adapter_index != Some(new_adapter_index) {
+                                self.adapter_pattern = new_adapter_pattern;
+                                if let Err(error) = self.reserve_adapter() {
+                                    error!("{}", error);
+                                    warn!("Waiting for Bluetooth adapter to become available.");
+                                }
+                            } else if self.adapter_index != Some(new_adapter_index)
+                                || self.adapter_pattern != new_adapter_pattern
+                            {
                                 //  store the adapter_index and exit with boolean value that causes main loop
                                 //  to restart us cleanly
-                                self.stop_scan()?;
+                                if self.bt_central.is_some() {
+                                    self.stop_scan()?;
+                                }
                                 self.adapter_index = Some(new_adapter_index);
+                                self.adapter_pattern = new_adapter_pattern;
                                 trace!("Restarting through main loop to finalize change of associated Bluetooth adapter");
                                 return Ok(false);
                             } else {
                                 trace!("No change to associated Bluetooth adapter");
                             }
                             // (re)start scanning as a precaution against timeouts on some hardware or for the first time
-                            self.stop_scan()?;
-                            self.start_scan()?;
+                            if self.bt_central.is_some() && !self.scan_idle {
+                                self.stop_scan()?;
+                                self.start_scan()?;
+                            }
                         }
                         None => debug!("Empty collect config received from CNC channel"),
                     },
                 }
             }
 
+            // poll for the adapter to detect unplugged and returned USB dongles
+            if self.last_adapter_check.elapsed() >= time::Duration::from_secs(5) {
+                self.check_adapter();
+            }
+
+            if self.duty_cycle.is_some() && self.bt_central.is_some() {
+                self.apply_duty_cycle()?;
+            }
+
+            if self.history_sync_due() {
+                self.sync_history()?;
+            }
+
             // check into the channel to see if there are beacons to relay to the mqtt broker
             if self.bt_receiver.is_some() && self.bt_central.is_some() {
                 if let Ok(event) = self.bt_receiver.as_ref().unwrap().try_recv() {
@@ -286,87 +567,32 @@ impl BluetoothScanner {
                         .peripheral(bd_addr.unwrap())
                         .unwrap();
                     let properties = peripheral.properties();
+                    self.adapter_stats(|stats| stats.advertisements_seen += 1);
 
                     if let Some(data) = properties.manufacturer_data {
-                        if data[0] == 153 && data[1] == 4 {
-                            // these values in DEC instead of HEX to identify ruuvi tags with dataformat 5
-                            // ^--- fields in index 0 and 1 indicate 99 4 as the manufacturer (ruuvi) and index 3 points data version
-                            let packet = match data[2] {
-                                // https://github.com/ruuvi/ruuvi-sensor-protocols/blob/master/dataformat_05.md
-                                // ^--- field in index 3 points to data version and everything forward from there are data points
-                                // @TODO: error handling, aka handle unwrap()
-                                5 => {
-                                    let payload = match RuuviTagDataFormat5::view(&data[3..]) {
-                                        Ok(payload) => payload,
-                                        Err(error) => return Err(
-                                            eyre!("Unable to parse Bluetooth packets peripheral properties into Ruuvitag v5 structure.")
-                                                .with_section(move || error.to_string().header("Reason:")) 
-                                            )
-                                    };
-
-                                    let beacon = RuuviBluetoothBeacon {
-                                        data: *payload,
-                                        timestamp: chrono::Utc::now(),
-                                        address: bd_addr.unwrap().to_string(),
-                                    };
-
-                                    // check against value measured 3 minutes ago and if it is identical
-                                    //  something is wrong in the stack in which case restart thread to recover.
-                                    if beacon_stuck_inventory.contains_key(&beacon.address) {
-                                        trace!(
-                                            "Comparing beacon data to see if scanner is stuck"
-                                        );
-                                        let old_beacon = beacon_stuck_inventory
-                                            .get(&beacon.address)
-                                            .unwrap();
-                                        if chrono::Utc::now()
-                                            .signed_duration_since(old_beacon.timestamp)
-                                            >= self.stuck_data_threshold()
-                                        {
-                                            if beacon.data.to_string()
-                                                == old_beacon.data.to_string()
-                                            {
-                                                error!("Values from {} seconds ago are identical for Ruuvi tag: {}", 
-                                                    self.stuck_data_threshold().num_seconds(), beacon.address);
-                                                warn!("Bluetooth stack probably stuck.");
-                                                return Ok(false);
-                                            } else {
-                                                debug!("Updating Ruuvi tag: {} in beacon_stuck_inventory after succesful test.", beacon.address);
-                                                // values from 3 minutes ago seemed to differ as expected. update inventory with this beacon
-                                                let beacon_clone = beacon.clone();
-                                                beacon_stuck_inventory.insert(
-                                                    beacon_clone.address.clone(),
-                                                    beacon_clone,
-                                                );
-                                            }
-                                        }
-                                    } else {
-                                        debug!("Adding discovered Ruuvi tag: {} to beacon_stuck_inventory to track stuck beacons (if any)", beacon.address);
-                                        // first time im seeing this Ruuvi tag. add initial beacon
-                                        let beacon_clone = beacon.clone();
-                                        beacon_stuck_inventory
-                                            .insert(beacon_clone.address.clone(), beacon_clone);
-                                    }
-
-                                    Some(beacon)
-                                }
-                                _ => {
-                                    warn!(
-                                        "Ruuvitag data format '{}' not implemented yet.",
-                                        data[2]
-                                    );
-                                    None
-                                }
-                            };
-
-                            if let Some(packet) = packet {
-                                self.channel_sender.send(packet).unwrap();
+                        if let Some(beacon) = self.decode_beacon(&data, bd_addr.unwrap()) {
+                            // check against value measured 3 minutes ago and if it is identical
+                            //  something is wrong in the stack in which case restart thread to recover.
+                            if beacon.data.is_some()
+                                && self.is_stuck(&mut beacon_stuck_inventory, &beacon)
+                            {
+                                warn!("Bluetooth stack probably stuck.");
+                                self.failures += 1;
+                                return Ok(false);
                             }
+
+                            Metrics::inc(&self.metrics.beacons_received);
+                            self.channel_sender.send(beacon).unwrap();
                         }
                     }
                 }
             }
 
+            // decoders are known only after collect config has been received
+            if self.adapter_index.is_some() {
+                self.relay_udp_advertisements();
+            }
+
             // sleep for a while to reduce amount of CPU burn and idle for a while
             thread::sleep(time::Duration::from_millis(100));
         }
@@ -376,6 +602,539 @@ impl BluetoothScanner {
         Ok(true)
     }
 
+    fn decode_beacon(&mut self, data: &[u8], address: BDAddr) -> Option<RuuviBluetoothBeacon> {
+        trace!("in decode_beacon");
+        // manufacturer data starts with little endian manufacturer id followed by data format
+        if data.len() < 3 {
+            return None;
+        }
+        let manufacturer_id = u16::from_le_bytes([data[0], data[1]]);
+        let data_format = data[2];
+        if !self.decoders.knows_manufacturer(manufacturer_id) {
+            return None;
+        }
+
+        let (decoder, decoded) = match self.decoders.find(manufacturer_id, data_format) {
+            Some(decoder) => match decoder.decode(&data[2..]) {
+                Ok(decoded) => {
+                    self.adapter_stats(|stats| stats.frames_decoded += 1);
+                    (Some(decoder.name().to_string()), Some(decoded))
+                }
+                Err(error) => {
+                    self.adapter_stats(|stats| stats.decode_errors += 1);
+                    // a single malformed advertisement must not stop the scanner
+                    warn!("{}", error);
+                    if self.raw_data == RawDataMode::NONE {
+                        return None;
+                    }
+                    // pass the malformed data through for analysis downstream
+                    (None, None)
+                }
+            },
+            None if self.raw_data != RawDataMode::NONE => {
+                // pass the undecoded data through for analysis downstream
+                debug!(
+                    "Passing through raw data of manufacturer {:#06x} data format '{}'",
+                    manufacturer_id, data_format
+                );
+                (None, None)
+            }
+            None => {
+                warn!(
+                    "Data format '{}' of manufacturer {:#06x} not implemented yet.",
+                    data_format, manufacturer_id
+                );
+                return None;
+            }
+        };
+
+        self.sequence += 1;
+        Some(RuuviBluetoothBeacon {
+            decoder,
+            data: decoded.as_ref().map(|decoded| decoded.data.clone()),
+            raw: to_hex(data),
+            extensions: decoded
+                .and_then(|decoded| decoded.extensions)
+                .map(|extensions| to_hex(&extensions)),
+            timestamp: chrono::Utc::now(),
+            address: address.to_string(),
+            sequence: self.sequence,
+            received_monotonic_ms: self.started.elapsed().as_millis() as u64,
+            tag_sequence: None,
+            source: None,
+            position: None,
+        })
+    }
+
+    fn configure_decoders(&mut self, collectconfig: &CollectConfig) {
+        trace!("in configure_decoders");
+        self.raw_data = collectconfig.raw_data();
+        // generic beacons are decoded only when a subfolder is configured for them
+        if collectconfig.generic_beacon_subfolder().is_some() {
+            if !self.decoders.has_decoder(IBEACON_DECODER) {
+                self.decoders.register(Box::new(IBeaconDecoder));
+            }
+        } else {
+            self.decoders.unregister(IBEACON_DECODER);
+        }
+    }
+
+    fn replay_record(&mut self, record: ReplayRecord) -> Option<RuuviBluetoothBeacon> {
+        trace!("in replay_record");
+        match record {
+            ReplayRecord::Advertisement { address, data, .. } => self.decode_beacon(&data, address),
+            ReplayRecord::Beacon(mut beacon) => {
+                self.sequence += 1;
+                beacon.timestamp = chrono::Utc::now();
+                beacon.sequence = self.sequence;
+                beacon.received_monotonic_ms = self.started.elapsed().as_millis() as u64;
+                beacon.tag_sequence = None;
+                Some(beacon)
+            }
+        }
+    }
+
+    /// Relays recorded or simulated beacons through the publishing pipeline instead of scanning.
+    /// pauses between beacons are divided by speed and speed 0 replays as fast as possible
+    pub fn start_replay<I: IntoIterator<Item = ReplayRecord>>(
+        &mut self,
+        records: I,
+        speed: f64,
+    ) -> Result<(), Report> {
+        trace!("in start_replay");
+        let mut records = records.into_iter().peekable();
+        // as with scanning, beacons are relayed only after collect config has been received
+        let mut configured = false;
+        let mut finished = false;
+        let mut next_at = Instant::now();
+        loop {
+            if let Ok(msg) = self.cnc_receiver.try_recv() {
+                match msg {
+                    IOTCoreCNCMessageKind::COMMAND(Some(command)) => match command.command {
+                        CNCCommand::SHUTDOWN => {
+                            warn!("CNC command received: SHUTDOWN software");
+                            break;
+                        }
+                        _ => debug!("CNC command ignored in replay mode: {:?}", command.command),
+                    },
+                    IOTCoreCNCMessageKind::COMMAND(None) => {
+                        debug!("Empty command received from CNC channel")
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(Some(collectconfig)) => {
+                        self.configure_decoders(&collectconfig);
+                        if !configured {
+                            match records.size_hint() {
+                                (_, Some(count)) => info!("Starting replay of {} beacons", count),
+                                (_, None) => info!("Starting to relay simulated beacons"),
+                            }
+                            configured = true;
+                            next_at = Instant::now();
+                        }
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(None) => {
+                        debug!("Empty configuration received from CNC channel")
+                    }
+                }
+            }
+
+            while configured && !finished && Instant::now() >= next_at {
+                let record = match records.next() {
+                    Some(record) => record,
+                    None => {
+                        // keep running so that queued beacons get published
+                        info!("Replay finished. Waiting for SHUTDOWN command.");
+                        finished = true;
+                        break;
+                    }
+                };
+                let timestamp = record.timestamp();
+                if let Some(beacon) = self.replay_record(record) {
+                    Metrics::inc(&self.metrics.beacons_received);
+                    self.channel_sender.send(beacon).unwrap();
+                }
+                if let Some(next) = records.peek() {
+                    if speed > 0.0 {
+                        let pause = next
+                            .timestamp()
+                            .signed_duration_since(timestamp)
+                            .to_std()
+                            .unwrap_or_default();
+                        next_at += pause.div_f64(speed);
+                    }
+                }
+            }
+
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        Ok(())
+    }
+
+    // decodes advertisements forwarded over UDP and relays them along with locally scanned ones
+    fn relay_udp_advertisements(&mut self) {
+        let advertisements: Vec<UdpAdvertisement> = match &self.udp_receiver {
+            Some(receiver) => receiver.try_iter().collect(),
+            None => return,
+        };
+        for advertisement in advertisements {
+            if let Some(mut beacon) = self.decode_beacon(&advertisement.data, advertisement.address)
+            {
+                beacon.source = Some(advertisement.source);
+                Metrics::inc(&self.metrics.beacons_received);
+                self.channel_sender.send(beacon).unwrap();
+            }
+        }
+    }
+
+    /// Scans with the adapter matching the name or MAC address pattern whatever collect config
+    /// selects, so that pipelines can have adapters of their own.
+    pub fn bind_adapter(&mut self, pattern: &str) {
+        trace!("in bind_adapter");
+        self.bound_adapter = Some(pattern.to_string());
+    }
+
+    /// Decodes advertisements forwarded over UDP along with the ones scanned locally.
+    pub fn register_udp_input(&mut self, receiver: &channel::Receiver<UdpAdvertisement>) {
+        trace!("in register_udp_input");
+        self.udp_receiver = Some(receiver.clone());
+    }
+
+    /// Relays advertisements that other scanners publish to an MQTT broker instead of scanning.
+    #[cfg(feature = "paho")]
+    pub fn start_bridge(&mut self, bridge: &mut MqttBridge) -> Result<(), Report> {
+        trace!("in start_bridge");
+        // as with scanning, beacons are relayed only after collect config has been received
+        let mut configured = false;
+        loop {
+            if let Ok(msg) = self.cnc_receiver.try_recv() {
+                match msg {
+                    IOTCoreCNCMessageKind::COMMAND(Some(command)) => match command.command {
+                        CNCCommand::SHUTDOWN => {
+                            warn!("CNC command received: SHUTDOWN software");
+                            break;
+                        }
+                        _ => debug!("CNC command ignored in bridge mode: {:?}", command.command),
+                    },
+                    IOTCoreCNCMessageKind::COMMAND(None) => {
+                        debug!("Empty command received from CNC channel")
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(Some(collectconfig)) => {
+                        self.configure_decoders(&collectconfig);
+                        configured = true;
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(None) => {
+                        debug!("Empty configuration received from CNC channel")
+                    }
+                }
+            }
+
+            for record in bridge.receive() {
+                if !configured {
+                    continue;
+                }
+                if let Some(beacon) = self.replay_record(record) {
+                    Metrics::inc(&self.metrics.beacons_received);
+                    self.channel_sender.send(beacon).unwrap();
+                }
+            }
+
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        bridge.disconnect();
+        Ok(())
+    }
+
+    /// Waits for shutdown without scanning when scanning is not possible, e.g. without the
+    /// capabilities it needs, instead of restarting the scanner over and over. advertisements
+    /// forwarded over UDP are still relayed
+    pub fn start_degraded(&mut self, reason: &str) {
+        trace!("in start_degraded");
+        let mut configured = false;
+        let mut last_reminder = Instant::now();
+        loop {
+            if let Ok(msg) = self.cnc_receiver.try_recv() {
+                match msg {
+                    IOTCoreCNCMessageKind::COMMAND(Some(command)) => match command.command {
+                        CNCCommand::SHUTDOWN => {
+                            warn!("CNC command received: SHUTDOWN software");
+                            break;
+                        }
+                        _ => debug!(
+                            "CNC command ignored without scanning: {:?}",
+                            command.command
+                        ),
+                    },
+                    IOTCoreCNCMessageKind::COMMAND(None) => {
+                        debug!("Empty command received from CNC channel")
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(Some(collectconfig)) => {
+                        self.configure_decoders(&collectconfig);
+                        configured = true;
+                    }
+                    IOTCoreCNCMessageKind::CONFIG(None) => {
+                        debug!("Empty configuration received from CNC channel")
+                    }
+                }
+            }
+
+            if configured {
+                self.relay_udp_advertisements();
+            }
+            if last_reminder.elapsed() >= DEGRADED_REMINDER_INTERVAL {
+                warn!("Bluetooth scanning is still disabled: {}", reason);
+                last_reminder = Instant::now();
+            }
+
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    fn is_stuck(
+        &mut self,
+        inventory: &mut HashMap<String, RuuviBluetoothBeacon>,
+        beacon: &RuuviBluetoothBeacon,
+    ) -> bool {
+        trace!("in is_stuck");
+        match inventory.get(&beacon.address) {
+            Some(old_beacon) => {
+                trace!("Comparing beacon data to see if scanner is stuck");
+                if chrono::Utc::now().signed_duration_since(old_beacon.timestamp)
+                    >= self.stuck_data_threshold()
+                {
+                    if data_is_stuck(old_beacon, beacon) {
+                        error!(
+                            "Measurement has not changed in {} seconds for Ruuvi tag: {}",
+                            self.stuck_data_threshold().num_seconds(),
+                            beacon.address
+                        );
+                        return true;
+                    }
+                    debug!(
+                        "Updating Ruuvi tag: {} in beacon_stuck_inventory after succesful test.",
+                        beacon.address
+                    );
+                    // Bluetooth stack is healthy again
+                    self.failures = 0;
+                    // values from 3 minutes ago seemed to differ as expected. update inventory with this beacon
+                    inventory.insert(beacon.address.clone(), beacon.clone());
+                }
+            }
+            None => {
+                debug!("Adding discovered Ruuvi tag: {} to beacon_stuck_inventory to track stuck beacons (if any)", beacon.address);
+                // first time im seeing this Ruuvi tag. add initial beacon
+                inventory.insert(beacon.address.clone(), beacon.clone());
+            }
+        }
+        false
+    }
+
+    fn check_adapter(&mut self) {
+        trace!("in check_adapter");
+        self.last_adapter_check = Instant::now();
+        if self.adapter_index.is_none() {
+            // no adapter configured yet
+            return;
+        }
+
+        if self.bt_central.is_some() {
+            let present = match Manager::new().and_then(|manager| manager.adapters()) {
+                Ok(adapters) => adapters
+                    .iter()
+                    .any(|adapter| Some(adapter.addr) == self.adapter_address),
+                Err(error) => {
+                    warn!("Unable to list Bluetooth adapters: {}", error);
+                    return;
+                }
+            };
+            if !present {
+                warn!("Bluetooth adapter disappeared. Waiting for it to return.");
+                self.bt_central = None;
+                self.bt_receiver = None;
+            }
+        } else {
+            match self.reserve_adapter().and_then(|_| self.start_scan()) {
+                Ok(_) => info!("Bluetooth adapter is available again."),
+                Err(error) => debug!("Bluetooth adapter is still unavailable: {}", error),
+            }
+        }
+    }
+
+    fn history_sync_due(&self) -> bool {
+        match &self.history {
+            Some(history) if !history.tags.is_empty() && self.bt_central.is_some() => {
+                match self.last_history_check {
+                    Some(last_history_check) => {
+                        last_history_check.elapsed()
+                            >= time::Duration::from_secs(history.interval())
+                    }
+                    // give the scanner some time to discover the tags first
+                    None => self.started.elapsed() >= time::Duration::from_secs(60),
+                }
+            }
+            _ => false,
+        }
+    }
+
+    // reads logged measurements from configured tags over GATT to fill gaps after gateway downtime
+    fn sync_history(&mut self) -> Result<(), Report> {
+        trace!("in sync_history");
+        let history = self.history.clone().unwrap();
+        self.last_history_check = Some(Instant::now());
+        // scanning is paused for the duration of GATT connections
+        self.stop_scan()?;
+        for tag in history.tags.iter() {
+            let peripheral = self
+                .bt_central
+                .as_ref()
+                .unwrap()
+                .peripherals()
+                .into_iter()
+                .find(|peripheral| peripheral.address().to_string().eq_ignore_ascii_case(tag));
+            let peripheral = match peripheral {
+                Some(peripheral) => peripheral,
+                None => {
+                    warn!(
+                        "Ruuvi tag {} has not been discovered yet. Skipping history sync.",
+                        tag
+                    );
+                    continue;
+                }
+            };
+            let since = match self.last_history_sync.get(tag) {
+                Some(last_history_sync) => *last_history_sync,
+                None => chrono::Utc::now() - chrono::Duration::seconds(history.max_age()),
+            };
+            let synced = chrono::Utc::now();
+            info!(
+                "Reading logged measurements of Ruuvi tag {} since {}",
+                tag, since
+            );
+            let notifications = self
+                .history_notifications
+                .entry(tag.to_string())
+                .or_insert_with(|| history::notifications(&peripheral));
+            match history::read_history(
+                &peripheral,
+                notifications,
+                since,
+                time::Duration::from_secs(history.timeout()),
+            ) {
+                Ok(records) => {
+                    info!(
+                        "Read {} logged measurements from Ruuvi tag {}",
+                        records.len(),
+                        tag
+                    );
+                    self.last_history_sync.insert(tag.to_string(), synced);
+                    // split into several beacons to stay within message size limits
+                    for chunk in records.chunks(500) {
+                        self.sequence += 1;
+                        let beacon = RuuviBluetoothBeacon {
+                            decoder: Some(HISTORY_DECODER.to_string()),
+                            data: Some(json!({ "history": chunk })),
+                            raw: String::new(),
+                            extensions: None,
+                            timestamp: chrono::Utc::now(),
+                            address: peripheral.address().to_string(),
+                            sequence: self.sequence,
+                            received_monotonic_ms: self.started.elapsed().as_millis() as u64,
+                            tag_sequence: None,
+                            source: None,
+                            position: None,
+                        };
+                        self.channel_sender.send(beacon).unwrap();
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+        }
+        if self.scan_idle {
+            Ok(())
+        } else {
+            self.start_scan()
+        }
+    }
+
+    fn set_duty_cycle(&mut self, duty_cycle: Option<DutyCycleConfig>) -> Result<(), Report> {
+        trace!("in set_duty_cycle");
+        if duty_cycle == self.duty_cycle {
+            return Ok(());
+        }
+        self.duty_cycle = duty_cycle;
+        self.duty_cycle_started = Instant::now();
+        // return to continuous scanning if radio was left idle
+        if self.duty_cycle.is_none() && self.scan_idle {
+            self.scan_idle = false;
+            if self.bt_central.is_some() {
+                self.start_scan()?;
+            }
+        }
+        Ok(())
+    }
+
+    // scans only for scan_window seconds of every scan_period to save power
+    fn apply_duty_cycle(&mut self) -> Result<(), Report> {
+        let duty_cycle = self.duty_cycle.as_ref().unwrap();
+        if duty_cycle.scan_period == 0 {
+            return Ok(());
+        }
+        let position = self.duty_cycle_started.elapsed().as_secs() % duty_cycle.scan_period;
+        let idle = position >= duty_cycle.scan_window;
+        if idle && !self.scan_idle {
+            debug!("Scanner duty cycle: idling the Bluetooth radio");
+            self.stop_scan()?;
+            self.scan_idle = true;
+        } else if !idle && self.scan_idle {
+            debug!("Scanner duty cycle: scanning");
+            self.start_scan()?;
+            self.scan_idle = false;
+        }
+        Ok(())
+    }
+
+    /// Allows registering decoders for other Bluetooth sensors in addition to built-in ones
+    pub fn register_decoder(&mut self, decoder: Box<dyn BeaconDecoder>) {
+        trace!("in register_decoder");
+        self.decoders.register(decoder);
+    }
+
+    // escalates to a deeper reset of the adapter when the scanner keeps failing even though the
+    //  adapter is reset on every restart
+    fn hard_reset_adapter(&mut self) {
+        trace!("in hard_reset_adapter");
+        let mode = match self.hard_reset {
+            Some(mode) if self.failures >= self.hard_reset_after => mode,
+            _ => return,
+        };
+        let adapter_name = match &self.adapter_name {
+            Some(adapter_name) => adapter_name.clone(),
+            None => return,
+        };
+        warn!(
+            "Bluetooth scanner failed {} times in a row. Performing {:?} hard reset of adapter {}",
+            self.failures, mode, adapter_name
+        );
+        self.failures = 0;
+        match hci::hard_reset(mode, &adapter_name) {
+            Ok(_) => {
+                info!("Hard reset of Bluetooth adapter {} done", adapter_name);
+                self.adapter_stats(|stats| stats.last_reset = Some(chrono::Utc::now()));
+            }
+            Err(error) => {
+                // fall back to regular restart of the scanner
+                self.adapter_stats(|stats| stats.hci_errors += 1);
+                error!("{}", error);
+            }
+        }
+    }
+
+    fn adapter_stats<F: FnOnce(&mut AdapterStats)>(&self, update: F) {
+        if let Some(adapter_name) = &self.adapter_name {
+            self.metrics.update_adapter(adapter_name, update);
+        }
+    }
+
     fn stuck_data_threshold(&self) -> chrono::Duration {
         let default = 180;
         if self.stuck_data_threshold.is_some() {
@@ -394,6 +1153,7 @@ impl BluetoothScanner {
     pub fn build(
         s: &channel::Sender<RuuviBluetoothBeacon>,
         cnc_r: &channel::Receiver<IOTCoreCNCMessageKind>,
+        metrics: &Arc<Metrics>,
     ) -> Result<BluetoothScanner, Report> {
         trace!("in build");
         Ok(BluetoothScanner {
@@ -403,8 +1163,184 @@ impl BluetoothScanner {
             channel_sender: s.clone(),
             cnc_receiver: cnc_r.clone(),
             stuck_data_threshold: None,
+            metrics: metrics.clone(),
+            sequence: 0,
+            started: Instant::now(),
+            raw_data: RawDataMode::NONE,
+            decoders: DecoderRegistry::default(),
+            history: None,
+            adapter_pattern: None,
+            bound_adapter: None,
+            adapter_address: None,
+            adapter_name: None,
+            hard_reset: None,
+            hard_reset_after: 2,
+            advertisement_monitor: false,
+            monitor: None,
+            failures: 0,
+            last_adapter_check: Instant::now(),
+            duty_cycle: None,
+            duty_cycle_started: Instant::now(),
+            scan_idle: false,
+            last_history_check: None,
+            last_history_sync: HashMap::new(),
+            history_notifications: HashMap::new(),
+            udp_receiver: None,
         })
     }
 }
 
+impl Scanner for BluetoothScanner {
+    fn start_scanner(&mut self) -> Result<bool, Report> {
+        trace!("in start_scanner");
+        let result = self.run_scanner();
+        if result.is_err() {
+            self.failures += 1;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{data_is_stuck, dedup_id, BluetoothScanner, RuuviBluetoothBeacon};
+    use crate::iotcore::{CNCCommand, CNCCommandMessage, CollectConfig, IOTCoreCNCMessageKind};
+    use crate::metrics::Metrics;
+    use crate::replay;
+    use crate::udp::UdpAdvertisement;
+    use btleplug::api::BDAddr;
+    use crossbeam::channel;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn beacon(decoder: &str, data: serde_json::Value, raw: &str) -> RuuviBluetoothBeacon {
+        RuuviBluetoothBeacon {
+            decoder: Some(decoder.to_string()),
+            data: Some(data),
+            raw: raw.to_string(),
+            extensions: None,
+            timestamp: chrono::Utc::now(),
+            address: "C4:D9:12:ED:63:C6".to_string(),
+            sequence: 0,
+            received_monotonic_ms: 0,
+            tag_sequence: None,
+            source: None,
+            position: None,
+        }
+    }
+
+    fn v5_beacon(temperature: f64, measurement_sequence_number: u64) -> RuuviBluetoothBeacon {
+        beacon(
+            "ruuvi_v5",
+            json!({
+                "temperature": temperature,
+                "measurement_sequence_number": measurement_sequence_number,
+            }),
+            &format!("9904051234{:04X}", measurement_sequence_number),
+        )
+    }
+
+    #[test]
+    fn sequence_advanced() {
+        // identical measurements in a very stable environment are not stuck data
+        assert!(!data_is_stuck(&v5_beacon(21.5, 100), &v5_beacon(21.5, 101)));
+        // sequence number wraps around
+        assert!(!data_is_stuck(&v5_beacon(21.5, 65534), &v5_beacon(21.5, 0)));
+    }
+
+    #[test]
+    fn sequence_not_advanced() {
+        assert!(data_is_stuck(&v5_beacon(21.5, 100), &v5_beacon(21.5, 100)));
+    }
+
+    #[test]
+    fn sequence_not_available() {
+        // falls back to comparing raw data
+        assert!(data_is_stuck(
+            &v5_beacon(21.5, 65535),
+            &v5_beacon(21.5, 65535)
+        ));
+        let old_beacon = v5_beacon(21.5, 65535);
+        let mut beacon = v5_beacon(21.6, 65535);
+        beacon.raw = "9904051235FFFF".to_string();
+        assert!(!data_is_stuck(&old_beacon, &beacon));
+    }
+
+    #[test]
+    fn without_sequence() {
+        let old_beacon = beacon("ruuvi_v3", json!({ "temperature": 21.5 }), "990403152A");
+        assert!(data_is_stuck(&old_beacon, &old_beacon.clone()));
+        let beacon = beacon("ruuvi_v3", json!({ "temperature": 21.6 }), "990403152B");
+        assert!(!data_is_stuck(&old_beacon, &beacon));
+    }
+
+    #[test]
+    fn static_beacon() {
+        let ibeacon = beacon("ibeacon", json!({ "major": 1, "minor": 2 }), "4C000215");
+        assert!(!data_is_stuck(&ibeacon, &ibeacon.clone()));
+    }
+
+    #[test]
+    fn dedup_id_identifies_advertisement() {
+        // gateways hearing the same measurement agree on the id regardless of their own sequence
+        let mut heard = v5_beacon(21.5, 100);
+        heard.sequence = 7;
+        let mut other_gateway = v5_beacon(21.5, 100);
+        other_gateway.sequence = 4000;
+        let id = dedup_id(&json!(heard));
+        assert_eq!(id, "C4:D9:12:ED:63:C6:100");
+        assert_eq!(id, dedup_id(&json!(other_gateway)));
+        assert_ne!(id, dedup_id(&json!(v5_beacon(21.5, 101))));
+
+        // without a measurement sequence the raw payload within the time window identifies it
+        let v3 = beacon("ruuvi_v3", json!({ "temperature": 21.5 }), "990403152A");
+        let mut later = v3.clone();
+        later.timestamp = v3.timestamp + chrono::Duration::seconds(60);
+        let mut changed = v3.clone();
+        changed.raw = "990403162A".to_string();
+        assert_eq!(dedup_id(&json!(v3)), dedup_id(&json!(v3.clone())));
+        assert_ne!(dedup_id(&json!(v3)), dedup_id(&json!(later)));
+        assert_ne!(dedup_id(&json!(v3)), dedup_id(&json!(changed)));
+    }
+
+    #[test]
+    fn degraded_scanner_relays_udp_until_shutdown() {
+        let (beacon_s, beacon_r) = channel::unbounded();
+        let (cnc_s, cnc_r) = channel::unbounded();
+        let (udp_s, udp_r) = channel::unbounded();
+        let mut scanner =
+            BluetoothScanner::build(&beacon_s, &cnc_r, &Arc::new(Metrics::default())).unwrap();
+        scanner.register_udp_input(&udp_r);
+        let degraded = thread::spawn(move || scanner.start_degraded("missing CAP_NET_RAW"));
+
+        let collectconfig: CollectConfig =
+            serde_json::from_value(json!({ "collecting": true })).unwrap();
+        cnc_s
+            .send(IOTCoreCNCMessageKind::CONFIG(Some(Box::new(collectconfig))))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        udp_s
+            .send(UdpAdvertisement {
+                source: "esp32-kitchen".to_string(),
+                address: BDAddr::from_str("CB:B8:33:4C:88:4F").unwrap(),
+                data: replay::from_hex("99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F")
+                    .unwrap(),
+            })
+            .unwrap();
+        let beacon = beacon_r.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(beacon.source.as_deref(), Some("esp32-kitchen"));
+        assert_eq!(beacon.decoder.as_deref(), Some("ruuvi_v5"));
+
+        cnc_s
+            .send(IOTCoreCNCMessageKind::COMMAND(Some(CNCCommandMessage {
+                command: CNCCommand::SHUTDOWN,
+                duty_cycle: None,
+            })))
+            .unwrap();
+        degraded.join().unwrap();
+    }
+}
+
 // eof
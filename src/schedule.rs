@@ -0,0 +1,154 @@
+use chrono::{DateTime, NaiveTime, Timelike, Utc};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Times of day and cron expressions beacons are collected in, part of collect config.
+/// Collecting is paused outside of them.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, PartialOrd)]
+pub struct ScheduleConfig {
+    /// Daily windows as "HH:MM-HH:MM", a window ending at or before its start spans midnight
+    windows: Option<Vec<String>>,
+    /// Cron expressions of the minutes beacons are collected in, e.g. "* 6-21 * * Mon-Fri"
+    cron: Option<Vec<String>>,
+}
+
+/// Collection schedule parsed from its config.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<(NaiveTime, NaiveTime)>,
+    cron: Vec<cron::Schedule>,
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()
+}
+
+fn parse_window(window: &str) -> Result<(NaiveTime, NaiveTime), Report> {
+    let times = window
+        .split_once('-')
+        .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)));
+    match times {
+        Some(times) => Ok(times),
+        None => {
+            let window = window.to_string();
+            Err(eyre!("Invalid schedule window, expected HH:MM-HH:MM")
+                .with_section(move || window.header("Window:")))
+        }
+    }
+}
+
+// the cron crate expects seconds in front of the usual five fields, a five field expression
+//  covers every second of the minutes it matches
+fn parse_cron(expression: &str) -> Result<cron::Schedule, Report> {
+    let full_expression = match expression.split_whitespace().count() {
+        5 => format!("* {}", expression),
+        _ => expression.to_string(),
+    };
+    match cron::Schedule::from_str(&full_expression) {
+        Ok(schedule) => Ok(schedule),
+        Err(error) => {
+            let expression = expression.to_string();
+            Err(eyre!("Invalid schedule cron expression")
+                .with_section(move || expression.header("Expression:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    }
+}
+
+impl Schedule {
+    pub fn build(config: &ScheduleConfig) -> Result<Schedule, Report> {
+        trace!("in build");
+        Ok(Schedule {
+            windows: config
+                .windows
+                .iter()
+                .flatten()
+                .map(|window| parse_window(window))
+                .collect::<Result<_, _>>()?,
+            cron: config
+                .cron
+                .iter()
+                .flatten()
+                .map(|expression| parse_cron(expression))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether beacons are collected at the time, in the time zone or else UTC.
+    pub fn collecting_at(&self, time: &DateTime<Utc>, timezone: Option<&chrono_tz::Tz>) -> bool {
+        match timezone {
+            Some(timezone) => self.includes(time.with_timezone(timezone)),
+            None => self.includes(*time),
+        }
+    }
+
+    fn includes<Z: chrono::TimeZone>(&self, time: DateTime<Z>) -> bool {
+        let time_of_day = time.time().with_nanosecond(0).unwrap();
+        self.windows.iter().any(|(start, end)| {
+            if start < end {
+                *start <= time_of_day && time_of_day < *end
+            } else {
+                *start <= time_of_day || time_of_day < *end
+            }
+        }) || self
+            .cron
+            .iter()
+            .any(|schedule| schedule.includes(time.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Schedule, ScheduleConfig};
+    use chrono::{DateTime, Utc};
+
+    fn schedule(config: &str) -> Schedule {
+        let config: ScheduleConfig = serde_json::from_str(config).unwrap();
+        Schedule::build(&config).unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn windows_include_times_of_day() {
+        let daytime = schedule(r#"{"windows": ["06:00-22:00"]}"#);
+        assert!(!daytime.collecting_at(&at("2021-06-01T05:59:59Z"), None));
+        assert!(daytime.collecting_at(&at("2021-06-01T06:00:00Z"), None));
+        assert!(!daytime.collecting_at(&at("2021-06-01T22:00:00Z"), None));
+        // 06:00 in Helsinki is 03:00 UTC in summer
+        let timezone: chrono_tz::Tz = "Europe/Helsinki".parse().unwrap();
+        assert!(daytime.collecting_at(&at("2021-06-01T03:00:00Z"), Some(&timezone)));
+        assert!(!daytime.collecting_at(&at("2021-06-01T20:00:00Z"), Some(&timezone)));
+
+        let overnight = schedule(r#"{"windows": ["22:00-06:00"]}"#);
+        assert!(overnight.collecting_at(&at("2021-06-01T23:30:00Z"), None));
+        assert!(overnight.collecting_at(&at("2021-06-01T05:00:00Z"), None));
+        assert!(!overnight.collecting_at(&at("2021-06-01T12:00:00Z"), None));
+    }
+
+    #[test]
+    fn cron_expressions_include_minutes() {
+        let schedule = schedule(r#"{"cron": ["* 6-21 * * Mon-Fri"]}"#);
+        // 2021-06-01 is a Tuesday and 2021-06-05 a Saturday
+        assert!(schedule.collecting_at(&at("2021-06-01T06:00:30Z"), None));
+        assert!(!schedule.collecting_at(&at("2021-06-01T22:00:00Z"), None));
+        assert!(!schedule.collecting_at(&at("2021-06-05T12:00:00Z"), None));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for config in &[
+            r#"{"windows": ["6-22"]}"#,
+            r#"{"windows": ["06:00-25:00"]}"#,
+            r#"{"cron": ["every day"]}"#,
+        ] {
+            let config: ScheduleConfig = serde_json::from_str(config).unwrap();
+            assert!(Schedule::build(&config).is_err());
+        }
+    }
+}
+
+// eof
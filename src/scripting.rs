@@ -0,0 +1,115 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::configfile::ScriptingConfig;
+
+// name of the function called in scripts for every published beacon
+const TRANSFORM_FUNCTION: &str = "transform";
+// guards against scripts stuck in an endless loop blocking publishing
+const MAX_OPERATIONS: u64 = 100_000;
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST, Report> {
+    trace!("in compile");
+    match engine.compile_file(path.to_path_buf()) {
+        Ok(ast) => Ok(ast),
+        Err(error) => {
+            let path = path.display().to_string();
+            Err(eyre!("Unable to compile payload script")
+                .with_section(move || path.header("File name:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    }
+}
+
+// scripts transforming the JSON of beacons before they are published. script of the tag is used
+//  when configured and the global script otherwise
+pub struct PayloadScripts {
+    engine: Engine,
+    global: Option<AST>,
+    tags: HashMap<String, AST>,
+}
+
+impl PayloadScripts {
+    fn run(
+        &self,
+        ast: &AST,
+        beacon: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>, Report> {
+        let beacon = rhai::serde::to_dynamic(beacon).map_err(|error| {
+            eyre!("Unable to pass beacon to payload script")
+                .with_section(move || error.to_string().header("Reason:"))
+        })?;
+        let result: Dynamic = self
+            .engine
+            .call_fn(&mut Scope::new(), ast, TRANSFORM_FUNCTION, (beacon,))
+            .map_err(|error| {
+                eyre!("Error in payload script")
+                    .with_section(move || error.to_string().header("Reason:"))
+            })?;
+        // returning nothing filters the beacon out
+        if result.is::<()>() {
+            return Ok(None);
+        }
+        match rhai::serde::from_dynamic(&result) {
+            Ok(beacon) => Ok(Some(beacon)),
+            Err(error) => Err(eyre!("Invalid beacon returned from payload script")
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+
+    // returns the beacon to publish or None if it was filtered out. beacon is published as it is
+    //  if the script fails
+    pub fn transform(&self, beacon: serde_json::Value) -> Option<serde_json::Value> {
+        trace!("in transform");
+        let address = beacon["address"]
+            .as_str()
+            .unwrap_or_default()
+            .to_uppercase();
+        let ast = match self.tags.get(&address).or(self.global.as_ref()) {
+            Some(ast) => ast,
+            None => return Some(beacon),
+        };
+        match self.run(ast, beacon.clone()) {
+            Ok(Some(transformed)) => Some(transformed),
+            Ok(None) => {
+                debug!("Beacon from '{}' filtered out by payload script", address);
+                None
+            }
+            Err(error) => {
+                warn!("{}", error);
+                Some(beacon)
+            }
+        }
+    }
+
+    pub fn build(config: &ScriptingConfig) -> Result<Option<PayloadScripts>, Report> {
+        trace!("in build");
+        if config.script().is_none() && config.tags().is_empty() {
+            return Ok(None);
+        }
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let global = match config.script() {
+            Some(path) => Some(compile(&engine, &path)?),
+            None => None,
+        };
+        let mut tags = HashMap::new();
+        for (address, path) in config.tags() {
+            tags.insert(address.to_uppercase(), compile(&engine, &path)?);
+        }
+        info!(
+            "Payload scripts loaded (global: {}, per tag: {})",
+            global.is_some(),
+            tags.len()
+        );
+        Ok(Some(PayloadScripts {
+            engine,
+            global,
+            tags,
+        }))
+    }
+}
+
+// eof
@@ -0,0 +1,236 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::Mutex;
+use std::{env, fs, path::Path};
+
+// encrypted values in config files are prefixed with this marker
+pub const ENCRYPTED_PREFIX: &str = "enc:";
+// key can be supplied directly via environment..
+const KEY_ENV_VARIABLE: &str = "RUUVI2IOTCORE_CONFIG_KEY";
+// ..or as a systemd credential (LoadCredential=config_key:/path/to/key)
+const KEY_CREDENTIAL_NAME: &str = "config_key";
+const NONCE_LENGTH: usize = 12;
+// plain text of the values decrypted so far, masked when configs are written to the log
+static DECRYPTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn read_key() -> Result<Vec<u8>, Report> {
+    trace!("in read_key");
+    let encoded = match env::var(KEY_ENV_VARIABLE) {
+        Ok(key) => key,
+        Err(_) => match env::var("CREDENTIALS_DIRECTORY") {
+            Ok(directory) => {
+                let path = Path::new(&directory).join(KEY_CREDENTIAL_NAME);
+                match fs::read_to_string(&path) {
+                    Ok(key) => key,
+                    Err(error) => {
+                        return Err(eyre!("Unable to read config encryption key credential")
+                            .with_section(move || {
+                                path.to_string_lossy().to_string().header("File name:")
+                            })
+                            .with_section(move || error.to_string().header("Reason:")))
+                    }
+                }
+            }
+            Err(_) => {
+                return Err(
+                    eyre!("No config encryption key available").with_section(|| {
+                        format!(
+                            "Set {} environment variable or supply '{}' systemd credential.",
+                            KEY_ENV_VARIABLE, KEY_CREDENTIAL_NAME
+                        )
+                        .header("Hint:")
+                    }),
+                )
+            }
+        },
+    };
+
+    let key = match base64::decode(encoded.trim()) {
+        Ok(key) => key,
+        Err(error) => {
+            return Err(eyre!("Config encryption key is not valid base64")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    if key.len() != 32 {
+        return Err(eyre!("Config encryption key must be 32 bytes (AES-256)")
+            .with_section(move || key.len().to_string().header("Key length:")));
+    }
+
+    Ok(key)
+}
+
+pub fn encrypt(plaintext: &str) -> Result<String, Report> {
+    trace!("in encrypt");
+    let key = read_key()?;
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = match cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()) {
+        Ok(ciphertext) => ciphertext,
+        Err(error) => {
+            return Err(eyre!("Unable to encrypt value")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(sealed)))
+}
+
+// reads a line from the terminal with echo turned off so that the value is not shown
+fn read_without_echo(stdin: &io::Stdin) -> io::Result<String> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let original = unsafe { termios.assume_init() };
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+    silent.c_lflag |= libc::ECHONL;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut line = String::new();
+    let result = stdin.lock().read_line(&mut line);
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+    result.map(|_| line)
+}
+
+/// Reads a value to encrypt from standard input, prompting for it without echo on a terminal,
+/// so that it does not end up in shell history or the command line of the process.
+pub fn read_plaintext() -> Result<String, Report> {
+    trace!("in read_plaintext");
+    let stdin = io::stdin();
+    let result = if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        eprint!("Value to encrypt: ");
+        let _ = io::stderr().flush();
+        read_without_echo(&stdin)
+    } else {
+        let mut value = String::new();
+        stdin.lock().read_to_string(&mut value).map(|_| value)
+    };
+    let value = match result {
+        Ok(value) => value,
+        Err(error) => {
+            return Err(eyre!("Unable to read value to encrypt")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    // a single trailing newline is from the terminal or echo, not part of the value
+    let value = value
+        .strip_suffix('\n')
+        .map(|value| value.strip_suffix('\r').unwrap_or(value))
+        .unwrap_or(&value)
+        .to_string();
+    if value.is_empty() {
+        return Err(eyre!("No value to encrypt given"));
+    }
+    Ok(value)
+}
+
+pub fn decrypt(value: &str) -> Result<String, Report> {
+    trace!("in decrypt");
+    let encoded = value.trim_start_matches(ENCRYPTED_PREFIX);
+    let sealed = match base64::decode(encoded) {
+        Ok(sealed) if sealed.len() > NONCE_LENGTH => sealed,
+        Ok(_) => return Err(eyre!("Encrypted value is too short")),
+        Err(error) => {
+            return Err(eyre!("Encrypted value is not valid base64")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+
+    let key = read_key()?;
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(plaintext) => plaintext,
+        Err(_) => {
+            return Err(eyre!(
+                "Unable to decrypt value. Is the config encryption key correct?"
+            ))
+        }
+    };
+
+    match String::from_utf8(plaintext) {
+        Ok(plaintext) => {
+            if !plaintext.is_empty() {
+                DECRYPTED.lock().unwrap().push(plaintext.clone());
+            }
+            Ok(plaintext)
+        }
+        Err(error) => Err(eyre!("Decrypted value is not valid UTF-8")
+            .with_section(move || error.to_string().header("Reason:"))),
+    }
+}
+
+// walks through the parsed config document and decrypts every encrypted string value in place
+pub fn decrypt_values(value: &mut serde_yaml::Value) -> Result<(), Report> {
+    match value {
+        serde_yaml::Value::String(string) if string.starts_with(ENCRYPTED_PREFIX) => {
+            *string = decrypt(string)?;
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                decrypt_values(value)?;
+            }
+        }
+        serde_yaml::Value::Sequence(sequence) => {
+            for value in sequence.iter_mut() {
+                decrypt_values(value)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Debug formatting of a value, e.g. the application configuration, with the values decrypted
+/// from the config replaced by ***.
+pub struct Redacted<'a, T: fmt::Debug>(pub &'a T);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut text = format!("{:?}", self.0);
+        for secret in DECRYPTED.lock().unwrap().iter() {
+            // strings are escaped in debug output
+            let escaped = format!("{:?}", secret);
+            text = text
+                .replace(&escaped[1..escaped.len() - 1], "***")
+                .replace(secret.as_str(), "***");
+        }
+        f.write_str(&text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_values, encrypt, Redacted, KEY_ENV_VARIABLE};
+    use crate::configfile::SinkConfig;
+
+    #[test]
+    fn decrypted_values_are_redacted() {
+        std::env::set_var(KEY_ENV_VARIABLE, base64::encode([7u8; 32]));
+        let password = "s3cr3t \"sink\" password";
+        let mut config: serde_yaml::Value = serde_yaml::from_str(&format!(
+            "kind: amqp\nurl: amqp://broker.local\npassword: \"{}\"",
+            encrypt(password).unwrap()
+        ))
+        .unwrap();
+        decrypt_values(&mut config).unwrap();
+        let config: SinkConfig = serde_yaml::from_value(config).unwrap();
+        assert_eq!(config.password().unwrap(), password);
+
+        let logged = format!("{:?}", Redacted(&config));
+        assert!(!logged.contains("s3cr3t"));
+        assert!(logged.contains("password: Some(\"***\")"));
+        assert!(logged.contains("amqp://broker.local"));
+    }
+}
+
+// eof
@@ -0,0 +1,163 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use ring::rand::SystemRandom;
+use ring::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// fields of a service account key file of Google Cloud that are used
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    project_id: Option<String>,
+    private_key: String,
+    client_email: String,
+    token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+// DER of the PKCS#8 private key in PEM
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(|line| line.trim())
+        .collect();
+    base64::decode(body).ok()
+}
+
+fn read_key(key_file: &Path) -> Result<ServiceAccountKey, Report> {
+    trace!("in read_key");
+    // the key is not echoed in errors, only why it could not be read
+    let reason = match fs::read_to_string(key_file) {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(key) => return Ok(key),
+            Err(error) => error.to_string(),
+        },
+        Err(error) => error.to_string(),
+    };
+    let key_file = key_file.display().to_string();
+    Err(eyre!("Unable to read service account key")
+        .with_section(move || key_file.header("File name:"))
+        .with_section(move || reason.header("Reason:")))
+}
+
+/// Google Cloud service account of sinks writing to Google Cloud APIs, exchanging a JWT signed
+/// with the key of the account for OAuth access tokens of a scope.
+pub struct ServiceAccount {
+    project_id: Option<String>,
+    client_email: String,
+    token_uri: String,
+    scope: String,
+    private_key: RsaKeyPair,
+    rng: SystemRandom,
+    token: Option<(String, Instant)>,
+}
+
+impl ServiceAccount {
+    pub fn read(key_file: &Path, scope: &str) -> Result<ServiceAccount, Report> {
+        trace!("in read");
+        let key = read_key(key_file)?;
+        let private_key = match pem_to_der(&key.private_key)
+            .ok_or_else(|| "private key is not PEM".to_string())
+            .and_then(|der| RsaKeyPair::from_pkcs8(&der).map_err(|error| error.to_string()))
+        {
+            Ok(private_key) => private_key,
+            Err(reason) => {
+                let key_file = key_file.display().to_string();
+                return Err(eyre!("Unable to read private key of service account")
+                    .with_section(move || key_file.header("File name:"))
+                    .with_section(move || reason.header("Reason:")));
+            }
+        };
+        Ok(ServiceAccount {
+            project_id: key.project_id,
+            client_email: key.client_email,
+            token_uri: key.token_uri,
+            scope: scope.to_string(),
+            private_key,
+            rng: SystemRandom::new(),
+            token: None,
+        })
+    }
+
+    /// Project the service account belongs to
+    pub fn project_id(&self) -> Option<String> {
+        self.project_id.clone()
+    }
+
+    // signed JWT asserting the service account, exchanged for an access token
+    fn assertion(&self) -> Result<String, String> {
+        let now = chrono::Utc::now().timestamp();
+        let header = json!({ "alg": "RS256", "typ": "JWT" });
+        let claims = json!({
+            "iss": self.client_email,
+            "scope": self.scope,
+            "aud": self.token_uri,
+            "iat": now,
+            "exp": now + 3600,
+        });
+        let message = format!(
+            "{}.{}",
+            base64url(header.to_string().as_bytes()),
+            base64url(claims.to_string().as_bytes())
+        );
+        let mut signature = vec![0; self.private_key.public_modulus_len()];
+        self.private_key
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &self.rng,
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| "unable to sign the token request".to_string())?;
+        Ok(format!("{}.{}", message, base64url(&signature)))
+    }
+
+    /// Access token of the service account, renewed a minute before it expires
+    pub fn access_token(&mut self, agent: &ureq::Agent) -> Result<String, String> {
+        trace!("in access_token");
+        if let Some((token, expires)) = &self.token {
+            if Instant::now() + Duration::from_secs(60) < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let response = agent
+            .post(&self.token_uri)
+            .send_form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &self.assertion()?),
+            ])
+            .map_err(|error| format!("unable to get access token: {}", error))?;
+        let token: AccessToken = response
+            .into_string()
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .ok_or_else(|| "unable to parse access token".to_string())?;
+        debug!(
+            "got access token of {} expiring in {} seconds",
+            self.client_email, token.expires_in
+        );
+        self.token = Some((
+            token.access_token.clone(),
+            Instant::now() + Duration::from_secs(token.expires_in),
+        ));
+        Ok(token.access_token)
+    }
+
+    /// Forgets the access token, e.g. after it was rejected, so that a new one is requested
+    pub fn revoke(&mut self) {
+        self.token = None;
+    }
+}
+
+// eof
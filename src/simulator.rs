@@ -0,0 +1,178 @@
+use btleplug::api::BDAddr;
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::str::FromStr;
+
+use crate::decoder::RUUVI_MANUFACTURER_ID;
+use crate::replay::ReplayRecord;
+
+// Ruuvi tags with default firmware advertise about once a second
+const ADVERTISEMENT_INTERVAL_MS: i64 = 1000;
+
+// drifting measurements of a single virtual tag
+struct SimulatedTag {
+    address: BDAddr,
+    mac: [u8; 6],
+    base_temperature: f64,
+    temperature: f64,
+    humidity: f64,
+    pressure: f64,
+    battery: u16,
+    movement_counter: u8,
+    sequence: u16,
+}
+
+impl SimulatedTag {
+    fn build(index: usize) -> SimulatedTag {
+        // random static address so that virtual tags can not collide with real ones
+        let mac = [
+            0xc0,
+            0xff,
+            0xee,
+            (index >> 16) as u8,
+            (index >> 8) as u8,
+            index as u8,
+        ];
+        let address: Vec<String> = mac.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let base_temperature = 18.0 + (index % 8) as f64;
+        SimulatedTag {
+            address: BDAddr::from_str(&address.join(":")).unwrap(),
+            mac,
+            base_temperature,
+            temperature: base_temperature,
+            humidity: 40.0 + (index % 5) as f64 * 5.0,
+            pressure: 101_325.0,
+            battery: 3000,
+            movement_counter: 0,
+            sequence: 0,
+        }
+    }
+
+    // random walk pulled slowly back towards the base values
+    fn drift<R: Rng>(&mut self, rng: &mut R) {
+        self.temperature +=
+            rng.gen_range(-0.05..0.05) + (self.base_temperature - self.temperature) * 0.01;
+        self.humidity = (self.humidity + rng.gen_range(-0.2..0.2)).clamp(0.0, 100.0);
+        self.pressure += rng.gen_range(-5.0..5.0) + (101_325.0 - self.pressure) * 0.01;
+        if rng.gen_bool(0.01) {
+            self.movement_counter = self.movement_counter.wrapping_add(1);
+        }
+        // 65535 is reserved for "not available"
+        self.sequence = (self.sequence + 1) % 65535;
+    }
+
+    // manufacturer data of a data format 5 advertisement
+    fn advertisement<R: Rng>(&self, rng: &mut R) -> Vec<u8> {
+        let mut data = RUUVI_MANUFACTURER_ID.to_le_bytes().to_vec();
+        data.push(5);
+        data.extend(((self.temperature / 0.005).round() as i16).to_be_bytes());
+        data.extend(((self.humidity / 0.0025).round() as u16).to_be_bytes());
+        data.extend(((self.pressure - 50_000.0).round() as u16).to_be_bytes());
+        // tag lying still on a table
+        for acceleration in [0, 0, 1000] {
+            let acceleration: i16 = acceleration + rng.gen_range(-8..8);
+            data.extend(acceleration.to_be_bytes());
+        }
+        // battery voltage above 1.6 V in mV (11 bits) and tx power of 4 dBm in 2 dBm steps above
+        //  -40 dBm (5 bits)
+        let power_info: u16 = ((self.battery - 1600) << 5) | ((4 + 40) / 2);
+        data.extend(power_info.to_be_bytes());
+        data.push(self.movement_counter);
+        data.extend(self.sequence.to_be_bytes());
+        data.extend(self.mac);
+        data
+    }
+}
+
+// endless stream of advertisements from virtual Ruuvi tags with plausible drifting temperature,
+//  humidity and pressure. every tag advertises once per interval
+pub struct Simulator {
+    tags: Vec<SimulatedTag>,
+    next_tag: usize,
+    timestamp: DateTime<Utc>,
+    rng: StdRng,
+}
+
+impl Iterator for Simulator {
+    type Item = ReplayRecord;
+
+    fn next(&mut self) -> Option<ReplayRecord> {
+        if self.tags.is_empty() {
+            return None;
+        }
+        if self.next_tag == self.tags.len() {
+            self.next_tag = 0;
+            self.timestamp += chrono::Duration::milliseconds(ADVERTISEMENT_INTERVAL_MS);
+        }
+        let tag = &mut self.tags[self.next_tag];
+        self.next_tag += 1;
+        tag.drift(&mut self.rng);
+        Some(ReplayRecord::Advertisement {
+            timestamp: self.timestamp,
+            address: tag.address,
+            data: tag.advertisement(&mut self.rng),
+        })
+    }
+}
+
+impl Simulator {
+    pub fn build(tags: usize) -> Simulator {
+        trace!("in build");
+        Simulator {
+            tags: (0..tags).map(SimulatedTag::build).collect(),
+            next_tag: 0,
+            timestamp: Utc::now(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Simulator;
+    use crate::decoder::{BeaconDecoder, RuuviDataFormat5Decoder};
+    use crate::replay::ReplayRecord;
+
+    #[test]
+    fn advertisements_decode() {
+        let records: Vec<ReplayRecord> = Simulator::build(3).take(30).collect();
+        for (index, record) in records.iter().enumerate() {
+            let data = match record {
+                ReplayRecord::Advertisement { data, .. } => data,
+                _ => panic!("simulator produced a beacon instead of an advertisement"),
+            };
+            assert_eq!(data.len(), 26);
+            let decoded = RuuviDataFormat5Decoder.decode(&data[2..]).unwrap().data;
+            let temperature = decoded["temperature"].as_f64().unwrap();
+            assert!((15.0..30.0).contains(&temperature));
+            let humidity = decoded["humidity"].as_f64().unwrap();
+            assert!((30.0..70.0).contains(&humidity));
+            assert_eq!(
+                decoded["measurement_sequence_number"].as_u64().unwrap(),
+                (index / 3 + 1) as u64
+            );
+        }
+    }
+
+    #[test]
+    fn tags_advertise_once_per_interval() {
+        let records: Vec<ReplayRecord> = Simulator::build(2).take(4).collect();
+        let addresses: Vec<String> = records
+            .iter()
+            .map(|record| match record {
+                ReplayRecord::Advertisement { address, .. } => address.to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(addresses[0], addresses[2]);
+        assert_ne!(addresses[0], addresses[1]);
+        assert_eq!(records[0].timestamp(), records[1].timestamp());
+        assert_eq!(
+            (records[2].timestamp() - records[0].timestamp()).num_milliseconds(),
+            1000
+        );
+    }
+}
+
+// eof
@@ -0,0 +1,239 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use crate::alerts::{Alert, AlertState, Notifier};
+use crate::configfile::SnmpConfig;
+
+// variables every SNMPv2 trap starts with
+const SYS_UP_TIME: &str = "1.3.6.1.2.1.1.3.0";
+const SNMP_TRAP_OID: &str = "1.3.6.1.6.3.1.1.4.1.0";
+
+// BER encoding of a value with its tag and length
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    if value.len() < 0x80 {
+        encoded.push(value.len() as u8);
+    } else {
+        let length: Vec<u8> = value
+            .len()
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        encoded.push(0x80 | length.len() as u8);
+        encoded.extend(length);
+    }
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+// two's complement of the value in as few bytes as possible
+fn integer_content(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn integer(value: i64) -> Vec<u8> {
+    tlv(0x02, &integer_content(value))
+}
+
+fn octet_string(value: &str) -> Vec<u8> {
+    tlv(0x04, value.as_bytes())
+}
+
+fn object_identifier(oid: &str) -> Result<Vec<u8>, String> {
+    let arcs: Vec<u64> = match oid.split('.').map(|arc| arc.parse()).collect() {
+        Ok(arcs) => arcs,
+        Err(_) => return Err(format!("invalid object identifier {}", oid)),
+    };
+    if arcs.len() < 2 || arcs[0] > 2 {
+        return Err(format!("invalid object identifier {}", oid));
+    }
+    // the first two arcs share a byte, the rest are in base 128 with the high bit set on all but
+    //  the last byte
+    let mut content = Vec::new();
+    for arc in std::iter::once(arcs[0] * 40 + arcs[1]).chain(arcs[2..].iter().copied()) {
+        let mut bytes = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            bytes.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        bytes.reverse();
+        content.extend(bytes);
+    }
+    Ok(tlv(0x06, &content))
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(0x30, &items.concat())
+}
+
+/// Notifier sending alerts as SNMPv2c traps to an SNMP manager. The trap is the configured object
+/// identifier followed by .1 when the alert is triggered and .2 when it is cleared, with the tag,
+/// metric, value and threshold of the alert in variables .3.1 to .3.4 under it.
+pub struct SnmpNotifier {
+    target: String,
+    port: u16,
+    community: String,
+    oid: String,
+    socket: UdpSocket,
+    started: Instant,
+    request_id: i64,
+}
+
+impl SnmpNotifier {
+    fn trap(&self, alert: &Alert) -> Vec<u8> {
+        // the object identifiers were checked when the notifier was built
+        let oid = |suffix: &str| object_identifier(&format!("{}.{}", self.oid, suffix)).unwrap();
+        let trap = match alert.state {
+            AlertState::TRIGGERED => "1",
+            AlertState::CLEARED => "2",
+        };
+        let uptime = (self.started.elapsed().as_millis() / 10) as i64;
+        let variables = vec![
+            sequence(&[
+                object_identifier(SYS_UP_TIME).unwrap(),
+                tlv(0x43, &integer_content(uptime)),
+            ]),
+            sequence(&[object_identifier(SNMP_TRAP_OID).unwrap(), oid(trap)]),
+            sequence(&[oid("3.1"), octet_string(&alert.address)]),
+            sequence(&[oid("3.2"), octet_string(&alert.metric)]),
+            sequence(&[oid("3.3"), octet_string(&alert.value.to_string())]),
+            sequence(&[oid("3.4"), octet_string(&alert.threshold.to_string())]),
+        ];
+        let pdu = tlv(
+            0xa7,
+            &[
+                integer(self.request_id),
+                integer(0),
+                integer(0),
+                sequence(&variables),
+            ]
+            .concat(),
+        );
+        // version 1 is SNMPv2c
+        sequence(&[integer(1), octet_string(&self.community), pdu])
+    }
+
+    pub fn build(config: &SnmpConfig) -> Result<SnmpNotifier, Report> {
+        trace!("in build");
+        let oid = config.oid();
+        if let Err(reason) = object_identifier(&oid) {
+            return Err(eyre!("Invalid SNMP object identifier")
+                .with_section(move || oid.header("OID:"))
+                .with_section(move || reason.header("Reason:")));
+        }
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => socket,
+            Err(error) => {
+                return Err(eyre!("Unable to open socket for SNMP traps")
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        Ok(SnmpNotifier {
+            target: config.target(),
+            port: config.port(),
+            community: config.community(),
+            oid,
+            socket,
+            started: Instant::now(),
+            request_id: 0,
+        })
+    }
+}
+
+impl Notifier for SnmpNotifier {
+    fn name(&self) -> String {
+        format!("SNMP manager {}:{}", self.target, self.port)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report> {
+        trace!("in notify");
+        self.request_id = (self.request_id + 1) % i64::from(i32::MAX);
+        // traps are not acknowledged, so only failing to send them is noticed
+        match self
+            .socket
+            .send_to(&self.trap(alert), (self.target.as_str(), self.port))
+        {
+            Ok(_) => Ok(()),
+            Err(error) => Err(eyre!("Unable to send SNMP trap")
+                .with_section(move || error.to_string().header("Reason:"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{integer, object_identifier, octet_string, SnmpNotifier};
+    use crate::alerts::{Alerts, Notifier};
+    use crate::configfile::{AlertsConfig, SnmpConfig};
+    use crate::mock::beacon;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn snmp_notifier_sends_traps() {
+        assert_eq!(
+            object_identifier("1.3.6.1.2.1.1.3.0").unwrap(),
+            vec![0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x03, 0x00]
+        );
+        assert_eq!(
+            object_identifier("1.3.6.1.4.1.8072").unwrap(),
+            vec![0x06, 0x07, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xbf, 0x08]
+        );
+        assert!(object_identifier("1.3.six").is_err());
+        assert_eq!(integer(128), vec![0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(integer(-1), vec![0x02, 0x01, 0xff]);
+
+        let manager = UdpSocket::bind("127.0.0.1:0").unwrap();
+        manager
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config: SnmpConfig = serde_json::from_value(json!({
+            "target": "127.0.0.1",
+            "port": manager.local_addr().unwrap().port(),
+            "community": "ruuvi",
+        }))
+        .unwrap();
+        let mut notifier = SnmpNotifier::build(&config).unwrap();
+        let alerts_config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [{ "metric": "temperature", "operator": "above", "threshold": 20.0 }],
+        }))
+        .unwrap();
+        let alert = Alerts::build(&alerts_config, "test-gateway")
+            .unwrap()
+            .check(&beacon("AA:BB:CC:DD:EE:01", 1))
+            .remove(0);
+        notifier.notify(&alert).unwrap();
+
+        let mut trap = [0; 1024];
+        let length = manager.recv(&mut trap).unwrap();
+        let trap = &trap[..length];
+        assert_eq!(trap[0], 0x30);
+        assert!(contains(trap, &octet_string("ruuvi")));
+        assert!(contains(
+            trap,
+            &object_identifier("1.3.6.1.4.1.8072.9999.9999.1").unwrap()
+        ));
+        assert!(contains(trap, &octet_string("AA:BB:CC:DD:EE:01")));
+        assert!(contains(trap, &octet_string("21.5")));
+    }
+}
+
+// eof
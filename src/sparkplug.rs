@@ -0,0 +1,971 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel::{self, Receiver, Sender, TryRecvError};
+use rumqttc::{v5, Connection, Event, LastWill, MqttOptions, Packet, QoS, TlsConfiguration};
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::configfile::SinkConfig;
+use crate::naming;
+use crate::scanner::SharedBeacon;
+use crate::sinks::Sink;
+use crate::tls;
+
+// MQTT keep alive, the broker publishes the death certificate of the edge node when it has not
+//  heard from it in one and a half times this
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// time the death certificate of a node going away has to reach the broker
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+// metric of the edge node that Sparkplug host applications set to ask for births again
+const REBIRTH: &str = "Node Control/Rebirth";
+
+// Sparkplug B data types of the metrics
+const UINT64: u32 = 8;
+const DOUBLE: u32 = 10;
+const BOOLEAN: u32 = 11;
+
+// host and port of a mqtt:// or mqtts:// URL
+fn parse_url(url: &str) -> Result<(String, u16), String> {
+    let (address, default_port) = match (url.strip_prefix("mqtt://"), url.strip_prefix("mqtts://"))
+    {
+        (Some(address), _) => (address, 1883),
+        (_, Some(address)) => (address, 8883),
+        _ => return Err("only mqtt:// and mqtts:// URLs are supported".to_string()),
+    };
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => Ok((host.to_string(), port)),
+            Err(_) => Err(format!("invalid port {}", port)),
+        },
+        None => Ok((address.to_string(), default_port)),
+    }
+}
+
+// protocol buffers encoding of Sparkplug B payloads
+fn varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn field_varint(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    varint(buffer, field << 3);
+    varint(buffer, value);
+}
+
+fn field_bytes(buffer: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(buffer, field << 3 | 2);
+    varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value);
+}
+
+fn field_double(buffer: &mut Vec<u8>, field: u64, value: f64) {
+    varint(buffer, field << 3 | 1);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+enum MetricValue {
+    Long(u64),
+    Double(f64),
+    Boolean(bool),
+}
+
+// metric of a payload, data messages refer to metrics by their alias only
+fn metric(name: Option<&str>, alias: Option<u64>, timestamp: u64, value: MetricValue) -> Vec<u8> {
+    let mut metric = Vec::new();
+    if let Some(name) = name {
+        field_bytes(&mut metric, 1, name.as_bytes());
+    }
+    if let Some(alias) = alias {
+        field_varint(&mut metric, 2, alias);
+    }
+    field_varint(&mut metric, 3, timestamp);
+    match value {
+        MetricValue::Long(value) => {
+            field_varint(&mut metric, 4, UINT64 as u64);
+            field_varint(&mut metric, 11, value);
+        }
+        MetricValue::Double(value) => {
+            field_varint(&mut metric, 4, DOUBLE as u64);
+            field_double(&mut metric, 13, value);
+        }
+        MetricValue::Boolean(value) => {
+            field_varint(&mut metric, 4, BOOLEAN as u64);
+            field_varint(&mut metric, 14, value as u64);
+        }
+    }
+    metric
+}
+
+fn payload(timestamp: u64, metrics: &[Vec<u8>], seq: Option<u64>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    field_varint(&mut payload, 1, timestamp);
+    for metric in metrics {
+        field_bytes(&mut payload, 2, metric);
+    }
+    if let Some(seq) = seq {
+        field_varint(&mut payload, 3, seq);
+    }
+    payload
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+// varint at the start of the data, which it is advanced past
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// fields of a protocol buffers message, None if it is malformed
+fn fields(mut data: &[u8]) -> Option<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let field = match key & 0x07 {
+            0 => Field::Varint(read_varint(&mut data)?),
+            1 | 5 => {
+                let length = if key & 0x07 == 1 { 8 } else { 4 };
+                data = data.get(length..)?;
+                Field::Fixed
+            }
+            2 => {
+                let length = read_varint(&mut data)? as usize;
+                let bytes = data.get(..length)?;
+                data = &data[length..];
+                Field::Bytes(bytes)
+            }
+            _ => return None,
+        };
+        fields.push((key >> 3, field));
+    }
+    Some(fields)
+}
+
+// whether the payload of a command sets the rebirth metric of the edge node
+fn is_rebirth(payload: &[u8]) -> bool {
+    let metrics = match fields(payload) {
+        Some(fields) => fields,
+        None => return false,
+    };
+    metrics.iter().any(|(field, metric)| match (field, metric) {
+        (2, Field::Bytes(metric)) => {
+            let metric = fields(metric).unwrap_or_default();
+            let named = metric.iter().any(|(field, name)| match (field, name) {
+                (1, Field::Bytes(name)) => *name == REBIRTH.as_bytes(),
+                _ => false,
+            });
+            let set = metric
+                .iter()
+                .any(|(field, value)| matches!((field, value), (14, Field::Varint(1))));
+            named && set
+        }
+        _ => false,
+    })
+}
+
+// events of a connection relayed from the thread driving its event loop
+enum ConnectionEvent {
+    Connected,
+    // return code of a CONNACK refusing the connection
+    Refused(String),
+    // topic and payload of a message from the broker
+    Message(String, Vec<u8>),
+    Lost(String),
+}
+
+// drives the event loop of an MQTT 3.1.1 connection until it is lost or closed. rumqttc would
+//  reconnect on its own, but the sink reconnects itself to publish a new bdSeq and births
+fn drive(mut connection: Connection, events: Sender<ConnectionEvent>) {
+    trace!("in drive");
+    for event in connection.iter() {
+        let event = match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => ConnectionEvent::Connected,
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                ConnectionEvent::Message(publish.topic, publish.payload.to_vec())
+            }
+            Ok(_) => continue,
+            Err(rumqttc::ConnectionError::ConnectionRefused(code)) => {
+                events
+                    .send(ConnectionEvent::Refused(format!("{:?}", code)))
+                    .ok();
+                break;
+            }
+            Err(error) => {
+                events.send(ConnectionEvent::Lost(error.to_string())).ok();
+                break;
+            }
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+// same as drive for an MQTT 5 connection
+fn drive_v5(mut connection: v5::Connection, events: Sender<ConnectionEvent>) {
+    trace!("in drive_v5");
+    for event in connection.iter() {
+        let event = match event {
+            Ok(v5::Event::Incoming(packet)) => match *packet {
+                v5::mqttbytes::v5::Packet::ConnAck(_) => ConnectionEvent::Connected,
+                v5::mqttbytes::v5::Packet::Publish(publish, _) => ConnectionEvent::Message(
+                    String::from_utf8_lossy(&publish.topic).to_string(),
+                    publish.payload.to_vec(),
+                ),
+                _ => continue,
+            },
+            Ok(_) => continue,
+            Err(v5::ConnectionError::ConnectionRefused(code)) => {
+                events
+                    .send(ConnectionEvent::Refused(format!("{:?}", code)))
+                    .ok();
+                break;
+            }
+            Err(error) => {
+                events.send(ConnectionEvent::Lost(error.to_string())).ok();
+                break;
+            }
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+// MQTT 3.1.1 or MQTT 5 client of the edge node
+enum Client {
+    V4(rumqttc::Client),
+    V5(v5::Client),
+}
+
+impl Client {
+    // Sparkplug births and data are published with QoS 0
+    fn publish(&mut self, topic: &str, payload: Vec<u8>) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client
+                .publish(topic, QoS::AtMostOnce, false, payload)
+                .map_err(|error| error.to_string()),
+            Client::V5(client) => client
+                .publish(topic, v5::mqttbytes::QoS::AtMostOnce, false, payload)
+                .map_err(|error| error.to_string()),
+        }
+    }
+
+    // commands are received with QoS 1
+    fn subscribe(&mut self, topic: &str) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client
+                .subscribe(topic, QoS::AtLeastOnce)
+                .map_err(|error| error.to_string()),
+            Client::V5(client) => client
+                .subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce)
+                .map_err(|error| error.to_string()),
+        }
+    }
+
+    fn disconnect(&mut self) -> Result<(), String> {
+        match self {
+            Client::V4(client) => client.disconnect().map_err(|error| error.to_string()),
+            Client::V5(client) => client.disconnect().map_err(|error| error.to_string()),
+        }
+    }
+}
+
+// connection of the edge node to the broker. rumqttc keeps it alive with pings, and the events
+//  of its event loop tell when it is lost and when host applications ask for rebirth
+struct Session {
+    client: Client,
+    events: Receiver<ConnectionEvent>,
+    alive: bool,
+    rebirth: bool,
+    // birth and death sequence of the connection, in the birth and the will of the node
+    bd_seq: u64,
+    // sequence number of the next message, NBIRTH starts from zero
+    seq: u64,
+}
+
+impl Session {
+    // handles the events of the connection since the last call
+    fn poll(&mut self, command_topic: &str) {
+        loop {
+            match self.events.try_recv() {
+                Ok(ConnectionEvent::Message(topic, payload)) => {
+                    if topic == command_topic && is_rebirth(&payload) {
+                        info!("Sparkplug host application asked for rebirth");
+                        self.rebirth = true;
+                    }
+                }
+                Ok(ConnectionEvent::Lost(reason)) => {
+                    debug!("Sparkplug connection lost: {}", reason);
+                    self.alive = false;
+                }
+                Ok(ConnectionEvent::Connected) | Ok(ConnectionEvent::Refused(_)) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.alive = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+}
+
+/// Sink publishing beacons as a Sparkplug B edge node to an MQTT broker, each tag as a device
+/// of the node with the numeric fields of its data as metrics. Births are published on each
+/// connection and when a host application asks for them, data messages refer to metrics by
+/// their alias.
+pub struct SparkplugSink {
+    url: String,
+    host: String,
+    port: u16,
+    tls_config: Option<TlsConfiguration>,
+    group_id: String,
+    edge_node_id: String,
+    username: Option<String>,
+    password: Option<String>,
+    timeout: Duration,
+    mqtt5: bool,
+    // birth and death sequence of the next connection
+    bd_seq: u64,
+    // latest metrics of the tags, which are born again on rebirth
+    devices: BTreeMap<String, (u64, BTreeMap<String, f64>)>,
+    aliases: HashMap<String, u64>,
+    session: Option<Session>,
+}
+
+impl SparkplugSink {
+    fn topic(&self, message_type: &str, device: Option<&str>) -> String {
+        match device {
+            Some(device) => format!(
+                "spBv1.0/{}/{}/{}/{}",
+                self.group_id, message_type, self.edge_node_id, device
+            ),
+            None => format!(
+                "spBv1.0/{}/{}/{}",
+                self.group_id, message_type, self.edge_node_id
+            ),
+        }
+    }
+
+    fn death_certificate(&self, bd_seq: u64) -> Vec<u8> {
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        payload(
+            now,
+            &[metric(Some("bdSeq"), None, now, MetricValue::Long(bd_seq))],
+            None,
+        )
+    }
+
+    fn connect(&mut self) -> Result<Session, String> {
+        trace!("in connect");
+        let bd_seq = self.bd_seq;
+        self.bd_seq = (self.bd_seq + 1) % 256;
+        let client_id = format!("ruuvi2iotcore-{}", self.edge_node_id);
+        let transport = match &self.tls_config {
+            Some(tls_config) => rumqttc::Transport::Tls(tls_config.clone()),
+            None => rumqttc::Transport::Tcp,
+        };
+        // clean session with the death certificate of the node as will, published with QoS 1
+        let (will_topic, will) = (self.topic("NDEATH", None), self.death_certificate(bd_seq));
+        let (events_s, events_r) = channel::unbounded();
+        let mut client = if self.mqtt5 {
+            let mut options = v5::MqttOptions::new(client_id, self.host.clone(), self.port);
+            options
+                .set_transport(transport)
+                .set_keep_alive(KEEP_ALIVE)
+                .set_clean_session(true)
+                .set_connection_timeout(self.timeout.as_secs())
+                .set_last_will(v5::mqttbytes::LastWill::new(
+                    will_topic,
+                    will,
+                    v5::mqttbytes::QoS::AtLeastOnce,
+                    false,
+                ));
+            if let Some(username) = &self.username {
+                options
+                    .set_credentials(username.clone(), self.password.clone().unwrap_or_default());
+            }
+            let (client, connection) = v5::Client::new(options, 10);
+            thread::spawn(move || drive_v5(connection, events_s));
+            Client::V5(client)
+        } else {
+            let mut options = MqttOptions::new(client_id, self.host.clone(), self.port);
+            options
+                .set_transport(transport)
+                .set_keep_alive(KEEP_ALIVE)
+                .set_clean_session(true)
+                .set_connection_timeout(self.timeout.as_secs())
+                .set_last_will(LastWill::new(will_topic, will, QoS::AtLeastOnce, false));
+            if let Some(username) = &self.username {
+                options
+                    .set_credentials(username.clone(), self.password.clone().unwrap_or_default());
+            }
+            let (client, connection) = rumqttc::Client::new(options, 10);
+            thread::spawn(move || drive(connection, events_s));
+            Client::V4(client)
+        };
+
+        // dropping the client on errors stops the event loop thread
+        match events_r.recv_deadline(Instant::now() + self.timeout) {
+            Ok(ConnectionEvent::Connected) => {}
+            Ok(ConnectionEvent::Refused(code)) => {
+                return Err(format!("the broker refused the connection with {}", code))
+            }
+            Ok(ConnectionEvent::Lost(reason)) => return Err(reason),
+            Ok(ConnectionEvent::Message(..)) | Err(_) => {
+                return Err("timed out waiting for the broker".to_string())
+            }
+        }
+        client.subscribe(&self.topic("NCMD", None))?;
+        debug!(
+            "connected to MQTT broker {} as Sparkplug edge node",
+            self.url
+        );
+        Ok(Session {
+            client,
+            events: events_r,
+            alive: true,
+            // births are published first on every connection
+            rebirth: true,
+            bd_seq,
+            seq: 0,
+        })
+    }
+
+    fn device_birth(&self, session: &mut Session, device: &str) -> Result<(), String> {
+        let (timestamp, values) = &self.devices[device];
+        let metrics: Vec<Vec<u8>> = values
+            .iter()
+            .map(|(name, value)| {
+                metric(
+                    Some(name),
+                    self.aliases.get(&format!("{}/{}", device, name)).copied(),
+                    *timestamp,
+                    MetricValue::Double(*value),
+                )
+            })
+            .collect();
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        let seq = session.next_seq();
+        session.client.publish(
+            &self.topic("DBIRTH", Some(device)),
+            payload(now, &metrics, Some(seq)),
+        )
+    }
+
+    // publishes the birth certificates of the node and of the tags seen so far
+    fn birth(&self, session: &mut Session) -> Result<(), String> {
+        trace!("in birth");
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        session.seq = 0;
+        let seq = session.next_seq();
+        let node = payload(
+            now,
+            &[
+                metric(Some("bdSeq"), None, now, MetricValue::Long(session.bd_seq)),
+                metric(Some(REBIRTH), None, now, MetricValue::Boolean(false)),
+            ],
+            Some(seq),
+        );
+        session.client.publish(&self.topic("NBIRTH", None), node)?;
+        for device in self.devices.keys() {
+            self.device_birth(session, device)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, session: &mut Session, beacons: &[SharedBeacon]) -> Result<(), String> {
+        if session.rebirth {
+            session.rebirth = false;
+            self.birth(session)?;
+        }
+        for beacon in beacons {
+            let device = beacon.address.replace(':', "");
+            // names of nested fields are joined with slashes, which Sparkplug host applications
+            //  show as folders
+            let values = match &beacon.data {
+                Some(data) => naming::numeric_fields(data, "/"),
+                None => continue,
+            };
+            if values.is_empty() {
+                continue;
+            }
+            // a tag is born when it is first seen and again when its metrics change, e.g. with
+            //  another data format
+            let born = match self.devices.get(&device) {
+                Some((_, known)) => known.keys().eq(values.keys()),
+                None => false,
+            };
+            for name in values.keys() {
+                let alias = self.aliases.len() as u64 + 1;
+                self.aliases
+                    .entry(format!("{}/{}", device, name))
+                    .or_insert(alias);
+            }
+            let timestamp = beacon.timestamp.timestamp_millis() as u64;
+            if !born {
+                self.devices.insert(device.clone(), (timestamp, values));
+                self.device_birth(session, &device)?;
+                continue;
+            }
+            let metrics: Vec<Vec<u8>> = values
+                .iter()
+                .map(|(name, value)| {
+                    metric(
+                        None,
+                        self.aliases.get(&format!("{}/{}", device, name)).copied(),
+                        timestamp,
+                        MetricValue::Double(*value),
+                    )
+                })
+                .collect();
+            self.devices.insert(device.clone(), (timestamp, values));
+            let now = chrono::Utc::now().timestamp_millis() as u64;
+            let seq = session.next_seq();
+            session.client.publish(
+                &self.topic("DDATA", Some(&device)),
+                payload(now, &metrics, Some(seq)),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, beacons: &[SharedBeacon]) -> Result<(), String> {
+        trace!("in publish");
+        let command_topic = self.topic("NCMD", None);
+        if let Some(session) = self.session.as_mut() {
+            session.poll(&command_topic);
+        }
+        // a lost session is dropped, which stops its event loop thread
+        let mut session = match self.session.take() {
+            Some(session) if session.alive => session,
+            _ => self.connect()?,
+        };
+        let result = self.send(&mut session, beacons);
+        // reconnected with new births on the next delivery if publishing failed
+        if result.is_ok() {
+            self.session = Some(session);
+        }
+        result
+    }
+
+    pub fn build(config: &SinkConfig, device_id: &str) -> Result<SparkplugSink, Report> {
+        trace!("in build");
+        let url = match config.url() {
+            Some(url) => url,
+            None => return Err(eyre!("No URL given for Sparkplug sink")),
+        };
+        let (host, port) = match parse_url(&url) {
+            Ok(address) => address,
+            Err(reason) => {
+                return Err(eyre!("Invalid MQTT URL")
+                    .with_section(move || url.header("URL:"))
+                    .with_section(move || reason.header("Reason:")))
+            }
+        };
+        // client certificates are not used, the node authenticates with username and password
+        let tls_config = if tls::is_secure(&url) {
+            let alpn_protocols = config.tls.alpn_protocols();
+            Some(TlsConfiguration::Simple {
+                ca: tls::pem_bundle(&config.tls)?,
+                alpn: if alpn_protocols.is_empty() {
+                    None
+                } else {
+                    Some(
+                        alpn_protocols
+                            .into_iter()
+                            .map(|alpn| alpn.into_bytes())
+                            .collect(),
+                    )
+                },
+                client_auth: None,
+            })
+        } else {
+            None
+        };
+        // rumqttc does not give publishes MQTT 5 properties
+        if config.message_expiry().is_some() {
+            warn!("MQTT 5 message expiry is not supported by the Sparkplug sink and is ignored.");
+        }
+        Ok(SparkplugSink {
+            url,
+            host,
+            port,
+            tls_config,
+            group_id: config.group_id(),
+            edge_node_id: config
+                .edge_node_id()
+                .unwrap_or_else(|| device_id.to_string()),
+            username: config.username(),
+            password: config.password(),
+            timeout: Duration::from_secs(config.timeout()),
+            mqtt5: config.mqtt_version() == 5,
+            bd_seq: 0,
+            devices: BTreeMap::new(),
+            aliases: HashMap::new(),
+            session: None,
+        })
+    }
+}
+
+impl Drop for SparkplugSink {
+    // an edge node disconnecting on purpose publishes its death certificate itself, the broker
+    //  publishes the will only when the connection is lost
+    fn drop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            session.poll(&self.topic("NCMD", None));
+            if session.alive {
+                let death = self.death_certificate(session.bd_seq);
+                let _ = session
+                    .client
+                    .publish(&self.topic("NDEATH", None), death)
+                    .and_then(|_| session.client.disconnect());
+                // the event loop thread stops once the broker has closed the connection
+                let deadline = Instant::now() + CLOSE_TIMEOUT;
+                while session.events.recv_deadline(deadline).is_ok() {}
+            }
+        }
+    }
+}
+
+impl Sink for SparkplugSink {
+    fn name(&self) -> String {
+        format!(
+            "Sparkplug edge node {}/{}",
+            self.group_id, self.edge_node_id
+        )
+    }
+
+    fn deliver(&mut self, beacons: &[SharedBeacon]) -> Result<(), Report> {
+        trace!("in deliver");
+        match self.publish(beacons) {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                let url = self.url.clone();
+                Err(eyre!("Unable to deliver beacons to Sparkplug")
+                    .with_section(move || url.header("URL:"))
+                    .with_section(move || reason.header("Reason:")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{field_bytes, field_varint, fields, parse_url, Field, SparkplugSink};
+    use crate::configfile::SinkConfig;
+    use crate::mock::{serve_lines, shared_beacon};
+    use crate::sinks::Sink;
+    use bytes::BytesMut;
+    use crossbeam::channel;
+    use rumqttc::mqttbytes::v4;
+    use rumqttc::v5::mqttbytes::{self as v5bytes, v5::Packet as V5Packet};
+    use rumqttc::QoS;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    // topics and payloads published to the broker
+    type Published = channel::Receiver<(String, Vec<u8>)>;
+
+    const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+    // packets of the stream as the decoder reads them, until the stream is closed
+    fn packets<P>(
+        mut stream: TcpStream,
+        decode: fn(&mut BytesMut) -> Option<P>,
+    ) -> impl Iterator<Item = P> {
+        let mut buffer = BytesMut::new();
+        std::iter::from_fn(move || loop {
+            if let Some(packet) = decode(&mut buffer) {
+                return Some(packet);
+            }
+            let mut chunk = [0; 4096];
+            match stream.read(&mut chunk) {
+                Ok(0) | Err(_) => return None,
+                Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            }
+        })
+    }
+
+    // MQTT 3.1.1 broker accepting the edge node and relaying the will of the node and the
+    //  topics and payloads published by it. the connection is handed over for sending commands
+    fn serve() -> (String, Published, channel::Receiver<TcpStream>) {
+        let (sender, receiver) = channel::unbounded();
+        let (connection_sender, connection) = channel::unbounded();
+        let (address, _) = serve_lines(1, move |reader| {
+            let mut stream = reader.get_ref().try_clone().unwrap();
+            connection_sender.send(stream.try_clone().unwrap()).unwrap();
+            let reader = stream.try_clone().unwrap();
+            for packet in packets(reader, |buffer| v4::read(buffer, MAX_PACKET_SIZE).ok()) {
+                let mut answer = BytesMut::new();
+                match packet {
+                    v4::Packet::Connect(connect) => {
+                        let will = connect.last_will.unwrap();
+                        sender.send((will.topic, will.message.to_vec())).unwrap();
+                        v4::ConnAck::new(v4::ConnectReturnCode::Success, false)
+                            .write(&mut answer)
+                            .unwrap();
+                    }
+                    v4::Packet::Subscribe(subscribe) => {
+                        sender.send(("SUBSCRIBE".to_string(), Vec::new())).unwrap();
+                        let granted = v4::SubscribeReasonCode::Success(QoS::AtLeastOnce);
+                        v4::SubAck::new(subscribe.pkid, vec![granted])
+                            .write(&mut answer)
+                            .unwrap();
+                    }
+                    v4::Packet::Publish(publish) => {
+                        sender
+                            .send((publish.topic, publish.payload.to_vec()))
+                            .unwrap();
+                    }
+                    v4::Packet::PingReq => {
+                        v4::PingResp.write(&mut answer).unwrap();
+                    }
+                    v4::Packet::Disconnect => {
+                        sender.send(("DISCONNECT".to_string(), Vec::new())).unwrap();
+                        break;
+                    }
+                    _ => {}
+                }
+                stream.write_all(&answer).unwrap();
+            }
+        });
+        (format!("mqtt://{}", address), receiver, connection)
+    }
+
+    // sequence number of the payload and its metrics as name or alias and value
+    fn decode(payload: &[u8]) -> (Option<u64>, Vec<(String, u64)>) {
+        let mut seq = None;
+        let mut metrics = Vec::new();
+        for (field, value) in fields(payload).unwrap() {
+            match (field, value) {
+                (2, Field::Bytes(metric)) => {
+                    let mut name = String::new();
+                    let mut value = 0;
+                    for (field, metric_field) in fields(metric).unwrap() {
+                        match (field, metric_field) {
+                            (1, Field::Bytes(bytes)) => {
+                                name = String::from_utf8(bytes.to_vec()).unwrap()
+                            }
+                            (2, Field::Varint(alias)) if name.is_empty() => {
+                                name = format!("alias {}", alias)
+                            }
+                            (11, Field::Varint(long)) | (14, Field::Varint(long)) => value = long,
+                            _ => {}
+                        }
+                    }
+                    metrics.push((name, value));
+                }
+                (3, Field::Varint(value)) => seq = Some(value),
+                _ => {}
+            }
+        }
+        (seq, metrics)
+    }
+
+    #[test]
+    fn tags_are_published_as_sparkplug_devices() {
+        assert_eq!(
+            parse_url("mqtt://localhost").unwrap(),
+            ("localhost".to_string(), 1883)
+        );
+        assert_eq!(
+            parse_url("mqtts://broker.example.com/").unwrap(),
+            ("broker.example.com".to_string(), 8883)
+        );
+        assert!(parse_url("tcp://localhost:1883").is_err());
+
+        let (url, received, connection) = serve();
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "sparkplug",
+            "url": url,
+            "group_id": "plant",
+        }))
+        .unwrap();
+        let mut sink = SparkplugSink::build(&config, "test-gateway").unwrap();
+        assert_eq!(sink.name(), "Sparkplug edge node plant/test-gateway");
+        sink.deliver(&[
+            shared_beacon("AA:BB:CC:DD:EE:01", 1),
+            shared_beacon("AA:BB:CC:DD:EE:02", 2),
+        ])
+        .unwrap();
+
+        let (topic, will) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/NDEATH/test-gateway");
+        assert_eq!(decode(&will), (None, vec![("bdSeq".to_string(), 0)]));
+        assert_eq!(received.recv().unwrap().0, "SUBSCRIBE");
+        let (topic, birth) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/NBIRTH/test-gateway");
+        assert_eq!(
+            decode(&birth),
+            (
+                Some(0),
+                vec![
+                    ("bdSeq".to_string(), 0),
+                    ("Node Control/Rebirth".to_string(), 0)
+                ]
+            )
+        );
+        let (topic, birth) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/DBIRTH/test-gateway/AABBCCDDEE01");
+        let (seq, metrics) = decode(&birth);
+        assert_eq!(seq, Some(1));
+        let names: Vec<&str> = metrics.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "humidity",
+                "measurement_sequence_number",
+                "temperature",
+                "tx_power"
+            ]
+        );
+        assert_eq!(
+            received.recv().unwrap().0,
+            "spBv1.0/plant/DBIRTH/test-gateway/AABBCCDDEE02"
+        );
+
+        // data of a tag already born refers to its metrics by alias
+        sink.deliver(&[shared_beacon("AA:BB:CC:DD:EE:01", 3)])
+            .unwrap();
+        let (topic, data) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/DDATA/test-gateway/AABBCCDDEE01");
+        let (seq, metrics) = decode(&data);
+        assert_eq!(seq, Some(3));
+        assert_eq!(metrics[0].0, "alias 1");
+
+        // a host application asks for rebirth, which births the node and all tags again
+        let mut metric = Vec::new();
+        field_bytes(&mut metric, 1, b"Node Control/Rebirth");
+        field_varint(&mut metric, 4, 11);
+        field_varint(&mut metric, 14, 1);
+        let mut command = Vec::new();
+        field_bytes(&mut command, 2, &metric);
+        let mut packet = BytesMut::new();
+        v4::Publish::new("spBv1.0/plant/NCMD/test-gateway", QoS::AtMostOnce, command)
+            .write(&mut packet)
+            .unwrap();
+        connection.recv().unwrap().write_all(&packet).unwrap();
+        let command_topic = sink.topic("NCMD", None);
+        let session = sink.session.as_mut().unwrap();
+        while !session.rebirth {
+            thread::sleep(Duration::from_millis(10));
+            session.poll(&command_topic);
+        }
+        sink.deliver(&[shared_beacon("AA:BB:CC:DD:EE:02", 4)])
+            .unwrap();
+        let (topic, birth) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/NBIRTH/test-gateway");
+        assert_eq!(decode(&birth).0, Some(0));
+        assert!(received.recv().unwrap().0.contains("/DBIRTH/"));
+        assert!(received.recv().unwrap().0.contains("/DBIRTH/"));
+        let (topic, data) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/DDATA/test-gateway/AABBCCDDEE02");
+        assert_eq!(decode(&data).0, Some(3));
+
+        // the node publishes its death certificate when it goes away
+        drop(sink);
+        let (topic, death) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/plant/NDEATH/test-gateway");
+        assert_eq!(decode(&death).1, vec![("bdSeq".to_string(), 0)]);
+        assert_eq!(received.recv().unwrap().0, "DISCONNECT");
+    }
+
+    #[test]
+    fn edge_node_connects_with_mqtt5() {
+        let (sender, received) = channel::unbounded();
+        let (address, _) = serve_lines(1, move |reader| {
+            let mut stream = reader.get_ref().try_clone().unwrap();
+            let reader = stream.try_clone().unwrap();
+            for packet in packets(reader, |buffer| {
+                V5Packet::read(buffer, MAX_PACKET_SIZE).ok()
+            }) {
+                let answer = match packet {
+                    V5Packet::Connect(_, _, will, _, _) => {
+                        let will = will.unwrap();
+                        let topic = String::from_utf8(will.topic.to_vec()).unwrap();
+                        sender.send((topic, will.message.to_vec())).unwrap();
+                        V5Packet::ConnAck(v5bytes::ConnAck {
+                            session_present: false,
+                            code: v5bytes::ConnectReturnCode::Success,
+                        })
+                    }
+                    V5Packet::Subscribe(subscribe, _) => V5Packet::SubAck(
+                        v5bytes::SubAck {
+                            pkid: subscribe.pkid,
+                            return_codes: vec![v5bytes::SubscribeReasonCode::QoS1],
+                        },
+                        None,
+                    ),
+                    // the connection is closed after the last message the test waits for
+                    V5Packet::Publish(publish, _) => {
+                        let topic = String::from_utf8(publish.topic.to_vec()).unwrap();
+                        let last = topic.contains("/DDATA/");
+                        sender.send((topic, publish.payload.to_vec())).unwrap();
+                        if last {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let mut buffer = BytesMut::new();
+                answer.write(&mut buffer).unwrap();
+                stream.write_all(&buffer).unwrap();
+            }
+        });
+
+        let config: SinkConfig = serde_json::from_value(json!({
+            "kind": "sparkplug",
+            "url": format!("mqtt://{}", address),
+            "mqtt_version": 5,
+        }))
+        .unwrap();
+        let mut sink = SparkplugSink::build(&config, "test-gateway").unwrap();
+        sink.deliver(&[shared_beacon("AA:BB:CC:DD:EE:01", 1)])
+            .unwrap();
+        sink.deliver(&[shared_beacon("AA:BB:CC:DD:EE:01", 2)])
+            .unwrap();
+
+        let (topic, will) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/ruuvi/NDEATH/test-gateway");
+        assert_eq!(decode(&will), (None, vec![("bdSeq".to_string(), 0)]));
+        assert_eq!(
+            received.recv().unwrap().0,
+            "spBv1.0/ruuvi/NBIRTH/test-gateway"
+        );
+        assert_eq!(
+            received.recv().unwrap().0,
+            "spBv1.0/ruuvi/DBIRTH/test-gateway/AABBCCDDEE01"
+        );
+        let (topic, data) = received.recv().unwrap();
+        assert_eq!(topic, "spBv1.0/ruuvi/DDATA/test-gateway/AABBCCDDEE01");
+        assert_eq!(decode(&data).0, Some(2));
+    }
+}
+
+// eof
@@ -0,0 +1,141 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use crate::metrics::Metrics;
+
+// totals of the counters of the gateway, of which the statistics report the growth during an
+//  interval
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    beacons_received: u64,
+    beacons_published: u64,
+    publish_errors: u64,
+    decode_errors: u64,
+    mqtt_connects: u64,
+}
+
+impl Totals {
+    fn read(metrics: &Metrics) -> Totals {
+        Totals {
+            beacons_received: metrics.beacons_received.load(Ordering::Relaxed),
+            beacons_published: metrics.beacons_published.load(Ordering::Relaxed),
+            publish_errors: metrics.publish_errors.load(Ordering::Relaxed),
+            decode_errors: metrics
+                .adapters
+                .lock()
+                .unwrap()
+                .values()
+                .map(|adapter| adapter.decode_errors)
+                .sum(),
+            mqtt_connects: metrics.mqtt_connects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of the gateway over an interval, published to the stats subfolder of the events
+/// topic of the gateway.
+#[derive(Debug, Serialize, Clone)]
+pub struct GatewayStats {
+    pub interval_start: chrono::DateTime<chrono::Utc>,
+    pub interval_end: chrono::DateTime<chrono::Utc>,
+    pub beacons_received: u64,
+    pub beacons_per_minute: f64,
+    /// Beacons received from each tag, by MAC address
+    pub tags: BTreeMap<String, u64>,
+    pub decode_errors: u64,
+    pub beacons_published: u64,
+    pub publish_errors: u64,
+    pub mqtt_connects: u64,
+}
+
+impl GatewayStats {
+    pub fn payload(&self) -> serde_json::Value {
+        json!({ "stats": self })
+    }
+}
+
+/// Counts beacons of each tag and the growth of the counters of the gateway between
+/// statistics.
+pub struct StatsCollector {
+    started: chrono::DateTime<chrono::Utc>,
+    baseline: Totals,
+    tags: BTreeMap<String, u64>,
+}
+
+impl StatsCollector {
+    pub fn new(metrics: &Metrics) -> StatsCollector {
+        StatsCollector {
+            started: chrono::Utc::now(),
+            baseline: Totals::read(metrics),
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a beacon received from the tag
+    pub fn received(&mut self, address: &str) {
+        *self.tags.entry(address.to_uppercase()).or_insert(0) += 1;
+    }
+
+    /// Statistics since the previous ones, starting the next interval
+    pub fn take(&mut self, metrics: &Metrics) -> GatewayStats {
+        trace!("in take");
+        let now = chrono::Utc::now();
+        let totals = Totals::read(metrics);
+        let baseline = std::mem::replace(&mut self.baseline, totals);
+        let minutes = (now - self.started).num_milliseconds() as f64 / 60_000.0;
+        let beacons_received = totals.beacons_received - baseline.beacons_received;
+        GatewayStats {
+            interval_start: std::mem::replace(&mut self.started, now),
+            interval_end: now,
+            beacons_received,
+            beacons_per_minute: if minutes > 0.0 {
+                (beacons_received as f64 / minutes * 10.0).round() / 10.0
+            } else {
+                0.0
+            },
+            tags: std::mem::take(&mut self.tags),
+            decode_errors: totals.decode_errors - baseline.decode_errors,
+            beacons_published: totals.beacons_published - baseline.beacons_published,
+            publish_errors: totals.publish_errors - baseline.publish_errors,
+            mqtt_connects: totals.mqtt_connects - baseline.mqtt_connects,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatsCollector;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn stats_count_interval() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.beacons_received);
+        let mut collector = StatsCollector::new(&metrics);
+
+        for _ in 0..3 {
+            Metrics::inc(&metrics.beacons_received);
+        }
+        Metrics::inc(&metrics.publish_errors);
+        metrics.update_adapter("hci0", |adapter| adapter.decode_errors += 2);
+        collector.received("aa:bb:cc:dd:ee:01");
+        collector.received("AA:BB:CC:DD:EE:01");
+        collector.received("AA:BB:CC:DD:EE:02");
+        let stats = collector.take(&metrics);
+        assert_eq!(stats.beacons_received, 3);
+        assert_eq!(stats.publish_errors, 1);
+        assert_eq!(stats.decode_errors, 2);
+        assert_eq!(stats.tags["AA:BB:CC:DD:EE:01"], 2);
+        assert_eq!(stats.tags["AA:BB:CC:DD:EE:02"], 1);
+        assert_eq!(stats.payload()["stats"]["mqtt_connects"], 0);
+
+        // the next interval starts from zero
+        let stats = collector.take(&metrics);
+        assert_eq!(stats.beacons_received, 0);
+        assert_eq!(stats.decode_errors, 0);
+        assert!(stats.tags.is_empty());
+    }
+}
+
+// eof
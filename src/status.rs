@@ -0,0 +1,65 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::fatal::ErrorCode;
+use crate::scanner::SharedBeacon;
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct TagStatus {
+    pub last_beacon: Option<SharedBeacon>,
+    pub queue_depth: usize,
+    pub last_publish: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_publish_ok: Option<bool>,
+    // IoT Core reported the tag is not bound to the gateway
+    pub unbound: bool,
+}
+
+// parameters negotiated in the TLS handshake with the broker
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct TlsHandshake {
+    pub version: String,
+    pub cipher_suite: String,
+    pub alpn: Option<String>,
+    pub handshake_ms: u64,
+    pub peer_certificates: usize,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct ConnectionStatus {
+    pub endpoint: Option<String>,
+    pub connected_since: Option<chrono::DateTime<chrono::Utc>>,
+    pub connect_duration_ms: Option<u64>,
+    pub connack: Option<String>,
+    pub session_present: Option<bool>,
+    // probed with a separate handshake, as the MQTT clients do not expose theirs
+    pub tls: Option<TlsHandshake>,
+    pub connects: u64,
+    pub failures: u64,
+    pub last_failure: Option<String>,
+    pub last_failure_code: Option<ErrorCode>,
+    pub last_failure_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub disconnected_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub disconnect_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct GatewayStatus {
+    pub collecting: bool,
+    pub connected: bool,
+    // redundant gateway on standby does not publish beacons
+    pub standby: bool,
+    // drift of the local clock from the NTP server at the latest measurement
+    pub clock_drift_ms: Option<i64>,
+    // update was installed and the gateway exits to be restarted by the service manager
+    pub updated: bool,
+    // scanning is disabled, e.g. for missing capabilities, and why
+    pub degraded: Option<String>,
+    pub connection: ConnectionStatus,
+    pub tags: BTreeMap<String, TagStatus>,
+}
+
+// status is written by the IoT Core client thread and read by local interfaces
+pub type SharedStatus = Arc<Mutex<GatewayStatus>>;
+
+// eof
@@ -0,0 +1,162 @@
+use btleplug::api::BDAddr;
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::configfile::UdpConfig;
+use crate::replay;
+
+/// Advertisement forwarded by a collector, e.g. an ESP32 board, over UDP.
+#[derive(Debug, Clone)]
+pub struct UdpAdvertisement {
+    /// Name the collector reported or its IP address
+    pub source: String,
+    pub address: BDAddr,
+    /// Manufacturer data starting with the manufacturer id
+    pub data: Vec<u8>,
+}
+
+// collectors send either advertising data structures or bare manufacturer data
+fn manufacturer_data(data: Vec<u8>) -> Vec<u8> {
+    replay::manufacturer_data(&data).unwrap_or(data)
+}
+
+// packets are either JSON ({"source": "..", "address": "AA:BB:CC:DD:EE:FF", "data": "<hex>"}) or
+//  binary with the 6 byte address in display order followed by the data
+pub fn parse_packet(packet: &[u8], sender: &SocketAddr) -> Option<UdpAdvertisement> {
+    trace!("in parse_packet");
+    if packet.first() == Some(&b'{') {
+        let message: serde_json::Value = serde_json::from_slice(packet).ok()?;
+        return Some(UdpAdvertisement {
+            source: message["source"]
+                .as_str()
+                .map(|source| source.to_string())
+                .unwrap_or_else(|| sender.ip().to_string()),
+            address: BDAddr::from_str(message["address"].as_str()?).ok()?,
+            data: manufacturer_data(replay::from_hex(message["data"].as_str()?)?),
+        });
+    }
+    if packet.len() < 9 {
+        return None;
+    }
+    let address: Vec<String> = packet[..6]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    Some(UdpAdvertisement {
+        source: sender.ip().to_string(),
+        address: BDAddr::from_str(&address.join(":")).ok()?,
+        data: manufacturer_data(packet[6..].to_vec()),
+    })
+}
+
+// receives advertisements forwarded over UDP and passes them to the scanner, which decodes them
+//  along with locally scanned ones
+pub struct UdpListener {
+    config: UdpConfig,
+    sender: channel::Sender<UdpAdvertisement>,
+    running: Arc<AtomicBool>,
+}
+
+impl UdpListener {
+    pub fn start_listener(&self) -> Result<(), Report> {
+        trace!("in start_listener");
+        let port = match self.config.port() {
+            Some(port) => port,
+            None => {
+                debug!("UDP listener not enabled.");
+                return Ok(());
+            }
+        };
+        let address = format!("{}:{}", self.config.address(), port);
+        let socket = match UdpSocket::bind(&address) {
+            Ok(socket) => socket,
+            Err(error) => {
+                return Err(eyre!("Unable to bind UDP listener")
+                    .with_section(move || address.header("Address:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        // wake up regularly to notice shutdown
+        socket
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        info!("Receiving advertisements over UDP at {}", address);
+
+        let mut buffer = [0; 1024];
+        while self.running.load(Ordering::Relaxed) {
+            let (length, sender) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(error) => {
+                    error!("Unable to receive from UDP socket: {}", error);
+                    continue;
+                }
+            };
+            match parse_packet(&buffer[..length], &sender) {
+                Some(advertisement) => {
+                    debug!("UDP advertisement: {:?}", advertisement);
+                    self.sender.send(advertisement).ok();
+                }
+                None => warn!("Invalid UDP packet of {} bytes from {}", length, sender),
+            }
+        }
+        info!("Shutting down UDP listener.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &UdpConfig,
+        sender: &channel::Sender<UdpAdvertisement>,
+        running: &Arc<AtomicBool>,
+    ) -> UdpListener {
+        trace!("in build");
+        UdpListener {
+            config: config.clone(),
+            sender: sender.clone(),
+            running: running.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_packet;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn json_and_binary_packets() {
+        let sender: SocketAddr = "192.168.1.20:5000".parse().unwrap();
+        let packet = json!({
+            "source": "esp32-kitchen",
+            "address": "CB:B8:33:4C:88:4F",
+            "data": "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F",
+        });
+        let advertisement = parse_packet(packet.to_string().as_bytes(), &sender).unwrap();
+        assert_eq!(advertisement.source, "esp32-kitchen");
+        assert_eq!(advertisement.address.to_string(), "CB:B8:33:4C:88:4F");
+        assert_eq!(advertisement.data[..3], [0x99, 0x04, 0x05]);
+
+        let mut packet = vec![0xcb, 0xb8, 0x33, 0x4c, 0x88, 0x4f];
+        packet.extend(&advertisement.data);
+        let binary = parse_packet(&packet, &sender).unwrap();
+        assert_eq!(binary.source, "192.168.1.20");
+        assert_eq!(binary.address.to_string(), "CB:B8:33:4C:88:4F");
+        assert_eq!(binary.data, advertisement.data);
+
+        assert!(parse_packet(br#"{"address": "invalid"}"#, &sender).is_none());
+    }
+}
+
+// eof
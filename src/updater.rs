@@ -0,0 +1,465 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use crate::configfile::{UpdateChannel, UpdateConfig};
+use crate::iotcore::payload_hash;
+
+// downloads larger than this are not release binaries of the gateway
+const MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+const SIGNATURE_LENGTH: usize = 64;
+const PUBLIC_KEY_LENGTH: usize = 32;
+
+fn download(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, Report> {
+    trace!("in download");
+    let result = agent.get(url).call().map_err(|error| error.to_string());
+    let mut body = Vec::new();
+    let result = result.and_then(|response| {
+        response
+            .into_reader()
+            .take(MAX_DOWNLOAD_SIZE + 1)
+            .read_to_end(&mut body)
+            .map_err(|error| error.to_string())
+    });
+    let result = result.and_then(|length| {
+        if length as u64 > MAX_DOWNLOAD_SIZE {
+            Err(format!("larger than {} bytes", MAX_DOWNLOAD_SIZE))
+        } else {
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) => Ok(body),
+        Err(reason) => {
+            let url = url.to_string();
+            Err(eyre!("Unable to download update")
+                .with_section(move || url.header("URL:"))
+                .with_section(move || reason.header("Reason:")))
+        }
+    }
+}
+
+/// Release of the gateway software in a channel of the release manifest.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Release {
+    pub version: String,
+    pub url: String,
+    signature_url: Option<String>,
+    rollout: Option<u64>,
+}
+
+impl Release {
+    /// URL of the signature, the URL of the binary with .sig appended if not set
+    pub fn signature_url(&self) -> String {
+        self.signature_url
+            .clone()
+            .unwrap_or_else(|| format!("{}.sig", self.url))
+    }
+
+    /// Percentage of gateways the release is rolled out to
+    pub fn rollout(&self) -> u64 {
+        self.rollout.unwrap_or(100).min(100)
+    }
+
+    /// Whether the gateway is among the gateways the release is rolled out to. Gateways are
+    /// picked by a hash of their id and the version, so that the same gateways are not the first
+    /// ones to receive every release.
+    pub fn rolled_out_to(&self, device_id: &str) -> bool {
+        let hash = payload_hash(&format!("{}/{}", device_id, self.version));
+        u64::from_str_radix(&hash, 16).unwrap() % 100 < self.rollout()
+    }
+}
+
+// latest release of each channel
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    stable: Option<Release>,
+    beta: Option<Release>,
+}
+
+// numeric parts of a version without trailing zeros, and whether it is not a pre-release
+fn version_key(version: &str) -> (Vec<u64>, bool) {
+    let mut parts = version.trim().trim_start_matches('v').splitn(2, '-');
+    let mut numbers: Vec<u64> = parts
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|number| number.parse().unwrap_or(0))
+        .collect();
+    while numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+    (numbers, parts.next().is_none())
+}
+
+/// Whether the version is newer than the current one, a pre-release is older than the release
+/// of the same version.
+pub fn is_newer(version: &str, current: &str) -> bool {
+    version_key(version) > version_key(current)
+}
+
+/// Versions of the gateway software, reported in the state of the gateway after each check of
+/// the release manifest.
+#[derive(Debug, Serialize, Clone)]
+pub struct SoftwareState {
+    pub current: String,
+    pub channel: UpdateChannel,
+    /// Version in the channel when it is newer than the current one
+    pub available: Option<String>,
+    /// The available version is rolled out to the gateway
+    pub rolled_out: bool,
+    pub checked: chrono::DateTime<chrono::Utc>,
+}
+
+impl SoftwareState {
+    pub fn payload(&self) -> serde_json::Value {
+        json!({ "software": self })
+    }
+}
+
+/// Result of a check of the release manifest.
+#[derive(Debug, Clone)]
+pub struct ReleaseCheck {
+    pub state: SoftwareState,
+    /// Newer release rolled out to the gateway
+    pub release: Option<Release>,
+}
+
+fn agent(config: &UpdateConfig) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout()))
+        .build()
+}
+
+// release of the configured channel in the release manifest
+fn fetch_release(
+    agent: &ureq::Agent,
+    manifest_url: &str,
+    channel: UpdateChannel,
+) -> Result<Option<Release>, Report> {
+    trace!("in fetch_release");
+    let manifest: Manifest = match serde_json::from_slice(&download(agent, manifest_url)?) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            let manifest_url = manifest_url.to_string();
+            return Err(eyre!("Invalid release manifest")
+                .with_section(move || manifest_url.header("URL:"))
+                .with_section(move || error.to_string().header("Reason:")));
+        }
+    };
+    Ok(match channel {
+        UpdateChannel::STABLE => manifest.stable,
+        UpdateChannel::BETA => manifest.beta,
+    })
+}
+
+/// Checks the release manifest for a release newer than the running version.
+pub fn check(config: &UpdateConfig, device_id: &str) -> Result<ReleaseCheck, Report> {
+    trace!("in check");
+    let manifest_url = match config.manifest_url() {
+        Some(manifest_url) => manifest_url,
+        None => return Err(eyre!("No release manifest configured")),
+    };
+    let current = env!("CARGO_PKG_VERSION");
+    let release = fetch_release(&agent(config), &manifest_url, config.channel())?
+        .filter(|release| is_newer(&release.version, current));
+    let rolled_out = matches!(&release, Some(release) if release.rolled_out_to(device_id));
+    Ok(ReleaseCheck {
+        state: SoftwareState {
+            current: current.to_string(),
+            channel: config.channel(),
+            available: release.as_ref().map(|release| release.version.clone()),
+            rolled_out,
+            checked: chrono::Utc::now(),
+        },
+        release: release.filter(|_| rolled_out),
+    })
+}
+
+/// Checks the release manifest every check interval in a thread of its own until the receiver
+/// is dropped. Nothing is checked unless a manifest URL is configured.
+pub fn start_checker(
+    config: &UpdateConfig,
+    device_id: &str,
+) -> Option<channel::Receiver<ReleaseCheck>> {
+    trace!("in start_checker");
+    let manifest_url = config.manifest_url()?;
+    info!(
+        "Checking {:?} releases in {} every {} seconds",
+        config.channel(),
+        manifest_url,
+        config.check_interval()
+    );
+    let (sender, receiver) = channel::bounded(1);
+    let config = config.clone();
+    let device_id = device_id.to_string();
+    thread::spawn(move || loop {
+        match check(&config, &device_id) {
+            Ok(release_check) => {
+                if sender.send(release_check).is_err() {
+                    break;
+                }
+            }
+            Err(error) => warn!("Unable to check for updates: {}", error),
+        }
+        thread::sleep(Duration::from_secs(config.check_interval()));
+    });
+    Some(receiver)
+}
+
+// signatures are accepted raw as written by openssl pkeyutl or base64 encoded
+fn parse_signature(signature: &[u8]) -> Result<Vec<u8>, Report> {
+    if signature.len() == SIGNATURE_LENGTH {
+        return Ok(signature.to_vec());
+    }
+    match base64::decode(String::from_utf8_lossy(signature).trim()) {
+        Ok(signature) if signature.len() == SIGNATURE_LENGTH => Ok(signature),
+        _ => Err(eyre!("Invalid update signature")
+            .with_section(|| "not a raw or base64 encoded Ed25519 signature".header("Reason:"))),
+    }
+}
+
+fn parse_public_key(public_key: &str) -> Result<Vec<u8>, Report> {
+    match base64::decode(public_key.trim()) {
+        Ok(public_key) if public_key.len() == PUBLIC_KEY_LENGTH => Ok(public_key),
+        _ => Err(eyre!("Invalid update public key")
+            .with_section(|| "not a base64 encoded raw Ed25519 public key".header("Reason:"))),
+    }
+}
+
+/// Message the signature of a release in the manifest covers, its version followed by the binary.
+/// The manifest itself is not signed, so signing the version along with the binary keeps the
+/// manifest from offering an older signed binary as a newer release.
+pub fn signed_release(version: &str, binary: &[u8]) -> Vec<u8> {
+    let mut message = format!("ruuvi2iotcore {}\n", version.trim()).into_bytes();
+    message.extend_from_slice(binary);
+    message
+}
+
+/// Verifies the Ed25519 signature of the binary against the public key.
+pub fn verify(public_key: &[u8], binary: &[u8], signature: &[u8]) -> Result<(), Report> {
+    trace!("in verify");
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(binary, signature)
+        .map_err(|_| {
+            eyre!("Update signature does not match")
+                .with_section(|| "binary was not signed with the pinned key".header("Reason:"))
+        })
+}
+
+// writes the binary next to the executable and renames it over the executable, so that the
+//  executable is never left half written. the running process keeps the old file open
+fn install(executable: &Path, binary: &[u8]) -> Result<(), Report> {
+    trace!("in install");
+    let staged = executable.with_extension("update");
+    let result = fs::write(&staged, binary)
+        .and_then(|_| fs::metadata(executable))
+        .and_then(|metadata| fs::set_permissions(&staged, metadata.permissions()))
+        .and_then(|_| fs::File::open(&staged)?.sync_all())
+        .and_then(|_| fs::rename(&staged, executable));
+    match result {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            fs::remove_file(&staged).ok();
+            let executable = executable.display().to_string();
+            Err(eyre!("Unable to replace executable")
+                .with_section(move || executable.header("Executable:"))
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    }
+}
+
+/// Downloads the release, or the newer release in the channel of the release manifest, or the
+/// binary and version configured in the "update" section, verifies the signature of the version
+/// and binary against the pinned public key and replaces the running executable with it. The new
+/// version runs once the gateway is restarted.
+pub fn update(config: &UpdateConfig, release: Option<&Release>) -> Result<(), Report> {
+    trace!("in update");
+    let public_key = match config.public_key() {
+        Some(public_key) => parse_public_key(&public_key)?,
+        // unsigned binaries are never installed
+        None => return Err(eyre!("No update public key configured")),
+    };
+    let agent = agent(config);
+    let current = env!("CARGO_PKG_VERSION");
+    let release = match (release, config.manifest_url()) {
+        (Some(release), _) => release.clone(),
+        (None, Some(manifest_url)) => {
+            match fetch_release(&agent, &manifest_url, config.channel())? {
+                Some(release) => release,
+                None => {
+                    return Err(eyre!("No newer release available")
+                        .with_section(move || current.header("Current version:")))
+                }
+            }
+        }
+        (None, None) => match (config.url(), config.version()) {
+            // the configured binary is signed with its version like the releases in a manifest
+            (Some(url), Some(version)) => Release {
+                version,
+                url,
+                signature_url: config.signature_url(),
+                rollout: None,
+            },
+            (Some(url), None) => {
+                return Err(
+                    eyre!("No update version configured").with_section(move || url.header("URL:"))
+                )
+            }
+            (None, _) => return Err(eyre!("No update URL configured")),
+        },
+    };
+    // the version of a release is signed along with its binary, so a release that is not newer
+    //  is never installed and one that only claims to be newer fails the signature check
+    if !is_newer(&release.version, current) {
+        let version = release.version.clone();
+        return Err(eyre!("No newer release available")
+            .with_section(move || current.header("Current version:"))
+            .with_section(move || version.header("Release version:")));
+    }
+    let executable = match std::env::current_exe() {
+        Ok(executable) => executable,
+        Err(error) => {
+            return Err(eyre!("Unable to locate executable")
+                .with_section(move || error.to_string().header("Reason:")))
+        }
+    };
+    info!("Downloading update from {}", release.url);
+    let binary = download(&agent, &release.url)?;
+    let signature = parse_signature(&download(&agent, &release.signature_url())?)?;
+    verify(
+        &public_key,
+        &signed_release(&release.version, &binary),
+        &signature,
+    )?;
+    install(&executable, &binary)?;
+    info!(
+        "Installed update of {} bytes to {}",
+        binary.len(),
+        executable.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check, install, is_newer, parse_public_key, parse_signature, signed_release, update,
+        verify, Release,
+    };
+    use crate::configfile::UpdateConfig;
+    use crate::mock::{serve, temp_path};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::fs;
+
+    #[test]
+    fn release_manifest_is_checked() {
+        assert!(is_newer("1.2.0", "1.1.9"));
+        assert!(is_newer("v1.10", "1.9.3"));
+        assert!(is_newer("1.2.0", "1.2.0-beta.1"));
+        assert!(!is_newer("1.2", "1.2.0"));
+        assert!(!is_newer("1.2.0-beta.2", "1.2.0"));
+
+        let release = |rollout: u64| -> Release {
+            serde_json::from_value(json!({
+                "version": "99.0.0",
+                "url": "https://releases.example.com/ruuvi2iotcore",
+                "rollout": rollout,
+            }))
+            .unwrap()
+        };
+        assert_eq!(
+            release(100).signature_url(),
+            "https://releases.example.com/ruuvi2iotcore.sig"
+        );
+        assert!(!release(0).rolled_out_to("gateway-1"));
+        assert!(release(100).rolled_out_to("gateway-1"));
+        let rolled_out = (0..1000)
+            .filter(|gateway| release(25).rolled_out_to(&format!("gateway-{}", gateway)))
+            .count();
+        assert!(rolled_out > 150 && rolled_out < 350);
+
+        let manifest = json!({
+            "stable": { "version": "0.0.1", "url": "https://releases.example.com/0.0.1" },
+            "beta": { "version": "99.0.0-beta.1", "url": "https://releases.example.com/99" },
+        })
+        .to_string();
+        let (url, _requests) = serve(vec![(200, manifest.clone()), (200, manifest)]);
+        let manifest_url = format!("{}/manifest.json", url);
+
+        let config = |channel: &str| -> UpdateConfig {
+            serde_json::from_value(json!({ "manifest_url": manifest_url, "channel": channel }))
+                .unwrap()
+        };
+        // stable release is older than the running version
+        let stable = check(&config("stable"), "gateway-1").unwrap();
+        assert_eq!(stable.state.current, env!("CARGO_PKG_VERSION"));
+        assert_eq!(stable.state.available, None);
+        assert!(stable.release.is_none());
+        let beta = check(&config("beta"), "gateway-1").unwrap();
+        assert_eq!(beta.state.available.as_deref(), Some("99.0.0-beta.1"));
+        assert!(beta.state.rolled_out);
+        assert_eq!(beta.state.payload()["software"]["channel"], "beta");
+        assert_eq!(beta.release.unwrap().url, "https://releases.example.com/99");
+    }
+
+    #[test]
+    fn update_is_verified_and_installed() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let keypair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = parse_public_key(&base64::encode(keypair.public_key())).unwrap();
+        assert!(parse_public_key("c2hvcnQ=").is_err());
+
+        let binary = b"new version".to_vec();
+        let signature = keypair.sign(&binary);
+        let raw = parse_signature(signature.as_ref()).unwrap();
+        let encoded = parse_signature(format!("{}\n", base64::encode(&raw)).as_bytes()).unwrap();
+        assert_eq!(raw, encoded);
+        verify(&public_key, &binary, &raw).unwrap();
+        assert!(verify(&public_key, b"tampered version", &raw).is_err());
+
+        // releases in the manifest are signed with their version, so an older signed binary
+        //  can not be passed off as a newer release
+        let release = keypair.sign(&signed_release("0.9.0", &binary));
+        verify(
+            &public_key,
+            &signed_release("0.9.0", &binary),
+            release.as_ref(),
+        )
+        .unwrap();
+        assert!(verify(
+            &public_key,
+            &signed_release("99.0.0", &binary),
+            release.as_ref()
+        )
+        .is_err());
+        assert!(verify(&public_key, &signed_release("0.9.0", &binary), &raw).is_err());
+
+        // a configured binary without a version to check its signature against is not installed
+        let config: UpdateConfig = serde_json::from_value(json!({
+            "url": "http://127.0.0.1:9/ruuvi2iotcore",
+            "public_key": base64::encode(keypair.public_key()),
+        }))
+        .unwrap();
+        let error = update(&config, None).unwrap_err();
+        assert_eq!(error.to_string(), "No update version configured");
+
+        let directory = temp_path("update");
+        fs::create_dir_all(&directory).unwrap();
+        let executable = directory.join("ruuvi2iotcore");
+        fs::write(&executable, b"old version").unwrap();
+        install(&executable, &binary).unwrap();
+        assert_eq!(fs::read(&executable).unwrap(), binary);
+        assert!(!directory.join("ruuvi2iotcore.update").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
+
+// eof
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::scanner::{RuuviBluetoothBeacon, SharedBeacon};
+
+// last published tag sequence of each tag, so that beacons are not published twice and tag
+//  sequences continue where they left off after a restart. kept only in memory unless a file is
+//  given
+pub struct Watermarks {
+    file: Option<PathBuf>,
+    published: HashMap<String, u64>,
+}
+
+impl Watermarks {
+    fn save(&self) {
+        trace!("in save");
+        if let Some(file) = &self.file {
+            // written without syncing, the page cache is flushed by the operating system in its
+            //  own pace which keeps writes to flash storage down
+            if let Err(error) = fs::write(file, serde_json::to_string(&self.published).unwrap()) {
+                warn!(
+                    "Unable to save published watermarks to {}: {}",
+                    file.display(),
+                    error
+                );
+            }
+        }
+    }
+
+    // last published tag sequence of the tag, zero if none has been published
+    pub fn get(&self, address: &str) -> u64 {
+        self.published.get(address).copied().unwrap_or(0)
+    }
+
+    pub fn is_published(&self, beacon: &RuuviBluetoothBeacon) -> bool {
+        match beacon.tag_sequence {
+            Some(tag_sequence) => tag_sequence <= self.get(&beacon.address),
+            None => false,
+        }
+    }
+
+    // raises the watermarks to the published beacons
+    pub fn update(&mut self, beacons: &[SharedBeacon]) {
+        let mut changed = false;
+        for beacon in beacons {
+            if let Some(tag_sequence) = beacon.tag_sequence {
+                let watermark = self.published.entry(beacon.address.clone()).or_insert(0);
+                if tag_sequence > *watermark {
+                    *watermark = tag_sequence;
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    pub fn build(file: Option<PathBuf>) -> Watermarks {
+        trace!("in build");
+        let published = match &file {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(json) => serde_json::from_str(&json).unwrap_or_else(|error| {
+                    warn!(
+                        "Ignoring published watermarks in {}: {}",
+                        file.display(),
+                        error
+                    );
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            },
+            None => HashMap::new(),
+        };
+        Watermarks { file, published }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watermarks;
+    use crate::mock::{beacon, temp_path};
+    use std::fs;
+    use std::sync::Arc;
+
+    #[test]
+    fn watermarks_survive_restart() {
+        let file = temp_path("watermarks.json");
+        let mut first = beacon("AA:BB:CC:DD:EE:01", 1);
+        first.tag_sequence = Some(41);
+        let mut second = first.clone();
+        second.tag_sequence = Some(42);
+        let mut other = beacon("AA:BB:CC:DD:EE:02", 2);
+        other.tag_sequence = Some(7);
+
+        let mut watermarks = Watermarks::build(Some(file.clone()));
+        watermarks.update(&[
+            Arc::new(second.clone()),
+            Arc::new(first.clone()),
+            Arc::new(other),
+        ]);
+        assert_eq!(watermarks.get("AA:BB:CC:DD:EE:01"), 42);
+
+        let watermarks = Watermarks::build(Some(file.clone()));
+        assert!(watermarks.is_published(&first));
+        assert!(watermarks.is_published(&second));
+        second.tag_sequence = Some(43);
+        assert!(!watermarks.is_published(&second));
+        assert_eq!(watermarks.get("AA:BB:CC:DD:EE:02"), 7);
+        assert_eq!(watermarks.get("AA:BB:CC:DD:EE:03"), 0);
+        fs::remove_file(file).unwrap();
+    }
+}
+
+// eof
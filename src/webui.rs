@@ -0,0 +1,175 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use crossbeam::channel;
+use ring::constant_time;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::configfile::WebUiConfig;
+use crate::iotcore::{CNCCommand, CNCCommandMessage};
+use crate::status::SharedStatus;
+
+const INDEX_HTML: &str = include_str!("webui.html");
+
+pub struct WebUi {
+    config: WebUiConfig,
+    status: SharedStatus,
+    command_sender: channel::Sender<CNCCommandMessage>,
+    running: Arc<AtomicBool>,
+}
+
+impl WebUi {
+    fn respond(request: Request, status_code: u16, content_type: &str, body: String) {
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+        let response = Response::from_string(body)
+            .with_status_code(status_code)
+            .with_header(header);
+        if let Err(error) = request.respond(response) {
+            warn!("Unable to respond to web dashboard request: {}", error);
+        }
+    }
+
+    fn send_command(&self, command: CNCCommand) -> bool {
+        trace!("in send_command");
+        match self.command_sender.send(CNCCommandMessage {
+            command,
+            duty_cycle: None,
+        }) {
+            Ok(_) => true,
+            Err(error) => {
+                error!("Unable to relay command from web dashboard: {}", error);
+                false
+            }
+        }
+    }
+
+    // POST requests change the state of the gateway and need the bearer token when one is set
+    fn authorized(&self, request: &Request) -> bool {
+        let token = match self.config.token() {
+            Some(token) => token,
+            None => return true,
+        };
+        let expected = format!("Bearer {}", token);
+        request.headers().iter().any(|header| {
+            header.field.equiv("Authorization")
+                && constant_time::verify_slices_are_equal(
+                    header.value.as_str().as_bytes(),
+                    expected.as_bytes(),
+                )
+                .is_ok()
+        })
+    }
+
+    fn handle_request(&self, request: Request) {
+        trace!("in handle_request");
+        debug!(
+            "web dashboard request: {} {}",
+            request.method(),
+            request.url()
+        );
+        if request.method() == &Method::Post && !self.authorized(&request) {
+            warn!("Unauthorized web dashboard request: {}", request.url());
+            return WebUi::respond(
+                request,
+                401,
+                "application/json",
+                json!({ "accepted": false }).to_string(),
+            );
+        }
+        match (request.method(), request.url()) {
+            (Method::Get, "/") => {
+                WebUi::respond(request, 200, "text/html", INDEX_HTML.to_string());
+            }
+            (Method::Get, "/api/status") => {
+                let status = self.status.lock().unwrap().clone();
+                WebUi::respond(
+                    request,
+                    200,
+                    "application/json",
+                    serde_json::to_string(&status).unwrap(),
+                );
+            }
+            (Method::Post, "/api/collect") => {
+                info!("Web dashboard requested COLLECT beacons");
+                let accepted = self.send_command(CNCCommand::COLLECT);
+                WebUi::respond(
+                    request,
+                    if accepted { 202 } else { 503 },
+                    "application/json",
+                    json!({ "accepted": accepted }).to_string(),
+                );
+            }
+            (Method::Post, "/api/pause") => {
+                warn!("Web dashboard requested PAUSE collecting beacons");
+                let accepted = self.send_command(CNCCommand::PAUSE);
+                WebUi::respond(
+                    request,
+                    if accepted { 202 } else { 503 },
+                    "application/json",
+                    json!({ "accepted": accepted }).to_string(),
+                );
+            }
+            _ => WebUi::respond(request, 404, "text/plain", "Not found".to_string()),
+        }
+    }
+
+    pub fn start_server(&self) -> Result<(), Report> {
+        trace!("in start_server");
+        let port = match self.config.port() {
+            Some(port) => port,
+            None => {
+                debug!("Web dashboard not enabled.");
+                return Ok(());
+            }
+        };
+        let address = format!("{}:{}", self.config.address(), port);
+        let server = match Server::http(&address) {
+            Ok(server) => server,
+            Err(error) => {
+                return Err(eyre!("Unable to start web dashboard")
+                    .with_section(move || address.header("Listen address:"))
+                    .with_section(move || error.to_string().header("Reason:")))
+            }
+        };
+        info!("Web dashboard listening on http://{}", address);
+        let loopback = match self.config.address().parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback(),
+            Err(_) => self.config.address() == "localhost",
+        };
+        if self.config.token().is_none() && !loopback {
+            warn!(
+                "Web dashboard accepts pause and collect commands without a token from the network"
+            );
+        }
+
+        while self.running.load(Ordering::Relaxed) {
+            match server.recv_timeout(Duration::from_millis(100)) {
+                Ok(Some(request)) => self.handle_request(request),
+                Ok(None) => {}
+                Err(error) => error!("Error while receiving web dashboard request: {}", error),
+            }
+        }
+        info!("Shutting down web dashboard.");
+
+        Ok(())
+    }
+
+    pub fn build(
+        config: &WebUiConfig,
+        status: &SharedStatus,
+        command_sender: &channel::Sender<CNCCommandMessage>,
+        running: &Arc<AtomicBool>,
+    ) -> WebUi {
+        trace!("in build");
+        WebUi {
+            config: config.clone(),
+            status: status.clone(),
+            command_sender: command_sender.clone(),
+            running: running.clone(),
+        }
+    }
+}
+
+// eof
@@ -0,0 +1,171 @@
+use color_eyre::{eyre::eyre, eyre::Report, Section, SectionExt};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::alerts::{Alert, AlertState, Notifier};
+use crate::configfile::ZabbixConfig;
+
+// packets of the Zabbix protocol start with this, followed by the length of the data
+const HEADER: &[u8] = b"ZBXD\x01";
+
+fn packet(data: &serde_json::Value) -> Vec<u8> {
+    let data = data.to_string().into_bytes();
+    let mut packet = HEADER.to_vec();
+    packet.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    packet.extend(data);
+    packet
+}
+
+/// Notifier sending alerts to a Zabbix server like zabbix_sender does, as value 1 of a trapper
+/// item of the tag and metric when the alert is triggered and 0 when it is cleared.
+pub struct ZabbixNotifier {
+    server: String,
+    port: u16,
+    host: String,
+    key: String,
+    timeout: Duration,
+}
+
+impl ZabbixNotifier {
+    // sends the request and returns the response of the server
+    fn send(&self, request: &serde_json::Value) -> Result<serde_json::Value, String> {
+        trace!("in send");
+        let address = match (self.server.as_str(), self.port).to_socket_addrs() {
+            Ok(mut addresses) => match addresses.next() {
+                Some(address) => address,
+                None => return Err(format!("unable to resolve {}", self.server)),
+            },
+            Err(error) => return Err(error.to_string()),
+        };
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)
+            .map_err(|error| error.to_string())?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .and_then(|_| stream.write_all(&packet(request)))
+            .map_err(|error| error.to_string())?;
+        let mut header = [0; 13];
+        stream
+            .read_exact(&mut header)
+            .map_err(|error| error.to_string())?;
+        if &header[..5] != HEADER {
+            return Err("unexpected response from the server".to_string());
+        }
+        let length = u64::from_le_bytes(header[5..].try_into().unwrap()) as usize;
+        let mut response = vec![0; length];
+        stream
+            .read_exact(&mut response)
+            .map_err(|error| error.to_string())?;
+        serde_json::from_slice(&response).map_err(|error| error.to_string())
+    }
+
+    pub fn build(config: &ZabbixConfig, device_id: &str) -> ZabbixNotifier {
+        trace!("in build");
+        ZabbixNotifier {
+            server: config.server(),
+            port: config.port(),
+            host: config.host().unwrap_or_else(|| device_id.to_string()),
+            key: config.key(),
+            timeout: Duration::from_secs(config.timeout()),
+        }
+    }
+}
+
+impl Notifier for ZabbixNotifier {
+    fn name(&self) -> String {
+        format!("Zabbix server {}:{}", self.server, self.port)
+    }
+
+    fn notify(&mut self, alert: &Alert) -> Result<(), Report> {
+        trace!("in notify");
+        let key = self
+            .key
+            .replace("{address}", &alert.address)
+            .replace("{metric}", &alert.metric);
+        let request = json!({
+            "request": "sender data",
+            "data": [{
+                "host": self.host,
+                "key": key,
+                "value": if alert.state == AlertState::TRIGGERED { "1" } else { "0" },
+                "clock": alert.timestamp.timestamp(),
+            }],
+        });
+        // the server tells how many of the values it processed, values of items that do not
+        //  exist fail
+        let result = self.send(&request).and_then(|response| {
+            let info = response["info"].as_str().unwrap_or_default().to_string();
+            if response["response"] == "success" && info.contains("failed: 0") {
+                Ok(())
+            } else {
+                Err(info)
+            }
+        });
+        result.map_err(|reason| {
+            eyre!("Unable to send alert to Zabbix")
+                .with_section(move || key.header("Item key:"))
+                .with_section(move || reason.header("Reason:"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{packet, ZabbixNotifier, HEADER};
+    use crate::alerts::{Alerts, Notifier};
+    use crate::configfile::{AlertsConfig, ZabbixConfig};
+    use crate::mock::{beacon, serve_lines};
+    use std::convert::TryInto;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn zabbix_notifier_sends_items() {
+        let mut infos = vec![
+            "processed: 1; failed: 0; total: 1",
+            "processed: 0; failed: 1; total: 1",
+        ]
+        .into_iter();
+        let (address, server) = serve_lines(2, move |stream| {
+            let mut header = [0; 13];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(&header[..5], HEADER);
+            let mut request = vec![0; u64::from_le_bytes(header[5..].try_into().unwrap()) as usize];
+            stream.read_exact(&mut request).unwrap();
+            let info = infos.next().unwrap();
+            stream
+                .get_mut()
+                .write_all(&packet(&json!({ "response": "success", "info": info })))
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&request).unwrap()
+        });
+        let port = address.port();
+
+        let config: ZabbixConfig =
+            serde_json::from_value(json!({ "server": "127.0.0.1", "port": port })).unwrap();
+        let mut notifier = ZabbixNotifier::build(&config, "test-gateway");
+        let alerts_config: AlertsConfig = serde_json::from_value(json!({
+            "rules": [{ "metric": "temperature", "operator": "below", "threshold": 25.0 }],
+        }))
+        .unwrap();
+        let alert = Alerts::build(&alerts_config, "test-gateway")
+            .unwrap()
+            .check(&beacon("AA:BB:CC:DD:EE:01", 1))
+            .remove(0);
+        notifier.notify(&alert).unwrap();
+        // the item does not exist in Zabbix
+        assert!(notifier.notify(&alert).is_err());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0]["request"], "sender data");
+        assert_eq!(requests[0]["data"][0]["host"], "test-gateway");
+        assert_eq!(
+            requests[0]["data"][0]["key"],
+            "ruuvi.alert[AA:BB:CC:DD:EE:01,temperature]"
+        );
+        assert_eq!(requests[0]["data"][0]["value"], "1");
+    }
+}
+
+// eof